@@ -0,0 +1,139 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmarks of the hot paths of interpreting SMTP traffic, i.e. parsing of
+//! commands and replies and scanning of mail data for its end.
+
+use std::convert::TryFrom;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+use envoy_smtp_filter::smtp::agent::{CommandRegistry, Mode, Session, SessionConfig};
+use envoy_smtp_filter::smtp::spec::core::ReplyLine;
+
+const COMMANDS: &[&[u8]] = &[
+    b"EHLO client.example.org",
+    b"MAIL FROM:<alice@example.org> SIZE=102400 BODY=8BITMIME",
+    b"RCPT TO:<bob@example.com> NOTIFY=SUCCESS,FAILURE",
+    b"RCPT TO:<carol@example.net>",
+    b"DATA",
+    b"RSET",
+    b"NOOP",
+    b"QUIT",
+];
+
+const REPLIES: &[&[u8]] = &[
+    b"220 mail.example.org ESMTP Postfix",
+    b"250-mail.example.org",
+    b"250-PIPELINING",
+    b"250-SIZE 10240000",
+    b"250 8BITMIME",
+    b"250 2.1.0 Ok",
+    b"550 5.1.1 <nobody@example.com>: Recipient address rejected: User unknown",
+    b"354 End data with <CR><LF>.<CR><LF>",
+];
+
+// Size of a chunk of data `Envoy` passes to a filter at once.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+fn parse_commands(c: &mut Criterion) {
+    // commands are parsed by the registry a session has been created with
+    let commands = CommandRegistry::default();
+    let mut group = c.benchmark_group("commands");
+    group.throughput(Throughput::Elements(COMMANDS.len() as u64));
+    group.bench_function("parse", |b| {
+        b.iter(|| {
+            for line in COMMANDS {
+                commands.parse(line.to_vec()).unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+fn parse_replies(c: &mut Criterion) {
+    let mut group = c.benchmark_group("replies");
+    group.throughput(Throughput::Elements(REPLIES.len() as u64));
+    group.bench_function("parse", |b| {
+        b.iter(|| {
+            for line in REPLIES {
+                ReplyLine::try_from(line.to_vec()).unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+// Returns a session that is about to receive mail data.
+fn session_in_data_mode() -> Session<()> {
+    let mut session = Session::new(SessionConfig::default(), ());
+    session.on_new_conection().unwrap();
+    session
+        .on_upstream_data(b"220 mail.example.org ESMTP\r\n".to_vec().into())
+        .unwrap();
+    for (command, reply) in [
+        (
+            &b"EHLO client.example.org\r\n"[..],
+            &b"250 mail.example.org\r\n"[..],
+        ),
+        (b"MAIL FROM:<alice@example.org>\r\n", b"250 Ok\r\n"),
+        (b"RCPT TO:<bob@example.com>\r\n", b"250 Ok\r\n"),
+        (b"DATA\r\n", b"354 Go ahead\r\n"),
+    ] {
+        session.on_downstream_data(command.to_vec().into()).unwrap();
+        session.on_upstream_data(reply.to_vec().into()).unwrap();
+    }
+    assert_eq!(session.mode(), Mode::Data);
+    session
+}
+
+// Returns a message of about a given size with lines of typical length.
+fn message(size: usize) -> Vec<u8> {
+    let mut message =
+        b"From: alice@example.org\r\nTo: bob@example.com\r\nSubject: Hello\r\n\r\n".to_vec();
+    let line = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod.\r\n";
+    while message.len() < size {
+        message.extend_from_slice(line);
+    }
+    message.extend_from_slice(b".\r\n");
+    message
+}
+
+fn scan_data(c: &mut Criterion) {
+    let mut group = c.benchmark_group("data");
+    for (name, size) in [
+        ("4KiB", 4 * 1024),
+        ("64KiB", 64 * 1024),
+        ("1MiB", 1024 * 1024),
+    ] {
+        let message = message(size);
+        group.throughput(Throughput::Bytes(message.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                session_in_data_mode,
+                |mut session| {
+                    for chunk in message.chunks(CHUNK_SIZE) {
+                        session.on_downstream_data(chunk.to_vec().into()).unwrap();
+                    }
+                    session
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse_commands, parse_replies, scan_data);
+criterion_main!(benches);
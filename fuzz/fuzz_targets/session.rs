@@ -0,0 +1,51 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![no_main]
+
+//! Feeds a transcript of an SMTP session through the `Session` state machine.
+//!
+//! Every line of the input that starts with `S:` is sent upstream, i.e. as
+//! if it was received from the server; every other line, with or without
+//! the `C:` prefix, is sent downstream, i.e. as if it was received from the
+//! client. Line endings are passed through as is, so a single chunk of data
+//! may contain several commands, an incomplete one, or a bare `LF`.
+
+use libfuzzer_sys::fuzz_target;
+
+use envoy_smtp_filter::smtp::agent::{Session, SessionConfig};
+
+fuzz_target!(|transcript: &[u8]| {
+    let mut session = Session::new(SessionConfig::default(), ());
+    if session.on_new_conection().is_err() {
+        return;
+    }
+    for chunk in transcript.split_inclusive(|&b| b == b'\n') {
+        let result = match chunk {
+            [b'S', b':', data @ ..] => session.on_upstream_data(data.to_vec().into()),
+            [b'C', b':', data @ ..] => session.on_downstream_data(data.to_vec().into()),
+            data => session.on_downstream_data(data.to_vec().into()),
+        };
+        session.take_downstream_edits();
+        session.take_upstream_edits();
+        session.take_events();
+        if result.is_err() || session.take_close_request() {
+            break;
+        }
+    }
+    let _ = session
+        .on_downstream_end_of_stream()
+        .and_then(|_| session.on_upstream_end_of_stream())
+        .and_then(|_| session.on_connection_closed());
+});
@@ -0,0 +1,304 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Aggregation of SMTP events across connections.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use envoy::extension::{factory, ConfigStatus, Result, Service};
+use envoy::host::shared_queue::SharedQueueHandle;
+use envoy::host::stats::{Counter, Gauge};
+use envoy::host::{log, ByteString, SharedQueue, Stats};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::config::AggregatorConfig;
+
+/// Maximum number of distinct senders counted between flushes, which keeps
+/// the memory of the service bounded.
+const MAX_SENDERS: usize = 10_000;
+
+/// Singleton service that drains the shared queue SMTP filter publishes events
+/// onto and periodically flushes aggregates of them to stats and logs,
+/// e.g. senders with the most transactions and ratios of failed transactions
+/// and commands.
+pub struct SmtpEventAggregator<'a> {
+    // Shared Queue API implementation.
+    shared_queue: &'a dyn SharedQueue,
+    config: AggregatorConfig,
+    // Handle of the queue once it has been registered.
+    queue: Option<SharedQueueHandle>,
+    // Aggregates since the latest flush.
+    aggregates: Aggregates,
+    transactions_total: Box<dyn Counter>,
+    transactions_failed_total: Box<dyn Counter>,
+    transactions_failure_ratio: Box<dyn Gauge>,
+    commands_total: Box<dyn Counter>,
+    commands_failed_total: Box<dyn Counter>,
+    commands_failure_ratio: Box<dyn Gauge>,
+}
+
+// Aggregates of events between flushes.
+#[derive(Default)]
+struct Aggregates {
+    transactions: u64,
+    failed_transactions: u64,
+    commands: u64,
+    failed_commands: u64,
+    // Number of transactions per sender.
+    senders: HashMap<String, u64>,
+}
+
+// Fields of published events the service aggregates.
+#[derive(Deserialize)]
+struct PublishedEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    from: Option<String>,
+    #[serde(default)]
+    reply_code: Option<String>,
+}
+
+impl<'a> SmtpEventAggregator<'a> {
+    /// Creates a new aggregator bound to given host APIs.
+    pub fn new(stats: &'a dyn Stats, shared_queue: &'a dyn SharedQueue) -> Result<Self> {
+        Ok(SmtpEventAggregator {
+            shared_queue,
+            config: AggregatorConfig::default(),
+            queue: None,
+            aggregates: Aggregates::default(),
+            transactions_total: stats.counter("smtp.aggregate.transactions.total")?,
+            transactions_failed_total: stats.counter("smtp.aggregate.transactions.failed.total")?,
+            transactions_failure_ratio: stats
+                .gauge("smtp.aggregate.transactions.failure_ratio_permille")?,
+            commands_total: stats.counter("smtp.aggregate.commands.total")?,
+            commands_failed_total: stats.counter("smtp.aggregate.commands.failed.total")?,
+            commands_failure_ratio: stats
+                .gauge("smtp.aggregate.commands.failure_ratio_permille")?,
+        })
+    }
+
+    /// Creates a new aggregator bound to the actual Envoy ABI.
+    #[allow(clippy::should_implement_trait)]
+    pub fn default() -> Result<Self> {
+        Self::new(<dyn Stats>::default(), <dyn SharedQueue>::default())
+    }
+
+    // Accounts a single event, ignoring malformed ones.
+    fn aggregate(&mut self, event: &[u8]) {
+        let event: PublishedEvent = match serde_json::from_slice(event) {
+            Ok(event) => event,
+            Err(err) => {
+                log::debug!("ignoring malformed event: {}", err);
+                return;
+            }
+        };
+        let failed = event
+            .reply_code
+            .as_deref()
+            .is_some_and(|code| code.starts_with(['4', '5']));
+        let aggregates = &mut self.aggregates;
+        match event.kind.as_str() {
+            "transaction" => {
+                aggregates.transactions += 1;
+                aggregates.failed_transactions += u64::from(failed);
+                if let Some(from) = event.from {
+                    if aggregates.senders.len() < MAX_SENDERS
+                        || aggregates.senders.contains_key(&from)
+                    {
+                        *aggregates.senders.entry(from).or_default() += 1;
+                    }
+                }
+            }
+            "command" => {
+                aggregates.commands += 1;
+                aggregates.failed_commands += u64::from(failed);
+            }
+            _ => {}
+        }
+    }
+
+    // Renders aggregates since the latest flush as JSON.
+    fn summary(&self) -> Value {
+        let aggregates = &self.aggregates;
+        let mut senders: Vec<_> = aggregates.senders.iter().collect();
+        senders.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        senders.truncate(self.config.top_senders);
+        json!({
+            "transactions": aggregates.transactions,
+            "failed_transactions": aggregates.failed_transactions,
+            "commands": aggregates.commands,
+            "failed_commands": aggregates.failed_commands,
+            "top_senders": senders
+                .into_iter()
+                .map(|(from, transactions)| json!({"from": from, "transactions": transactions}))
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    // Adds aggregates since the latest flush to stats, logs them and starts over.
+    fn flush(&mut self) -> Result<()> {
+        let aggregates = &self.aggregates;
+        if aggregates.transactions == 0 && aggregates.commands == 0 {
+            return Ok(());
+        }
+        log::info!("smtp aggregates {}", self.summary());
+        let aggregates = std::mem::take(&mut self.aggregates);
+        self.transactions_total.add(aggregates.transactions)?;
+        self.transactions_failed_total
+            .add(aggregates.failed_transactions)?;
+        self.commands_total.add(aggregates.commands)?;
+        self.commands_failed_total.add(aggregates.failed_commands)?;
+        if aggregates.transactions > 0 {
+            self.transactions_failure_ratio.set(permille(
+                aggregates.failed_transactions,
+                aggregates.transactions,
+            ))?;
+        }
+        if aggregates.commands > 0 {
+            self.commands_failure_ratio
+                .set(permille(aggregates.failed_commands, aggregates.commands))?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Service for SmtpEventAggregator<'a> {
+    /// The reference name for the SMTP event aggregator.
+    ///
+    /// This name appears in `Envoy` configuration as a value of `root_id` field.
+    fn name() -> &'static str {
+        "tetratelabs.services.smtp_aggregator"
+    }
+
+    /// Is called when `Envoy` starts the singleton service.
+    fn on_configure(
+        &mut self,
+        config: ByteString,
+        ops: &dyn factory::ConfigureOps,
+    ) -> Result<ConfigStatus> {
+        self.config = match AggregatorConfig::try_from(config.as_bytes()) {
+            Ok(config) => config,
+            Err(err) => {
+                log::error!("rejecting configuration: {}", err);
+                return Ok(ConfigStatus::Rejected);
+            }
+        };
+        self.queue = Some(self.shared_queue.register(&self.config.queue_name)?);
+        ops.set_tick_period(self.config.flush_interval())?;
+        Ok(ConfigStatus::Accepted)
+    }
+
+    /// Is called once per flush interval.
+    fn on_tick(&mut self, _ops: &dyn factory::TickOps) -> Result<()> {
+        self.flush()
+    }
+
+    /// Is called when SMTP filter has published events onto the queue.
+    fn on_queue_ready(&mut self, queue_id: SharedQueueHandle) -> Result<()> {
+        if self.queue != Some(queue_id) {
+            return Ok(());
+        }
+        while let Some(event) = self.shared_queue.dequeue(queue_id)? {
+            self.aggregate(event.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+// Returns a ratio in thousandths.
+fn permille(part: u64, total: u64) -> u64 {
+    part * 1000 / total
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::host::fake::FakeHost;
+
+    #[test]
+    fn should_aggregate_events_across_connections() {
+        let host = FakeHost::default();
+        let mut aggregator = SmtpEventAggregator::new(&host, &host).unwrap();
+        let config = ByteString::from(r#"{"flush_interval_ms": 1000, "top_senders": 1}"#);
+        assert_eq!(
+            aggregator.on_configure(config, &host).unwrap(),
+            ConfigStatus::Accepted
+        );
+        assert_eq!(host.tick_period(), Duration::from_secs(1));
+
+        // published by filter instances of different connections
+        let queue = host.lookup("", "smtp_events").unwrap().unwrap();
+        for event in [
+            &br#"{"type": "transaction", "from": "alice@example.org", "reply_code": "250"}"#[..],
+            br#"{"type": "command", "verb": "MAIL", "reply_code": "250"}"#,
+            br#"{"type": "transaction", "from": "bob@example.org", "reply_code": "550"}"#,
+            br#"{"type": "command", "verb": "RCPT", "reply_code": "450"}"#,
+            br#"{"type": "transaction", "from": "alice@example.org", "reply_code": "250"}"#,
+            b"not an event",
+        ] {
+            host.enqueue(queue, event).unwrap();
+        }
+        aggregator.on_queue_ready(queue).unwrap();
+        assert_eq!(host.dequeue(queue).unwrap(), None);
+        assert_eq!(
+            aggregator.summary(),
+            json!({
+                "transactions": 3,
+                "failed_transactions": 1,
+                "commands": 2,
+                "failed_commands": 1,
+                "top_senders": [{"from": "alice@example.org", "transactions": 2}],
+            })
+        );
+
+        aggregator.on_tick(&host).unwrap();
+        assert_eq!(host.counter_value("smtp.aggregate.transactions.total"), 3);
+        assert_eq!(
+            host.counter_value("smtp.aggregate.transactions.failed.total"),
+            1
+        );
+        assert_eq!(
+            host.counter_value("smtp.aggregate.transactions.failure_ratio_permille"),
+            333
+        );
+        assert_eq!(
+            host.counter_value("smtp.aggregate.commands.failure_ratio_permille"),
+            500
+        );
+
+        // aggregates start over after a flush
+        aggregator.on_tick(&host).unwrap();
+        assert_eq!(host.counter_value("smtp.aggregate.transactions.total"), 3);
+        assert_eq!(aggregator.summary()["transactions"], json!(0));
+    }
+
+    #[test]
+    fn should_reject_invalid_configuration() {
+        let host = FakeHost::default();
+        let mut aggregator = SmtpEventAggregator::new(&host, &host).unwrap();
+        for config in [r#"{"flush_interval_ms": 0}"#, r#"{"queue": "smtp_events"}"#] {
+            assert_eq!(
+                aggregator
+                    .on_configure(ByteString::from(config), &host)
+                    .unwrap(),
+                ConfigStatus::Rejected
+            );
+        }
+    }
+}
@@ -12,25 +12,1505 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
 
 use serde::Deserialize;
 
+use envoy::error::format_err;
 use envoy::extension;
+use envoy::host::log;
+
+use crate::lists::PolicyLists;
+use crate::protobuf;
+use crate::redact::Redaction;
+use crate::runtime::{RuntimeLogLevel, RuntimeToggles};
+use crate::smtp::agent::{
+    EhloRewrite, FallbackConfig, FilterLogLevel, LocalReplies, RecipientRewrite, ReplyCodeRewrite,
+    SessionConfig, Strictness, Tap,
+};
 
 /// Configuration for a SMTP Filter.
-#[derive(Debug, Default, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
 pub struct SmtpFilterConfig {
+    /// Version of the configuration schema.
+    ///
+    /// Unknown fields are rejected in configurations of the current version,
+    /// i.e. `2`, and only logged in earlier ones.
+    pub config_version: u32,
+    /// Direction of mail traffic through the listener, which determines
+    /// the policies and stats that are active.
+    pub direction: Direction,
+    /// The only direction of the traffic that is visible to SMTP filter, i.e.
+    /// `commands` or `replies`, in deployments where the other one is mirrored
+    /// elsewhere.
+    ///
+    /// SMTP filter keeps best-effort stats instead of treating the missing half
+    /// as a protocol error.
+    pub tap: Option<Tap>,
     /// Indicates whether SMTP filter should produce individual stats for
     /// each of the SMTP verbs and reply codes.
     pub detailed_stats: bool,
+    /// Verbs and reply codes to produce detailed stats for, all if unset.
+    pub detailed_stats_selection: Option<DetailedStatsSelection>,
+    /// Indicates whether detailed stats should be scoped to the upstream
+    /// cluster, e.g. `smtp.cluster.<name>.command.DATA.total`.
+    pub upstream_cluster_stats: bool,
+    /// Indicates whether detailed stats should carry verbs, reply codes, etc
+    /// as tags rather than as a part of the stat name.
+    ///
+    /// Tags must be declared in `stats_config.stats_tags` of the `Envoy` bootstrap.
+    pub tagged_stats: bool,
+    /// Maximum number of recipient domains to produce individual stats for.
+    ///
+    /// Recipients in other domains are accounted under the `other` domain.
+    /// Per-domain stats are disabled if `0`.
+    pub recipient_domain_stats_limit: usize,
+    /// Path of the connection property that holds the id of the tenant to scope
+    /// detailed stats to, e.g. `["metadata", "filter_metadata", "<namespace>", "tenant"]`
+    /// for dynamic metadata or `["<key>"]` for filter state set by an earlier filter.
+    pub tenant_property: Option<Vec<String>>,
+    /// Form of client addresses to produce per-source connection stats for,
+    /// e.g. `smtp.sources.192_0_2_0.connections.total`.
+    ///
+    /// Per-source stats are disabled if unset.
+    pub source_stats: Option<SourceStatsMode>,
+    /// Indicates whether a multi-line reply with inconsistent reply codes
+    /// should be treated as a protocol error.
+    pub strict_reply_codes: bool,
+    /// How strictly the protocol is enforced, i.e. `strict`, `lenient` or `permissive`.
+    pub strictness: Strictness,
+    /// Actions to take on protocol parsing errors by their class, i.e. on
+    /// `command` and `reply` lines that cannot be parsed and on replies out of
+    /// `sequence`.
+    ///
+    /// SMTP filter passes the rest of the connection through by default.
+    pub fallback: FallbackConfig,
+    /// Text to replace the greeting of the upstream SMTP server with before it
+    /// reaches the client, e.g. `mx.example.org ESMTP`.
+    ///
+    /// Prevents fingerprinting of the upstream SMTP server.
+    pub greeting_banner: Option<String>,
+    /// Rewrite of the upstream reply to EHLO command that removes or masks
+    /// selected service extensions before it reaches the client.
+    pub ehlo_rewrite: Option<EhloRewrite>,
+    /// Rewrites of upstream replies by code toward the client, e.g. to turn
+    /// backend `421` overload replies into `451` with a branded message.
+    ///
+    /// Rules are applied in order until the first match.
+    pub reply_code_rewrites: Vec<ReplyCodeRewrite>,
+    /// Rewrite of recipients of RCPT commands before they reach the upstream
+    /// SMTP server, e.g. by alias maps or domain rewrites.
+    ///
+    /// Commands that cannot be rewritten are forwarded as is and accounted
+    /// in `smtp.rcpt.rewrites.failed.total`.
+    pub recipient_rewrite: Option<RecipientRewrite>,
+    /// Whether `Envoy` handles TLS negotiated by STARTTLS command itself,
+    /// e.g. by `envoy.transport_sockets.starttls` transport socket, so that
+    /// SMTP filter keeps observing plaintext after the upgrade.
+    ///
+    /// Otherwise, SMTP filter stops interpreting the traffic after STARTTLS.
+    pub starttls_offload: bool,
+    /// Header to add to every message with the id of its mail transaction, so that
+    /// delivered mail can be traced back to the logs of SMTP filter.
+    pub trace_header: Option<TraceHeaderConfig>,
+    /// Patterns of VERP senders of MAIL commands, e.g. `bounce-*=*@lists.example.org`,
+    /// to tell mailing list traffic apart from other submissions.
+    ///
+    /// `*` matches any number of characters, and letters match regardless of case.
+    pub verp_patterns: Vec<String>,
+    /// Whether to forward the real client IP address and HELO name to upstream
+    /// SMTP servers that advertise support for XFORWARD command.
+    ///
+    /// Must only be enabled for trusted upstreams. XCLIENT command is not
+    /// supported since it restarts the SMTP session with a new greeting.
+    pub xforward: bool,
+    /// Policy service to consult on MAIL and RCPT commands.
+    pub envelope_policy: Option<EnvelopePolicyConfig>,
+    /// Content scanning service to consult on messages.
+    pub content_scan: Option<ContentScanConfig>,
+    /// DNS-based block lists to look up client addresses in.
+    pub dnsbl: Option<DnsblConfig>,
+    /// Reputation of client addresses shared by all filter instances.
+    pub reputation: Option<ReputationConfig>,
+    /// Throttling of AUTH commands of clients that fail to authenticate repeatedly.
+    pub auth_throttle: Option<AuthThrottleConfig>,
+    /// Endpoint to notify of every mail transaction SMTP server has replied to.
+    pub transaction_webhook: Option<WebhookConfig>,
+    /// Shared queue to publish SMTP events onto.
+    pub event_queue: Option<EventQueueConfig>,
+    /// Logging of every mail transaction SMTP server has replied to as a
+    /// single line of JSON.
+    pub transaction_log: Option<LogConfig>,
+    /// Logging of a single-line summary of every connection once it is closed.
+    pub session_log: Option<LogConfig>,
+    /// Capture of command and reply transcripts of connections.
+    pub transcript: Option<TranscriptConfig>,
+    /// Limit on the time SMTP client may stay silent for.
+    pub idle_timeout: Option<IdleTimeoutConfig>,
+    /// Limit on the time a message may take to arrive after DATA command
+    /// has been accepted.
+    pub data_timeout: Option<DataTimeoutConfig>,
+    /// Detection of clients that trickle commands a few bytes at a time.
+    pub slow_client: Option<SlowClientConfig>,
+    /// Shared data key to poll runtime switches from.
+    pub runtime: Option<RuntimeConfig>,
+    /// Log level of SMTP filter regardless of the log level of `Envoy`,
+    /// i.e. `trace`, `debug` or `info`.
+    pub log_level: Option<FilterLogLevel>,
+    /// Promotes debug and trace logs of 1 in every `debug_sample_rate` connections,
+    /// including every command and reply, to `info` level.
+    pub debug_sample_rate: Option<u32>,
+    /// Redaction of personal data in logs, filter state, events and webhook
+    /// payloads, e.g. masking of local parts of mailboxes.
+    pub redaction: Redaction,
+    /// Allow and deny lists of senders and recipients.
+    pub lists: Rc<PolicyLists>,
+    /// Templates of replies to send in place of SMTP server, e.g. to commands
+    /// with denied mailboxes.
+    pub local_replies: LocalReplies,
+    /// Shared data key to poll policy lists from, which replace `lists` once set.
+    pub policy_lists: Option<PolicyListsConfig>,
+    /// Named sets of overrides of the policies above that can be selected
+    /// per connection by `profile_selector`.
+    pub profiles: HashMap<String, PolicyProfile>,
+    /// Selection of a policy profile per connection.
+    pub profile_selector: Option<ProfileSelector>,
+    // Configurations with profiles applied.
+    #[serde(skip)]
+    resolved_profiles: HashMap<String, Rc<SmtpFilterConfig>>,
+}
+
+impl SmtpFilterConfig {
+    /// Returns the configuration with a given profile applied.
+    pub fn profile(&self, name: &str) -> Option<Rc<SmtpFilterConfig>> {
+        self.resolved_profiles.get(name).cloned()
+    }
+
+    /// Returns how often the factory has to check on connections and shared data,
+    /// if at all, i.e. often enough to notice idle clients within a second and
+    /// to poll runtime switches and policy lists once per poll interval.
+    pub fn tick_period(&self) -> Option<Duration> {
+        let idle_timeouts = std::iter::once(self)
+            .chain(self.resolved_profiles.values().map(Rc::as_ref))
+            .filter_map(|config| config.idle_timeout.as_ref())
+            .map(|idle_timeout| idle_timeout.timeout().min(MAX_TICK_PERIOD));
+        let poll_intervals = self
+            .runtime
+            .as_ref()
+            .map(RuntimeConfig::poll_interval)
+            .into_iter()
+            .chain(
+                self.policy_lists
+                    .as_ref()
+                    .map(PolicyListsConfig::poll_interval),
+            );
+        idle_timeouts.chain(poll_intervals).min()
+    }
+
+    /// Returns the configuration with given policy lists in place of the configured ones.
+    pub fn with_policy_lists(&self, lists: &PolicyLists) -> SmtpFilterConfig {
+        let mut config = self.clone();
+        config.lists = Rc::new(lists.clone());
+        config.resolve_profiles();
+        config
+    }
+
+    /// Returns the configuration with given runtime switches applied.
+    pub fn with_runtime(&self, toggles: &RuntimeToggles) -> SmtpFilterConfig {
+        let mut config = self.clone();
+        if let Some(detailed_stats) = toggles.detailed_stats {
+            config.detailed_stats = detailed_stats;
+            config.resolve_profiles();
+        }
+        if let Some(debug_sample_rate) = toggles.debug_sample_rate {
+            config.debug_sample_rate = Some(debug_sample_rate).filter(|rate| *rate > 0);
+        }
+        if let Some(enforce) = toggles.enforce {
+            config = config.with_enforcement(enforce);
+        }
+        config
+    }
+
+    /// Returns the configuration with actions on listed, idle and slow clients
+    /// switched to either enforcing or observing, including all profiles.
+    pub fn with_enforcement(&self, enforce: bool) -> SmtpFilterConfig {
+        let mut config = self.clone();
+        config.enforce(enforce);
+        for profile in config.resolved_profiles.values_mut() {
+            let mut enforced = profile.as_ref().clone();
+            enforced.enforce(enforce);
+            *profile = Rc::new(enforced);
+        }
+        config
+    }
+
+    // Switches actions on listed, idle and slow clients between observing and enforcing.
+    fn enforce(&mut self, enforce: bool) {
+        let (dnsbl_action, timeout_action) = if enforce {
+            (DnsblAction::Reject, TimeoutAction::Close)
+        } else {
+            (DnsblAction::Observe, TimeoutAction::Observe)
+        };
+        if let Some(dnsbl) = self.dnsbl.as_mut() {
+            dnsbl.action = dnsbl_action;
+        }
+        if let Some(idle_timeout) = self.idle_timeout.as_mut() {
+            idle_timeout.action = timeout_action;
+        }
+        if let Some(slow_client) = self.slow_client.as_mut() {
+            slow_client.action = timeout_action;
+        }
+    }
+
+    // Returns an error that names the first invalid field of the configuration,
+    // prefixed with a given path, e.g. `profiles.strict.`.
+    fn validate(&self, path: &str) -> extension::Result<()> {
+        let field = |name: &str| format!("{}{}", path, name);
+        for (enabled, compiled, name, feature) in [
+            (
+                self.detailed_stats,
+                cfg!(feature = "detailed-stats"),
+                "detailed_stats",
+                "detailed-stats",
+            ),
+            (
+                self.upstream_cluster_stats,
+                cfg!(feature = "detailed-stats"),
+                "upstream_cluster_stats",
+                "detailed-stats",
+            ),
+            (
+                self.source_stats.is_some(),
+                cfg!(feature = "detailed-stats"),
+                "source_stats",
+                "detailed-stats",
+            ),
+            (
+                self.content_scan.is_some(),
+                cfg!(feature = "content-inspection"),
+                "content_scan",
+                "content-inspection",
+            ),
+            (
+                self.transcript.is_some(),
+                cfg!(feature = "content-inspection"),
+                "transcript",
+                "content-inspection",
+            ),
+            (
+                self.envelope_policy.is_some(),
+                cfg!(feature = "policy"),
+                "envelope_policy",
+                "policy",
+            ),
+            (
+                self.dnsbl.is_some(),
+                cfg!(feature = "policy"),
+                "dnsbl",
+                "policy",
+            ),
+            (
+                self.reputation.is_some(),
+                cfg!(feature = "policy"),
+                "reputation",
+                "policy",
+            ),
+            (
+                self.auth_throttle.is_some(),
+                cfg!(feature = "policy"),
+                "auth_throttle",
+                "policy",
+            ),
+        ] {
+            ensure(
+                !enabled || compiled,
+                field(name),
+                format!("requires the `{}` feature of the Wasm module", feature),
+            )?;
+        }
+        if let Some(selection) = self.detailed_stats_selection.as_ref() {
+            for (i, code) in selection.reply_codes.iter().enumerate() {
+                let is_pattern = code.len() == 3
+                    && code
+                        .chars()
+                        .all(|c| c.is_ascii_digit() || c.eq_ignore_ascii_case(&'x'));
+                ensure(
+                    is_pattern,
+                    field(&format!("detailed_stats_selection.reply_codes[{}]", i)),
+                    "must be a reply code with optional x digits, e.g. 4xx",
+                )?;
+            }
+        }
+        for (template, name) in [
+            (self.local_replies.drain.as_ref(), "drain"),
+            (self.local_replies.sender_denied.as_ref(), "sender_denied"),
+            (
+                self.local_replies.recipient_denied.as_ref(),
+                "recipient_denied",
+            ),
+            (self.local_replies.auth_throttled.as_ref(), "auth_throttled"),
+        ] {
+            if let Some(template) = template {
+                let code = template.get(..3).unwrap_or_default();
+                let is_negative = code.starts_with(['4', '5'])
+                    && code.bytes().all(|octet| octet.is_ascii_digit())
+                    && matches!(template.as_bytes().get(3), None | Some(b' '));
+                ensure(
+                    is_negative && is_reply_text(template),
+                    field(&format!("local_replies.{}", name)),
+                    "must be a single-line reply with a 4xx or 5xx code",
+                )?;
+            }
+        }
+        if let Some(banner) = self.greeting_banner.as_ref() {
+            ensure(
+                is_reply_text(banner),
+                field("greeting_banner"),
+                "must be a single line",
+            )?;
+        }
+        let mut names = HashSet::new();
+        for (i, rewrite) in self.reply_code_rewrites.iter().enumerate() {
+            let field = |name: &str| field(&format!("reply_code_rewrites[{}].{}", i, name));
+            ensure(!rewrite.name.is_empty(), field("name"), "must not be empty")?;
+            ensure(names.insert(&rewrite.name), field("name"), "must be unique")?;
+            if let Some(text) = rewrite.text.as_ref() {
+                ensure(is_reply_text(text), field("text"), "must be a single line")?;
+            }
+        }
+        if let Some(policy) = self.envelope_policy.as_ref() {
+            ensure_cluster(&policy.cluster, field("envelope_policy.cluster"))?;
+            ensure_positive(policy.timeout_ms, field("envelope_policy.timeout_ms"))?;
+        }
+        if let Some(scan) = self.content_scan.as_ref() {
+            ensure_cluster(&scan.cluster, field("content_scan.cluster"))?;
+            ensure_positive(scan.timeout_ms, field("content_scan.timeout_ms"))?;
+            ensure_positive(
+                scan.max_message_bytes as u64,
+                field("content_scan.max_message_bytes"),
+            )?;
+        }
+        if let Some(tap) = self.tap {
+            for (enabled, name) in [
+                (self.envelope_policy.is_some(), "envelope_policy"),
+                (self.content_scan.is_some(), "content_scan"),
+                (self.xforward, "xforward"),
+                (self.trace_header.is_some(), "trace_header"),
+            ] {
+                ensure(
+                    !enabled,
+                    field(name),
+                    format!("is mutually exclusive with tap {:?}", tap),
+                )?;
+            }
+        }
+        if let Some(dnsbl) = self.dnsbl.as_ref() {
+            ensure_cluster(&dnsbl.cluster, field("dnsbl.cluster"))?;
+            ensure(
+                !dnsbl.zones.is_empty(),
+                field("dnsbl.zones"),
+                "must not be empty",
+            )?;
+            for (i, zone) in dnsbl.zones.iter().enumerate() {
+                let is_name = !zone.is_empty() && !zone.starts_with('.') && !zone.ends_with('.');
+                ensure(
+                    is_name,
+                    field(&format!("dnsbl.zones[{}]", i)),
+                    "must be a domain name",
+                )?;
+            }
+            ensure_positive(dnsbl.timeout_ms, field("dnsbl.timeout_ms"))?;
+        }
+        if let Some(reputation) = self.reputation.as_ref() {
+            ensure_positive(reputation.ttl_secs, field("reputation.ttl_secs"))?;
+            if let Some(max_offenses) = reputation.max_offenses {
+                ensure_positive(u64::from(max_offenses), field("reputation.max_offenses"))?;
+            }
+        }
+        if let Some(throttle) = self.auth_throttle.as_ref() {
+            if let Some(max_failures) = throttle.max_failures {
+                ensure_positive(u64::from(max_failures), field("auth_throttle.max_failures"))?;
+            }
+            if let Some(max_source_failures) = throttle.max_source_failures {
+                ensure_positive(
+                    u64::from(max_source_failures),
+                    field("auth_throttle.max_source_failures"),
+                )?;
+                ensure(
+                    self.reputation.is_some(),
+                    field("auth_throttle.max_source_failures"),
+                    "requires `reputation` to account failures per client address",
+                )?;
+            }
+        }
+        if let Some(webhook) = self.transaction_webhook.as_ref() {
+            ensure_cluster(&webhook.cluster, field("transaction_webhook.cluster"))?;
+            ensure_positive(webhook.timeout_ms, field("transaction_webhook.timeout_ms"))?;
+        }
+        if let Some(queue) = self.event_queue.as_ref() {
+            ensure(
+                !queue.name.is_empty(),
+                field("event_queue.name"),
+                "must not be empty",
+            )?;
+        }
+        if let Some(idle_timeout) = self.idle_timeout.as_ref() {
+            ensure_positive(idle_timeout.timeout_ms, field("idle_timeout.timeout_ms"))?;
+        }
+        if let Some(data_timeout) = self.data_timeout.as_ref() {
+            ensure_positive(data_timeout.timeout_ms, field("data_timeout.timeout_ms"))?;
+        }
+        if let Some(slow_client) = self.slow_client.as_ref() {
+            ensure_positive(slow_client.window_ms, field("slow_client.window_ms"))?;
+            ensure_positive(slow_client.min_bytes as u64, field("slow_client.min_bytes"))?;
+        }
+        if let Some(runtime) = self.runtime.as_ref() {
+            ensure(
+                !runtime.key.is_empty(),
+                field("runtime.key"),
+                "must not be empty",
+            )?;
+        }
+        if let Some(rate) = self.debug_sample_rate {
+            ensure_positive(u64::from(rate), field("debug_sample_rate"))?;
+        }
+        for pattern in self.verp_patterns.iter() {
+            ensure(
+                pattern.contains('@'),
+                field("verp_patterns"),
+                format!("{:?} must be a mailbox pattern", pattern),
+            )?;
+        }
+        if let Some(trace_header) = self.trace_header.as_ref() {
+            // field names of RFC 5322, section 2.2
+            let is_name = !trace_header.name.is_empty()
+                && trace_header
+                    .name
+                    .bytes()
+                    .all(|octet| octet.is_ascii_graphic() && octet != b':');
+            ensure(
+                is_name,
+                field("trace_header.name"),
+                "must be a header field name",
+            )?;
+        }
+        if let Some(transcript) = self.transcript.as_ref() {
+            ensure_positive(transcript.max_bytes as u64, field("transcript.max_bytes"))?;
+            ensure(
+                !transcript.publish || self.event_queue.is_some(),
+                field("transcript.publish"),
+                "requires event_queue",
+            )?;
+        }
+        if let Some(policy_lists) = self.policy_lists.as_ref() {
+            ensure(
+                !policy_lists.key.is_empty(),
+                field("policy_lists.key"),
+                "must not be empty",
+            )?;
+        }
+        if let Some(selector) = self.profile_selector.as_ref() {
+            for (server_name, profile) in selector.server_names.iter() {
+                ensure(
+                    self.profiles.contains_key(profile),
+                    field(&format!("profile_selector.server_names.{}", server_name)),
+                    format!("refers to unknown profile {:?}", profile),
+                )?;
+            }
+        }
+        let mut base = self.clone();
+        base.profiles.clear();
+        base.profile_selector = None;
+        for (name, profile) in self.profiles.iter() {
+            profile
+                .apply(&base)
+                .validate(&field(&format!("profiles.{}.", name)))?;
+        }
+        Ok(())
+    }
+
+    fn resolve_profiles(&mut self) {
+        let mut base = self.clone();
+        base.profiles.clear();
+        base.profile_selector = None;
+        self.resolved_profiles = self
+            .profiles
+            .iter()
+            .map(|(name, profile)| {
+                let mut config = profile.apply(&base);
+                config.apply_direction();
+                (name.clone(), Rc::new(config))
+            })
+            .collect();
+    }
+
+    // Disables policies and stats that don't make sense in the configured direction.
+    fn apply_direction(&mut self) {
+        if self.direction == Direction::Outbound {
+            // clients are trusted submission clients rather than arbitrary hosts
+            self.dnsbl = None;
+            self.reputation = None;
+            self.source_stats = None;
+            if self.recipient_domain_stats_limit == 0 {
+                self.recipient_domain_stats_limit = DEFAULT_OUTBOUND_RECIPIENT_DOMAINS;
+            }
+        }
+    }
+}
+
+fn ensure(condition: bool, field: String, reason: impl fmt::Display) -> extension::Result<()> {
+    if condition {
+        Ok(())
+    } else {
+        Err(format_err!("invalid {}: {}", field, reason))
+    }
+}
+
+fn ensure_positive(value: u64, field: String) -> extension::Result<()> {
+    ensure(value > 0, field, "must be greater than 0")
+}
+
+fn ensure_cluster(cluster: &str, field: String) -> extension::Result<()> {
+    ensure(!cluster.is_empty(), field, "must name an Envoy cluster")
+}
+
+// Formats a path of a field the way `validate` does, e.g. `dnsbl.zones[0]`.
+fn field_path(path: &serde_ignored::Path) -> String {
+    match path {
+        serde_ignored::Path::Root => String::new(),
+        serde_ignored::Path::Seq { parent, index } => format!("{}[{}]", field_path(parent), index),
+        serde_ignored::Path::Map { parent, key } => match field_path(parent) {
+            parent if parent.is_empty() => key.clone(),
+            parent => format!("{}.{}", parent, key),
+        },
+        serde_ignored::Path::Some { parent }
+        | serde_ignored::Path::NewtypeStruct { parent }
+        | serde_ignored::Path::NewtypeVariant { parent } => field_path(parent),
+    }
+}
+
+// Whether a given text can be sent as a line of an SMTP reply.
+fn is_reply_text(text: &str) -> bool {
+    !text.contains(['\r', '\n'])
+}
+
+/// Current version of the configuration schema.
+pub const CONFIG_VERSION: u32 = 2;
+
+/// Maximum number of destination domains to produce individual stats for
+/// in outbound direction unless configured explicitly.
+const DEFAULT_OUTBOUND_RECIPIENT_DOMAINS: usize = 100;
+
+/// Longest period of ticks of the factory while connections need to be checked on.
+const MAX_TICK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Selection of verbs and reply codes that get detailed stats, which keeps
+/// the number of individual stats under control.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct DetailedStatsSelection {
+    /// Verbs to produce per-verb stats for, e.g. `["MAIL", "RCPT", "DATA"]`, all if empty.
+    pub verbs: Vec<String>,
+    /// Reply codes to produce per-code stats for, with `x` standing for any digit,
+    /// e.g. `["4xx", "5xx"]`, all if empty.
+    pub reply_codes: Vec<String>,
+}
+
+impl DetailedStatsSelection {
+    /// Returns `true` if a given verb should get detailed stats.
+    pub fn includes_verb(&self, verb: &str) -> bool {
+        self.verbs.is_empty()
+            || self
+                .verbs
+                .iter()
+                .any(|selected| selected.eq_ignore_ascii_case(verb))
+    }
+
+    /// Returns `true` if a given reply code should get detailed stats.
+    pub fn includes_reply_code(&self, code: &str) -> bool {
+        self.reply_codes.is_empty()
+            || self.reply_codes.iter().any(|selected| {
+                selected.len() == code.len()
+                    && selected
+                        .chars()
+                        .zip(code.chars())
+                        .all(|(s, c)| s.eq_ignore_ascii_case(&'x') || s == c)
+            })
+    }
+}
+
+/// Direction of mail traffic through the listener.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Mail from arbitrary hosts on the Internet to local mailboxes, e.g. MX.
+    ///
+    /// Client policies, such as DNSBL lookups and reputation, are active.
+    #[default]
+    Inbound,
+    /// Mail from local submission clients to remote domains, e.g. egress relay.
+    ///
+    /// Client policies are disabled in favour of per-destination-domain stats.
+    Outbound,
+}
+
+/// Latest configuration shared by the factory with filter instances, so that
+/// long-lived connections pick up configuration updates.
+#[derive(Clone, Default)]
+pub struct ConfigHandle(Rc<RefCell<Rc<SmtpFilterConfig>>>);
+
+impl ConfigHandle {
+    pub fn new(config: Rc<SmtpFilterConfig>) -> Self {
+        ConfigHandle(Rc::new(RefCell::new(config)))
+    }
+
+    /// Returns the latest configuration.
+    pub fn get(&self) -> Rc<SmtpFilterConfig> {
+        Rc::clone(&self.0.borrow())
+    }
+
+    /// Replaces the configuration for all filter instances.
+    pub fn set(&self, config: Rc<SmtpFilterConfig>) {
+        *self.0.borrow_mut() = config
+    }
+}
+
+/// Form of client addresses in per-source stats.
+///
+/// Individual addresses are never used to keep the number of stats bounded.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceStatsMode {
+    /// Network of the client, i.e. `/24` for IPv4 and `/48` for IPv6.
+    Truncated,
+    /// One of 256 buckets by a hash of the client address.
+    Hashed,
+}
+
+/// Overrides of policies selected per connection.
+///
+/// Policies that are not set are inherited from the filter configuration.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct PolicyProfile {
+    pub strict_reply_codes: Option<bool>,
+    pub strictness: Option<Strictness>,
+    pub greeting_banner: Option<String>,
+    pub ehlo_rewrite: Option<EhloRewrite>,
+    pub reply_code_rewrites: Option<Vec<ReplyCodeRewrite>>,
+    pub recipient_rewrite: Option<RecipientRewrite>,
+    pub xforward: Option<bool>,
+    pub envelope_policy: Option<EnvelopePolicyConfig>,
+    pub content_scan: Option<ContentScanConfig>,
+    pub dnsbl: Option<DnsblConfig>,
+    pub reputation: Option<ReputationConfig>,
+    pub auth_throttle: Option<AuthThrottleConfig>,
+}
+
+impl PolicyProfile {
+    fn apply(&self, base: &SmtpFilterConfig) -> SmtpFilterConfig {
+        let mut config = base.clone();
+        if let Some(value) = self.strict_reply_codes {
+            config.strict_reply_codes = value;
+        }
+        if let Some(value) = self.strictness {
+            config.strictness = value;
+        }
+        if let Some(value) = self.greeting_banner.as_ref() {
+            config.greeting_banner = Some(value.clone());
+        }
+        if let Some(value) = self.ehlo_rewrite.as_ref() {
+            config.ehlo_rewrite = Some(value.clone());
+        }
+        if let Some(value) = self.reply_code_rewrites.as_ref() {
+            config.reply_code_rewrites = value.clone();
+        }
+        if let Some(value) = self.recipient_rewrite.as_ref() {
+            config.recipient_rewrite = Some(value.clone());
+        }
+        if let Some(value) = self.xforward {
+            config.xforward = value;
+        }
+        if let Some(value) = self.envelope_policy.as_ref() {
+            config.envelope_policy = Some(value.clone());
+        }
+        if let Some(value) = self.content_scan.as_ref() {
+            config.content_scan = Some(value.clone());
+        }
+        if let Some(value) = self.dnsbl.as_ref() {
+            config.dnsbl = Some(value.clone());
+        }
+        if let Some(value) = self.reputation.as_ref() {
+            config.reputation = Some(value.clone());
+        }
+        if let Some(value) = self.auth_throttle.as_ref() {
+            config.auth_throttle = Some(value.clone());
+        }
+        config
+    }
+}
+
+/// Selection of a policy profile per connection.
+///
+/// A profile is selected by the requested server name (SNI) if any,
+/// otherwise by the value of a given key of the listener metadata, e.g.
+/// `metadata: {filter_metadata: {tetratelabs.filters.network.smtp: {profile: strict}}}`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProfileSelector {
+    /// Names of profiles by requested server name.
+    #[serde(default)]
+    pub server_names: HashMap<String, String>,
+    /// Namespace of the listener metadata.
+    #[serde(default = "ProfileSelector::default_metadata_namespace")]
+    pub metadata_namespace: String,
+    /// Key of the listener metadata that holds the profile name.
+    #[serde(default = "ProfileSelector::default_metadata_key")]
+    pub metadata_key: String,
+}
+
+impl ProfileSelector {
+    fn default_metadata_namespace() -> String {
+        "tetratelabs.filters.network.smtp".to_owned()
+    }
+
+    fn default_metadata_key() -> String {
+        "profile".to_owned()
+    }
+}
+
+/// Configuration of a policy service consulted on envelope commands.
+///
+/// The service receives a JSON description of a command in a POST request
+/// and replies with `2xx` to allow it or with `403` to deny it,
+/// in which case the client connection is closed.
+#[derive(Clone, Debug, Deserialize)]
+pub struct EnvelopePolicyConfig {
+    /// Name of the `Envoy` cluster of the policy service.
+    pub cluster: String,
+    /// Path of the policy endpoint.
+    #[serde(default = "EnvelopePolicyConfig::default_path")]
+    pub path: String,
+    /// Timeout of a policy request in milliseconds.
+    #[serde(default = "EnvelopePolicyConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Whether to allow commands when the policy service fails to reply.
+    #[serde(default)]
+    pub fail_open: bool,
+}
+
+impl EnvelopePolicyConfig {
+    fn default_path() -> String {
+        "/smtp/envelope".to_owned()
+    }
+
+    fn default_timeout_ms() -> u64 {
+        200
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+/// Configuration of a content scanning service consulted on messages.
+///
+/// The service receives a message in a POST request once the client has sent it
+/// and replies with `2xx` to pass it or with `403` to reject it, in which case
+/// the client connection is closed before the message reaches the upstream.
+/// The value of `x-smtp-scan-result` response header, if any, is exported
+/// as `smtp.scan.result` filter state.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ContentScanConfig {
+    /// Name of the `Envoy` cluster of the scanning service.
+    pub cluster: String,
+    /// Path of the scanning endpoint.
+    #[serde(default = "ContentScanConfig::default_path")]
+    pub path: String,
+    /// Timeout of a scanning request in milliseconds.
+    #[serde(default = "ContentScanConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Whether to pass messages when the scanning service fails to reply.
+    #[serde(default)]
+    pub fail_open: bool,
+    /// Maximum number of leading bytes of a message to send to the scanning service.
+    #[serde(default = "ContentScanConfig::default_max_message_bytes")]
+    pub max_message_bytes: usize,
+}
+
+impl ContentScanConfig {
+    fn default_path() -> String {
+        "/smtp/content".to_owned()
+    }
+
+    fn default_timeout_ms() -> u64 {
+        1000
+    }
+
+    fn default_max_message_bytes() -> usize {
+        1024 * 1024
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+/// Configuration of DNS-based block lists (DNSBL) looked up through
+/// a DNS-over-HTTPS resolver that supports JSON API, e.g. `dns.google`.
+///
+/// Client addresses are looked up once a connection is open and downstream
+/// data is held back until all answers are known. Answers are cached in
+/// shared data for their TTL.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DnsblConfig {
+    /// Name of the `Envoy` cluster of the DNS-over-HTTPS resolver.
+    pub cluster: String,
+    /// Value of the `:authority` header, if other than the cluster name.
+    #[serde(default)]
+    pub authority: Option<String>,
+    /// Path of the JSON API endpoint.
+    #[serde(default = "DnsblConfig::default_path")]
+    pub path: String,
+    /// DNSBL zones to look up client addresses in, e.g. `zen.spamhaus.org`.
+    pub zones: Vec<String>,
+    /// What to do with clients listed in any of the zones.
+    #[serde(default)]
+    pub action: DnsblAction,
+    /// Timeout of a lookup in milliseconds.
+    ///
+    /// Failed lookups are treated as if the address is not listed.
+    #[serde(default = "DnsblConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Time to cache negative answers for in seconds unless the resolver
+    /// provides one.
+    #[serde(default = "DnsblConfig::default_negative_ttl_secs")]
+    pub negative_ttl_secs: u64,
+}
+
+impl DnsblConfig {
+    fn default_path() -> String {
+        "/resolve".to_owned()
+    }
+
+    fn default_timeout_ms() -> u64 {
+        500
+    }
+
+    fn default_negative_ttl_secs() -> u64 {
+        300
+    }
+
+    pub fn authority(&self) -> &str {
+        self.authority.as_deref().unwrap_or(&self.cluster)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn negative_ttl(&self) -> Duration {
+        Duration::from_secs(self.negative_ttl_secs)
+    }
+}
+
+/// Action to take on clients listed in a DNSBL.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsblAction {
+    /// Only account listed clients in stats and filter state.
+    #[default]
+    Observe,
+    /// Close connections of listed clients.
+    Reject,
+}
+
+/// Configuration of runtime switches in shared data.
+///
+/// The value of the key is a JSON object, e.g.
+/// `{"detailed_stats": true, "enforce": true, "log_level": "debug"}`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RuntimeConfig {
+    /// Shared data key of the switches.
+    #[serde(default = "RuntimeConfig::default_key")]
+    pub key: String,
+    /// Minimum time between polls in milliseconds.
+    #[serde(default = "RuntimeConfig::default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+}
+
+impl RuntimeConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms)
+    }
+
+    fn default_key() -> String {
+        "smtp.runtime".to_owned()
+    }
+
+    fn default_poll_interval_ms() -> u64 {
+        10_000
+    }
+}
+
+/// Configuration of policy lists in shared data.
+///
+/// The value of the key is a JSON object, e.g.
+/// `{"senders": {"deny": ["example.net"]}, "recipient_rewrite": {"aliases": {}}}`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PolicyListsConfig {
+    /// Shared data key of the lists.
+    #[serde(default = "PolicyListsConfig::default_key")]
+    pub key: String,
+    /// Minimum time between polls in milliseconds.
+    #[serde(default = "RuntimeConfig::default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+}
+
+impl PolicyListsConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms)
+    }
+
+    fn default_key() -> String {
+        "smtp.policy_lists".to_owned()
+    }
+}
+
+/// Configuration of the idle timeout of SMTP clients.
+///
+/// Connections are checked on every second, as well as on every event
+/// of the connection.
+#[derive(Clone, Debug, Deserialize)]
+pub struct IdleTimeoutConfig {
+    /// Maximum time between chunks of data from the client in milliseconds.
+    pub timeout_ms: u64,
+    /// What to do with clients that have been idle for longer.
+    #[serde(default)]
+    pub action: TimeoutAction,
+}
+
+impl IdleTimeoutConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+/// Configuration of the timeout of the DATA phase of mail transactions.
+///
+/// Unlike the idle timeout, it is only checked on events of the connection.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DataTimeoutConfig {
+    /// Maximum time between the reply to DATA command and the end of the message
+    /// in milliseconds.
+    pub timeout_ms: u64,
+    /// What to do with messages that take longer.
+    #[serde(default)]
+    pub action: DataTimeoutAction,
+}
+
+impl DataTimeoutConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+/// Action to take on a message that takes too long to arrive.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataTimeoutAction {
+    /// Stop buffering the message and pass the rest of the connection through.
+    #[default]
+    PassThrough,
+    /// Close the connection.
+    Close,
+}
+
+/// Configuration of the detection of slow SMTP clients, e.g. Slowloris-style
+/// attacks that keep connections open by trickling commands byte by byte.
+///
+/// A client is considered slow if a command line has been incomplete for `window_ms`
+/// while fewer than `min_bytes` of it have arrived.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SlowClientConfig {
+    /// Time an incomplete command line is measured over in milliseconds.
+    #[serde(default = "SlowClientConfig::default_window_ms")]
+    pub window_ms: u64,
+    /// Minimum number of bytes of a command line to arrive within the window.
+    #[serde(default = "SlowClientConfig::default_min_bytes")]
+    pub min_bytes: usize,
+    /// What to do with slow clients.
+    #[serde(default)]
+    pub action: TimeoutAction,
+}
+
+impl SlowClientConfig {
+    pub fn window(&self) -> Duration {
+        Duration::from_millis(self.window_ms)
+    }
+
+    fn default_window_ms() -> u64 {
+        10_000
+    }
+
+    fn default_min_bytes() -> usize {
+        64
+    }
+}
+
+/// Action to take on a connection that has run out of time.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeoutAction {
+    /// Only account the connection in stats and filter state.
+    #[default]
+    Observe,
+    /// Close the connection.
+    Close,
+}
+
+/// Configuration of client reputation tracking.
+///
+/// Rejects, protocol parsing errors and authentication failures are accounted
+/// per client address in shared data, so that repeat offenders are recognized
+/// on new connections. The number of offenses is exported as
+/// `smtp.reputation.offenses` filter state and passed to the envelope policy service.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReputationConfig {
+    /// Time in seconds after which a client without new offenses is forgotten.
+    #[serde(default = "ReputationConfig::default_ttl_secs")]
+    pub ttl_secs: u64,
+    /// Number of offenses after which connections of a client are closed.
+    ///
+    /// Offenders are only accounted in stats if unset.
+    #[serde(default)]
+    pub max_offenses: Option<u32>,
+}
+
+impl ReputationConfig {
+    fn default_ttl_secs() -> u64 {
+        3600
+    }
+
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+}
+
+/// Configuration of throttling of AUTH commands.
+///
+/// Once a client has failed to authenticate too many times, its further AUTH
+/// commands are turned down with `local_replies.auth_throttled` reply, or the
+/// connection is closed if the reply is unset.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AuthThrottleConfig {
+    /// Number of failed AUTH exchanges over a connection after which
+    /// AUTH commands of the connection are throttled.
+    pub max_failures: Option<u32>,
+    /// Number of authentication failures of a client address accounted by
+    /// `reputation` after which AUTH commands of the client are throttled.
+    pub max_source_failures: Option<u32>,
+}
+
+/// Configuration of an endpoint notified of mail transactions.
+///
+/// The endpoint receives a JSON summary of a transaction in a POST request
+/// once SMTP server has replied to the transaction commit. Notifications
+/// don't hold back the traffic and are not retried.
+#[derive(Clone, Debug, Deserialize)]
+pub struct WebhookConfig {
+    /// Name of the `Envoy` cluster of the endpoint.
+    pub cluster: String,
+    /// Path of the endpoint.
+    #[serde(default = "WebhookConfig::default_path")]
+    pub path: String,
+    /// Timeout of a notification in milliseconds.
+    #[serde(default = "WebhookConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl WebhookConfig {
+    fn default_path() -> String {
+        "/smtp/transactions".to_owned()
+    }
+
+    fn default_timeout_ms() -> u64 {
+        1000
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+/// Configuration of a shared queue SMTP events are published onto as JSON.
+///
+/// The queue must be registered by a consumer, e.g. a singleton service,
+/// events are dropped until then.
+#[derive(Clone, Debug, Deserialize)]
+pub struct EventQueueConfig {
+    /// Name of the queue.
+    pub name: String,
+    /// Id of the Wasm VM that has registered the queue, the current one if empty.
+    #[serde(default)]
+    pub vm_id: String,
+    /// Whether to publish replies to individual commands in addition to
+    /// mail transactions.
+    #[serde(default)]
+    pub commands: bool,
+}
+
+/// Configuration of the singleton service that aggregates events published
+/// onto a shared queue across connections.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AggregatorConfig {
+    /// Name of the queue to register, i.e. `event_queue.name` of SMTP filter.
+    pub queue_name: String,
+    /// Period aggregates are flushed to stats and logs with in milliseconds.
+    pub flush_interval_ms: u64,
+    /// Number of senders with the most transactions to log on every flush.
+    pub top_senders: usize,
+}
+
+impl AggregatorConfig {
+    pub fn flush_interval(&self) -> Duration {
+        Duration::from_millis(self.flush_interval_ms)
+    }
+}
+
+impl Default for AggregatorConfig {
+    fn default() -> Self {
+        AggregatorConfig {
+            queue_name: "smtp_events".to_owned(),
+            flush_interval_ms: 60_000,
+            top_senders: 10,
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for AggregatorConfig {
+    type Error = extension::Error;
+
+    /// Parses configuration of the service from JSON, which is optional.
+    fn try_from(value: &[u8]) -> extension::Result<Self> {
+        if value.is_empty() {
+            return Ok(AggregatorConfig::default());
+        }
+        let config: AggregatorConfig =
+            serde_json::from_slice(value).map_err(extension::Error::from)?;
+        ensure_positive(config.flush_interval_ms, "flush_interval_ms".to_owned())?;
+        Ok(config)
+    }
+}
+
+/// Configuration of a log of transactions or connections.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// Level to log at, `info` by default.
+    pub level: RuntimeLogLevel,
+}
+
+/// Configuration of the trace header.
+#[derive(Clone, Debug, Deserialize)]
+pub struct TraceHeaderConfig {
+    /// Name of the header.
+    #[serde(default = "TraceHeaderConfig::default_name")]
+    pub name: String,
+}
+
+impl TraceHeaderConfig {
+    fn default_name() -> String {
+        "X-Envoy-SMTP-Trace-Id".to_owned()
+    }
+}
+
+/// Configuration of transcript capture.
+#[derive(Clone, Debug, Deserialize)]
+pub struct TranscriptConfig {
+    /// Whether to capture transcripts of connections sampled by `debug_sample_rate` only.
+    #[serde(default)]
+    pub sampled_only: bool,
+    /// Limit on the size of a transcript.
+    #[serde(default = "TranscriptConfig::default_max_bytes")]
+    pub max_bytes: usize,
+    /// Whether to publish transcripts onto `event_queue` rather than to log them.
+    #[serde(default)]
+    pub publish: bool,
+}
+
+impl TranscriptConfig {
+    fn default_max_bytes() -> usize {
+        64 * 1024
+    }
+}
+
+// Format of the filter configuration.
+enum ConfigFormat {
+    Json,
+    Yaml,
+    Protobuf,
+}
+
+impl ConfigFormat {
+    // Tells the format apart by the content, since `Envoy` passes no type along.
+    //
+    // Serialized messages always contain control characters, e.g. field tags,
+    // while JSON is a subset of YAML that is only told apart for the sake of
+    // better error messages.
+    fn detect(value: &[u8]) -> Self {
+        let is_text = value
+            .iter()
+            .all(|octet| !octet.is_ascii_control() || octet.is_ascii_whitespace());
+        if !is_text {
+            return ConfigFormat::Protobuf;
+        }
+        match value.iter().find(|octet| !octet.is_ascii_whitespace()) {
+            None | Some(b'{') => ConfigFormat::Json,
+            Some(_) => ConfigFormat::Yaml,
+        }
+    }
 }
 
 impl TryFrom<&[u8]> for SmtpFilterConfig {
     type Error = extension::Error;
 
-    /// Parses filter configuration from JSON.
+    /// Parses filter configuration from JSON, YAML or a serialized
+    /// `google.protobuf.Struct`.
     fn try_from(value: &[u8]) -> extension::Result<Self> {
-        serde_json::from_slice(value).map_err(extension::Error::from)
+        let value: serde_json::Value = match ConfigFormat::detect(value) {
+            ConfigFormat::Json => serde_json::from_slice(value).map_err(extension::Error::from)?,
+            ConfigFormat::Yaml => serde_yaml::from_slice(value).map_err(extension::Error::from)?,
+            ConfigFormat::Protobuf => protobuf::decode_struct(value)?,
+        };
+        let mut unknown_fields = Vec::new();
+        let mut config: SmtpFilterConfig =
+            serde_ignored::deserialize(value, |path| unknown_fields.push(field_path(&path)))
+                .map_err(extension::Error::from)?;
+        if config.config_version > CONFIG_VERSION {
+            return Err(format_err!(
+                "unsupported config_version {}, at most {} is supported",
+                config.config_version,
+                CONFIG_VERSION
+            ));
+        }
+        if let Some(field) = unknown_fields.first() {
+            if config.config_version == CONFIG_VERSION {
+                return Err(format_err!("unknown field {}", field));
+            }
+            // configurations of earlier versions might carry fields of other builds
+            log::warn!("ignoring unknown fields: {}", unknown_fields.join(", "));
+        }
+        config.validate("")?;
+        config.resolve_profiles();
+        config.apply_direction();
+        Ok(config)
+    }
+}
+
+impl From<&SmtpFilterConfig> for SessionConfig {
+    fn from(config: &SmtpFilterConfig) -> Self {
+        SessionConfig {
+            strict_reply_codes: config.strict_reply_codes
+                || config.strictness == Strictness::Strict,
+            strictness: config.strictness,
+            greeting_banner: config.greeting_banner.clone(),
+            ehlo_rewrite: config.ehlo_rewrite.clone(),
+            reply_code_rewrites: config.reply_code_rewrites.clone(),
+            recipient_rewrite: config
+                .lists
+                .recipient_rewrite
+                .as_ref()
+                .or(config.recipient_rewrite.as_ref())
+                .cloned(),
+            starttls_offload: config.starttls_offload,
+            tap: config.tap,
+            fallback: config.fallback,
+            xforward: config.xforward,
+            envelope_checks: config.envelope_policy.is_some(),
+            lists: if config.lists.is_empty() {
+                None
+            } else {
+                Some(Rc::clone(&config.lists))
+            },
+            local_replies: config.local_replies.clone(),
+            max_auth_failures: config
+                .auth_throttle
+                .as_ref()
+                .and_then(|throttle| throttle.max_failures),
+            log_level: config.log_level,
+            redaction: config.redaction.clone(),
+            trace_header: config
+                .trace_header
+                .as_ref()
+                .map(|trace_header| trace_header.name.clone()),
+            verp_patterns: config.verp_patterns.clone(),
+            content_checks: config.content_scan.is_some(),
+            command_events: config
+                .event_queue
+                .as_ref()
+                .is_some_and(|queue| queue.commands),
+            transaction_events: config.transaction_webhook.is_some()
+                || config.event_queue.is_some()
+                || config.transaction_log.is_some(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_apply_profiles() {
+        let config = SmtpFilterConfig::try_from(
+            &br#"{
+                "greeting_banner": "mx.example.org ESMTP",
+                "profiles": {"strict": {"strict_reply_codes": true}}
+            }"#[..],
+        )
+        .unwrap();
+        let strict = config.profile("strict").unwrap();
+        assert!(strict.strict_reply_codes);
+        assert_eq!(
+            strict.greeting_banner.as_deref(),
+            Some("mx.example.org ESMTP")
+        );
+        assert!(config.profile("other").is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "policy")]
+    fn should_reject_invalid_config() {
+        for (config, error) in [
+            (
+                r#"{"idle_timeout": {"timeout_ms": 0}}"#,
+                "invalid idle_timeout.timeout_ms: must be greater than 0",
+            ),
+            (
+                r#"{"tap": "commands", "envelope_policy": {"cluster": "policy"}}"#,
+                "invalid envelope_policy: is mutually exclusive with tap Commands",
+            ),
+            (
+                r#"{"profile_selector": {"server_names": {"mx.example.org": "mx"}}}"#,
+                r#"invalid profile_selector.server_names.mx.example.org: refers to unknown profile "mx""#,
+            ),
+            (
+                r#"{"profiles": {"strict": {"dnsbl": {"cluster": "doh", "zones": []}}}}"#,
+                "invalid profiles.strict.dnsbl.zones: must not be empty",
+            ),
+            (
+                r#"{"greeting_banner": "mx.example.org\r\n250 OK"}"#,
+                "invalid greeting_banner: must be a single line",
+            ),
+        ] {
+            let err = SmtpFilterConfig::try_from(config.as_bytes()).unwrap_err();
+            assert_eq!(err.to_string(), error);
+        }
+    }
+
+    #[test]
+    fn should_reject_unknown_fields_in_current_version() {
+        let config = SmtpFilterConfig::try_from(
+            &br#"{"detailed_stat": true, "idle_timeout": {"timeout_ms": 1000, "acton": "close"}}"#
+                [..],
+        )
+        .unwrap();
+        assert!(!config.detailed_stats);
+
+        let err = SmtpFilterConfig::try_from(
+            &br#"{"config_version": 2, "idle_timeout": {"timeout_ms": 1000, "acton": "close"}}"#[..],
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "unknown field idle_timeout.acton");
+
+        let err = SmtpFilterConfig::try_from(&br#"{"config_version": 3}"#[..]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unsupported config_version 3, at most 2 is supported"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "detailed-stats")]
+    fn should_parse_yaml() {
+        let config = SmtpFilterConfig::try_from(
+            &b"
+detailed_stats: true
+source_stats: hashed
+idle_timeout:
+  timeout_ms: 30000
+"[..],
+        )
+        .unwrap();
+        assert!(config.detailed_stats);
+        assert_eq!(config.source_stats, Some(SourceStatsMode::Hashed));
+        assert_eq!(config.idle_timeout.unwrap().timeout_ms, 30000);
+        assert!(SmtpFilterConfig::try_from(&b"detailed_stats: [true"[..]).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "policy")]
+    fn should_disable_client_policies_outbound() {
+        let config = SmtpFilterConfig::try_from(
+            &br#"{
+                "direction": "outbound",
+                "reputation": {},
+                "profiles": {"mx": {"reputation": {}}}
+            }"#[..],
+        )
+        .unwrap();
+        assert!(config.reputation.is_none());
+        assert!(config.profile("mx").unwrap().reputation.is_none());
+        assert_eq!(
+            config.recipient_domain_stats_limit,
+            DEFAULT_OUTBOUND_RECIPIENT_DOMAINS
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "policy")]
+    fn should_apply_runtime_switches() {
+        let config = SmtpFilterConfig::try_from(
+            &br#"{
+                "dnsbl": {"cluster": "dns", "zones": ["zen.example.org"]},
+                "profiles": {"mx": {"strict_reply_codes": true}}
+            }"#[..],
+        )
+        .unwrap();
+        let toggles = RuntimeToggles {
+            detailed_stats: Some(true),
+            enforce: Some(true),
+            log_level: None,
+            debug_sample_rate: None,
+        };
+        let config = config.with_runtime(&toggles);
+        assert!(config.detailed_stats);
+        assert_eq!(config.dnsbl.as_ref().unwrap().action, DnsblAction::Reject);
+        let mx = config.profile("mx").unwrap();
+        assert!(mx.detailed_stats);
+        assert_eq!(mx.dnsbl.as_ref().unwrap().action, DnsblAction::Reject);
+    }
+
+    #[test]
+    fn should_reject_options_that_are_compiled_out() {
+        let result = SmtpFilterConfig::try_from(
+            &br#"{"dnsbl": {"cluster": "dns", "zones": ["zen.example.org"]}}"#[..],
+        );
+        if cfg!(feature = "policy") {
+            assert!(result.is_ok());
+        } else {
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "invalid dnsbl: requires the `policy` feature of the Wasm module"
+            );
+        }
     }
 }
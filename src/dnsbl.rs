@@ -0,0 +1,191 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! DNS-based block lists (DNSBL) queried through DNS-over-HTTPS JSON API.
+
+use std::net::IpAddr;
+use std::time::{Duration, UNIX_EPOCH};
+
+use envoy::extension::Result;
+use envoy::host::{Clock, SharedData};
+use serde_json::Value;
+
+// DNS response codes.
+const NOERROR: u64 = 0;
+const NXDOMAIN: u64 = 3;
+// DNS record type of IPv4 addresses.
+const TYPE_A: u64 = 1;
+
+/// Returns the DNS name to look up a given address in a given DNSBL zone with.
+///
+/// E.g., `2.0.0.127.zen.spamhaus.org` for `127.0.0.2`.
+pub fn query_name(address: IpAddr, zone: &str) -> String {
+    let labels: Vec<String> = match address {
+        IpAddr::V4(address) => address
+            .octets()
+            .iter()
+            .rev()
+            .map(|octet| octet.to_string())
+            .collect(),
+        IpAddr::V6(address) => address
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|octet| vec![octet & 0x0f, octet >> 4])
+            .map(|nibble| format!("{:x}", nibble))
+            .collect(),
+    };
+    format!("{}.{}", labels.join("."), zone)
+}
+
+/// Answer represents an answer of a DNSBL on a single address.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Answer {
+    /// Whether the address is listed.
+    pub listed: bool,
+    /// Time to cache the answer for.
+    pub ttl: Duration,
+}
+
+impl Answer {
+    /// Parses a response of DNS-over-HTTPS JSON API.
+    ///
+    /// Returns `None` if the response is not a valid answer.
+    pub fn from_json(body: &[u8], negative_ttl: Duration) -> Option<Answer> {
+        let response: Value = serde_json::from_slice(body).ok()?;
+        match response["Status"].as_u64()? {
+            NOERROR => {
+                let ttl = response["Answer"]
+                    .as_array()?
+                    .iter()
+                    .filter(|record| record["type"].as_u64() == Some(TYPE_A))
+                    .filter(|record| {
+                        record["data"]
+                            .as_str()
+                            .is_some_and(|data| data.starts_with("127."))
+                    })
+                    .filter_map(|record| record["TTL"].as_u64())
+                    .min()?;
+                Some(Answer {
+                    listed: true,
+                    ttl: Duration::from_secs(ttl),
+                })
+            }
+            NXDOMAIN => {
+                let ttl = response["Authority"][0]["TTL"]
+                    .as_u64()
+                    .map(Duration::from_secs)
+                    .unwrap_or(negative_ttl);
+                Some(Answer { listed: false, ttl })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Cache of DNSBL answers shared by all filter instances.
+pub struct DnsblCache<'a> {
+    // Shared Data API implementation.
+    shared_data: &'a dyn SharedData,
+    // Clock API implementation.
+    clock: &'a dyn Clock,
+}
+
+impl<'a> DnsblCache<'a> {
+    pub fn new(shared_data: &'a dyn SharedData, clock: &'a dyn Clock) -> Self {
+        DnsblCache { shared_data, clock }
+    }
+
+    /// Returns whether a given address is listed in a given zone,
+    /// or `None` if there is no fresh answer in the cache.
+    pub fn get(&self, zone: &str, address: IpAddr) -> Result<Option<bool>> {
+        let value = match self.shared_data.get(&cache_key(zone, address))?.0 {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        // <listed> <expires at>
+        let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+        let mut fields = value.split(' ');
+        let listed = fields.next() == Some("1");
+        let expires_at = fields.next().and_then(|secs| secs.parse::<u64>().ok());
+        match expires_at {
+            Some(expires_at) if expires_at > self.now_secs()? => Ok(Some(listed)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Caches a given answer on a given address in a given zone.
+    pub fn put(&self, zone: &str, address: IpAddr, answer: &Answer) -> Result<()> {
+        let expires_at = self.now_secs()? + answer.ttl.as_secs();
+        let value = format!("{} {}", answer.listed as u8, expires_at);
+        self.shared_data
+            .set(&cache_key(zone, address), value.as_bytes(), None)
+    }
+
+    fn now_secs(&self) -> Result<u64> {
+        Ok(self
+            .clock
+            .now()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs())
+    }
+}
+
+fn cache_key(zone: &str, address: IpAddr) -> String {
+    format!("smtp.dnsbl.{}.{}", zone, address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_build_query_name() {
+        assert_eq!(
+            query_name("192.0.2.1".parse().unwrap(), "zen.spamhaus.org"),
+            "1.2.0.192.zen.spamhaus.org"
+        );
+        assert_eq!(
+            query_name("2001:db8::1".parse().unwrap(), "example.org"),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.example.org"
+        );
+    }
+
+    #[test]
+    fn should_parse_answers() {
+        let negative_ttl = Duration::from_secs(60);
+        assert_eq!(
+            Answer::from_json(
+                br#"{"Status":0,"Answer":[{"type":1,"TTL":300,"data":"127.0.0.2"}]}"#,
+                negative_ttl
+            ),
+            Some(Answer {
+                listed: true,
+                ttl: Duration::from_secs(300)
+            })
+        );
+        assert_eq!(
+            Answer::from_json(
+                br#"{"Status":3,"Authority":[{"type":6,"TTL":900}]}"#,
+                negative_ttl
+            ),
+            Some(Answer {
+                listed: false,
+                ttl: Duration::from_secs(900)
+            })
+        );
+        assert_eq!(Answer::from_json(br#"{"Status":2}"#, negative_ttl), None);
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Export of SMTP events outside of the filter.
+
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use envoy::extension::Result;
+use envoy::host::shared_queue::{SharedQueue, SharedQueueHandle};
+use serde_json::{json, Value};
+
+use crate::config::EventQueueConfig;
+use crate::redact::Redaction;
+use crate::smtp::agent::{Event, TransactionSummary};
+
+/// Renders a given event as JSON.
+pub fn to_json(event: &Event, client_address: Option<SocketAddr>, redaction: &Redaction) -> Value {
+    let client_address = client_address.map(|address| address.ip().to_string());
+    match event {
+        Event::CommandReply { verb, code } => json!({
+            "type": "command",
+            "client_address": client_address,
+            "verb": verb,
+            "reply_code": code,
+        }),
+        Event::Transaction(summary) => {
+            let mut value = json!(TransactionSummary {
+                from: redaction.mailbox(&summary.from).into_owned().into(),
+                to: summary
+                    .to
+                    .iter()
+                    .map(|to| redaction.mailbox(to).into_owned().into())
+                    .collect(),
+                auth_identity: summary
+                    .auth_identity
+                    .as_ref()
+                    .map(|identity| redaction.identity(identity).into_owned().into()),
+                submitter: summary
+                    .submitter
+                    .as_ref()
+                    .map(|submitter| redaction.mailbox(submitter).into_owned().into()),
+                ..summary.clone()
+            });
+            value["type"] = json!("transaction");
+            value["client_address"] = json!(client_address);
+            value["duration_ms"] = json!(summary
+                .duration()
+                .map(|duration| duration.as_millis() as u64));
+            value
+        }
+    }
+}
+
+/// Returns a given time as milliseconds since the Unix epoch.
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Renders a transcript of a connection as JSON.
+#[cfg(feature = "content-inspection")]
+pub fn transcript_to_json(transcript: &str, client_address: Option<SocketAddr>) -> Value {
+    json!({
+        "type": "transcript",
+        "client_address": client_address.map(|address| address.ip().to_string()),
+        "transcript": transcript,
+    })
+}
+
+/// Publisher of events onto a shared queue registered by another Wasm extension,
+/// e.g. a singleton service.
+pub struct EventQueue<'a> {
+    // Shared Queue API implementation.
+    shared_queue: &'a dyn SharedQueue,
+    // Handle of the queue once it has been looked up.
+    handle: Option<SharedQueueHandle>,
+}
+
+impl<'a> EventQueue<'a> {
+    pub fn new(shared_queue: &'a dyn SharedQueue) -> Self {
+        EventQueue {
+            shared_queue,
+            handle: None,
+        }
+    }
+
+    /// Enqueues a given event.
+    ///
+    /// Returns `false` if the queue hasn't been registered yet.
+    pub fn publish(&mut self, config: &EventQueueConfig, event: &[u8]) -> Result<bool> {
+        if self.handle.is_none() {
+            self.handle = self.shared_queue.lookup(&config.vm_id, &config.name)?;
+        }
+        match self.handle {
+            Some(handle) => {
+                self.shared_queue.enqueue(handle, event)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::smtp::agent::{Session, SessionConfig};
+
+    use super::*;
+
+    #[test]
+    fn should_render_mailboxes_of_transactions() {
+        let mut session = Session::new(
+            SessionConfig {
+                transaction_events: true,
+                ..Default::default()
+            },
+            (),
+        );
+        session.on_new_conection().unwrap();
+        for (client, data) in [
+            (false, &b"220 mail.example.org ESMTP\r\n"[..]),
+            (true, b"HELO client.example.org\r\n"),
+            (false, b"250 mail.example.org\r\n"),
+            (true, b"MAIL FROM:<alice@example.org> SIZE=512\r\n"),
+            (false, b"250 OK\r\n"),
+            (true, b"RCPT TO:<bob@example.com>\r\n"),
+            (false, b"250 OK\r\n"),
+            (true, b"DATA\r\n"),
+            (false, b"354 Go ahead\r\n"),
+            (true, b"Subject: Hello\r\n\r\nHi Bob\r\n.\r\n"),
+            (false, b"250 Queued\r\n"),
+        ] {
+            if client {
+                session.on_downstream_data(data.to_vec().into()).unwrap();
+            } else {
+                session.on_upstream_data(data.to_vec().into()).unwrap();
+            }
+        }
+        let events = session.take_events();
+        let transaction = events
+            .iter()
+            .find(|event| matches!(event, Event::Transaction(_)))
+            .unwrap();
+        let client_address = "192.0.2.1:40000".parse().ok();
+
+        let value = to_json(transaction, client_address, &Redaction::default());
+        assert_eq!(value["type"], json!("transaction"));
+        assert_eq!(value["client_address"], json!("192.0.2.1"));
+        assert_eq!(value["from"], json!("alice@example.org"));
+        assert_eq!(value["to"], json!(["bob@example.com"]));
+
+        let redaction = Redaction {
+            mask_local_parts: true,
+            ..Default::default()
+        };
+        let value = to_json(transaction, client_address, &redaction);
+        assert_eq!(value["from"], json!("***@example.org"));
+        assert_eq!(value["to"], json!(["***@example.com"]));
+    }
+}
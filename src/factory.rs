@@ -12,48 +12,259 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cell::Cell;
 use std::convert::TryFrom;
-use std::rc::Rc;
+use std::marker::PhantomData;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
 
-use envoy::extension::{factory, ConfigStatus, ExtensionFactory, InstanceId, Result};
-use envoy::host::{ByteString, Stats};
+use envoy::extension::{factory, ConfigStatus, DrainStatus, ExtensionFactory, InstanceId, Result};
+use envoy::host::log::{self, LogLevel};
+use envoy::host::{ByteString, Clock, HttpClient, SharedData, SharedQueue, Stats, StreamInfo};
 
-use super::config::SmtpFilterConfig;
+use super::config::{ConfigHandle, SmtpFilterConfig, TimeoutAction};
 use super::filter::SmtpFilter;
-use super::stats::SmtpFilterStats;
+use super::host::{DownstreamDataMutationOps, DownstreamFlowOps, UpstreamDataMutationOps};
+use super::idle::IdleWatch;
+use super::lists::PolicyLists;
+use super::policy::PolicyHook;
+use super::runtime::{RuntimeToggles, SharedDataPoller};
+use super::smtp::agent::{CommandExtension, CommandRegistry, SmtpEventSink, StatsSink};
+use super::state;
+use super::stats::{FilterStatsSink, SmtpFilterStats, SmtpSessionStats};
 
 /// Factory for creating SMTP Filter instances
 /// (one filter instance per TCP connection).
-pub struct SmtpFilterFactory<'a> {
+///
+/// Stats of each connection are reported to `S`, which is created out of
+/// Envoy stats shared by filter instances.
+pub struct SmtpFilterFactory<'a, S = SmtpSessionStats<'a>> {
     // Stats API implementation.
     stats: &'a dyn Stats,
-    // Configuration shared by multiple filter instances.
-    filter_config: Rc<SmtpFilterConfig>,
+    // Stream Info API implementation.
+    stream_info: &'a dyn StreamInfo,
+    // Downstream data mutation API implementation.
+    downstream_data_ops: &'a dyn DownstreamDataMutationOps,
+    // Downstream flow control API implementation.
+    downstream_flow_ops: &'a dyn DownstreamFlowOps,
+    // Upstream data mutation API implementation.
+    upstream_data_ops: &'a dyn UpstreamDataMutationOps,
+    // HTTP Client API implementation.
+    http_client: &'a dyn HttpClient,
+    // Shared Data API implementation.
+    shared_data: &'a dyn SharedData,
+    // Shared Queue API implementation.
+    shared_queue: &'a dyn SharedQueue,
+    // Clock API implementation.
+    clock: &'a dyn Clock,
+    // Configuration to apply when `Envoy` provides none.
+    default_config: Rc<SmtpFilterConfig>,
+    // Configuration as received from `Envoy`.
+    base_config: Rc<SmtpFilterConfig>,
+    // Configuration shared by multiple filter instances, i.e. with policy lists
+    // and runtime switches applied.
+    filter_config: ConfigHandle,
     // Stats shared by multiple filter instances.
     filter_stats: Rc<SmtpFilterStats<'a>>,
+    // Whether the factory is being drained, shared by filter instances.
+    draining: Rc<Cell<bool>>,
+    // Poller of runtime switches.
+    runtime: SharedDataPoller<'a, RuntimeToggles>,
+    // Poller of policy lists.
+    policy_lists: SharedDataPoller<'a, PolicyLists>,
+    // Number of connections created, for sampling of debug logging.
+    connections: u64,
+    // Downstream activity of open connections, checked for idle clients on ticks.
+    idle_watches: Vec<Weak<IdleWatch>>,
+    // Commands filter instances understand, including registered extensions.
+    commands: Rc<CommandRegistry>,
+    // Observers every filter instance reports session events to.
+    event_sinks: Vec<Rc<dyn SmtpEventSink>>,
+    // Sinks every filter instance reports stats to along with Envoy stats.
+    stats_sinks: Vec<Rc<dyn StatsSink + 'a>>,
+    // Local policies every filter instance consults.
+    policy_hooks: Vec<Rc<dyn PolicyHook>>,
+    // Whether actions on listed, idle and slow clients are enforced by default.
+    enforce: bool,
+    // Type of stats sinks of filter instances.
+    stats_sink: PhantomData<fn() -> S>,
 }
 
-impl<'a> SmtpFilterFactory<'a> {
+impl<'a, S> SmtpFilterFactory<'a, S>
+where
+    S: FilterStatsSink + From<Rc<SmtpFilterStats<'a>>>,
+{
     /// Creates a new SmtpFilter factory.
-    pub fn new(stats: &'a dyn Stats) -> Result<Self> {
-        let config = SmtpFilterConfig::default();
-        let filter_stats = SmtpFilterStats::new(config.detailed_stats, stats)?;
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        stats: &'a dyn Stats,
+        stream_info: &'a dyn StreamInfo,
+        downstream_data_ops: &'a dyn DownstreamDataMutationOps,
+        downstream_flow_ops: &'a dyn DownstreamFlowOps,
+        upstream_data_ops: &'a dyn UpstreamDataMutationOps,
+        http_client: &'a dyn HttpClient,
+        shared_data: &'a dyn SharedData,
+        shared_queue: &'a dyn SharedQueue,
+        clock: &'a dyn Clock,
+    ) -> Result<Self> {
+        let config = Rc::new(SmtpFilterConfig::default());
+        let filter_stats = SmtpFilterStats::new(&config, stats)?;
         // Inject dependencies on Envoy host APIs
         Ok(SmtpFilterFactory {
             stats,
-            filter_config: Rc::new(config),
+            stream_info,
+            downstream_data_ops,
+            downstream_flow_ops,
+            upstream_data_ops,
+            http_client,
+            shared_data,
+            shared_queue,
+            clock,
+            default_config: Rc::clone(&config),
+            base_config: Rc::clone(&config),
+            filter_config: ConfigHandle::new(config),
             filter_stats: Rc::new(filter_stats),
+            draining: Rc::new(Cell::new(false)),
+            runtime: SharedDataPoller::new(shared_data, clock),
+            policy_lists: SharedDataPoller::new(shared_data, clock),
+            connections: 0,
+            idle_watches: Vec::new(),
+            commands: Rc::new(CommandRegistry::default()),
+            event_sinks: Vec::new(),
+            stats_sinks: Vec::new(),
+            policy_hooks: Vec::new(),
+            enforce: false,
+            stats_sink: PhantomData,
         })
     }
 
+    /// Replaces the configuration to apply when `Envoy` provides none.
+    pub fn set_default_config(&mut self, config: SmtpFilterConfig) -> Result<()> {
+        self.default_config = Rc::new(config);
+        self.base_config = Rc::clone(&self.default_config);
+        self.set_config(Rc::clone(&self.base_config))
+    }
+
+    /// Replaces the commands filter instances understand.
+    ///
+    /// Applies to connections created afterwards.
+    pub fn set_command_registry(&mut self, commands: CommandRegistry) {
+        self.commands = Rc::new(commands);
+    }
+
+    /// Registers a command that is not built into SMTP filter, e.g. a vendor X-command.
+    ///
+    /// Applies to connections created afterwards.
+    pub fn register_command(&mut self, extension: Rc<dyn CommandExtension>) {
+        Rc::make_mut(&mut self.commands).register(extension);
+    }
+
+    /// Adds a sink that is told about every connection along with Envoy stats,
+    /// e.g. an access log or a shared queue.
+    ///
+    /// Applies to connections created afterwards.
+    pub fn add_stats_sink(&mut self, stats_sink: Rc<dyn StatsSink + 'a>) {
+        self.stats_sinks.push(stats_sink);
+    }
+
+    /// Adds a local policy that is consulted on envelope commands and messages
+    /// ahead of external policy services.
+    ///
+    /// Applies to connections created afterwards.
+    pub fn add_policy_hook(&mut self, policy_hook: Rc<dyn PolicyHook>) {
+        self.policy_hooks.push(policy_hook);
+    }
+
+    /// Adds an observer of commands, replies and mail transactions, e.g. for
+    /// export of metadata.
+    ///
+    /// Applies to connections created afterwards.
+    pub fn add_event_sink(&mut self, event_sink: Rc<dyn SmtpEventSink>) {
+        self.event_sinks.push(event_sink);
+    }
+
+    // Replaces the configuration of new and existing filter instances.
+    fn set_config(&mut self, config: Rc<SmtpFilterConfig>) -> Result<()> {
+        if !self.filter_stats.is_configured_for(&config) {
+            let filter_stats = SmtpFilterStats::new(&config, self.stats)?;
+            self.filter_stats = Rc::new(filter_stats);
+        }
+        self.filter_config.set(config);
+        Ok(())
+    }
+
+    // Applies runtime switches and policy lists once either has changed.
+    fn poll_shared_data(&mut self) -> Result<()> {
+        let mut changed = false;
+        if let Some(runtime) = self.base_config.runtime.as_ref() {
+            if self.runtime.poll(&runtime.key, runtime.poll_interval())? {
+                let toggles = self.runtime.current().cloned().unwrap_or_default();
+                log::info!("applying runtime switches: {:?}", toggles);
+                log::set_max_level(toggles.log_level.map_or(LogLevel::Info, LogLevel::from));
+                changed = true;
+            }
+        }
+        if let Some(policy_lists) = self.base_config.policy_lists.as_ref() {
+            if self
+                .policy_lists
+                .poll(&policy_lists.key, policy_lists.poll_interval())?
+            {
+                log::info!("applying policy lists from {}", policy_lists.key);
+                changed = true;
+            }
+        }
+        if !changed {
+            return Ok(());
+        }
+        let mut config = match self.policy_lists.current() {
+            Some(lists) => self.base_config.with_policy_lists(lists),
+            None => self.base_config.as_ref().clone(),
+        };
+        if let Some(toggles) = self.runtime.current() {
+            config = config.with_runtime(toggles);
+        }
+        self.set_config(Rc::new(config))
+    }
+}
+
+impl<'a> SmtpFilterFactory<'a> {
+    /// Returns a builder of a factory bound to the actual Envoy ABI.
+    pub fn builder() -> SmtpFilterFactoryBuilder<'a> {
+        SmtpFilterFactoryBuilder::new(
+            <dyn Stats>::default(),
+            <dyn StreamInfo>::default(),
+            <dyn DownstreamDataMutationOps>::default(),
+            <dyn DownstreamFlowOps>::default(),
+            <dyn UpstreamDataMutationOps>::default(),
+            <dyn HttpClient>::default(),
+            <dyn SharedData>::default(),
+            <dyn SharedQueue>::default(),
+            <dyn Clock>::default(),
+        )
+    }
+
     /// Creates a new factory bound to the actual Envoy ABI.
+    #[allow(clippy::should_implement_trait)]
     pub fn default() -> Result<Self> {
-        Self::new(Stats::default())
+        Self::new(
+            <dyn Stats>::default(),
+            <dyn StreamInfo>::default(),
+            <dyn DownstreamDataMutationOps>::default(),
+            <dyn DownstreamFlowOps>::default(),
+            <dyn UpstreamDataMutationOps>::default(),
+            <dyn HttpClient>::default(),
+            <dyn SharedData>::default(),
+            <dyn SharedQueue>::default(),
+            <dyn Clock>::default(),
+        )
     }
 }
 
-impl<'a> ExtensionFactory for SmtpFilterFactory<'a> {
-    type Extension = SmtpFilter<'a>;
+impl<'a, S> ExtensionFactory for SmtpFilterFactory<'a, S>
+where
+    S: FilterStatsSink + From<Rc<SmtpFilterStats<'a>>>,
+{
+    type Extension = SmtpFilter<'a, S>;
 
     /// The reference name for the SMTP Filter.
     ///
@@ -66,28 +277,454 @@ impl<'a> ExtensionFactory for SmtpFilterFactory<'a> {
     fn on_configure(
         &mut self,
         config: ByteString,
-        _ops: &dyn factory::ConfigureOps,
+        ops: &dyn factory::ConfigureOps,
     ) -> Result<ConfigStatus> {
         let filter_config = if config.is_empty() {
-            SmtpFilterConfig::default()
+            self.default_config.as_ref().clone()
         } else {
-            SmtpFilterConfig::try_from(config.as_bytes())?
+            match SmtpFilterConfig::try_from(config.as_bytes()) {
+                Ok(filter_config) => filter_config,
+                Err(err) => {
+                    log::error!("rejecting configuration: {}", err);
+                    return Ok(ConfigStatus::Rejected);
+                }
+            }
         };
-        self.filter_config = Rc::new(filter_config);
-        if self.filter_config.detailed_stats != self.filter_stats.is_detailed() {
-            let filter_stats = SmtpFilterStats::new(self.filter_config.detailed_stats, self.stats)?;
-            self.filter_stats = Rc::new(filter_stats);
-        }
+        self.base_config = if self.enforce {
+            Rc::new(filter_config.with_enforcement(true))
+        } else {
+            Rc::new(filter_config)
+        };
+        // runtime switches and policy lists are re-applied on the next tick or connection
+        self.runtime = SharedDataPoller::new(self.shared_data, self.clock);
+        self.policy_lists = SharedDataPoller::new(self.shared_data, self.clock);
+        self.set_config(Rc::clone(&self.base_config))?;
+        ops.set_tick_period(self.base_config.tick_period().unwrap_or(Duration::ZERO))?;
         Ok(ConfigStatus::Accepted)
     }
 
     /// Is called to create a unique instance of SMTP Filter
     /// for each TCP connection.
     fn new_extension(&mut self, instance_id: InstanceId) -> Result<Self::Extension> {
-        Ok(SmtpFilter::new(
+        self.poll_shared_data()?;
+        self.connections += 1;
+        let mut filter = SmtpFilter::new(
             instance_id,
-            Rc::clone(&self.filter_config),
-            Rc::clone(&self.filter_stats),
-        ))
+            self.filter_config.clone(),
+            S::from(Rc::clone(&self.filter_stats)),
+            Rc::clone(&self.draining),
+            self.stream_info,
+            self.downstream_data_ops,
+            self.downstream_flow_ops,
+            self.upstream_data_ops,
+            self.http_client,
+            self.shared_data,
+            self.shared_queue,
+            self.clock,
+        );
+        self.idle_watches.push(Rc::downgrade(&filter.idle_watch()));
+        filter.set_command_registry(Rc::clone(&self.commands));
+        for stats_sink in self.stats_sinks.iter() {
+            filter.add_stats_sink(Rc::clone(stats_sink));
+        }
+        for event_sink in self.event_sinks.iter() {
+            filter.add_event_sink(Rc::clone(event_sink));
+        }
+        for policy_hook in self.policy_hooks.iter() {
+            filter.add_policy_hook(Rc::clone(policy_hook));
+        }
+        if let Some(rate) = self.filter_config.get().debug_sample_rate {
+            if self.connections.is_multiple_of(u64::from(rate)) {
+                filter.enable_debug_logging();
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Is called when the factory is about to be destroyed, e.g. upon shutdown.
+    ///
+    /// Connections that remain open are asked to wind down on their next MAIL command.
+    fn on_drain(&mut self) -> Result<DrainStatus> {
+        log::info!("draining SMTP connections");
+        self.draining.set(true);
+        Ok(DrainStatus::Complete)
+    }
+
+    /// Is called periodically once connections or shared data need to be checked on.
+    ///
+    /// Runtime switches and policy lists reach open connections on their next event,
+    /// while clients that have gone silent give their connections no events,
+    /// so their idle timeout is checked here.
+    fn on_tick(&mut self, ops: &dyn factory::TickOps) -> Result<()> {
+        self.poll_shared_data()?;
+        let now = self.clock.now()?;
+        self.idle_watches.retain(|watch| watch.strong_count() > 0);
+        for watch in self.idle_watches.iter().filter_map(Weak::upgrade) {
+            let (idle_for, action) = match watch.check(now) {
+                Some(timeout) => timeout,
+                None => continue,
+            };
+            // subsequent calls apply to the connection of the idle client
+            ops.set_effective_context(watch.instance_id())?;
+            log::info!(
+                "#{} client has been idle for {:?}",
+                watch.instance_id(),
+                idle_for
+            );
+            self.filter_stats.on_idle_timeout()?;
+            self.stream_info
+                .set_stream_property(&[state::IDLE_TIMEOUT], b"true")?;
+            if action == TimeoutAction::Close {
+                self.downstream_flow_ops.close_downstream()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builder of SMTP Filter factory with custom configuration and extensions,
+/// e.g. for a module that embeds SMTP Filter along with its own policies.
+pub struct SmtpFilterFactoryBuilder<'a, S = SmtpSessionStats<'a>> {
+    stats: &'a dyn Stats,
+    stream_info: &'a dyn StreamInfo,
+    downstream_data_ops: &'a dyn DownstreamDataMutationOps,
+    downstream_flow_ops: &'a dyn DownstreamFlowOps,
+    upstream_data_ops: &'a dyn UpstreamDataMutationOps,
+    http_client: &'a dyn HttpClient,
+    shared_data: &'a dyn SharedData,
+    shared_queue: &'a dyn SharedQueue,
+    clock: &'a dyn Clock,
+    config: Option<SmtpFilterConfig>,
+    commands: CommandRegistry,
+    stats_sinks: Vec<Rc<dyn StatsSink + 'a>>,
+    event_sinks: Vec<Rc<dyn SmtpEventSink>>,
+    policy_hooks: Vec<Rc<dyn PolicyHook>>,
+    stats_sink: PhantomData<fn() -> S>,
+}
+
+impl<'a, S> SmtpFilterFactoryBuilder<'a, S>
+where
+    S: FilterStatsSink + From<Rc<SmtpFilterStats<'a>>>,
+{
+    /// Creates a new builder of a factory bound to given host APIs.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        stats: &'a dyn Stats,
+        stream_info: &'a dyn StreamInfo,
+        downstream_data_ops: &'a dyn DownstreamDataMutationOps,
+        downstream_flow_ops: &'a dyn DownstreamFlowOps,
+        upstream_data_ops: &'a dyn UpstreamDataMutationOps,
+        http_client: &'a dyn HttpClient,
+        shared_data: &'a dyn SharedData,
+        shared_queue: &'a dyn SharedQueue,
+        clock: &'a dyn Clock,
+    ) -> Self {
+        SmtpFilterFactoryBuilder {
+            stats,
+            stream_info,
+            downstream_data_ops,
+            downstream_flow_ops,
+            upstream_data_ops,
+            http_client,
+            shared_data,
+            shared_queue,
+            clock,
+            config: None,
+            commands: CommandRegistry::default(),
+            stats_sinks: Vec::new(),
+            event_sinks: Vec::new(),
+            policy_hooks: Vec::new(),
+            stats_sink: PhantomData,
+        }
+    }
+
+    /// Sets the configuration to apply when `Envoy` provides none.
+    pub fn config(mut self, config: SmtpFilterConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Replaces the commands filter instances understand.
+    pub fn command_registry(mut self, commands: CommandRegistry) -> Self {
+        self.commands = commands;
+        self
+    }
+
+    /// Registers a command that is not built into SMTP filter.
+    pub fn register_command(mut self, extension: Rc<dyn CommandExtension>) -> Self {
+        self.commands.register(extension);
+        self
+    }
+
+    /// Adds a sink that is told about every connection along with Envoy stats.
+    pub fn stats_sink(mut self, stats_sink: Rc<dyn StatsSink + 'a>) -> Self {
+        self.stats_sinks.push(stats_sink);
+        self
+    }
+
+    /// Adds an observer of commands, replies and mail transactions.
+    pub fn event_sink(mut self, event_sink: Rc<dyn SmtpEventSink>) -> Self {
+        self.event_sinks.push(event_sink);
+        self
+    }
+
+    /// Adds a local policy that is consulted on envelope commands and messages.
+    pub fn policy_hook(mut self, policy_hook: Rc<dyn PolicyHook>) -> Self {
+        self.policy_hooks.push(policy_hook);
+        self
+    }
+
+    /// Creates the factory.
+    pub fn build(self) -> Result<SmtpFilterFactory<'a, S>> {
+        let mut factory = SmtpFilterFactory::new(
+            self.stats,
+            self.stream_info,
+            self.downstream_data_ops,
+            self.downstream_flow_ops,
+            self.upstream_data_ops,
+            self.http_client,
+            self.shared_data,
+            self.shared_queue,
+            self.clock,
+        )?;
+        if let Some(config) = self.config {
+            factory.set_default_config(config)?;
+        }
+        factory.set_command_registry(self.commands);
+        factory.stats_sinks = self.stats_sinks;
+        factory.event_sinks = self.event_sinks;
+        factory.policy_hooks = self.policy_hooks;
+        Ok(factory)
+    }
+
+    /// Creates the factory registered under the name of the enforcing SMTP Filter.
+    pub fn build_enforcing(self) -> Result<SmtpEnforcingFilterFactory<'a, S>> {
+        let mut factory = self.build()?;
+        factory.enforce = true;
+        Ok(SmtpEnforcingFilterFactory(factory))
+    }
+}
+
+/// Factory for creating SMTP Filter instances that reject listed clients
+/// and close idle and slow ones unless configured otherwise.
+///
+/// It is registered under a name of its own, so that operators can run it
+/// on some filter chains while others keep observing only.
+pub struct SmtpEnforcingFilterFactory<'a, S = SmtpSessionStats<'a>>(SmtpFilterFactory<'a, S>);
+
+impl<'a, S> ExtensionFactory for SmtpEnforcingFilterFactory<'a, S>
+where
+    S: FilterStatsSink + From<Rc<SmtpFilterStats<'a>>>,
+{
+    type Extension = SmtpFilter<'a, S>;
+
+    /// The reference name for the enforcing SMTP Filter.
+    ///
+    /// This name appears in `Envoy` configuration as a value of `root_id` field.
+    fn name() -> &'static str {
+        "tetratelabs.filters.network.smtp.enforce"
+    }
+
+    fn on_configure(
+        &mut self,
+        config: ByteString,
+        ops: &dyn factory::ConfigureOps,
+    ) -> Result<ConfigStatus> {
+        self.0.on_configure(config, ops)
+    }
+
+    fn new_extension(&mut self, instance_id: InstanceId) -> Result<Self::Extension> {
+        self.0.new_extension(instance_id)
+    }
+
+    fn on_drain(&mut self) -> Result<DrainStatus> {
+        self.0.on_drain()
+    }
+
+    fn on_tick(&mut self, ops: &dyn factory::TickOps) -> Result<()> {
+        self.0.on_tick(ops)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use envoy::extension::NetworkFilter;
+    use envoy::host::{SharedData, StreamInfo};
+
+    use super::*;
+    use crate::host::fake::FakeHost;
+
+    fn configure_idle_timeout<F: ExtensionFactory>(factory: &mut F, host: &FakeHost) {
+        let config = ByteString::from(r#"{"idle_timeout": {"timeout_ms": 1000}}"#);
+        assert_eq!(
+            factory.on_configure(config, host).unwrap(),
+            ConfigStatus::Accepted
+        );
+    }
+
+    #[test]
+    fn should_enforce_only_when_registered_as_enforcing() {
+        let host = FakeHost::default();
+        let builder = || {
+            SmtpFilterFactoryBuilder::new(
+                &host, &host, &host, &host, &host, &host, &host, &host, &host,
+            )
+        };
+
+        let mut observing: SmtpFilterFactory = builder().build().unwrap();
+        configure_idle_timeout(&mut observing, &host);
+        let config = observing.filter_config.get();
+        assert_eq!(
+            config.idle_timeout.as_ref().unwrap().action,
+            TimeoutAction::Observe
+        );
+
+        let mut enforcing: SmtpEnforcingFilterFactory = builder().build_enforcing().unwrap();
+        configure_idle_timeout(&mut enforcing, &host);
+        let config = enforcing.0.filter_config.get();
+        assert_eq!(
+            config.idle_timeout.as_ref().unwrap().action,
+            TimeoutAction::Close
+        );
+
+        assert_ne!(
+            SmtpFilterFactory::<SmtpSessionStats>::name(),
+            SmtpEnforcingFilterFactory::<SmtpSessionStats>::name()
+        );
+    }
+
+    #[test]
+    fn should_close_idle_connections_on_tick() {
+        let host = FakeHost::default();
+        let mut factory: SmtpEnforcingFilterFactory = SmtpFilterFactoryBuilder::new(
+            &host, &host, &host, &host, &host, &host, &host, &host, &host,
+        )
+        .build_enforcing()
+        .unwrap();
+        factory.on_configure(ByteString::default(), &host).unwrap();
+        assert_eq!(host.tick_period(), Duration::ZERO);
+        configure_idle_timeout(&mut factory, &host);
+        assert_eq!(host.tick_period(), Duration::from_secs(1));
+
+        let mut filter = factory.new_extension(InstanceId::from(2)).unwrap();
+        filter.on_new_connection().unwrap();
+        host.deliver_upstream(&mut filter, b"220 mail.example.org ESMTP\r\n", false)
+            .unwrap();
+        host.advance(Duration::from_millis(500));
+        factory.on_tick(&host).unwrap();
+        assert!(!host.is_downstream_closed());
+
+        // the client sends nothing, so the filter gets no events
+        host.advance(Duration::from_millis(1000));
+        factory.on_tick(&host).unwrap();
+        assert_eq!(host.effective_context(), Some(InstanceId::from(2)));
+        assert!(host.is_downstream_closed());
+        assert_eq!(
+            host.counter_value("smtp.connections.idle_timeouts.total"),
+            1
+        );
+        assert_eq!(
+            host.stream_property(&[state::IDLE_TIMEOUT]).unwrap(),
+            Some(ByteString::from("true"))
+        );
+
+        // neither ticks nor events of the connection account it again
+        host.advance(Duration::from_millis(1000));
+        factory.on_tick(&host).unwrap();
+        host.deliver_downstream(&mut filter, b"QUIT\r\n", false)
+            .unwrap();
+        assert_eq!(
+            host.counter_value("smtp.connections.idle_timeouts.total"),
+            1
+        );
+
+        drop(filter);
+        factory.on_tick(&host).unwrap();
+        assert!(factory.0.idle_watches.is_empty());
+    }
+
+    #[test]
+    fn should_apply_runtime_switches_to_open_connections_on_tick() {
+        let host = FakeHost::default();
+        let mut factory: SmtpFilterFactory = SmtpFilterFactoryBuilder::new(
+            &host, &host, &host, &host, &host, &host, &host, &host, &host,
+        )
+        .build()
+        .unwrap();
+        let config = ByteString::from(
+            r#"{"idle_timeout": {"timeout_ms": 1000}, "runtime": {"poll_interval_ms": 500}}"#,
+        );
+        factory.on_configure(config, &host).unwrap();
+        assert_eq!(host.tick_period(), Duration::from_millis(500));
+
+        let mut filter = factory.new_extension(InstanceId::from(2)).unwrap();
+        filter.on_new_connection().unwrap();
+        host.deliver_upstream(&mut filter, b"220 mail.example.org ESMTP\r\n", false)
+            .unwrap();
+        host.set("smtp.runtime", br#"{"enforce": true}"#, None)
+            .unwrap();
+        host.advance(Duration::from_millis(500));
+        factory.on_tick(&host).unwrap();
+        assert_eq!(
+            factory
+                .filter_config
+                .get()
+                .idle_timeout
+                .as_ref()
+                .unwrap()
+                .action,
+            TimeoutAction::Close
+        );
+
+        // the connection picks up the switch on its next event
+        host.deliver_downstream(&mut filter, b"HELO client.example.org\r\n", false)
+            .unwrap();
+        host.advance(Duration::from_millis(1500));
+        factory.on_tick(&host).unwrap();
+        assert!(host.is_downstream_closed());
+        assert_eq!(
+            host.counter_value("smtp.connections.idle_timeouts.total"),
+            1
+        );
+    }
+
+    #[test]
+    fn should_apply_policy_lists_to_open_connections_on_tick() {
+        let host = FakeHost::default();
+        let mut factory: SmtpFilterFactory = SmtpFilterFactoryBuilder::new(
+            &host, &host, &host, &host, &host, &host, &host, &host, &host,
+        )
+        .build()
+        .unwrap();
+        let config = ByteString::from(r#"{"policy_lists": {"poll_interval_ms": 1000}}"#);
+        factory.on_configure(config, &host).unwrap();
+        assert_eq!(host.tick_period(), Duration::from_secs(1));
+
+        let mut filter = factory.new_extension(InstanceId::from(2)).unwrap();
+        filter.on_new_connection().unwrap();
+        for (data, is_downstream) in [
+            (&b"220 mail.example.org ESMTP\r\n"[..], false),
+            (b"HELO client.example.org\r\n", true),
+            (b"250 mail.example.org\r\n", false),
+        ] {
+            if is_downstream {
+                host.deliver_downstream(&mut filter, data, false).unwrap();
+            } else {
+                host.deliver_upstream(&mut filter, data, false).unwrap();
+            }
+        }
+        host.set(
+            "smtp.policy_lists",
+            br#"{"senders": {"deny": ["alice@example.org"]}}"#,
+            None,
+        )
+        .unwrap();
+        host.advance(Duration::from_millis(1000));
+        factory.on_tick(&host).unwrap();
+
+        host.deliver_downstream(&mut filter, b"MAIL FROM:<alice@example.org>\r\n", false)
+            .unwrap();
+        assert!(host.is_downstream_closed());
+        assert_eq!(host.to_upstream(), b"HELO client.example.org\r\n".to_vec());
     }
 }
@@ -12,83 +12,1724 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::fmt;
+use std::net::SocketAddr;
 use std::rc::Rc;
+use std::time::{Duration, SystemTime};
 
+use bstr::ByteSlice;
 use envoy::extension::{filter::network, InstanceId, NetworkFilter, Result};
-use envoy::host::log;
+use envoy::host::{
+    log, Clock, HttpClient, HttpClientRequestHandle, HttpClientResponseOps, SharedData,
+    SharedQueue, StreamInfo,
+};
+use proxy_wasm::types::PeerType;
+use serde_json::json;
 
-use crate::config::SmtpFilterConfig;
-use crate::smtp::agent::{Mode, Session};
-use crate::stats::SmtpFilterStats;
+#[cfg(feature = "policy")]
+use crate::config::DnsblAction;
+use crate::config::{ConfigHandle, DataTimeoutAction, SmtpFilterConfig, TimeoutAction};
+#[cfg(feature = "policy")]
+use crate::dnsbl::{Answer, DnsblCache};
+use crate::events::{self, EventQueue};
+use crate::host::{DownstreamDataMutationOps, DownstreamFlowOps, UpstreamDataMutationOps};
+use crate::idle::IdleWatch;
+use crate::policy::{Callout, Decision, PolicyClient, PolicyHook};
+#[cfg(feature = "policy")]
+use crate::reputation::{Reputation, ReputationStore};
+#[cfg(feature = "policy")]
+use crate::smtp::agent::Offense;
+use crate::smtp::agent::{
+    ClientCertificate, CommandRegistry, CompositeSink, Event, FilterLogLevel, Mode, Session,
+    SessionConfig, SessionSummary, SmtpEventSink, StatsSink,
+};
+use crate::state;
+use crate::stats::{stat_source, FilterStatsSink, SmtpSessionStats};
+#[cfg(feature = "content-inspection")]
+use crate::transcript::{Party, Transcript};
+use crate::webhook::WebhookClient;
 
 /// Envoy SMTP Filter.
-pub struct SmtpFilter<'a> {
-    // SMTP Filter instance id.
-    instance_id: InstanceId,
-    // Configuration shared by multiple filter instances.
+///
+/// Stats of the connection are reported to `S`, which is backed by Envoy stats
+/// by default.
+pub struct SmtpFilter<'a, S: FilterStatsSink = SmtpSessionStats<'a>> {
+    // SMTP Filter instance id along with the client address for logging.
+    log_id: LogId,
+    // Latest configuration shared by multiple filter instances.
+    config_handle: ConfigHandle,
+    // Latest configuration as of the latest refresh.
+    latest_config: Rc<SmtpFilterConfig>,
+    // Configuration of the connection, i.e. with the selected profile applied.
     config: Rc<SmtpFilterConfig>,
-    session: Session<Rc<SmtpFilterStats<'a>>>,
+    // Policy profile selected for the connection.
+    profile: Option<String>,
+    // Stream Info API implementation.
+    stream_info: &'a dyn StreamInfo,
+    // Downstream data mutation API implementation.
+    downstream_data_ops: &'a dyn DownstreamDataMutationOps,
+    // Downstream flow control API implementation.
+    downstream_flow_ops: &'a dyn DownstreamFlowOps,
+    // Upstream data mutation API implementation.
+    upstream_data_ops: &'a dyn UpstreamDataMutationOps,
+    // Clock API implementation.
+    clock: &'a dyn Clock,
+    session: Session<CompositeSink<'a, S>>,
+    // Client of external policy services.
+    policy_client: PolicyClient<'a>,
+    // Local policies consulted ahead of external policy services.
+    policy_hooks: Vec<Rc<dyn PolicyHook>>,
+    // Cache of DNSBL answers shared by filter instances.
+    #[cfg(feature = "policy")]
+    dnsbl_cache: DnsblCache<'a>,
+    // Store of client reputations shared by filter instances.
+    #[cfg(feature = "policy")]
+    reputation_store: ReputationStore<'a>,
+    // Reputation of the client as of the start of the connection.
+    #[cfg(feature = "policy")]
+    reputation: Option<Reputation>,
+    // Client of the transaction webhook.
+    webhook_client: WebhookClient<'a>,
+    // Shared queue to publish events onto.
+    event_queue: EventQueue<'a>,
+    // Summary of the session as of the latest export to stream properties.
+    exported_summary: Option<SessionSummary>,
+    // Size of downstream data that is being held back.
+    held_downstream_size: usize,
+    // Size of an incomplete command line at the end of data that is being held back.
+    held_partial_size: usize,
+    // Whether the factory is being drained, shared by filter instances.
+    draining: Rc<Cell<bool>>,
+    // Downstream activity for the sake of the idle timeout, shared with the factory.
+    idle_watch: Rc<IdleWatch>,
+    // Time the session has been observed to enter DATA phase at, if the DATA
+    // timeout is configured.
+    data_started_at: Option<SystemTime>,
+    // Time the latest incomplete command line has started to arrive at,
+    // if the detection of slow clients is configured.
+    partial_command_started_at: Option<SystemTime>,
+    // Whether the client has been found to trickle commands.
+    slow: bool,
+    // Whether the connection has been sampled for full debug logging.
+    debug_logging: bool,
+    // Time the connection has been open at.
+    connected_at: Option<SystemTime>,
+    // Size of data received from the client, for the summary on close.
+    downstream_bytes: usize,
+    // Size of data received from the server, for the summary on close.
+    upstream_bytes: usize,
+    // Transcript of the connection, if captured.
+    #[cfg(feature = "content-inspection")]
+    transcript: Option<Transcript>,
+}
+
+// Identifies a connection in logs, e.g. `#2 [192.0.2.1:51234]`.
+struct LogId {
+    instance_id: InstanceId,
+    client_address: Option<SocketAddr>,
+}
+
+impl fmt::Display for LogId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.client_address {
+            Some(address) => write!(f, "#{} [{}]", self.instance_id, address),
+            None => write!(f, "#{}", self.instance_id),
+        }
+    }
 }
 
-impl<'a> SmtpFilter<'a> {
+/// Verdict represents a decision on downstream data that is being held back.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Verdict {
+    /// Forward held data to the upstream.
+    Release,
+    /// Close the downstream connection.
+    Reject,
+}
+
+impl<'a, S: FilterStatsSink> SmtpFilter<'a, S> {
     /// Creates a new instance of SMTP Filter.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         instance_id: InstanceId,
-        config: Rc<SmtpFilterConfig>,
-        stats: Rc<SmtpFilterStats<'a>>,
+        config_handle: ConfigHandle,
+        stats_sink: S,
+        draining: Rc<Cell<bool>>,
+        stream_info: &'a dyn StreamInfo,
+        downstream_data_ops: &'a dyn DownstreamDataMutationOps,
+        downstream_flow_ops: &'a dyn DownstreamFlowOps,
+        upstream_data_ops: &'a dyn UpstreamDataMutationOps,
+        http_client: &'a dyn HttpClient,
+        shared_data: &'a dyn SharedData,
+        shared_queue: &'a dyn SharedQueue,
+        clock: &'a dyn Clock,
     ) -> Self {
         // Inject dependencies on Envoy host APIs
+        let config = config_handle.get();
+        let session_config = SessionConfig::from(config.as_ref());
+        let idle_watch = Rc::new(IdleWatch::new(instance_id, config.idle_timeout.clone()));
+        // shared data only backs the DNSBL cache and client reputations
+        #[cfg(not(feature = "policy"))]
+        let _ = shared_data;
+        #[cfg(feature = "content-inspection")]
+        let transcript = config
+            .transcript
+            .as_ref()
+            .filter(|transcript| !transcript.sampled_only)
+            .map(|transcript| Transcript::new(transcript.max_bytes));
         SmtpFilter {
-            instance_id,
+            log_id: LogId {
+                instance_id,
+                client_address: None,
+            },
+            config_handle,
+            latest_config: Rc::clone(&config),
             config,
-            session: Session::new(stats),
+            profile: None,
+            stream_info,
+            downstream_data_ops,
+            downstream_flow_ops,
+            upstream_data_ops,
+            clock,
+            session: Session::new(session_config, CompositeSink::new(stats_sink)),
+            policy_client: PolicyClient::new(http_client, clock),
+            policy_hooks: Vec::new(),
+            #[cfg(feature = "policy")]
+            dnsbl_cache: DnsblCache::new(shared_data, clock),
+            #[cfg(feature = "policy")]
+            reputation_store: ReputationStore::new(shared_data, clock),
+            #[cfg(feature = "policy")]
+            reputation: None,
+            webhook_client: WebhookClient::new(http_client),
+            event_queue: EventQueue::new(shared_queue),
+            exported_summary: None,
+            held_downstream_size: 0,
+            held_partial_size: 0,
+            draining,
+            idle_watch,
+            data_started_at: None,
+            partial_command_started_at: None,
+            slow: false,
+            debug_logging: false,
+            connected_at: None,
+            downstream_bytes: 0,
+            upstream_bytes: 0,
+            #[cfg(feature = "content-inspection")]
+            transcript,
+        }
+    }
+
+    /// Returns downstream activity of the connection, so that the factory
+    /// can check the idle timeout on its ticks.
+    pub(crate) fn idle_watch(&self) -> Rc<IdleWatch> {
+        Rc::clone(&self.idle_watch)
+    }
+
+    /// Promotes debug and trace logs of the connection, including every command
+    /// and reply, to `info` level.
+    pub fn enable_debug_logging(&mut self) {
+        self.debug_logging = true;
+        self.session.enable_debug_logging();
+        #[cfg(feature = "content-inspection")]
+        if let Some(transcript) = self.config.transcript.as_ref() {
+            self.transcript = Some(Transcript::new(transcript.max_bytes));
+        }
+    }
+
+    /// Replaces the commands the connection understands, e.g. with extensions
+    /// registered on the factory.
+    pub fn set_command_registry(&mut self, commands: Rc<CommandRegistry>) {
+        self.session.set_command_registry(commands);
+    }
+
+    /// Adds a sink that is told about the connection along with Envoy stats.
+    pub fn add_stats_sink(&mut self, stats_sink: Rc<dyn StatsSink + 'a>) {
+        self.session.stats_sink_mut().push(stats_sink);
+    }
+
+    /// Adds a local policy that is consulted on envelope commands and messages.
+    ///
+    /// Must be called before any data has been observed.
+    pub fn add_policy_hook(&mut self, policy_hook: Rc<dyn PolicyHook>) {
+        self.policy_hooks.push(policy_hook);
+        self.session.reconfigure(self.session_config(&self.config));
+    }
+
+    // Returns the configuration of the session, which collects envelope commands
+    // and messages for policy hooks as well.
+    fn session_config(&self, config: &SmtpFilterConfig) -> SessionConfig {
+        let mut session_config = SessionConfig::from(config);
+        if !self.policy_hooks.is_empty() {
+            session_config.envelope_checks = true;
+            session_config.content_checks = true;
+        }
+        session_config
+    }
+
+    /// Adds an observer of commands, replies and mail transactions of the connection.
+    pub fn add_event_sink(&mut self, event_sink: Rc<dyn SmtpEventSink>) {
+        self.session.add_event_sink(event_sink);
+    }
+
+    // Logs a single-line summary of the connection once it is closed.
+    fn log_session(&self) -> Result<()> {
+        let session_log = match self.config.session_log.as_ref() {
+            Some(session_log) => session_log,
+            None => return Ok(()),
+        };
+        let duration = match self.connected_at {
+            Some(connected_at) => self
+                .clock
+                .now()?
+                .duration_since(connected_at)
+                .unwrap_or_default(),
+            None => Duration::default(),
+        };
+        let totals = self.session.totals();
+        log_at!(
+            session_log.level,
+            "{} session closed: commands={} transactions={} messages={} parse_errors={} \
+             authenticated={} bytes_received={} bytes_sent={} mode={:?} duration_ms={}",
+            self.log_id,
+            totals.commands,
+            totals.transactions,
+            self.session.summary().messages,
+            totals.parse_errors,
+            self.session.summary().authenticated,
+            self.downstream_bytes,
+            self.upstream_bytes,
+            self.session.mode(),
+            duration.as_millis()
+        );
+        Ok(())
+    }
+
+    // Records data of the latest chunk in the transcript, if captured.
+    #[cfg(feature = "content-inspection")]
+    fn record_transcript(&mut self, party: Party, data: &[u8]) {
+        if self.transcript.is_none() {
+            return;
+        }
+        let in_data = party == Party::Client && self.session.mode() == Mode::Data;
+        let data = if party == Party::Client && self.session.awaits_sasl_response() {
+            Cow::Owned(self.config.redaction.sasl_response(data))
+        } else {
+            self.config.redaction.data(data)
+        };
+        if let Some(transcript) = self.transcript.as_mut() {
+            if in_data {
+                transcript.elide(data.len());
+            } else {
+                transcript.record(party, &data);
+            }
+        }
+    }
+
+    // Logs or publishes the transcript of the connection once it is closed.
+    #[cfg(feature = "content-inspection")]
+    fn emit_transcript(&mut self) -> Result<()> {
+        let (config, transcript) = match (self.config.transcript.as_ref(), self.transcript.take()) {
+            (Some(config), Some(transcript)) => (config, transcript.finish()),
+            _ => return Ok(()),
+        };
+        match self.config.event_queue.as_ref() {
+            Some(queue) if config.publish => {
+                let json = events::transcript_to_json(&transcript, self.session.client_address())
+                    .to_string();
+                let published = self
+                    .event_queue
+                    .publish(queue, json.as_bytes())
+                    .unwrap_or_else(|err| {
+                        log::warn!("{} failed to publish transcript: {}", self.log_id, err);
+                        false
+                    });
+                self.session
+                    .stats_sink()
+                    .primary()
+                    .on_event_published(published)?;
+            }
+            _ => log::info!("{} transcript:\n{}", self.log_id, transcript),
+        }
+        Ok(())
+    }
+
+    // Transcripts are compiled out.
+    #[cfg(not(feature = "content-inspection"))]
+    fn emit_transcript(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    // Returns data the way it can be logged, i.e. with personal data and credentials
+    // redacted and messages truncated.
+    fn loggable<'d>(&self, data: &'d [u8]) -> Cow<'d, [u8]> {
+        let redaction = &self.config.redaction;
+        if self.session.awaits_sasl_response() {
+            Cow::Owned(redaction.sasl_response(data))
+        } else if self.session.mode() == Mode::Data {
+            redaction.data(redaction.body(data))
+        } else {
+            redaction.data(data)
+        }
+    }
+
+    // Returns the log level of the connection that overrides the one of `Envoy`, if any.
+    fn log_level(&self) -> Option<FilterLogLevel> {
+        if self.debug_logging {
+            Some(FilterLogLevel::Trace)
+        } else {
+            self.config.log_level
+        }
+    }
+
+    fn export_greeting(&self) -> Result<()> {
+        if let Some(greeting) = self.session.greeting() {
+            self.stream_info
+                .set_stream_property(&[state::GREETING_DOMAIN], greeting.domain())?;
+            self.stream_info
+                .set_stream_property(&[state::GREETING_TEXT], greeting.text())?;
+        }
+        Ok(())
+    }
+
+    // Exports the session summary as filter state once it has changed.
+    fn export_summary(&mut self) -> Result<()> {
+        let summary = self.session.summary();
+        if self.exported_summary.as_ref() == Some(summary) {
+            return Ok(());
+        }
+        let redaction = &self.config.redaction;
+        if let Some(mail_from) = summary.mail_from.as_ref() {
+            self.stream_info
+                .set_stream_property(&[state::MAIL_FROM], &redaction.mailbox(mail_from))?;
+        }
+        if let Some(submitter) = summary.submitter.as_ref() {
+            self.stream_info
+                .set_stream_property(&[state::SUBMITTER], &redaction.mailbox(submitter))?;
+        }
+        self.stream_info.set_stream_property(
+            &[state::RCPT_COUNT],
+            summary.rcpt_count.to_string().as_bytes(),
+        )?;
+        self.stream_info
+            .set_stream_property(&[state::MESSAGES], summary.messages.to_string().as_bytes())?;
+        if let Some(code) = summary.last_reply_code {
+            self.stream_info
+                .set_stream_property(&[state::LAST_REPLY_CODE], code.to_string().as_bytes())?;
+        }
+        if let Some(transaction_id) = summary.transaction_id.as_ref() {
+            self.stream_info
+                .set_stream_property(&[state::TRANSACTION_ID], transaction_id.as_bytes())?;
+        }
+        self.stream_info.set_stream_property(
+            &[state::AUTHENTICATED],
+            summary.authenticated.to_string().as_bytes(),
+        )?;
+        if let Some(identity) = summary.auth_identity.as_ref() {
+            self.stream_info.set_stream_property(
+                &[state::AUTH_IDENTITY],
+                &redaction.identity(&identity.authentication),
+            )?;
+            if let Some(authorization) = identity.authorization.as_ref() {
+                self.stream_info.set_stream_property(
+                    &[state::AUTH_AUTHORIZATION_IDENTITY],
+                    &redaction.identity(authorization),
+                )?;
+            }
+        }
+        let envelope = json!({
+            "mail_from": summary
+                .mail_from
+                .as_ref()
+                .map(|from| redaction.mailbox(from).to_str_lossy().into_owned()),
+            "submitter": summary
+                .submitter
+                .as_ref()
+                .map(|submitter| redaction.mailbox(submitter).to_str_lossy().into_owned()),
+            "rcpt_to": summary
+                .rcpt_to
+                .iter()
+                .map(|to| redaction.mailbox(to).to_str_lossy().into_owned())
+                .collect::<Vec<_>>(),
+            "messages": summary.messages,
+            "last_reply_code": summary.last_reply_code,
+            "transaction_id": summary.transaction_id,
+            "transaction_started_at_ms": summary.transaction_started_at.map(events::unix_millis),
+            "authenticated": summary.authenticated,
+            "auth_identity": summary
+                .auth_identity
+                .as_ref()
+                .map(|identity| redaction.identity(&identity.authentication).to_str_lossy().into_owned()),
+            "client_subject": self
+                .session
+                .client_certificate()
+                .and_then(|certificate| certificate.subject.as_ref()),
+        });
+        self.stream_info
+            .set_stream_property(&[state::ENVELOPE], envelope.to_string().as_bytes())?;
+        self.exported_summary = Some(summary.clone());
+        Ok(())
+    }
+
+    /// Holds back the latest command along with any downstream data after it
+    /// until [`on_downstream_verdict`] is called.
+    ///
+    /// Held data can only be released or rejected as a whole since `Envoy`
+    /// doesn't allow to change it outside of `on_downstream_data` callback.
+    ///
+    /// [`on_downstream_verdict`]: #method.on_downstream_verdict
+    pub fn hold_downstream(&mut self) {
+        self.session.hold_downstream()
+    }
+
+    /// Is called once a decision on downstream data that is being held back has been made.
+    pub fn on_downstream_verdict(&mut self, verdict: Verdict) -> Result<()> {
+        filter_debug!(
+            self.log_level(),
+            "{} downstream verdict: {:?}",
+            self.log_id,
+            verdict
+        );
+        match verdict {
+            Verdict::Release => {
+                self.session.release_downstream();
+                // an incomplete command line is held back until the rest of it is received
+                if self.held_downstream_size > 0
+                    && !self.session.is_downstream_held()
+                    && self.held_partial_size == 0
+                {
+                    self.held_downstream_size = 0;
+                    self.downstream_flow_ops.resume_downstream()?;
+                }
+                Ok(())
+            }
+            Verdict::Reject => {
+                #[cfg(feature = "policy")]
+                self.update_reputation(|reputation| reputation.on_reject())?;
+                self.downstream_flow_ops.close_downstream()
+            }
+        }
+    }
+
+    // Looks up reputation of the client and closes the connection of a repeat offender.
+    //
+    // Returns `false` if the connection has been closed.
+    #[cfg(feature = "policy")]
+    fn check_reputation(&mut self) -> Result<bool> {
+        let (config, address) = match (
+            self.config.reputation.as_ref(),
+            self.session.client_address(),
+        ) {
+            (Some(config), Some(address)) => (config, address.ip()),
+            _ => return Ok(true),
+        };
+        let reputation = self.reputation_store.get(address, config.ttl())?;
+        filter_debug!(
+            self.log_level(),
+            "{} client reputation: {:?}",
+            self.log_id,
+            reputation
+        );
+        self.stream_info.set_stream_property(
+            &[state::REPUTATION_OFFENSES],
+            reputation.offenses().to_string().as_bytes(),
+        )?;
+        self.reputation = Some(reputation);
+        let max_offenses = config.max_offenses;
+        self.check_auth_failures(&reputation);
+        match max_offenses {
+            Some(max_offenses) if reputation.offenses() >= max_offenses => {
+                log::info!("{} client is a repeat offender", self.log_id);
+                self.session
+                    .stats_sink()
+                    .primary()
+                    .on_reputation_offender()?;
+                // is not accounted as a new offense, so that the client is forgiven
+                // once the TTL has passed
+                self.downstream_flow_ops.close_downstream()?;
+                Ok(false)
+            }
+            _ => Ok(true),
+        }
+    }
+
+    // Client reputation is compiled out.
+    #[cfg(not(feature = "policy"))]
+    fn check_reputation(&mut self) -> Result<bool> {
+        Ok(true)
+    }
+
+    // Throttles AUTH commands of a client that has failed to authenticate
+    // too many times, including over other connections.
+    #[cfg(feature = "policy")]
+    fn check_auth_failures(&mut self, reputation: &Reputation) {
+        let max_source_failures = self
+            .config
+            .auth_throttle
+            .as_ref()
+            .and_then(|throttle| throttle.max_source_failures);
+        if let Some(max_source_failures) = max_source_failures {
+            if reputation.auth_failures >= max_source_failures {
+                filter_debug!(
+                    self.log_level(),
+                    "{} client has failed to authenticate {} times",
+                    self.log_id,
+                    reputation.auth_failures
+                );
+                self.session.throttle_auth();
+            }
+        }
+    }
+
+    // Accounts offenses of the client committed in the latest chunk of data.
+    #[cfg(feature = "policy")]
+    fn record_offenses(&mut self) -> Result<()> {
+        for offense in self.session.take_offenses() {
+            let reputation = self.update_reputation(|reputation| reputation.on_offense(offense))?;
+            if let (Offense::AuthFailure, Some(reputation)) = (offense, reputation) {
+                self.check_auth_failures(&reputation);
+            }
+        }
+        Ok(())
+    }
+
+    // Client reputation is compiled out, so offenses are only discarded.
+    #[cfg(not(feature = "policy"))]
+    fn record_offenses(&mut self) -> Result<()> {
+        self.session.take_offenses();
+        Ok(())
+    }
+
+    // Reports events of the latest chunk of data to the transaction webhook,
+    // the event queue and the transaction log.
+    fn publish_events(&mut self) -> Result<()> {
+        let config = Rc::clone(&self.config);
+        if config.transaction_webhook.is_none()
+            && config.event_queue.is_none()
+            && config.transaction_log.is_none()
+        {
+            return Ok(());
+        }
+        for event in self.session.take_events() {
+            let value = events::to_json(&event, self.session.client_address(), &config.redaction);
+            let json = value.to_string();
+            if let (Some(transaction_log), Event::Transaction(_)) =
+                (config.transaction_log.as_ref(), &event)
+            {
+                log_at!(
+                    transaction_log.level,
+                    "{} transaction {}",
+                    self.log_id,
+                    value
+                );
+            }
+            if let (Some(webhook), Event::Transaction(_)) =
+                (config.transaction_webhook.as_ref(), &event)
+            {
+                match self.webhook_client.notify(webhook, &json) {
+                    Ok(()) => self.session.stats_sink().primary().on_webhook_sent()?,
+                    Err(err) => {
+                        log::warn!(
+                            "{} failed to send transaction summary: {}",
+                            self.log_id,
+                            err
+                        );
+                        self.session
+                            .stats_sink()
+                            .primary()
+                            .on_webhook_response(false)?;
+                    }
+                }
+            }
+            if let Some(queue) = config.event_queue.as_ref() {
+                let published = self
+                    .event_queue
+                    .publish(queue, json.as_bytes())
+                    .unwrap_or_else(|err| {
+                        log::warn!("{} failed to publish event: {}", self.log_id, err);
+                        false
+                    });
+                self.session
+                    .stats_sink()
+                    .primary()
+                    .on_event_published(published)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Accounts an offense of the client and returns its new reputation, if known.
+    #[cfg(feature = "policy")]
+    fn update_reputation<F>(&self, f: F) -> Result<Option<Reputation>>
+    where
+        F: Fn(&mut Reputation),
+    {
+        let (config, address) = match (
+            self.config.reputation.as_ref(),
+            self.session.client_address(),
+        ) {
+            (Some(config), Some(address)) => (config, address.ip()),
+            _ => return Ok(None),
+        };
+        self.session
+            .stats_sink()
+            .primary()
+            .on_reputation_offense()?;
+        match self.reputation_store.update(address, config.ttl(), f) {
+            Ok(reputation) => Ok(Some(reputation)),
+            Err(err) => {
+                log::warn!(
+                    "{} failed to update client reputation: {}",
+                    self.log_id,
+                    err
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    // Consults policy hooks and services on envelope commands and messages of
+    // the latest chunk of downstream data and holds it back until decisions are made.
+    //
+    // Returns `false` if the connection has been closed.
+    fn check_policies(&mut self) -> Result<bool> {
+        #[cfg(any(feature = "policy", feature = "content-inspection"))]
+        let config = Rc::clone(&self.config);
+        let client_address = self.session.client_address();
+        let envelope_checks = self.session.take_envelope_checks();
+        let content_checks = self.session.take_content_checks();
+        let denied = self.policy_hooks.iter().any(|hook| {
+            envelope_checks
+                .iter()
+                .any(|check| hook.check_envelope(check, client_address) == Decision::Deny)
+                || content_checks
+                    .iter()
+                    .any(|check| hook.scan_content(check, client_address) == Decision::Deny)
+        });
+        if denied {
+            filter_debug!(self.log_level(), "{} denied by a policy hook", self.log_id);
+            self.on_downstream_verdict(Verdict::Reject)?;
+            return Ok(false);
+        }
+        #[cfg(feature = "policy")]
+        if let Some(policy) = config.envelope_policy.as_ref() {
+            for check in envelope_checks {
+                let result = self.policy_client.check_envelope(
+                    policy,
+                    &check,
+                    client_address,
+                    self.reputation.as_ref(),
+                );
+                self.on_policy_request_sent(Callout::Envelope, result, policy.fail_open)?;
+            }
+        }
+        #[cfg(feature = "content-inspection")]
+        if let Some(scan) = config.content_scan.as_ref() {
+            for check in content_checks {
+                let result = self
+                    .policy_client
+                    .scan_content(scan, &check, client_address);
+                self.on_policy_request_sent(Callout::Content, result, scan.fail_open)?;
+            }
+        }
+        if self.policy_client.is_pending() {
+            self.hold_downstream();
+        }
+        Ok(true)
+    }
+
+    // Looks up the client address in DNSBL zones that have no cached answer
+    // and holds back downstream data until the answers are known.
+    #[cfg(feature = "policy")]
+    fn lookup_dnsbl(&mut self) -> Result<()> {
+        let config = Rc::clone(&self.config);
+        let (dnsbl, address) = match (config.dnsbl.as_ref(), self.session.client_address()) {
+            (Some(dnsbl), Some(address)) => (dnsbl, address.ip()),
+            _ => return Ok(()),
+        };
+        for (zone, name) in dnsbl.zones.iter().enumerate() {
+            // a broken cache entry is no worse than a missing one
+            match self.dnsbl_cache.get(name, address).unwrap_or_default() {
+                Some(true) => {
+                    if self.on_dnsbl_listed(zone)? {
+                        return self.on_downstream_verdict(Verdict::Reject);
+                    }
+                }
+                Some(false) => {}
+                None => {
+                    let result = self.policy_client.lookup_dnsbl(dnsbl, zone, address);
+                    self.on_policy_request_sent(Callout::Dnsbl(zone), result, true)?;
+                }
+            }
+        }
+        if self.policy_client.is_pending() {
+            self.hold_downstream();
+        }
+        Ok(())
+    }
+
+    // DNSBL lookups are compiled out.
+    #[cfg(not(feature = "policy"))]
+    fn lookup_dnsbl(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    // Caches an answer of a DNSBL and returns the corresponding decision.
+    #[cfg(feature = "policy")]
+    fn on_dnsbl_answer(&self, zone: usize, body: &[u8]) -> Result<Decision> {
+        let (dnsbl, address) = match (self.config.dnsbl.as_ref(), self.session.client_address()) {
+            (Some(dnsbl), Some(address)) => (dnsbl, address.ip()),
+            _ => return Ok(Decision::Failure),
+        };
+        match Answer::from_json(body, dnsbl.negative_ttl()) {
+            Some(answer) => {
+                if let Err(err) = self.dnsbl_cache.put(&dnsbl.zones[zone], address, &answer) {
+                    log::warn!("{} failed to cache DNSBL answer: {}", self.log_id, err);
+                }
+                Ok(if answer.listed {
+                    Decision::Deny
+                } else {
+                    Decision::Allow
+                })
+            }
+            None => Ok(Decision::Failure),
+        }
+    }
+
+    // Accounts the client as listed in a DNSBL zone with a given index
+    // and returns `true` if it has to be rejected.
+    #[cfg(feature = "policy")]
+    fn on_dnsbl_listed(&self, zone: usize) -> Result<bool> {
+        let dnsbl = match self.config.dnsbl.as_ref() {
+            Some(dnsbl) => dnsbl,
+            None => return Ok(false),
+        };
+        let name = &dnsbl.zones[zone];
+        log::info!("{} client is listed in {}", self.log_id, name);
+        self.session.stats_sink().primary().on_dnsbl_listed(name)?;
+        self.stream_info
+            .set_stream_property(&[state::DNSBL_LISTED], name.as_bytes())?;
+        Ok(dnsbl.action == DnsblAction::Reject)
+    }
+
+    #[cfg(any(feature = "policy", feature = "content-inspection"))]
+    fn on_policy_request_sent(
+        &mut self,
+        callout: Callout,
+        result: Result<()>,
+        fail_open: bool,
+    ) -> Result<()> {
+        if let Err(err) = result {
+            log::warn!(
+                "{} failed to send {:?} policy request: {}",
+                self.log_id,
+                callout,
+                err
+            );
+            self.session.stats_sink().primary().on_policy_decision(
+                callout,
+                Decision::Failure,
+                Default::default(),
+            )?;
+            if !fail_open {
+                return self.on_downstream_verdict(Verdict::Reject);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "content-inspection")]
+    fn export_scan_result(&self, http_client_ops: &dyn HttpClientResponseOps) -> Result<()> {
+        if let Some(result) = http_client_ops.http_call_response_header("x-smtp-scan-result")? {
+            self.stream_info
+                .set_stream_property(&[state::SCAN_RESULT], result.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    // Records downstream activity for the sake of the idle timeout.
+    fn touch_downstream(&mut self) -> Result<()> {
+        self.idle_watch.touch(self.clock.now()?);
+        Ok(())
+    }
+
+    // Accounts a client that has been idle for longer than the idle timeout
+    // and closes its connection if configured so.
+    //
+    // Returns `false` if the connection has been closed.
+    fn check_idle_timeout(&mut self) -> Result<bool> {
+        let (idle_for, action) = match self.idle_watch.check(self.clock.now()?) {
+            Some(timeout) => timeout,
+            None => return Ok(true),
+        };
+        log::info!("{} client has been idle for {:?}", self.log_id, idle_for);
+        self.session.stats_sink().primary().on_idle_timeout()?;
+        self.stream_info
+            .set_stream_property(&[state::IDLE_TIMEOUT], b"true")?;
+        match action {
+            TimeoutAction::Observe => Ok(true),
+            TimeoutAction::Close => {
+                self.downstream_flow_ops.close_downstream()?;
+                Ok(false)
+            }
+        }
+    }
+
+    // Keeps track of the start of DATA phase for the sake of the DATA timeout.
+    fn track_data_phase(&mut self) -> Result<()> {
+        if self.config.data_timeout.is_none() {
+            return Ok(());
+        }
+        if self.session.mode() != Mode::Data {
+            self.data_started_at = None;
+        } else if self.data_started_at.is_none() {
+            self.data_started_at = Some(self.clock.now()?);
+        }
+        Ok(())
+    }
+
+    // Gives up on a message that takes longer than the DATA timeout to arrive.
+    //
+    // Returns `false` if the connection has been closed.
+    fn check_data_timeout(&mut self) -> Result<bool> {
+        let (config, started_at) = match (self.config.data_timeout.as_ref(), self.data_started_at) {
+            (Some(config), Some(started_at)) => (config, started_at),
+            _ => return Ok(true),
+        };
+        let elapsed = self
+            .clock
+            .now()?
+            .duration_since(started_at)
+            .unwrap_or_default();
+        if elapsed <= config.timeout() {
+            return Ok(true);
+        }
+        log::info!("{} message hasn't arrived in {:?}", self.log_id, elapsed);
+        self.data_started_at = None;
+        self.session.stats_sink().primary().on_data_timeout()?;
+        match config.action {
+            DataTimeoutAction::PassThrough => {
+                self.session.abandon("DATA timeout")?;
+                Ok(true)
+            }
+            DataTimeoutAction::Close => {
+                self.downstream_flow_ops.close_downstream()?;
+                Ok(false)
+            }
+        }
+    }
+
+    // Measures the rate incomplete command lines arrive at and accounts
+    // a client that trickles them.
+    //
+    // Returns `false` if the connection has been closed.
+    fn check_slow_client(&mut self) -> Result<bool> {
+        let config = match self.config.slow_client.as_ref() {
+            Some(config) if !self.slow => config,
+            _ => return Ok(true),
+        };
+        let received = self.session.partial_command_size();
+        if received == 0 {
+            self.partial_command_started_at = None;
+            return Ok(true);
+        }
+        let now = self.clock.now()?;
+        let started_at = *self.partial_command_started_at.get_or_insert(now);
+        let elapsed = now.duration_since(started_at).unwrap_or_default();
+        if elapsed < config.window() || received >= config.min_bytes {
+            return Ok(true);
+        }
+        log::info!(
+            "{} client has sent {} bytes of a command in {:?}",
+            self.log_id,
+            received,
+            elapsed
+        );
+        self.slow = true;
+        self.session.stats_sink().primary().on_slow_client()?;
+        self.stream_info
+            .set_stream_property(&[state::SLOW_CLIENT], b"true")?;
+        match config.action {
+            TimeoutAction::Observe => Ok(true),
+            TimeoutAction::Close => {
+                self.downstream_flow_ops.close_downstream()?;
+                Ok(false)
+            }
+        }
+    }
+
+    // Closes the connection if the session has given up on it, e.g. due to a parsing error.
+    //
+    // Returns `false` if the connection has been closed.
+    fn check_close_request(&mut self) -> Result<bool> {
+        if !self.session.take_close_request() {
+            return Ok(true);
+        }
+        log::info!(
+            "{} closing the connection on request of the session",
+            self.log_id
+        );
+        self.downstream_flow_ops.close_downstream()?;
+        Ok(false)
+    }
+
+    // Holds back downstream data while the session is waiting for a verdict
+    // or for the rest of an incomplete command line.
+    fn downstream_status(
+        &mut self,
+        data_size: usize,
+        end_of_stream: bool,
+    ) -> network::FilterStatus {
+        self.held_partial_size = if end_of_stream {
+            self.session.release_partial_command();
+            0
+        } else {
+            self.session.hold_partial_command()
+        };
+        if self.session.is_downstream_held() || self.held_partial_size > 0 {
+            self.held_downstream_size = data_size;
+            network::FilterStatus::StopIteration
+        } else {
+            self.held_downstream_size = 0;
+            network::FilterStatus::Continue
+        }
+    }
+
+    // Picks up a configuration update, if any, with the selected profile re-applied.
+    fn refresh_config(&mut self) {
+        let latest = self.config_handle.get();
+        if Rc::ptr_eq(&latest, &self.latest_config) {
+            return;
         }
+        filter_debug!(
+            self.log_level(),
+            "{} picking up a configuration update",
+            self.log_id
+        );
+        self.config = match self.profile.as_ref() {
+            Some(name) => latest.profile(name).unwrap_or_else(|| Rc::clone(&latest)),
+            None => Rc::clone(&latest),
+        };
+        self.latest_config = latest;
+        self.idle_watch.set_config(self.config.idle_timeout.clone());
+        self.session
+            .update_config(self.session_config(&self.config));
+    }
+
+    // Applies the policy profile selected for the connection, if any.
+    fn select_profile(&mut self) -> Result<()> {
+        let selector = match self.config.profile_selector.as_ref() {
+            Some(selector) => selector,
+            None => return Ok(()),
+        };
+        let by_server_name = self
+            .stream_info
+            .connection()
+            .requested_server_name()?
+            .and_then(|name| selector.server_names.get(&name).cloned());
+        let name = match by_server_name {
+            Some(name) => name,
+            None => match self.stream_info.stream_property(&[
+                "listener_metadata",
+                "filter_metadata",
+                &selector.metadata_namespace,
+                &selector.metadata_key,
+            ])? {
+                Some(name) => name.to_str_lossy().into_owned(),
+                None => return Ok(()),
+            },
+        };
+        match self.config.profile(&name) {
+            Some(config) => {
+                filter_debug!(self.log_level(), "{} policy profile: {}", self.log_id, name);
+                self.session
+                    .stats_sink()
+                    .primary()
+                    .on_policy_profile(&name)?;
+                self.session.reconfigure(self.session_config(&config));
+                self.idle_watch.set_config(config.idle_timeout.clone());
+                self.config = config;
+                self.profile = Some(name);
+            }
+            None => log::warn!("{} unknown policy profile: {}", self.log_id, name),
+        }
+        Ok(())
+    }
+
+    fn resolve_client_address(&mut self) -> Result<()> {
+        if let Some(address) = self.stream_info.source().address()? {
+            // e.g. a pipe rather than an IP socket
+            match address.parse() {
+                Ok(address) => {
+                    self.log_id.client_address = Some(address);
+                    self.session.set_client_address(address);
+                    if let Some(mode) = self.config.source_stats {
+                        let source = stat_source(address.ip(), mode);
+                        self.session
+                            .stats_sink()
+                            .primary()
+                            .on_source_connection(&source)?;
+                    }
+                }
+                Err(err) => filter_debug!(
+                    self.log_level(),
+                    "{} failed to parse client address {}: {}",
+                    self.log_id,
+                    address,
+                    err
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve_client_certificate(&mut self) -> Result<()> {
+        if self.session.client_certificate().is_some() {
+            return Ok(());
+        }
+        let connection = self.stream_info.connection();
+        let tls = connection.tls();
+        let certificate = ClientCertificate {
+            subject: tls.subject_peer_certificate()?,
+            uri_san: tls.uri_san_peer_certificate()?,
+            dns_san: tls.dns_san_peer_certificate()?,
+        };
+        if certificate == ClientCertificate::default() {
+            return Ok(()); // plaintext connection or no certificate presented (yet)
+        }
+        filter_debug!(
+            self.log_level(),
+            "{} client certificate: {:?}",
+            self.log_id,
+            certificate
+        );
+        for (key, value) in &[
+            (state::CLIENT_SUBJECT, certificate.subject.as_ref()),
+            (state::CLIENT_URI_SAN, certificate.uri_san.as_ref()),
+            (state::CLIENT_DNS_SAN, certificate.dns_san.as_ref()),
+        ] {
+            if let Some(value) = value {
+                self.stream_info
+                    .set_stream_property(&[key], value.as_bytes())?;
+            }
+        }
+        self.session.set_client_certificate(certificate);
+        Ok(())
+    }
+
+    fn resolve_tenant(&mut self) -> Result<()> {
+        let path = match self.config.tenant_property.as_ref() {
+            Some(path) if self.session.stats_sink().primary().tenant().is_none() => path,
+            _ => return Ok(()),
+        };
+        let path: Vec<&str> = path.iter().map(String::as_str).collect();
+        if let Some(tenant) = self.stream_info.stream_property(&path)? {
+            filter_debug!(self.log_level(), "{} tenant: {}", self.log_id, tenant);
+            self.session
+                .stats_sink_mut()
+                .primary_mut()
+                .set_tenant(&tenant.to_str_lossy());
+        }
+        Ok(())
+    }
+
+    fn resolve_upstream_cluster(&mut self) -> Result<()> {
+        if !self.config.upstream_cluster_stats
+            || self
+                .session
+                .stats_sink()
+                .primary()
+                .upstream_cluster()
+                .is_some()
+        {
+            return Ok(());
+        }
+        if let Some(name) = self.stream_info.cluster().name()? {
+            filter_debug!(
+                self.log_level(),
+                "{} upstream cluster: {}",
+                self.log_id,
+                name
+            );
+            self.session
+                .stats_sink_mut()
+                .primary_mut()
+                .set_upstream_cluster(name);
+        }
+        Ok(())
     }
 }
 
-impl<'a> NetworkFilter for SmtpFilter<'a> {
+impl<'a, S: FilterStatsSink> NetworkFilter for SmtpFilter<'a, S> {
     /// Called when a new TCP connection is opened.
     fn on_new_connection(&mut self) -> Result<network::FilterStatus> {
-        log::debug!(
-            "#{} new TCP connection starts with config: {:?}",
-            self.instance_id,
+        filter_debug!(
+            self.log_level(),
+            "{} new TCP connection starts with config: {:?}",
+            self.log_id,
             self.config,
         );
+        self.select_profile()?;
+        self.resolve_tenant()?;
+        self.resolve_client_address()?;
+        self.resolve_client_certificate()?;
+        let now = self.clock.now()?;
+        self.connected_at = Some(now);
+        self.session.set_time(now);
+        // unique within the Wasm VM as long as the clock doesn't go back
+        self.session.set_connection_id(format!(
+            "{:x}-{}",
+            events::unix_millis(now),
+            self.log_id.instance_id
+        ));
         self.session.on_new_conection()?;
+        self.touch_downstream()?;
+        // make counters available to access logs from the start
+        self.export_summary()?;
+        if self.check_reputation()? {
+            self.lookup_dnsbl()?;
+        }
         Ok(network::FilterStatus::Continue)
     }
 
     fn on_downstream_data(
         &mut self,
         data_size: usize,
-        _end_of_stream: bool,
+        end_of_stream: bool,
         ops: &dyn network::DownstreamDataOps,
     ) -> Result<network::FilterStatus> {
+        self.refresh_config();
+        self.session.set_time(self.clock.now()?);
+        // data that is being held back has been accounted for already
+        self.downstream_bytes += data_size - self.held_downstream_size;
+        if !self.check_idle_timeout()? || !self.check_data_timeout()? {
+            return Ok(network::FilterStatus::StopIteration);
+        }
+        self.touch_downstream()?;
         if self.session.mode() == Mode::PassThrough {
             // has fallen back into no-op mode, e.g. due to a parsing error or
             // because of STARTTLS command
-            return Ok(network::FilterStatus::Continue);
+            return Ok(self.downstream_status(data_size, end_of_stream));
+        }
+        // the tenant might be set by an earlier filter once data has been received
+        self.resolve_tenant()?;
+        // TLS handshake might not have been completed upon connect
+        self.resolve_client_certificate()?;
+        // data that is being held back is passed to the filter again
+        let held_size = self.held_downstream_size;
+        let held_partial_size = self.held_partial_size;
+        let new_data = ops.downstream_data(held_size, data_size - held_size)?;
+        filter_trace!(
+            self.log_level(),
+            "{} -> {}",
+            self.log_id,
+            self.loggable(new_data.as_bytes()).as_bstr()
+        );
+        #[cfg(feature = "content-inspection")]
+        self.record_transcript(Party::Client, new_data.as_bytes());
+        if self.draining.get() {
+            self.session.drain();
         }
-        let new_data = ops.downstream_data(0, data_size)?;
-        log::debug!("#{} -> {}", self.instance_id, new_data);
         self.session.on_downstream_data(new_data)?;
-        Ok(network::FilterStatus::Continue)
+        if end_of_stream {
+            self.session.on_downstream_end_of_stream()?;
+        }
+        if !self.check_close_request()? {
+            return Ok(network::FilterStatus::StopIteration);
+        }
+        self.track_data_phase()?;
+        if !self.check_slow_client()? {
+            return Ok(network::FilterStatus::StopIteration);
+        }
+        self.record_offenses()?;
+        self.publish_events()?;
+        if !self.check_policies()? {
+            return Ok(network::FilterStatus::StopIteration);
+        }
+        let edits = self.session.take_downstream_edits();
+        let mut data_size = data_size;
+        if !edits.is_empty() {
+            let data = ops.downstream_data(0, data_size)?;
+            // an incomplete command line that has been held back is edited along with new data
+            let (held, new) = data.as_bytes().split_at(held_size - held_partial_size);
+            let mut rewritten = held.to_vec();
+            rewritten.extend(edits.apply(new));
+            filter_debug!(
+                self.log_level(),
+                "{} -> (rewritten) {}",
+                self.log_id,
+                self.loggable(&rewritten).as_bstr()
+            );
+            self.downstream_data_ops
+                .set_downstream_data(0, data_size, &rewritten)?;
+            data_size = rewritten.len();
+        }
+        Ok(self.downstream_status(data_size, end_of_stream))
     }
 
     fn on_upstream_data(
         &mut self,
         data_size: usize,
-        _end_of_stream: bool,
+        end_of_stream: bool,
         ops: &dyn network::UpstreamDataOps,
     ) -> Result<network::FilterStatus> {
+        self.session.set_time(self.clock.now()?);
+        self.upstream_bytes += data_size;
+        if !self.check_idle_timeout()? || !self.check_data_timeout()? {
+            return Ok(network::FilterStatus::StopIteration);
+        }
         if self.session.mode() == Mode::PassThrough {
             // has fallen back into no-op mode, e.g. due to a parsing error or
             // because of STARTTLS command
             return Ok(network::FilterStatus::Continue);
         }
+        self.resolve_upstream_cluster()?;
         let new_data = ops.upstream_data(0, data_size)?;
-        log::debug!("#{} <- {}", self.instance_id, new_data);
+        filter_trace!(
+            self.log_level(),
+            "{} <- {}",
+            self.log_id,
+            self.loggable(new_data.as_bytes()).as_bstr()
+        );
+        #[cfg(feature = "content-inspection")]
+        self.record_transcript(Party::Server, new_data.as_bytes());
+        let had_greeting = self.session.greeting().is_some();
         self.session.on_upstream_data(new_data)?;
+        if end_of_stream {
+            self.session.on_upstream_end_of_stream()?;
+        }
+        if !self.check_close_request()? {
+            return Ok(network::FilterStatus::StopIteration);
+        }
+        self.track_data_phase()?;
+        self.export_summary()?;
+        self.record_offenses()?;
+        self.publish_events()?;
+        if !had_greeting {
+            self.export_greeting()?;
+        }
+        let edits = self.session.take_upstream_edits();
+        if !edits.is_empty() {
+            let data = ops.upstream_data(0, data_size)?;
+            let data = edits.apply(data.as_bytes());
+            filter_debug!(
+                self.log_level(),
+                "{} <- (rewritten) {}",
+                self.log_id,
+                self.loggable(&data).as_bstr()
+            );
+            self.upstream_data_ops
+                .set_upstream_data(0, data_size, &data)?;
+        }
         Ok(network::FilterStatus::Continue)
     }
+
+    /// Called when the downstream connection is closed.
+    fn on_downstream_close(
+        &mut self,
+        peer_type: PeerType,
+        _ops: &dyn network::DownstreamCloseOps,
+    ) -> Result<()> {
+        filter_debug!(
+            self.log_level(),
+            "{} downstream closed by {:?}",
+            self.log_id,
+            peer_type
+        );
+        self.session.on_downstream_end_of_stream()
+    }
+
+    /// Called when the upstream connection is closed.
+    fn on_upstream_close(
+        &mut self,
+        peer_type: PeerType,
+        _ops: &dyn network::UpstreamCloseOps,
+    ) -> Result<()> {
+        filter_debug!(
+            self.log_level(),
+            "{} upstream closed by {:?}",
+            self.log_id,
+            peer_type
+        );
+        self.session.on_upstream_end_of_stream()
+    }
+
+    /// Called when the TCP connection is complete.
+    fn on_connection_complete(&mut self, _ops: &dyn network::ConnectionCompleteOps) -> Result<()> {
+        filter_debug!(
+            self.log_level(),
+            "{} TCP connection is complete",
+            self.log_id
+        );
+        self.session.on_connection_closed()?;
+        self.log_session()?;
+        self.emit_transcript()
+    }
+
+    /// Called when the async HTTP request made through `Envoy` HTTP Client API is complete.
+    fn on_http_call_response(
+        &mut self,
+        request_id: HttpClientRequestHandle,
+        num_headers: usize,
+        body_size: usize,
+        _num_trailers: usize,
+        _filter_ops: &dyn network::Ops,
+        http_client_ops: &dyn HttpClientResponseOps,
+    ) -> Result<()> {
+        // the response might be the first event since the client has gone silent
+        if !self.check_idle_timeout()? || !self.check_data_timeout()? {
+            return Ok(());
+        }
+        // no headers are received when the request has failed, e.g. due to a timeout
+        let status = if num_headers > 0 {
+            http_client_ops.http_call_response_header(":status")?
+        } else {
+            None
+        };
+        if self.webhook_client.on_response(request_id) {
+            let delivered = Decision::from_status(status.as_ref().map(|status| status.as_bytes()))
+                == Decision::Allow;
+            return self
+                .session
+                .stats_sink()
+                .primary()
+                .on_webhook_response(delivered);
+        }
+        let (callout, decision, latency) = match self
+            .policy_client
+            .on_response(request_id, status.as_ref().map(|status| status.as_bytes()))?
+        {
+            Some(outcome) => outcome,
+            None => return Ok(()),
+        };
+        #[cfg(not(feature = "policy"))]
+        let _ = body_size;
+        #[cfg(feature = "policy")]
+        let decision = match (callout, decision) {
+            (Callout::Dnsbl(zone), Decision::Allow) => {
+                let body = http_client_ops.http_call_response_body(0, body_size)?;
+                self.on_dnsbl_answer(zone, body.as_bytes())?
+            }
+            _ => decision,
+        };
+        filter_debug!(
+            self.log_level(),
+            "{} {:?} policy decision: {:?} in {:?}",
+            self.log_id,
+            callout,
+            decision,
+            latency
+        );
+        self.session
+            .stats_sink()
+            .primary()
+            .on_policy_decision(callout, decision, latency)?;
+        let fail_open = match callout {
+            Callout::Envelope => self.config.envelope_policy.as_ref().map(|c| c.fail_open),
+            Callout::Content => self.config.content_scan.as_ref().map(|c| c.fail_open),
+            // failed lookups are treated as if the client is not listed
+            Callout::Dnsbl(_) => Some(true),
+        }
+        .unwrap_or_default();
+        #[cfg(feature = "content-inspection")]
+        if callout == Callout::Content && num_headers > 0 {
+            self.export_scan_result(http_client_ops)?;
+        }
+        let reject = match (callout, decision) {
+            (_, Decision::Allow) => false,
+            (_, Decision::Failure) => !fail_open,
+            #[cfg(feature = "policy")]
+            (Callout::Dnsbl(zone), Decision::Deny) => self.on_dnsbl_listed(zone)?,
+            (_, Decision::Deny) => true,
+        };
+        if reject {
+            return self.on_downstream_verdict(Verdict::Reject);
+        }
+        if !self.policy_client.is_pending() {
+            self.on_downstream_verdict(Verdict::Release)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use envoy::extension::{ExtensionFactory, InstanceId, NetworkFilter};
+    use envoy::host::ByteString;
+
+    use super::*;
+    use crate::factory::SmtpFilterFactoryBuilder;
+    use crate::host::fake::FakeHost;
+
+    // Creates a filter for a new connection the way `Envoy` does.
+    fn new_filter<'a>(host: &'a FakeHost, config: &str) -> SmtpFilter<'a> {
+        let mut factory =
+            SmtpFilterFactoryBuilder::new(host, host, host, host, host, host, host, host, host)
+                .build()
+                .unwrap();
+        factory
+            .on_configure(ByteString::from(config), host)
+            .unwrap();
+        factory.new_extension(InstanceId::from(1)).unwrap()
+    }
+
+    #[test]
+    fn should_interpret_data_split_across_deliveries() {
+        let host = FakeHost::default();
+        let mut filter = new_filter(&host, "");
+        let downstream: &[&[u8]] = &[
+            b"EH",
+            b"LO client.example.org\r\n",
+            b"MAIL FROM:<alice@example.org>\r\nRCPT TO:<bob@",
+            b"example.com>\r\nDATA\r\n",
+            b"Subject: Hello\r\n\r\nHi Bob\r\n.",
+            b"\r\nQUIT\r\n",
+        ];
+        let upstream: &[&[u8]] = &[
+            b"220 mail.example.org ESMTP\r\n",
+            b"250-mail.example.org\r\n250 PIPE",
+            b"LINING\r\n",
+            b"250 OK\r\n250 OK\r\n354 Go ahead\r\n",
+            b"250 Queued\r\n",
+            b"221 Bye\r\n",
+        ];
+
+        filter.on_new_connection().unwrap();
+        // incomplete command lines are held back until the rest of them is received
+        let deliveries = [
+            (upstream[0], false, false),
+            (downstream[0], true, true),
+            (downstream[1], true, false),
+            (upstream[1], false, false),
+            (upstream[2], false, false),
+            (downstream[2], true, true),
+            (downstream[3], true, false),
+            (upstream[3], false, false),
+            (downstream[4], true, false),
+            (downstream[5], true, false),
+            (upstream[4], false, false),
+            (upstream[5], false, false),
+        ];
+        for (data, is_downstream, held) in deliveries.iter() {
+            host.advance(Duration::from_millis(10));
+            let status = if *is_downstream {
+                host.deliver_downstream(&mut filter, data, false)
+            } else {
+                host.deliver_upstream(&mut filter, data, false)
+            };
+            assert_eq!(
+                status.unwrap() == network::FilterStatus::StopIteration,
+                *held
+            );
+        }
+        host.close(&mut filter).unwrap();
+
+        assert_eq!(host.to_upstream(), downstream.concat());
+        assert_eq!(host.to_downstream(), upstream.concat());
+        assert_eq!(host.counter_value("smtp.connections.total"), 1);
+        assert_eq!(host.counter_value("smtp.connections.active"), 0);
+        assert_eq!(host.counter_value("smtp.commands.total"), 5);
+        assert_eq!(host.counter_value("smtp.transactions.commits.total"), 1);
+        assert_eq!(
+            host.counter_value("smtp.transactions.commits.replies.positive.total"),
+            1
+        );
+        assert_eq!(host.counter_value("smtp.connections.parse_errors.total"), 0);
+        assert_eq!(
+            host.histogram_values("smtp.transactions.data_duration_ms"),
+            vec![30]
+        );
+    }
+
+    #[test]
+    fn should_rewrite_recipient_split_across_deliveries() {
+        let host = FakeHost::default();
+        let mut filter = new_filter(
+            &host,
+            r#"{"recipient_rewrite": {"aliases": {"info@example.org": "support@example.org"}}}"#,
+        );
+
+        filter.on_new_connection().unwrap();
+        host.deliver_upstream(&mut filter, b"220 mail.example.org ESMTP\r\n", false)
+            .unwrap();
+        host.deliver_downstream(&mut filter, b"HELO client.example.org\r\n", false)
+            .unwrap();
+        host.deliver_upstream(&mut filter, b"250 mail.example.org\r\n", false)
+            .unwrap();
+        host.deliver_downstream(&mut filter, b"MAIL FROM:<alice@example.com>\r\n", false)
+            .unwrap();
+        host.deliver_upstream(&mut filter, b"250 OK\r\n", false)
+            .unwrap();
+        let status = host
+            .deliver_downstream(&mut filter, b"RCPT TO:<in", false)
+            .unwrap();
+        assert_eq!(status, network::FilterStatus::StopIteration);
+        let status = host
+            .deliver_downstream(&mut filter, b"fo@example.org>\r\n", false)
+            .unwrap();
+        assert_eq!(status, network::FilterStatus::Continue);
+
+        assert_eq!(
+            host.to_upstream(),
+            b"HELO client.example.org\r\n\
+              MAIL FROM:<alice@example.com>\r\n\
+              RCPT TO:<support@example.org>\r\n"
+                .to_vec()
+        );
+        assert_eq!(host.counter_value("smtp.rcpt.rewrites.failed.total"), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "policy")]
+    fn should_hold_downstream_until_policy_decision() {
+        let host = FakeHost::default();
+        let mut filter = new_filter(&host, r#"{"envelope_policy": {"cluster": "policy"}}"#);
+        host.set_property("source.address", "192.0.2.1:40000");
+
+        filter.on_new_connection().unwrap();
+        host.deliver_upstream(&mut filter, b"220 mail.example.org ESMTP\r\n", false)
+            .unwrap();
+        host.deliver_downstream(&mut filter, b"HELO client.example.org\r\n", false)
+            .unwrap();
+        host.deliver_upstream(&mut filter, b"250 mail.example.org\r\n", false)
+            .unwrap();
+        let status = host
+            .deliver_downstream(&mut filter, b"MAIL FROM:<alice@", false)
+            .unwrap();
+        assert_eq!(status, network::FilterStatus::StopIteration);
+        let status = host
+            .deliver_downstream(&mut filter, b"example.org>\r\n", false)
+            .unwrap();
+        assert_eq!(status, network::FilterStatus::StopIteration);
+        assert_eq!(host.http_requests(), vec!["policy:/smtp/envelope"]);
+        assert_eq!(host.to_upstream(), b"HELO client.example.org\r\n".to_vec());
+
+        host.respond(&mut filter, 1, "200", b"").unwrap();
+        assert_eq!(
+            host.to_upstream(),
+            b"HELO client.example.org\r\nMAIL FROM:<alice@example.org>\r\n".to_vec()
+        );
+
+        host.deliver_upstream(&mut filter, b"250 OK\r\n", false)
+            .unwrap();
+        let status = host
+            .deliver_downstream(&mut filter, b"RCPT TO:<bob@example.com>\r\n", false)
+            .unwrap();
+        assert_eq!(status, network::FilterStatus::StopIteration);
+        host.respond(&mut filter, 2, "403", b"").unwrap();
+        assert!(host.is_downstream_closed());
+        assert_eq!(host.counter_value("smtp.commands.total"), 3);
+    }
+
+    #[test]
+    fn should_account_idle_clients() {
+        for (action, closed) in [("observe", false), ("close", true)] {
+            let host = FakeHost::default();
+            let config = format!(
+                r#"{{"idle_timeout": {{"timeout_ms": 1000, "action": "{}"}}}}"#,
+                action
+            );
+            let mut filter = new_filter(&host, &config);
+
+            filter.on_new_connection().unwrap();
+            host.deliver_upstream(&mut filter, b"220 mail.example.org ESMTP\r\n", false)
+                .unwrap();
+            host.deliver_downstream(&mut filter, b"HELO client.example.org\r\n", false)
+                .unwrap();
+            host.advance(Duration::from_millis(500));
+            host.deliver_upstream(&mut filter, b"250 mail.example.org\r\n", false)
+                .unwrap();
+            assert_eq!(
+                host.counter_value("smtp.connections.idle_timeouts.total"),
+                0
+            );
+
+            host.advance(Duration::from_millis(1000));
+            let status = host
+                .deliver_downstream(&mut filter, b"QUIT\r\n", false)
+                .unwrap();
+            assert_eq!(status == network::FilterStatus::StopIteration, closed);
+            assert_eq!(host.is_downstream_closed(), closed);
+            assert_eq!(
+                host.counter_value("smtp.connections.idle_timeouts.total"),
+                1
+            );
+        }
+    }
+
+    #[test]
+    fn should_give_up_on_slow_messages() {
+        for (action, closed) in [("passthrough", false), ("close", true)] {
+            let host = FakeHost::default();
+            let config = format!(
+                r#"{{"data_timeout": {{"timeout_ms": 1000, "action": "{}"}}}}"#,
+                action
+            );
+            let mut filter = new_filter(&host, &config);
+
+            filter.on_new_connection().unwrap();
+            for (data, is_downstream) in [
+                (&b"220 mail.example.org ESMTP\r\n"[..], false),
+                (b"HELO client.example.org\r\n", true),
+                (b"250 mail.example.org\r\n", false),
+                (b"MAIL FROM:<alice@example.org>\r\n", true),
+                (b"250 OK\r\n", false),
+                (b"RCPT TO:<bob@example.com>\r\n", true),
+                (b"250 OK\r\n", false),
+                (b"DATA\r\n", true),
+                (b"354 Go ahead\r\n", false),
+                (b"Subject: Hello\r\n", true),
+            ] {
+                if is_downstream {
+                    host.deliver_downstream(&mut filter, data, false).unwrap();
+                } else {
+                    host.deliver_upstream(&mut filter, data, false).unwrap();
+                }
+            }
+            assert_eq!(filter.session.mode(), Mode::Data);
+
+            host.advance(Duration::from_millis(1500));
+            let status = host
+                .deliver_downstream(&mut filter, b"\r\nHi Bob\r\n", false)
+                .unwrap();
+            assert_eq!(status == network::FilterStatus::StopIteration, closed);
+            assert_eq!(host.is_downstream_closed(), closed);
+            assert_eq!(
+                host.counter_value("smtp.transactions.data_timeouts.total"),
+                1
+            );
+            if !closed {
+                assert_eq!(filter.session.mode(), Mode::PassThrough);
+            }
+        }
+    }
+
+    #[test]
+    fn should_account_slow_clients() {
+        for (action, closed) in [("observe", false), ("close", true)] {
+            let host = FakeHost::default();
+            let config = format!(
+                r#"{{"slow_client": {{"window_ms": 5000, "min_bytes": 32, "action": "{}"}}}}"#,
+                action
+            );
+            let mut filter = new_filter(&host, &config);
+
+            filter.on_new_connection().unwrap();
+            host.deliver_upstream(&mut filter, b"220 mail.example.org ESMTP\r\n", false)
+                .unwrap();
+            host.deliver_downstream(&mut filter, b"HELO cl", false)
+                .unwrap();
+            host.advance(Duration::from_millis(3000));
+            host.deliver_downstream(&mut filter, b"ient", false)
+                .unwrap();
+            assert_eq!(host.counter_value("smtp.connections.slow_clients.total"), 0);
+
+            host.advance(Duration::from_millis(3000));
+            host.deliver_downstream(&mut filter, b".exa", false)
+                .unwrap();
+            assert_eq!(host.is_downstream_closed(), closed);
+            assert_eq!(host.counter_value("smtp.connections.slow_clients.total"), 1);
+        }
+    }
 }
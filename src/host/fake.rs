@@ -0,0 +1,504 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Simulated `Envoy` host, i.e. host APIs a filter instance is bound to,
+//! for tests that drive SMTP filter the way `Envoy` does.
+
+// policy callouts are the only tests that exercise HTTP responses
+#![cfg_attr(not(feature = "policy"), allow(dead_code))]
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use envoy::error::format_err;
+use envoy::extension::filter::network::{
+    self, ConnectionCompleteOps, DownstreamCloseOps, DownstreamDataOps, UpstreamCloseOps,
+    UpstreamDataOps,
+};
+use envoy::extension::{factory, InstanceId, NetworkFilter, Result};
+use envoy::host::shared_data::OptimisticLockVersion;
+use envoy::host::shared_queue::SharedQueueHandle;
+use envoy::host::stats::{Counter, Gauge, Histogram};
+use envoy::host::{
+    self, ByteString, Clock, HeaderMap, HttpClient, HttpClientRequestHandle, HttpClientResponseOps,
+    SharedData, SharedQueue, Stats, StreamInfo,
+};
+use proxy_wasm::types::PeerType;
+
+use super::{DownstreamDataMutationOps, DownstreamFlowOps, UpstreamDataMutationOps};
+
+/// FakeHost simulates host APIs of `Envoy` for a single connection.
+///
+/// Data is delivered to a filter the way `Envoy` does it, i.e. data held
+/// back by `StopIteration` is passed to the filter again along with new data,
+/// and data let through is recorded as forwarded.
+pub struct FakeHost {
+    now: Cell<SystemTime>,
+    properties: RefCell<BTreeMap<String, Vec<u8>>>,
+    counters: RefCell<BTreeMap<String, Rc<Cell<u64>>>>,
+    histograms: RefCell<BTreeMap<String, Rc<RefCell<Vec<u64>>>>>,
+    shared_data: RefCell<BTreeMap<String, (Vec<u8>, OptimisticLockVersion)>>,
+    queues: RefCell<Vec<FakeQueue>>,
+    http_requests: RefCell<Vec<String>>,
+    // Data received from the downstream that has not been forwarded yet.
+    downstream_buffer: RefCell<Vec<u8>>,
+    // Data received from the upstream that has not been forwarded yet.
+    upstream_buffer: RefCell<Vec<u8>>,
+    // Data forwarded to the upstream, i.e. what SMTP server receives.
+    to_upstream: RefCell<Vec<u8>>,
+    // Data forwarded to the downstream, i.e. what SMTP client receives.
+    to_downstream: RefCell<Vec<u8>>,
+    downstream_closed: Cell<bool>,
+    tick_period: Cell<Duration>,
+    // Extension instance subsequent calls apply to, if switched from a tick.
+    effective_context: Cell<Option<InstanceId>>,
+}
+
+impl Default for FakeHost {
+    fn default() -> Self {
+        FakeHost {
+            now: Cell::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000)),
+            properties: RefCell::default(),
+            counters: RefCell::default(),
+            histograms: RefCell::default(),
+            shared_data: RefCell::default(),
+            queues: RefCell::default(),
+            http_requests: RefCell::default(),
+            downstream_buffer: RefCell::default(),
+            upstream_buffer: RefCell::default(),
+            to_upstream: RefCell::default(),
+            to_downstream: RefCell::default(),
+            downstream_closed: Cell::new(false),
+            tick_period: Cell::new(Duration::ZERO),
+            effective_context: Cell::new(None),
+        }
+    }
+}
+
+impl FakeHost {
+    /// Sets a property of the connection, e.g. `source.address`.
+    pub fn set_property(&self, path: &str, value: &str) {
+        self.properties
+            .borrow_mut()
+            .insert(path.to_owned(), value.as_bytes().to_vec());
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+
+    /// Returns the value of a counter, or `0` if it has not been defined.
+    pub fn counter_value(&self, name: &str) -> u64 {
+        self.counters
+            .borrow()
+            .get(name)
+            .map_or(0, |counter| counter.get())
+    }
+
+    /// Returns values recorded by a histogram so far.
+    pub fn histogram_values(&self, name: &str) -> Vec<u64> {
+        self.histograms
+            .borrow()
+            .get(name)
+            .map_or_else(Vec::new, |histogram| histogram.borrow().clone())
+    }
+
+    /// Returns the period of ticks of the factory, or zero if there are none.
+    pub fn tick_period(&self) -> Duration {
+        self.tick_period.get()
+    }
+
+    /// Returns the extension instance switched to on the latest tick, if any.
+    pub fn effective_context(&self) -> Option<InstanceId> {
+        self.effective_context.get()
+    }
+
+    /// Returns URLs of HTTP requests sent so far, in the form `upstream:path`.
+    pub fn http_requests(&self) -> Vec<String> {
+        self.http_requests.borrow().clone()
+    }
+
+    /// Returns data forwarded to SMTP server so far.
+    pub fn to_upstream(&self) -> Vec<u8> {
+        self.to_upstream.borrow().clone()
+    }
+
+    /// Returns data forwarded to SMTP client so far.
+    pub fn to_downstream(&self) -> Vec<u8> {
+        self.to_downstream.borrow().clone()
+    }
+
+    pub fn is_downstream_closed(&self) -> bool {
+        self.downstream_closed.get()
+    }
+
+    /// Delivers data received from SMTP client to a filter.
+    pub fn deliver_downstream(
+        &self,
+        filter: &mut dyn NetworkFilter,
+        data: &[u8],
+        end_of_stream: bool,
+    ) -> Result<network::FilterStatus> {
+        self.downstream_buffer.borrow_mut().extend_from_slice(data);
+        let size = self.downstream_buffer.borrow().len();
+        let status = filter.on_downstream_data(size, end_of_stream, self)?;
+        if status == network::FilterStatus::Continue {
+            self.forward_downstream();
+        }
+        Ok(status)
+    }
+
+    /// Delivers data received from SMTP server to a filter.
+    pub fn deliver_upstream(
+        &self,
+        filter: &mut dyn NetworkFilter,
+        data: &[u8],
+        end_of_stream: bool,
+    ) -> Result<network::FilterStatus> {
+        self.upstream_buffer.borrow_mut().extend_from_slice(data);
+        let size = self.upstream_buffer.borrow().len();
+        let status = filter.on_upstream_data(size, end_of_stream, self)?;
+        if status == network::FilterStatus::Continue {
+            let data = self.upstream_buffer.replace(Vec::new());
+            self.to_downstream.borrow_mut().extend(data);
+        }
+        Ok(status)
+    }
+
+    /// Delivers a response to the `n`-th HTTP request sent so far, counting from 1.
+    pub fn respond(
+        &self,
+        filter: &mut dyn NetworkFilter,
+        n: u32,
+        status: &str,
+        body: &[u8],
+    ) -> Result<()> {
+        let response = FakeResponse {
+            headers: HeaderMap::builder().header(":status", status).build(),
+            body: body.to_vec(),
+        };
+        filter.on_http_call_response(
+            HttpClientRequestHandle::from(n),
+            response.headers.len(),
+            body.len(),
+            0,
+            self,
+            &response,
+        )
+    }
+
+    /// Tells a filter that both connections are closed.
+    pub fn close(&self, filter: &mut dyn NetworkFilter) -> Result<()> {
+        filter.on_downstream_close(PeerType::Remote, self)?;
+        filter.on_upstream_close(PeerType::Local, self)?;
+        filter.on_connection_complete(self)
+    }
+
+    fn forward_downstream(&self) {
+        let data = self.downstream_buffer.replace(Vec::new());
+        self.to_upstream.borrow_mut().extend(data);
+    }
+}
+
+// `proxy-wasm` logger is linked in along with runtime switches of the log
+// level, so the host function it calls must resolve outside of `Envoy` too.
+#[no_mangle]
+extern "C" fn proxy_log(_level: u32, _message_data: *const u8, _message_size: usize) -> u32 {
+    0
+}
+
+// Replaces `size` bytes of a buffer starting at `start`, as far as the buffer goes.
+fn splice(buffer: &RefCell<Vec<u8>>, start: usize, size: usize, value: &[u8]) {
+    let mut buffer = buffer.borrow_mut();
+    let start = start.min(buffer.len());
+    let end = (start + size).min(buffer.len());
+    buffer.splice(start..end, value.iter().copied());
+}
+
+// Returns `max_size` bytes of a buffer starting at `offset`, as far as the buffer goes.
+fn slice(buffer: &RefCell<Vec<u8>>, offset: usize, max_size: usize) -> ByteString {
+    let buffer = buffer.borrow();
+    let start = offset.min(buffer.len());
+    let end = (start + max_size).min(buffer.len());
+    buffer[start..end].to_vec().into()
+}
+
+impl DownstreamDataOps for FakeHost {
+    fn downstream_data(&self, offset: usize, max_size: usize) -> host::Result<ByteString> {
+        Ok(slice(&self.downstream_buffer, offset, max_size))
+    }
+}
+
+impl UpstreamDataOps for FakeHost {
+    fn upstream_data(&self, offset: usize, max_size: usize) -> host::Result<ByteString> {
+        Ok(slice(&self.upstream_buffer, offset, max_size))
+    }
+}
+
+impl DownstreamCloseOps for FakeHost {}
+
+impl UpstreamCloseOps for FakeHost {}
+
+impl ConnectionCompleteOps for FakeHost {}
+
+impl factory::ConfigureOps for FakeHost {
+    fn set_tick_period(&self, period: Duration) -> host::Result<()> {
+        self.tick_period.set(period);
+        Ok(())
+    }
+}
+
+impl factory::TickOps for FakeHost {
+    fn set_effective_context(&self, instance_id: InstanceId) -> host::Result<()> {
+        self.effective_context.set(Some(instance_id));
+        Ok(())
+    }
+}
+
+impl DownstreamDataMutationOps for FakeHost {
+    fn set_downstream_data(&self, start: usize, size: usize, value: &[u8]) -> host::Result<()> {
+        splice(&self.downstream_buffer, start, size, value);
+        Ok(())
+    }
+}
+
+impl UpstreamDataMutationOps for FakeHost {
+    fn set_upstream_data(&self, start: usize, size: usize, value: &[u8]) -> host::Result<()> {
+        splice(&self.upstream_buffer, start, size, value);
+        Ok(())
+    }
+}
+
+impl DownstreamFlowOps for FakeHost {
+    fn resume_downstream(&self) -> host::Result<()> {
+        self.forward_downstream();
+        Ok(())
+    }
+
+    fn close_downstream(&self) -> host::Result<()> {
+        self.downstream_closed.set(true);
+        Ok(())
+    }
+}
+
+impl StreamInfo for FakeHost {
+    fn stream_property(&self, path: &[&str]) -> host::Result<Option<ByteString>> {
+        let properties = self.properties.borrow();
+        Ok(properties
+            .get(&path.join("."))
+            .map(|value| value.clone().into()))
+    }
+
+    fn set_stream_property(&self, path: &[&str], value: &[u8]) -> host::Result<()> {
+        self.properties
+            .borrow_mut()
+            .insert(path.join("."), value.to_vec());
+        Ok(())
+    }
+}
+
+impl Clock for FakeHost {
+    fn now(&self) -> host::Result<SystemTime> {
+        Ok(self.now.get())
+    }
+}
+
+impl HttpClient for FakeHost {
+    fn send_request(
+        &self,
+        upstream: &str,
+        headers: &[(&str, &str)],
+        _body: Option<&[u8]>,
+        _trailers: Option<&[(&str, &str)]>,
+        _timeout: Duration,
+    ) -> host::Result<HttpClientRequestHandle> {
+        let path = headers
+            .iter()
+            .find(|(name, _)| *name == ":path")
+            .map_or("", |(_, value)| value);
+        let mut requests = self.http_requests.borrow_mut();
+        requests.push(format!("{}:{}", upstream, path));
+        Ok(HttpClientRequestHandle::from(requests.len() as u32))
+    }
+}
+
+impl SharedData for FakeHost {
+    fn get(&self, key: &str) -> host::Result<(Option<ByteString>, Option<OptimisticLockVersion>)> {
+        Ok(match self.shared_data.borrow().get(key) {
+            Some((value, version)) => (Some(value.clone().into()), Some(*version)),
+            None => (None, None),
+        })
+    }
+
+    fn set(
+        &self,
+        key: &str,
+        value: &[u8],
+        version: Option<OptimisticLockVersion>,
+    ) -> host::Result<()> {
+        let mut shared_data = self.shared_data.borrow_mut();
+        let current = shared_data.get(key).map(|(_, version)| *version);
+        if version.is_some() && version != current {
+            return Err(format_err!("CAS mismatch on {}", key));
+        }
+        let next = current.map_or(1, |version| version + 1);
+        shared_data.insert(key.to_owned(), (value.to_vec(), next));
+        Ok(())
+    }
+}
+
+impl SharedQueue for FakeHost {
+    fn register(&self, name: &str) -> host::Result<SharedQueueHandle> {
+        let mut queues = self.queues.borrow_mut();
+        let handle = SharedQueueHandle::from(queues.len() as u32 + 1);
+        queues.push(FakeQueue {
+            handle,
+            name: name.to_owned(),
+            items: Vec::new(),
+        });
+        Ok(handle)
+    }
+
+    fn lookup(&self, _vm_id: &str, name: &str) -> host::Result<Option<SharedQueueHandle>> {
+        let queues = self.queues.borrow();
+        Ok(queues
+            .iter()
+            .find(|queue| queue.name == name)
+            .map(|queue| queue.handle))
+    }
+
+    fn dequeue(&self, queue_id: SharedQueueHandle) -> host::Result<Option<ByteString>> {
+        let mut queues = self.queues.borrow_mut();
+        Ok(queues
+            .iter_mut()
+            .find(|queue| queue.handle == queue_id)
+            .filter(|queue| !queue.items.is_empty())
+            .map(|queue| queue.items.remove(0).into()))
+    }
+
+    fn enqueue(&self, queue_id: SharedQueueHandle, value: &[u8]) -> host::Result<()> {
+        let mut queues = self.queues.borrow_mut();
+        match queues.iter_mut().find(|queue| queue.handle == queue_id) {
+            Some(queue) => {
+                queue.items.push(value.to_vec());
+                Ok(())
+            }
+            None => Err(format_err!("unknown shared queue {}", queue_id)),
+        }
+    }
+}
+
+impl Stats for FakeHost {
+    fn counter(&self, name: &str) -> host::Result<Box<dyn Counter>> {
+        Ok(Box::new(FakeMetric(self.metric(name))))
+    }
+
+    fn gauge(&self, name: &str) -> host::Result<Box<dyn Gauge>> {
+        Ok(Box::new(FakeMetric(self.metric(name))))
+    }
+
+    fn histogram(&self, name: &str) -> host::Result<Box<dyn Histogram>> {
+        let mut histograms = self.histograms.borrow_mut();
+        let values = histograms.entry(name.to_owned()).or_default();
+        Ok(Box::new(FakeHistogram(Rc::clone(values))))
+    }
+}
+
+impl FakeHost {
+    // Counters and gauges are kept together, since a name identifies either.
+    fn metric(&self, name: &str) -> Rc<Cell<u64>> {
+        let mut counters = self.counters.borrow_mut();
+        Rc::clone(counters.entry(name.to_owned()).or_default())
+    }
+}
+
+struct FakeQueue {
+    handle: SharedQueueHandle,
+    name: String,
+    items: Vec<Vec<u8>>,
+}
+
+struct FakeResponse {
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl HttpClientResponseOps for FakeResponse {
+    fn http_call_response_headers(&self) -> host::Result<HeaderMap> {
+        Ok(self.headers.clone())
+    }
+
+    fn http_call_response_header(&self, name: &str) -> host::Result<Option<ByteString>> {
+        Ok(self.headers.get(name).cloned())
+    }
+
+    fn http_call_response_body(&self, start: usize, max_size: usize) -> host::Result<ByteString> {
+        let start = start.min(self.body.len());
+        let end = (start + max_size).min(self.body.len());
+        Ok(self.body[start..end].to_vec().into())
+    }
+
+    fn http_call_response_trailers(&self) -> host::Result<HeaderMap> {
+        Ok(HeaderMap::default())
+    }
+
+    fn http_call_response_trailer(&self, _name: &str) -> host::Result<Option<ByteString>> {
+        Ok(None)
+    }
+}
+
+struct FakeMetric(Rc<Cell<u64>>);
+
+impl Counter for FakeMetric {
+    fn add(&self, offset: u64) -> host::Result<()> {
+        self.0.set(self.0.get() + offset);
+        Ok(())
+    }
+
+    fn value(&self) -> host::Result<u64> {
+        Ok(self.0.get())
+    }
+}
+
+impl Gauge for FakeMetric {
+    fn add(&self, offset: u64) -> host::Result<()> {
+        self.0.set(self.0.get() + offset);
+        Ok(())
+    }
+
+    fn sub(&self, offset: u64) -> host::Result<()> {
+        self.0.set(self.0.get().saturating_sub(offset));
+        Ok(())
+    }
+
+    fn set(&self, value: u64) -> host::Result<()> {
+        self.0.set(value);
+        Ok(())
+    }
+
+    fn value(&self) -> host::Result<u64> {
+        Ok(self.0.get())
+    }
+}
+
+struct FakeHistogram(Rc<RefCell<Vec<u64>>>);
+
+impl Histogram for FakeHistogram {
+    fn record(&self, value: u64) -> host::Result<()> {
+        self.0.borrow_mut().push(value);
+        Ok(())
+    }
+}
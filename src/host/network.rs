@@ -0,0 +1,137 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use envoy::error::format_err;
+use envoy::host;
+
+/// An interface for mutating data received from the upstream.
+///
+/// Must be called from within `on_upstream_data` callback.
+pub trait UpstreamDataMutationOps {
+    /// Replaces `size` bytes of upstream data starting at `start` with a given value.
+    ///
+    /// `Envoy` only supports replacing a prefix of the data (`start == 0`)
+    /// and appending to the data (`start >= data_size`).
+    fn set_upstream_data(&self, start: usize, size: usize, value: &[u8]) -> host::Result<()>;
+}
+
+/// An interface for mutating data received from the downstream.
+///
+/// Must be called from within `on_downstream_data` callback.
+pub trait DownstreamDataMutationOps {
+    /// Replaces `size` bytes of downstream data starting at `start` with a given value.
+    ///
+    /// `Envoy` only supports replacing a prefix of the data (`start == 0`)
+    /// and appending to the data (`start >= data_size`).
+    fn set_downstream_data(&self, start: usize, size: usize, value: &[u8]) -> host::Result<()>;
+}
+
+/// An interface for controlling the flow of data received from the downstream.
+pub trait DownstreamFlowOps {
+    /// Resumes iteration of downstream data that has been held back by returning
+    /// `StopIteration` from `on_downstream_data` callback.
+    fn resume_downstream(&self) -> host::Result<()>;
+
+    /// Closes the downstream connection.
+    fn close_downstream(&self) -> host::Result<()>;
+}
+
+impl dyn UpstreamDataMutationOps {
+    /// Returns the default implementation that interacts with `Envoy`
+    /// through its [`ABI`].
+    ///
+    /// [`ABI`]: https://github.com/proxy-wasm/spec
+    pub fn default() -> &'static dyn UpstreamDataMutationOps {
+        &impls::Host
+    }
+}
+
+impl dyn DownstreamDataMutationOps {
+    /// Returns the default implementation that interacts with `Envoy`
+    /// through its [`ABI`].
+    ///
+    /// [`ABI`]: https://github.com/proxy-wasm/spec
+    pub fn default() -> &'static dyn DownstreamDataMutationOps {
+        &impls::Host
+    }
+}
+
+impl dyn DownstreamFlowOps {
+    /// Returns the default implementation that interacts with `Envoy`
+    /// through its [`ABI`].
+    ///
+    /// [`ABI`]: https://github.com/proxy-wasm/spec
+    pub fn default() -> &'static dyn DownstreamFlowOps {
+        &impls::Host
+    }
+}
+
+mod impls {
+    use proxy_wasm::hostcalls;
+    use proxy_wasm::types::BufferType;
+
+    use super::{
+        format_err, DownstreamDataMutationOps, DownstreamFlowOps, UpstreamDataMutationOps,
+    };
+    use envoy::host;
+
+    // `proxy-wasm` only knows about HTTP streams, so network streams
+    // have to be addressed through the raw ABI.
+    const DOWNSTREAM_STREAM: u32 = 2;
+    const STATUS_OK: u32 = 0;
+
+    #[allow(clashing_extern_declarations)]
+    extern "C" {
+        fn proxy_continue_stream(stream_type: u32) -> u32;
+        fn proxy_close_stream(stream_type: u32) -> u32;
+    }
+
+    pub(super) struct Host;
+
+    impl UpstreamDataMutationOps for Host {
+        fn set_upstream_data(&self, start: usize, size: usize, value: &[u8]) -> host::Result<()> {
+            hostcalls::set_buffer(BufferType::UpstreamData, start, size, value)
+                .map_err(|err| format_err!(err))
+        }
+    }
+
+    impl DownstreamDataMutationOps for Host {
+        fn set_downstream_data(&self, start: usize, size: usize, value: &[u8]) -> host::Result<()> {
+            hostcalls::set_buffer(BufferType::DownstreamData, start, size, value)
+                .map_err(|err| format_err!(err))
+        }
+    }
+
+    impl DownstreamFlowOps for Host {
+        fn resume_downstream(&self) -> host::Result<()> {
+            match unsafe { proxy_continue_stream(DOWNSTREAM_STREAM) } {
+                STATUS_OK => Ok(()),
+                status => Err(format_err!(
+                    "call to the host ABI function \"env.proxy_continue_stream\" has failed with status code {}",
+                    status
+                )),
+            }
+        }
+
+        fn close_downstream(&self) -> host::Result<()> {
+            match unsafe { proxy_close_stream(DOWNSTREAM_STREAM) } {
+                STATUS_OK => Ok(()),
+                status => Err(format_err!(
+                    "call to the host ABI function \"env.proxy_close_stream\" has failed with status code {}",
+                    status
+                )),
+            }
+        }
+    }
+}
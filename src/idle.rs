@@ -0,0 +1,83 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Idle timeout of SMTP clients.
+//!
+//! A client that has gone silent gives its filter instance no events to check
+//! the timeout on, so the factory checks connections on its ticks as well.
+
+use std::cell::{Cell, RefCell};
+use std::time::{Duration, SystemTime};
+
+use envoy::extension::InstanceId;
+
+use crate::config::{IdleTimeoutConfig, TimeoutAction};
+
+/// Downstream activity of a connection, shared by its filter instance
+/// and the factory.
+pub struct IdleWatch {
+    // Filter instance of the connection.
+    instance_id: InstanceId,
+    // Idle timeout of the connection, i.e. with the selected profile applied.
+    config: RefCell<Option<IdleTimeoutConfig>>,
+    // Time of the latest chunk of downstream data, if the idle timeout is configured.
+    last_activity: Cell<Option<SystemTime>>,
+    // Whether the client has been idle for longer than the idle timeout.
+    idle: Cell<bool>,
+}
+
+impl IdleWatch {
+    pub fn new(instance_id: InstanceId, config: Option<IdleTimeoutConfig>) -> Self {
+        IdleWatch {
+            instance_id,
+            config: RefCell::new(config),
+            last_activity: Cell::new(None),
+            idle: Cell::new(false),
+        }
+    }
+
+    pub fn instance_id(&self) -> InstanceId {
+        self.instance_id
+    }
+
+    /// Replaces the idle timeout, e.g. once a policy profile has been selected.
+    pub fn set_config(&self, config: Option<IdleTimeoutConfig>) {
+        self.config.replace(config);
+    }
+
+    /// Records downstream activity at a given time.
+    pub fn touch(&self, now: SystemTime) {
+        if self.config.borrow().is_some() {
+            self.last_activity.set(Some(now));
+        }
+    }
+
+    /// Returns how long the client has been idle for and what to do about it
+    /// once it has been idle for longer than the idle timeout.
+    ///
+    /// A client is only reported once.
+    pub fn check(&self, now: SystemTime) -> Option<(Duration, TimeoutAction)> {
+        let config = self.config.borrow();
+        let (config, last_activity) = match (config.as_ref(), self.last_activity.get()) {
+            (Some(config), Some(last_activity)) if !self.idle.get() => (config, last_activity),
+            _ => return None,
+        };
+        let idle_for = now.duration_since(last_activity).unwrap_or_default();
+        if idle_for <= config.timeout() {
+            return None;
+        }
+        self.idle.set(true);
+        Some((idle_for, config.action))
+    }
+}
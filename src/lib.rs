@@ -12,10 +12,77 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-pub use self::factory::SmtpFilterFactory;
+//! SMTP filter for `Envoy`.
+//!
+//! Without the default `envoy` feature, only the `smtp` module is built, i.e.
+//! a plain state machine that observes SMTP traffic, so that other proxies and
+//! tools can reuse it. Without the `std` feature either, only `smtp::spec` is
+//! built, under `no_std` with `alloc`.
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "envoy")]
+pub use self::aggregator::SmtpEventAggregator;
+#[cfg(feature = "envoy")]
+pub use self::config::SmtpFilterConfig;
+#[cfg(feature = "envoy")]
+pub use self::factory::{SmtpEnforcingFilterFactory, SmtpFilterFactory, SmtpFilterFactoryBuilder};
+#[cfg(feature = "envoy")]
+pub use self::filter::SmtpFilter;
+#[cfg(feature = "envoy")]
+pub use self::logger::SmtpAccessLogger;
+#[cfg(feature = "envoy")]
+pub use self::policy::PolicyHook;
+#[cfg(feature = "std")]
+pub use self::smtp::agent::{
+    CommandExtension, CommandRegistry, PendingReplySnapshot, Session, SessionConfig,
+    SessionSnapshot, SmtpEventSink, StatsSink,
+};
+#[cfg(feature = "envoy")]
+pub use self::stats::{FilterStatsSink, SmtpFilterStats, SmtpSessionStats};
+
+#[cfg(feature = "std")]
+#[macro_use]
+mod macros;
+
+#[cfg(feature = "envoy")]
+mod aggregator;
+#[cfg(feature = "envoy")]
 mod config;
+#[cfg(feature = "policy")]
+mod dnsbl;
+#[cfg(feature = "envoy")]
+mod events;
+#[cfg(feature = "envoy")]
 mod factory;
+#[cfg(feature = "envoy")]
 mod filter;
-mod smtp;
+#[cfg(feature = "envoy")]
+mod host;
+#[cfg(feature = "envoy")]
+mod idle;
+#[cfg(feature = "std")]
+mod lists;
+#[cfg(feature = "envoy")]
+mod logger;
+#[cfg(feature = "envoy")]
+mod policy;
+#[cfg(feature = "envoy")]
+mod protobuf;
+#[cfg(feature = "std")]
+mod redact;
+#[cfg(feature = "policy")]
+mod reputation;
+#[cfg(feature = "envoy")]
+mod runtime;
+pub mod smtp;
+#[cfg(feature = "envoy")]
+mod state;
+#[cfg(feature = "envoy")]
 mod stats;
+#[cfg(feature = "content-inspection")]
+mod transcript;
+#[cfg(feature = "envoy")]
+mod webhook;
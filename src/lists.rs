@@ -0,0 +1,127 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Allow and deny lists of senders and recipients.
+
+use bstr::ByteSlice;
+use serde::Deserialize;
+
+use crate::smtp::agent::RecipientRewrite;
+
+/// Lists of senders and recipients along with a recipient rewrite map that
+/// can be refreshed from shared data without a reconfiguration of the filter.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PolicyLists {
+    /// Lists of senders of MAIL commands.
+    pub senders: AccessList,
+    /// Lists of recipients of RCPT commands.
+    pub recipients: AccessList,
+    /// Rewrite of recipients that replaces `recipient_rewrite` of the filter configuration.
+    pub recipient_rewrite: Option<RecipientRewrite>,
+}
+
+impl PolicyLists {
+    /// Returns the verdict on the mailbox of a MAIL or RCPT command, if it is listed.
+    pub fn verdict(&self, verb: &str, mailbox: &[u8]) -> Option<ListVerdict> {
+        match verb {
+            "MAIL" => self.senders.verdict(mailbox),
+            "RCPT" => self.recipients.verdict(mailbox),
+            _ => None,
+        }
+    }
+
+    /// Indicates whether any mailboxes are listed.
+    pub fn is_empty(&self) -> bool {
+        self.senders.is_empty() && self.recipients.is_empty()
+    }
+}
+
+/// Lists of mailboxes, e.g. `user@example.org`, or whole domains, e.g. `example.org`.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct AccessList {
+    /// Mailboxes exempt from the envelope policy.
+    pub allow: Vec<String>,
+    /// Mailboxes to turn down, which takes precedence over `allow`.
+    pub deny: Vec<String>,
+}
+
+impl AccessList {
+    /// Returns the verdict on a given mailbox, if it is listed.
+    pub fn verdict(&self, mailbox: &[u8]) -> Option<ListVerdict> {
+        let mailbox = mailbox.to_str().ok()?;
+        if contains(&self.deny, mailbox) {
+            Some(ListVerdict::Deny)
+        } else if contains(&self.allow, mailbox) {
+            Some(ListVerdict::Allow)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+/// Verdict on a listed mailbox.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ListVerdict {
+    Allow,
+    Deny,
+}
+
+fn contains(entries: &[String], mailbox: &str) -> bool {
+    let domain = mailbox.rfind('@').map(|at| &mailbox[at + 1..]);
+    entries.iter().any(|entry| {
+        if entry.contains('@') {
+            entry.eq_ignore_ascii_case(mailbox)
+        } else {
+            domain.is_some_and(|domain| entry.eq_ignore_ascii_case(domain))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_match_mailboxes_and_domains() {
+        let lists: PolicyLists = serde_json::from_str(
+            r#"{
+                "senders": {"allow": ["example.org"], "deny": ["spammer@example.org"]},
+                "recipients": {"deny": ["Abuse@Example.net"]}
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            lists.verdict("MAIL", b"user@EXAMPLE.org"),
+            Some(ListVerdict::Allow)
+        );
+        assert_eq!(
+            lists.verdict("MAIL", b"spammer@example.org"),
+            Some(ListVerdict::Deny)
+        );
+        assert_eq!(lists.verdict("MAIL", b"user@example.net"), None);
+        assert_eq!(lists.verdict("MAIL", b""), None);
+        assert_eq!(
+            lists.verdict("RCPT", b"abuse@example.net"),
+            Some(ListVerdict::Deny)
+        );
+        assert_eq!(lists.verdict("RCPT", b"user@example.org"), None);
+    }
+}
@@ -0,0 +1,62 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Access logger of SMTP connections.
+
+use envoy::extension::access_logger::LogOps;
+use envoy::extension::{AccessLogger, Result};
+use envoy::host::{log, ByteString, StreamInfo};
+
+use crate::state;
+
+/// Access logger that renders SMTP fields exported by SMTP filter
+/// for a TCP connection into `Envoy` log.
+#[derive(Debug)]
+pub struct SmtpAccessLogger;
+
+impl AccessLogger for SmtpAccessLogger {
+    /// The reference name for the SMTP Access Logger.
+    ///
+    /// This name appears in `Envoy` configuration as a value of `root_id` field.
+    fn name() -> &'static str {
+        "tetratelabs.access_loggers.smtp"
+    }
+
+    /// Is called when the TCP connection is complete.
+    fn on_log(&mut self, ops: &dyn LogOps) -> Result<()> {
+        let stream_info = ops.stream_info();
+        let rcpt_count = match stream_info.stream_property(&[state::RCPT_COUNT])? {
+            Some(count) => count,
+            None => return Ok(()), // not an SMTP connection
+        };
+        let client = stream_info.source().address()?.unwrap_or_default();
+        let upstream = stream_info.upstream().address()?.unwrap_or_default();
+        log::info!(
+            "smtp {} -> {}: mail_from={} rcpt_count={} messages={} last_reply_code={}",
+            client,
+            upstream,
+            property(stream_info, state::MAIL_FROM)?,
+            rcpt_count,
+            property(stream_info, state::MESSAGES)?,
+            property(stream_info, state::LAST_REPLY_CODE)?,
+        );
+        Ok(())
+    }
+}
+
+fn property(stream_info: &dyn StreamInfo, name: &str) -> Result<ByteString> {
+    Ok(stream_info
+        .stream_property(&[name])?
+        .unwrap_or_else(|| "-".into()))
+}
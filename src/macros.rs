@@ -0,0 +1,59 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Logs a message of a connection at `debug` level, or according to the log
+/// level of SMTP filter if it is set, e.g. for a sampled connection.
+///
+/// Messages enabled by the log level of SMTP filter are logged at `info` level
+/// to get past the log level of `Envoy` without lowering it for every connection
+/// on the listener.
+macro_rules! filter_debug {
+    ($level:expr, $($arg:tt)+) => {
+        filter_log!($level, debug, $crate::smtp::agent::FilterLogLevel::Debug, $($arg)+)
+    };
+}
+
+/// Logs a message of a connection at `trace` level, or according to the log
+/// level of SMTP filter if it is set.
+macro_rules! filter_trace {
+    ($level:expr, $($arg:tt)+) => {
+        filter_log!($level, trace, $crate::smtp::agent::FilterLogLevel::Trace, $($arg)+)
+    };
+}
+
+macro_rules! filter_log {
+    ($level:expr, $macro:ident, $threshold:expr, $($arg:tt)+) => {
+        match $level {
+            None => log::$macro!($($arg)+),
+            Some(level) if level >= $threshold => log::info!($($arg)+),
+            Some(_) => {}
+        }
+    };
+}
+
+/// Logs a message at a given [`RuntimeLogLevel`].
+///
+/// [`RuntimeLogLevel`]: crate::runtime::RuntimeLogLevel
+#[cfg(feature = "envoy")]
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)+) => {
+        match $level {
+            $crate::runtime::RuntimeLogLevel::Trace => log::trace!($($arg)+),
+            $crate::runtime::RuntimeLogLevel::Debug => log::debug!($($arg)+),
+            $crate::runtime::RuntimeLogLevel::Info => log::info!($($arg)+),
+            $crate::runtime::RuntimeLogLevel::Warn => log::warn!($($arg)+),
+            $crate::runtime::RuntimeLogLevel::Error => log::error!($($arg)+),
+        }
+    };
+}
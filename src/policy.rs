@@ -0,0 +1,236 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Consultation of external policy services.
+
+use std::collections::HashMap;
+#[cfg(feature = "policy")]
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use bstr::ByteSlice;
+use envoy::extension::Result;
+#[cfg(any(feature = "policy", feature = "content-inspection"))]
+use envoy::host::log;
+use envoy::host::{Clock, HttpClient, HttpClientRequestHandle};
+#[cfg(feature = "policy")]
+use serde_json::json;
+
+#[cfg(feature = "content-inspection")]
+use crate::config::ContentScanConfig;
+#[cfg(feature = "policy")]
+use crate::config::{DnsblConfig, EnvelopePolicyConfig};
+#[cfg(feature = "policy")]
+use crate::dnsbl;
+#[cfg(feature = "policy")]
+use crate::reputation::Reputation;
+use crate::smtp::agent::{ContentCheck, EnvelopeCheck};
+
+/// Callout represents a kind of policy request.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Callout {
+    /// Policy request on an envelope command.
+    Envelope,
+    /// Scanning request on a message.
+    Content,
+    /// Lookup of the client address in a DNSBL zone with a given index.
+    Dnsbl(usize),
+}
+
+/// Decision represents an outcome of a policy request.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Decision {
+    /// Policy service has allowed the command or the message.
+    Allow,
+    /// Policy service has denied the command or the message.
+    Deny,
+    /// Policy service has failed to make a decision.
+    Failure,
+}
+
+impl Decision {
+    /// Returns a decision that corresponds to a given HTTP status code.
+    pub fn from_status(status: Option<&[u8]>) -> Self {
+        let status = status
+            .and_then(|status| status.to_str().ok())
+            .and_then(|status| status.parse::<u16>().ok());
+        match status {
+            Some(200..=299) => Decision::Allow,
+            Some(403) => Decision::Deny,
+            _ => Decision::Failure,
+        }
+    }
+}
+
+/// Local policy that is consulted on envelope commands and messages ahead of
+/// external policy services, e.g. by an embedder of SMTP filter.
+///
+/// Unlike external policy services, hooks decide synchronously.
+pub trait PolicyHook {
+    fn check_envelope(&self, _check: &EnvelopeCheck, _client: Option<SocketAddr>) -> Decision {
+        Decision::Allow
+    }
+
+    fn scan_content(&self, _check: &ContentCheck, _client: Option<SocketAddr>) -> Decision {
+        Decision::Allow
+    }
+}
+
+/// Client of external policy services.
+pub struct PolicyClient<'a> {
+    // HTTP Client API implementation.
+    http_client: &'a dyn HttpClient,
+    // Clock API implementation.
+    clock: &'a dyn Clock,
+    // Policy requests in flight along with the time they have been sent at.
+    pending: HashMap<HttpClientRequestHandle, (Callout, SystemTime)>,
+}
+
+impl<'a> PolicyClient<'a> {
+    pub fn new(http_client: &'a dyn HttpClient, clock: &'a dyn Clock) -> Self {
+        PolicyClient {
+            http_client,
+            clock,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Returns `true` if there are policy requests in flight.
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Sends a policy request on a given envelope command.
+    #[cfg(feature = "policy")]
+    pub fn check_envelope(
+        &mut self,
+        config: &EnvelopePolicyConfig,
+        check: &EnvelopeCheck,
+        client_address: Option<SocketAddr>,
+        reputation: Option<&Reputation>,
+    ) -> Result<()> {
+        let body = json!({
+            "verb": check.verb,
+            "mailbox": check.mailbox.to_str_lossy(),
+            "submitter": check.submitter.as_ref().map(|submitter| submitter.to_str_lossy()),
+            "client_address": client_address.map(|address| address.ip().to_string()),
+            "reputation": reputation,
+        })
+        .to_string();
+        log::debug!("sending policy request on {} command", check.verb);
+        self.send(
+            Callout::Envelope,
+            ("POST", &config.path, &config.cluster),
+            &config.cluster,
+            &[("content-type", "application/json")],
+            Some(body.as_bytes()),
+            config.timeout(),
+        )
+    }
+
+    /// Sends a scanning request on a given message.
+    #[cfg(feature = "content-inspection")]
+    pub fn scan_content(
+        &mut self,
+        config: &ContentScanConfig,
+        check: &ContentCheck,
+        client_address: Option<SocketAddr>,
+    ) -> Result<()> {
+        let message = check.message.as_bytes();
+        let message = &message[..message.len().min(config.max_message_bytes)];
+        let client_address = client_address
+            .map(|address| address.ip().to_string())
+            .unwrap_or_default();
+        log::debug!("sending scanning request: {} bytes", message.len());
+        self.send(
+            Callout::Content,
+            ("POST", &config.path, &config.cluster),
+            &config.cluster,
+            &[
+                ("content-type", "message/rfc822"),
+                ("x-smtp-client-address", &client_address),
+            ],
+            Some(message),
+            config.timeout(),
+        )
+    }
+
+    /// Sends a lookup of a given client address in a DNSBL zone with a given index.
+    #[cfg(feature = "policy")]
+    pub fn lookup_dnsbl(
+        &mut self,
+        config: &DnsblConfig,
+        zone: usize,
+        client_address: IpAddr,
+    ) -> Result<()> {
+        let name = dnsbl::query_name(client_address, &config.zones[zone]);
+        log::debug!("sending DNSBL lookup: {}", name);
+        let path = format!("{}?name={}&type=A", config.path, name);
+        self.send(
+            Callout::Dnsbl(zone),
+            ("GET", &path, config.authority()),
+            &config.cluster,
+            &[("accept", "application/dns-json")],
+            None,
+            config.timeout(),
+        )
+    }
+
+    /// Returns a decision along with its latency if a given response belongs
+    /// to a policy request.
+    pub fn on_response(
+        &mut self,
+        request: HttpClientRequestHandle,
+        status: Option<&[u8]>,
+    ) -> Result<Option<(Callout, Decision, Duration)>> {
+        let (callout, sent_at) = match self.pending.remove(&request) {
+            Some(pending) => pending,
+            None => return Ok(None),
+        };
+        let latency = self
+            .clock
+            .now()?
+            .duration_since(sent_at)
+            .unwrap_or_default();
+        Ok(Some((callout, Decision::from_status(status), latency)))
+    }
+
+    // Sends a request with given `(method, path, authority)` pseudo-headers.
+    #[cfg_attr(
+        not(any(feature = "policy", feature = "content-inspection")),
+        allow(dead_code)
+    )]
+    fn send(
+        &mut self,
+        callout: Callout,
+        (method, path, authority): (&str, &str, &str),
+        cluster: &str,
+        headers: &[(&str, &str)],
+        body: Option<&[u8]>,
+        timeout: Duration,
+    ) -> Result<()> {
+        let mut all_headers = vec![
+            (":method", method),
+            (":path", path),
+            (":authority", authority),
+        ];
+        all_headers.extend_from_slice(headers);
+        let request = self
+            .http_client
+            .send_request(cluster, &all_headers, body, None, timeout)?;
+        self.pending.insert(request, (callout, self.clock.now()?));
+        Ok(())
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Decoder of `google.protobuf.Struct` messages.
+//!
+//! `Envoy` passes the filter configuration as is if it is a `google.protobuf.StringValue`
+//! and as a serialized message otherwise, so a `typed_config` given as a
+//! `google.protobuf.Struct` arrives in protobuf wire format.
+
+use envoy::error::format_err;
+use envoy::extension::Result;
+use serde_json::{Map, Number, Value};
+
+// Wire types.
+const VARINT: u64 = 0;
+const FIXED64: u64 = 1;
+const LENGTH_DELIMITED: u64 = 2;
+const FIXED32: u64 = 5;
+
+/// Decodes a serialized `google.protobuf.Struct` into a JSON object.
+pub fn decode_struct(data: &[u8]) -> Result<Value> {
+    let mut fields = Map::new();
+    for field in Fields::new(data) {
+        if let (1, Wire::Bytes(entry)) = field? {
+            let (key, value) = decode_entry(entry)?;
+            fields.insert(key, value);
+        }
+    }
+    Ok(Value::Object(fields))
+}
+
+// Decodes an entry of `map<string, Value> fields`.
+fn decode_entry(data: &[u8]) -> Result<(String, Value)> {
+    let mut key = String::new();
+    let mut value = Value::Null;
+    for field in Fields::new(data) {
+        match field? {
+            (1, Wire::Bytes(bytes)) => key = decode_string(bytes)?,
+            (2, Wire::Bytes(bytes)) => value = decode_value(bytes)?,
+            _ => {}
+        }
+    }
+    Ok((key, value))
+}
+
+// Decodes a `google.protobuf.Value`.
+fn decode_value(data: &[u8]) -> Result<Value> {
+    let mut value = Value::Null;
+    for field in Fields::new(data) {
+        value = match field? {
+            (1, _) => Value::Null,
+            (2, Wire::Fixed64(bits)) => Number::from_f64(f64::from_bits(bits))
+                .map(Value::Number)
+                .ok_or_else(|| format_err!("number value is not finite"))?,
+            (3, Wire::Bytes(bytes)) => Value::String(decode_string(bytes)?),
+            (4, Wire::Varint(flag)) => Value::Bool(flag != 0),
+            (5, Wire::Bytes(bytes)) => decode_struct(bytes)?,
+            (6, Wire::Bytes(bytes)) => decode_list(bytes)?,
+            _ => continue,
+        };
+    }
+    Ok(integral(value))
+}
+
+// Decodes a `google.protobuf.ListValue`.
+fn decode_list(data: &[u8]) -> Result<Value> {
+    let mut values = Vec::new();
+    for field in Fields::new(data) {
+        if let (1, Wire::Bytes(bytes)) = field? {
+            values.push(decode_value(bytes)?);
+        }
+    }
+    Ok(Value::Array(values))
+}
+
+fn decode_string(data: &[u8]) -> Result<String> {
+    String::from_utf8(data.to_vec()).map_err(|_| format_err!("string value is not UTF-8"))
+}
+
+// `google.protobuf.Value` has no integers, so whole numbers are turned into ones
+// for the sake of integer configuration fields.
+fn integral(value: Value) -> Value {
+    match value.as_f64() {
+        Some(number) if number.fract() == 0.0 && number.abs() < (1u64 << 53) as f64 => {
+            if number < 0.0 {
+                Value::from(number as i64)
+            } else {
+                Value::from(number as u64)
+            }
+        }
+        _ => value,
+    }
+}
+
+// Value of a field in protobuf wire format.
+enum Wire<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32,
+}
+
+// Iterator over fields of a serialized message.
+struct Fields<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Fields { data }
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self
+                .data
+                .split_first()
+                .ok_or_else(|| format_err!("truncated varint"))?;
+            self.data = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(format_err!("varint is too long"))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(format_err!("truncated field"));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn field(&mut self) -> Result<(u64, Wire<'a>)> {
+        let key = self.varint()?;
+        let value = match key & 0x7 {
+            VARINT => Wire::Varint(self.varint()?),
+            FIXED64 => {
+                let mut bits = [0u8; 8];
+                bits.copy_from_slice(self.take(8)?);
+                Wire::Fixed64(u64::from_le_bytes(bits))
+            }
+            LENGTH_DELIMITED => {
+                let len = self.varint()? as usize;
+                Wire::Bytes(self.take(len)?)
+            }
+            FIXED32 => {
+                self.take(4)?;
+                Wire::Fixed32
+            }
+            wire_type => return Err(format_err!("unsupported wire type {}", wire_type)),
+        };
+        Ok((key >> 3, value))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Result<(u64, Wire<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let field = self.field();
+        if field.is_err() {
+            self.data = &[];
+        }
+        Some(field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_decode_struct() {
+        // {"detailed_stats": true, "source_stats": "hashed", "limit": 100.0, "tags": ["a"]}
+        let mut data = Vec::new();
+        for (key, value) in [
+            (&b"detailed_stats"[..], &[0x20, 0x01][..]),
+            (b"source_stats", b"\x1a\x06hashed"),
+            (b"limit", b"\x11\x00\x00\x00\x00\x00\x00\x59\x40"),
+            (b"tags", b"\x32\x05\x0a\x03\x1a\x01a"),
+        ] {
+            let mut entry = vec![0x0a, key.len() as u8];
+            entry.extend_from_slice(key);
+            entry.extend_from_slice(&[0x12, value.len() as u8]);
+            entry.extend_from_slice(value);
+            data.extend_from_slice(&[0x0a, entry.len() as u8]);
+            data.extend_from_slice(&entry);
+        }
+
+        let value = decode_struct(&data).unwrap();
+
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "detailed_stats": true,
+                "source_stats": "hashed",
+                "limit": 100,
+                "tags": ["a"],
+            })
+        );
+        assert!(decode_struct(&data[..data.len() - 1]).is_err());
+    }
+}
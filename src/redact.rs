@@ -0,0 +1,185 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Redaction of personal data and credentials from logs and exports.
+
+use std::borrow::Cow;
+
+use bstr::ByteSlice;
+use serde::Deserialize;
+
+// Replacement of masked local parts and of AUTH credentials.
+const MASK: &[u8] = b"***";
+
+/// Redaction of personal data applied to logs, filter state, events and
+/// webhook payloads.
+///
+/// Credentials of AUTH commands are never logged regardless of the configuration.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Redaction {
+    /// Whether local parts of mailboxes are masked, e.g. `<***@example.org>`.
+    pub mask_local_parts: bool,
+    /// Maximum number of octets of a message to log, unlimited if unset.
+    pub max_body_bytes: Option<usize>,
+    /// Whether identities SMTP clients authenticate as are masked, e.g.
+    /// `***@example.org` or `***`.
+    pub mask_auth_identities: bool,
+}
+
+impl Redaction {
+    /// Returns a mailbox, e.g. `<user@example.org>`, with its local part masked if configured.
+    pub fn mailbox<'a>(&self, mailbox: &'a [u8]) -> Cow<'a, [u8]> {
+        if !self.mask_local_parts {
+            return Cow::Borrowed(mailbox);
+        }
+        let start = if mailbox.starts_with(b"<") { 1 } else { 0 };
+        match mailbox.rfind_byte(b'@') {
+            Some(at) if at > start => {
+                let mut masked = mailbox[..start].to_vec();
+                masked.extend_from_slice(MASK);
+                masked.extend_from_slice(&mailbox[at..]);
+                Cow::Owned(masked)
+            }
+            _ => Cow::Borrowed(mailbox),
+        }
+    }
+
+    /// Returns an AUTH identity, e.g. `user@example.org`, masked if configured.
+    pub fn identity<'a>(&self, identity: &'a [u8]) -> Cow<'a, [u8]> {
+        if !self.mask_auth_identities {
+            return Cow::Borrowed(identity);
+        }
+        match identity.rfind_byte(b'@') {
+            Some(at) if at > 0 => {
+                let mut masked = MASK.to_vec();
+                masked.extend_from_slice(&identity[at..]);
+                Cow::Owned(masked)
+            }
+            _ => Cow::Borrowed(MASK),
+        }
+    }
+
+    /// Returns a message truncated to `max_body_bytes`.
+    pub fn body<'a>(&self, body: &'a [u8]) -> &'a [u8] {
+        match self.max_body_bytes {
+            Some(max) => &body[..body.len().min(max)],
+            None => body,
+        }
+    }
+
+    /// Returns commands or replies with credentials of AUTH commands removed
+    /// and mailboxes in angle brackets masked if configured.
+    pub fn data<'a>(&self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        if !self.mask_local_parts && !data.lines().any(is_auth) {
+            return Cow::Borrowed(data);
+        }
+        let mut redacted = Vec::with_capacity(data.len());
+        for line in data.split_inclusive(|&octet| octet == b'\n') {
+            let content = line.trim_end_with(|c| c == '\r' || c == '\n');
+            if is_auth(content) {
+                // keep the verb and the mechanism only
+                let mut words = content.splitn_str(3, " ");
+                redacted.extend_from_slice(words.next().unwrap_or_default());
+                if let Some(mechanism) = words.next() {
+                    redacted.push(b' ');
+                    redacted.extend_from_slice(mechanism);
+                }
+                if words.next().is_some() {
+                    redacted.push(b' ');
+                    redacted.extend_from_slice(MASK);
+                }
+            } else {
+                self.mask_mailboxes(content, &mut redacted);
+            }
+            redacted.extend_from_slice(&line[content.len()..]);
+        }
+        Cow::Owned(redacted)
+    }
+
+    /// Returns SASL responses of an AUTH exchange with every line masked,
+    /// e.g. `***\r\n`.
+    pub fn sasl_response(&self, data: &[u8]) -> Vec<u8> {
+        let mut redacted = Vec::with_capacity(data.len());
+        for line in data.split_inclusive(|&octet| octet == b'\n') {
+            let content = line.trim_end_with(|c| c == '\r' || c == '\n');
+            redacted.extend_from_slice(MASK);
+            redacted.extend_from_slice(&line[content.len()..]);
+        }
+        redacted
+    }
+
+    // Appends a line with mailboxes in angle brackets masked.
+    fn mask_mailboxes(&self, line: &[u8], redacted: &mut Vec<u8>) {
+        let mut rest = line;
+        while let Some(open) = rest.find_byte(b'<') {
+            let close = match rest[open..].find_byte(b'>') {
+                Some(close) => open + close + 1,
+                None => break,
+            };
+            redacted.extend_from_slice(&rest[..open]);
+            redacted.extend_from_slice(&self.mailbox(&rest[open..close]));
+            rest = &rest[close..];
+        }
+        redacted.extend_from_slice(rest);
+    }
+}
+
+// Indicates whether a given line is an AUTH command.
+fn is_auth(line: &[u8]) -> bool {
+    line.get(..5)
+        .is_some_and(|verb| verb.eq_ignore_ascii_case(b"AUTH "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_redact_mailboxes_and_credentials() {
+        let redaction = Redaction {
+            mask_local_parts: true,
+            max_body_bytes: Some(4),
+            mask_auth_identities: true,
+        };
+
+        assert_eq!(
+            redaction.mailbox(b"<user@example.org>").as_bstr(),
+            "<***@example.org>"
+        );
+        assert_eq!(redaction.mailbox(b"<>").as_bstr(), "<>");
+        assert_eq!(
+            redaction.identity(b"user@example.org").as_bstr(),
+            "***@example.org"
+        );
+        assert_eq!(redaction.identity(b"user").as_bstr(), "***");
+        assert_eq!(redaction.body(b"Subject: test").as_bstr(), "Subj");
+        assert_eq!(
+            redaction
+                .data(b"MAIL FROM:<a@example.org> SIZE=10\r\nauth plain AGFAYgBj\r\nAUTH LOGIN\r\n")
+                .as_bstr(),
+            "MAIL FROM:<***@example.org> SIZE=10\r\nauth plain ***\r\nAUTH LOGIN\r\n"
+        );
+        assert_eq!(
+            redaction.sasl_response(b"dXNlcg==\r\n").as_bstr(),
+            "***\r\n"
+        );
+        assert_eq!(
+            Redaction::default()
+                .data(b"RCPT TO:<b@example.org>\r\n")
+                .as_bstr(),
+            "RCPT TO:<b@example.org>\r\n"
+        );
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reputation of client addresses shared by all filter instances.
+
+use std::net::IpAddr;
+use std::time::{Duration, UNIX_EPOCH};
+
+use envoy::extension::Result;
+use envoy::host::{Clock, SharedData};
+use serde::Serialize;
+
+use crate::smtp::agent::Offense;
+
+// Number of attempts to update a record that is concurrently updated by other workers.
+const MAX_UPDATE_ATTEMPTS: usize = 3;
+
+/// Reputation represents recent misbehaviour of a client address.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default, Serialize)]
+pub struct Reputation {
+    /// Number of connections closed by the filter, e.g. on a policy decision.
+    pub rejects: u32,
+    /// Number of sessions with a protocol parsing error.
+    pub parse_errors: u32,
+    /// Number of failed authentication attempts.
+    pub auth_failures: u32,
+}
+
+impl Reputation {
+    /// Returns the total number of offenses.
+    pub fn offenses(&self) -> u32 {
+        self.rejects + self.parse_errors + self.auth_failures
+    }
+
+    pub fn on_reject(&mut self) {
+        self.rejects += 1
+    }
+
+    pub fn on_offense(&mut self, offense: Offense) {
+        match offense {
+            Offense::ParseError => self.parse_errors += 1,
+            Offense::AuthFailure => self.auth_failures += 1,
+        }
+    }
+}
+
+/// Store of client reputations in shared data.
+///
+/// A record is forgotten once it hasn't been updated for a given TTL.
+pub struct ReputationStore<'a> {
+    // Shared Data API implementation.
+    shared_data: &'a dyn SharedData,
+    // Clock API implementation.
+    clock: &'a dyn Clock,
+}
+
+impl<'a> ReputationStore<'a> {
+    pub fn new(shared_data: &'a dyn SharedData, clock: &'a dyn Clock) -> Self {
+        ReputationStore { shared_data, clock }
+    }
+
+    /// Returns reputation of a given address.
+    pub fn get(&self, address: IpAddr, ttl: Duration) -> Result<Reputation> {
+        let value = self.shared_data.get(&record_key(address))?.0;
+        self.parse(value.as_ref().map(|value| value.as_bytes()), ttl)
+    }
+
+    /// Updates reputation of a given address and returns the new one.
+    pub fn update<F>(&self, address: IpAddr, ttl: Duration, f: F) -> Result<Reputation>
+    where
+        F: Fn(&mut Reputation),
+    {
+        let key = record_key(address);
+        let mut attempt = 1;
+        loop {
+            let (value, version) = self.shared_data.get(&key)?;
+            let mut reputation = self.parse(value.as_ref().map(|value| value.as_bytes()), ttl)?;
+            f(&mut reputation);
+            // <rejects> <parse errors> <auth failures> <updated at>
+            let value = format!(
+                "{} {} {} {}",
+                reputation.rejects,
+                reputation.parse_errors,
+                reputation.auth_failures,
+                self.now_secs()?
+            );
+            match self.shared_data.set(&key, value.as_bytes(), version) {
+                Ok(()) => return Ok(reputation),
+                // the record has been updated concurrently
+                Err(_) if attempt < MAX_UPDATE_ATTEMPTS => attempt += 1,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn parse(&self, value: Option<&[u8]>, ttl: Duration) -> Result<Reputation> {
+        let value = match value {
+            Some(value) => String::from_utf8_lossy(value).into_owned(),
+            None => return Ok(Reputation::default()),
+        };
+        let fields: Vec<u64> = value
+            .split(' ')
+            .filter_map(|field| field.parse().ok())
+            .collect();
+        match fields[..] {
+            [rejects, parse_errors, auth_failures, updated_at]
+                if updated_at + ttl.as_secs() > self.now_secs()? =>
+            {
+                Ok(Reputation {
+                    rejects: rejects as u32,
+                    parse_errors: parse_errors as u32,
+                    auth_failures: auth_failures as u32,
+                })
+            }
+            _ => Ok(Reputation::default()),
+        }
+    }
+
+    fn now_secs(&self) -> Result<u64> {
+        Ok(self
+            .clock
+            .now()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs())
+    }
+}
+
+fn record_key(address: IpAddr) -> String {
+    format!("smtp.reputation.{}", address)
+}
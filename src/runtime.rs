@@ -0,0 +1,123 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runtime switches and other values read from shared data.
+//!
+//! Shared data is polled on ticks of the filter factory as well as whenever
+//! a new connection is open, at most once per poll interval.
+
+use std::time::{Duration, SystemTime};
+
+use envoy::extension::Result;
+use envoy::host::log::{self, LogLevel};
+use envoy::host::{Clock, SharedData};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+/// Runtime switches that override the filter configuration without a redeployment,
+/// e.g. `{"detailed_stats": true, "enforce": true, "log_level": "debug"}`.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RuntimeToggles {
+    /// Overrides `detailed_stats`.
+    pub detailed_stats: Option<bool>,
+    /// Switches actions on listed, idle and slow clients between observing and enforcing.
+    pub enforce: Option<bool>,
+    /// Overrides the maximum log level of the Wasm module.
+    pub log_level: Option<RuntimeLogLevel>,
+    /// Overrides `debug_sample_rate`, `0` disables sampling.
+    pub debug_sample_rate: Option<u32>,
+}
+
+/// Log level that can be set at runtime or picked for a kind of log lines.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuntimeLogLevel {
+    Trace,
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+impl From<RuntimeLogLevel> for LogLevel {
+    fn from(level: RuntimeLogLevel) -> Self {
+        match level {
+            RuntimeLogLevel::Trace => LogLevel::Trace,
+            RuntimeLogLevel::Debug => LogLevel::Debug,
+            RuntimeLogLevel::Info => LogLevel::Info,
+            RuntimeLogLevel::Warn => LogLevel::Warn,
+            RuntimeLogLevel::Error => LogLevel::Error,
+        }
+    }
+}
+
+/// Poller of a JSON value in shared data, e.g. runtime switches or policy lists.
+pub struct SharedDataPoller<'a, T> {
+    // Shared Data API implementation.
+    shared_data: &'a dyn SharedData,
+    // Clock API implementation.
+    clock: &'a dyn Clock,
+    // Time the value is due to be read again at.
+    next_poll_at: Option<SystemTime>,
+    // Value as of the latest poll, if any.
+    current: Option<T>,
+}
+
+impl<'a, T> SharedDataPoller<'a, T>
+where
+    T: DeserializeOwned + Eq,
+{
+    pub fn new(shared_data: &'a dyn SharedData, clock: &'a dyn Clock) -> Self {
+        SharedDataPoller {
+            shared_data,
+            clock,
+            next_poll_at: None,
+            current: None,
+        }
+    }
+
+    /// Returns the value as of the latest poll, if any.
+    pub fn current(&self) -> Option<&T> {
+        self.current.as_ref()
+    }
+
+    /// Reads the value under a given key if the poll interval has elapsed
+    /// and returns whether it has changed since the previous poll.
+    ///
+    /// Malformed values are logged and treated as if the key is not set.
+    pub fn poll(&mut self, key: &str, poll_interval: Duration) -> Result<bool> {
+        let now = self.clock.now()?;
+        if self
+            .next_poll_at
+            .is_some_and(|next_poll_at| now < next_poll_at)
+        {
+            return Ok(false);
+        }
+        self.next_poll_at = Some(now + poll_interval);
+        let value = self.shared_data.get(key)?.0.and_then(|value| {
+            serde_json::from_slice(value.as_bytes())
+                .map_err(|err| {
+                    log::warn!("ignoring malformed value of {}: {}: {}", key, value, err)
+                })
+                .ok()
+        });
+        if value == self.current {
+            return Ok(false);
+        }
+        self.current = value;
+        Ok(true)
+    }
+}
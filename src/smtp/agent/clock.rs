@@ -0,0 +1,125 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cell::Cell;
+use std::time::{Duration, SystemTime};
+
+/// Source of the current time of a session.
+///
+/// Sessions inside `Envoy` are told the time by the filter instead, see
+/// `Session::set_time`.
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+}
+
+/// Tells the time of the system.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Tells the time that only changes when told so, e.g. to test timing of
+/// mail transactions deterministically.
+#[derive(Debug)]
+pub struct FakeClock {
+    now: Cell<SystemTime>,
+}
+
+impl FakeClock {
+    pub fn new(now: SystemTime) -> Self {
+        FakeClock {
+            now: Cell::new(now),
+        }
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        self.now.set(now)
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration)
+    }
+}
+
+impl Default for FakeClock {
+    fn default() -> Self {
+        FakeClock::new(SystemTime::UNIX_EPOCH)
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> SystemTime {
+        self.now.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use anyhow::Result;
+
+    use super::*;
+    use crate::smtp::agent::{Session, SessionConfig, StatsSink};
+
+    #[derive(Default)]
+    struct TimingSink {
+        timing: Cell<Option<(Duration, Duration)>>,
+    }
+
+    impl StatsSink for Rc<TimingSink> {
+        fn on_smtp_transaction_timing(
+            &self,
+            duration: Duration,
+            data_duration: Duration,
+        ) -> Result<()> {
+            self.timing.set(Some((duration, data_duration)));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_time_transactions_by_clock() {
+        let clock = Rc::new(FakeClock::default());
+        let sink = Rc::new(TimingSink::default());
+        let mut session = Session::new(SessionConfig::default(), Rc::clone(&sink));
+        session.set_clock(clock.clone());
+        session.on_new_conection().unwrap();
+
+        let exchange: &[(&[u8], &[u8])] = &[
+            (b"", b"220 mail.example.org ESMTP\r\n"),
+            (b"HELO client.example.org\r\n", b"250 mail.example.org\r\n"),
+            (b"MAIL FROM:<alice@example.org>\r\n", b"250 Ok\r\n"),
+            (b"RCPT TO:<bob@example.com>\r\n", b"250 Ok\r\n"),
+            (b"DATA\r\n", b"354 Go ahead\r\n"),
+            (b"Subject: Hello\r\n\r\n.\r\n", b"250 Queued\r\n"),
+        ];
+        for (command, reply) in exchange {
+            clock.advance(Duration::from_secs(1));
+            if !command.is_empty() {
+                session.on_downstream_data(command.to_vec().into()).unwrap();
+            }
+            session.on_upstream_data(reply.to_vec().into()).unwrap();
+        }
+
+        assert_eq!(
+            sink.timing.get(),
+            Some((Duration::from_secs(3), Duration::from_secs(1)))
+        );
+    }
+}
@@ -13,80 +13,201 @@
 // limitations under the License.
 
 use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
 
-use bstr::ByteSlice;
-use envoy::extension::{Error, Result};
+use proxy_wasm::types::ByteString;
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
 
-use crate::smtp::spec::core::{
-    Data, Ehlo, Expn, Helo, Help, Mail, Noop, Quit, Rcpt, Rset, Vrfy, SP,
-};
+use super::registry::{CommandExtension, CommandRegistry};
+use crate::smtp::error::SmtpError;
+use crate::smtp::spec::core::{Data, Ehlo, Expn, Helo, Help, Mail, Noop, Quit, Rcpt, Rset, Vrfy};
+use crate::smtp::spec::extensions::auth::Auth;
+use crate::smtp::spec::extensions::burl::Burl;
+use crate::smtp::spec::extensions::chunking::Bdat;
 use crate::smtp::spec::extensions::starttls::StartTls;
+use crate::smtp::spec::legacy::Legacy;
+use crate::smtp::spec::line::CommandLine;
 use crate::smtp::spec::unknown::Unknown;
 
 /// Enumerates SMTP commands supported by this Mail Transfer Agent.
-#[derive(Debug)]
+///
+/// Commands are serialized along with their verb, e.g. `{"verb": "MAIL", "from": "FROM:<>"}`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "verb")]
 pub enum Command {
+    #[serde(rename = "HELO")]
     Helo(Helo),
+    #[serde(rename = "EHLO")]
     Ehlo(Ehlo),
+    #[serde(rename = "MAIL")]
     Mail(Mail),
+    #[serde(rename = "RCPT")]
     Rcpt(Rcpt),
+    #[serde(rename = "DATA")]
     Data(Data),
+    #[serde(rename = "BDAT")]
+    Bdat(Bdat),
+    #[serde(rename = "BURL")]
+    Burl(Burl),
+    #[serde(rename = "RSET")]
     Rset(Rset),
+    #[serde(rename = "VRFY")]
     Vrfy(Vrfy),
+    #[serde(rename = "EXPN")]
     Expn(Expn),
+    #[serde(rename = "HELP")]
     Help(Help),
+    #[serde(rename = "NOOP")]
     Noop(Noop),
+    #[serde(rename = "QUIT")]
     Quit(Quit),
+    #[serde(rename = "STARTTLS")]
     StartTls(StartTls),
+    #[serde(rename = "AUTH")]
+    Auth(Auth),
+    #[serde(untagged)]
+    Legacy(Legacy),
+    #[serde(untagged)]
+    Extension(ExtensionCommand),
+    #[serde(untagged)]
     Unknown(Unknown),
 }
 
 impl Command {
     pub fn verb(&self) -> &str {
+        self.line().verb()
+    }
+
+    // Returns the command as a line of a given type.
+    fn line(&self) -> &dyn CommandLine {
         match self {
-            Command::Helo(_) => Helo::VERB,
-            Command::Ehlo(_) => Ehlo::VERB,
-            Command::Mail(_) => Mail::VERB,
-            Command::Rcpt(_) => Rcpt::VERB,
-            Command::Data(_) => Data::VERB,
-            Command::Rset(_) => Rset::VERB,
-            Command::Vrfy(_) => Vrfy::VERB,
-            Command::Expn(_) => Expn::VERB,
-            Command::Help(_) => Help::VERB,
-            Command::Noop(_) => Noop::VERB,
-            Command::Quit(_) => Quit::VERB,
-            Command::StartTls(StartTls) => StartTls::VERB,
-            Command::Unknown(unknown) => &unknown.verb(),
+            Command::Helo(helo) => helo,
+            Command::Ehlo(ehlo) => ehlo,
+            Command::Mail(mail) => mail,
+            Command::Rcpt(rcpt) => rcpt,
+            Command::Data(data) => data,
+            Command::Bdat(bdat) => bdat,
+            Command::Burl(burl) => burl,
+            Command::Rset(rset) => rset,
+            Command::Vrfy(vrfy) => vrfy,
+            Command::Expn(expn) => expn,
+            Command::Help(help) => help,
+            Command::Noop(noop) => noop,
+            Command::Quit(quit) => quit,
+            Command::StartTls(starttls) => starttls,
+            Command::Auth(auth) => auth,
+            Command::Legacy(legacy) => legacy,
+            Command::Extension(extension) => extension,
+            Command::Unknown(unknown) => unknown,
         }
     }
 }
 
+/// Parses a command line without the trailing `CRLF` as one of the commands
+/// built into SMTP filter.
+///
+/// Creates a registry of the commands on every call, so lines of a session
+/// are rather parsed with `CommandRegistry::parse`.
 impl TryFrom<Vec<u8>> for Command {
-    type Error = Error;
-
-    fn try_from(line: Vec<u8>) -> Result<Self> {
-        let (verb, args) = match line.find(SP) {
-            Some(index) => (&line[0..index], &line[index + 1..]),
-            None => (&line[..], &line[0..0]),
-        };
-
-        let mut verb = String::from_utf8(verb.to_vec())?;
-        verb.make_ascii_uppercase();
-        let args = args.to_vec();
-        match verb.as_str() {
-            Helo::VERB => Helo::try_from(args).map(Command::Helo),
-            Ehlo::VERB => Ehlo::try_from(args).map(Command::Ehlo),
-            Mail::VERB => Mail::try_from(args).map(Command::Mail),
-            Rcpt::VERB => Rcpt::try_from(args).map(Command::Rcpt),
-            Data::VERB => Ok(Command::Data(Data)),
-            Rset::VERB => Ok(Command::Rset(Rset)),
-            Vrfy::VERB => Vrfy::try_from(args).map(Command::Vrfy),
-            Expn::VERB => Expn::try_from(args).map(Command::Expn),
-            Help::VERB => Help::try_from(args).map(Command::Help),
-            Noop::VERB => Noop::try_from(args).map(Command::Noop),
-            Quit::VERB => Ok(Command::Quit(Quit)),
-            StartTls::VERB => Ok(Command::StartTls(StartTls)),
-            _ => Unknown::try_from(line).map(Command::Unknown),
+    type Error = SmtpError;
+
+    fn try_from(line: Vec<u8>) -> Result<Self, Self::Error> {
+        CommandRegistry::default().parse(line)
+    }
+}
+
+impl CommandLine for Command {
+    fn verb(&self) -> &str {
+        Command::verb(self)
+    }
+
+    fn args(&self) -> &[u8] {
+        self.line().args()
+    }
+}
+
+/// Command is displayed in the form it is sent over the wire, without `CRLF`
+/// and with credentials of AUTH command masked, e.g. `MAIL FROM:<user@example.org>`.
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Helo(helo) => helo.fmt(f),
+            Command::Ehlo(ehlo) => ehlo.fmt(f),
+            Command::Mail(mail) => mail.fmt(f),
+            Command::Rcpt(rcpt) => rcpt.fmt(f),
+            Command::Data(data) => data.fmt(f),
+            Command::Bdat(bdat) => bdat.fmt(f),
+            Command::Burl(burl) => burl.fmt(f),
+            Command::Rset(rset) => rset.fmt(f),
+            Command::Vrfy(vrfy) => vrfy.fmt(f),
+            Command::Expn(expn) => expn.fmt(f),
+            Command::Help(help) => help.fmt(f),
+            Command::Noop(noop) => noop.fmt(f),
+            Command::Quit(quit) => quit.fmt(f),
+            Command::StartTls(starttls) => starttls.fmt(f),
+            Command::Auth(auth) => auth.fmt(f),
+            Command::Legacy(legacy) => legacy.fmt(f),
+            Command::Extension(extension) => extension.fmt(f),
+            Command::Unknown(unknown) => unknown.fmt(f),
+        }
+    }
+}
+
+/// Command registered as an extension of SMTP filter.
+pub struct ExtensionCommand {
+    extension: Rc<dyn CommandExtension>,
+    args: ByteString,
+}
+
+impl ExtensionCommand {
+    pub fn new(extension: Rc<dyn CommandExtension>, args: Vec<u8>) -> Self {
+        ExtensionCommand {
+            extension,
+            args: args.into(),
         }
     }
+
+    pub fn extension(&self) -> &dyn CommandExtension {
+        self.extension.as_ref()
+    }
+}
+
+impl CommandLine for ExtensionCommand {
+    fn verb(&self) -> &str {
+        self.extension.verb()
+    }
+
+    fn args(&self) -> &[u8] {
+        self.args.as_bytes()
+    }
+}
+
+impl fmt::Debug for ExtensionCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExtensionCommand")
+            .field("verb", &self.verb())
+            .field("args", &self.args)
+            .finish()
+    }
+}
+
+impl fmt::Display for ExtensionCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.args.is_empty() {
+            write!(f, "{}", self.verb())
+        } else {
+            write!(f, "{} {}", self.verb(), self.args)
+        }
+    }
+}
+
+/// Extension command is serialized with its verb only, like an unknown one.
+impl Serialize for ExtensionCommand {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut command = serializer.serialize_struct("ExtensionCommand", 1)?;
+        command.serialize_field("verb", self.verb())?;
+        command.end()
+    }
 }
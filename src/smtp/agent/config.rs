@@ -0,0 +1,242 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::rc::Rc;
+
+use bstr::ByteSlice;
+use serde::Deserialize;
+
+use super::rewrite::{EhloRewrite, RecipientRewrite, ReplyCodeRewrite};
+use crate::lists::PolicyLists;
+use crate::redact::Redaction;
+use crate::smtp::spec::core::CR_LF;
+
+/// Configuration of an SMTP session.
+#[derive(Clone, Debug, Default)]
+pub struct SessionConfig {
+    /// Indicates whether lines of a multi-line reply must carry the same reply code.
+    ///
+    /// Otherwise, a mismatch is counted and the code of the first line is used.
+    pub strict_reply_codes: bool,
+    /// Text to replace the greeting of SMTP server with, e.g. `mx.example.org ESMTP`.
+    pub greeting_banner: Option<String>,
+    /// Rewrite of the reply to EHLO command.
+    pub ehlo_rewrite: Option<EhloRewrite>,
+    /// Rewrites of upstream replies by code, applied in order until the first match.
+    pub reply_code_rewrites: Vec<ReplyCodeRewrite>,
+    /// Rewrite of recipients of RCPT commands.
+    pub recipient_rewrite: Option<RecipientRewrite>,
+    /// Whether TLS negotiated by STARTTLS command is handled by `Envoy`
+    /// so that the session remains in plaintext after the upgrade.
+    pub starttls_offload: bool,
+    /// Whether to forward attributes of SMTP client via XFORWARD command.
+    pub xforward: bool,
+    /// Whether envelope commands are subject to a policy decision.
+    pub envelope_checks: bool,
+    /// Whether messages are subject to a policy decision.
+    pub content_checks: bool,
+    /// Whether to report replies of SMTP server to commands as events.
+    pub command_events: bool,
+    /// Whether to report replies of SMTP server to mail transactions as events.
+    pub transaction_events: bool,
+    /// The only direction of the traffic that is visible, if any.
+    pub tap: Option<Tap>,
+    /// Actions to take on protocol parsing errors.
+    pub fallback: FallbackConfig,
+    /// Allow and deny lists of senders and recipients, if any.
+    pub lists: Option<Rc<PolicyLists>>,
+    /// Templates of replies sent in place of SMTP server.
+    pub local_replies: LocalReplies,
+    /// Number of AUTH exchanges failed with invalid credentials (`535`) after
+    /// which further AUTH commands of the session are turned down, if limited.
+    pub max_auth_failures: Option<u32>,
+    /// How strictly the protocol is enforced.
+    pub strictness: Strictness,
+    /// Log level of the session regardless of the log level of `Envoy`, if set.
+    pub log_level: Option<FilterLogLevel>,
+    /// Redaction of personal data and credentials in logs.
+    pub redaction: Redaction,
+    /// Name of the header to add to messages with the id of the mail transaction, if any.
+    pub trace_header: Option<String>,
+    /// Patterns of VERP senders, e.g. `bounce-*=*@lists.example.org`.
+    pub verp_patterns: Vec<String>,
+}
+
+impl SessionConfig {
+    /// Returns whether a sender mailbox matches any of VERP patterns.
+    pub fn is_verp_sender(&self, mailbox: &[u8]) -> bool {
+        self.verp_patterns
+            .iter()
+            .any(|pattern| matches_pattern(pattern.as_bytes(), mailbox))
+    }
+}
+
+// Matches a value against a pattern where `*` stands for any number of
+// characters, ignoring case.
+fn matches_pattern(pattern: &[u8], value: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => value.is_empty(),
+        Some((b'*', rest)) => (0..=value.len()).any(|at| matches_pattern(rest, &value[at..])),
+        Some((octet, rest)) => value.split_first().is_some_and(|(first, value)| {
+            first.eq_ignore_ascii_case(octet) && matches_pattern(rest, value)
+        }),
+    }
+}
+
+/// Log level of SMTP filter that overrides the log level of `Envoy` for its own logs.
+///
+/// Logs that are enabled by the level are logged at `info` level, the rest are dropped.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterLogLevel {
+    /// Only warnings, errors and notable events are logged.
+    Info,
+    /// Handling of commands and replies is logged as well.
+    Debug,
+    /// Raw data and reply lines are logged as well.
+    Trace,
+}
+
+/// Strictness represents how strictly the protocol is enforced, which bundles
+/// tolerance to line endings, length limits, command sequence validation and
+/// treatment of unknown commands.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strictness {
+    /// Command and reply lines longer than 512 octets, unknown commands,
+    /// envelope commands out of sequence and multi-line replies with inconsistent
+    /// reply codes are treated as protocol errors.
+    Strict,
+    /// Lines must end with `CRLF`, while anything that can be parsed is tolerated.
+    #[default]
+    Lenient,
+    /// As `lenient`, and command and reply lines may end with a bare `LF`.
+    Permissive,
+}
+
+impl Strictness {
+    /// Maximum length of a command or a reply line including `CRLF`, if limited.
+    pub fn max_line_length(self) -> Option<usize> {
+        match self {
+            Strictness::Strict => Some(MAX_LINE_LENGTH),
+            Strictness::Lenient | Strictness::Permissive => None,
+        }
+    }
+
+    /// Whether command and reply lines may end with a bare `LF`.
+    pub fn allows_bare_lf(self) -> bool {
+        self == Strictness::Permissive
+    }
+}
+
+// Maximum length of a command line and of a reply line as of RFC 5321, section 4.5.3.1.
+const MAX_LINE_LENGTH: usize = 512;
+
+/// Templates of replies sent to SMTP client in place of SMTP server, e.g.
+/// `550 5.7.1 Sender {sender} is not allowed`.
+///
+/// `{sender}` and `{recipient}` are replaced with the mailboxes of the envelope,
+/// `{limit}` with the limit that has been reached, e.g. `max_auth_failures`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct LocalReplies {
+    /// Reply to MAIL command while draining, `421 4.3.2 Service shutting down` by default.
+    pub drain: Option<String>,
+    /// Reply to MAIL command with a denied sender.
+    ///
+    /// The connection is closed instead if unset.
+    pub sender_denied: Option<String>,
+    /// Reply to RCPT command with a denied recipient.
+    ///
+    /// The connection is closed instead if unset.
+    pub recipient_denied: Option<String>,
+    /// Reply to AUTH command of a client that has failed to authenticate
+    /// too many times, e.g. `535 5.7.8 More than {limit} authentication failures`.
+    ///
+    /// The connection is closed instead if unset.
+    pub auth_throttled: Option<String>,
+}
+
+impl LocalReplies {
+    /// Renders a given template into a reply line.
+    pub fn render(template: &str, sender: &[u8], recipient: &[u8], limit: Option<u32>) -> Vec<u8> {
+        let limit = limit.map(|limit| limit.to_string()).unwrap_or_default();
+        let mut reply = template
+            .replace("{sender}", &sender.to_str_lossy())
+            .replace("{recipient}", &recipient.to_str_lossy())
+            .replace("{limit}", &limit)
+            .into_bytes();
+        reply.extend_from_slice(CR_LF);
+        reply
+    }
+}
+
+/// Actions to take on protocol parsing errors by their class.
+#[derive(Copy, Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct FallbackConfig {
+    /// Action on a command line that cannot be parsed.
+    pub command: FallbackAction,
+    /// Action on a reply line that cannot be parsed.
+    pub reply: FallbackAction,
+    /// Action on a reply that doesn't fit the session, e.g. one to no command.
+    pub sequence: FallbackAction,
+}
+
+impl FallbackConfig {
+    /// Returns the action to take on a given class of errors.
+    pub fn action(&self, class: ParseErrorClass) -> FallbackAction {
+        match class {
+            ParseErrorClass::Command => self.command,
+            ParseErrorClass::Reply => self.reply,
+            ParseErrorClass::Sequence => self.sequence,
+        }
+    }
+}
+
+/// Class of a protocol parsing error.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ParseErrorClass {
+    Command,
+    Reply,
+    Sequence,
+}
+
+/// Action to take on a protocol parsing error.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FallbackAction {
+    /// Stop interpreting the traffic and pass the rest of the connection through.
+    #[default]
+    PassThrough,
+    /// Close the connection.
+    Close,
+    /// Skip the offending line and keep interpreting the traffic best-effort.
+    Resync,
+}
+
+/// Tap represents the only direction of the traffic that is visible to the session,
+/// e.g. because the other one is mirrored elsewhere.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tap {
+    /// Only commands of SMTP client are visible.
+    ///
+    /// Commands are assumed to have been accepted by SMTP server.
+    Commands,
+    /// Only replies of SMTP server are visible.
+    ///
+    /// Replies other than the greeting are not correlated with commands.
+    Replies,
+}
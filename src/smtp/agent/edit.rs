@@ -0,0 +1,208 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Edit represents a replacement of a range of bytes within the latest chunk of data.
+#[derive(Debug)]
+pub struct Edit {
+    /// Offset of the range within the chunk.
+    pub start: usize,
+    /// Size of the range.
+    pub size: usize,
+    /// Replacement.
+    pub value: Vec<u8>,
+}
+
+impl Edit {
+    fn end(&self) -> usize {
+        self.start + self.size
+    }
+}
+
+/// Edits represents an ordered set of non-overlapping edits to a chunk of data.
+#[derive(Debug, Default)]
+pub struct Edits {
+    edits: Vec<Edit>,
+}
+
+impl Edits {
+    pub fn is_empty(&self) -> bool {
+        self.edits.is_empty()
+    }
+
+    /// Returns a copy of a given chunk of data with edits applied to it.
+    pub fn apply(&self, data: &[u8]) -> Vec<u8> {
+        let mut result = Vec::with_capacity(data.len());
+        let mut pos = 0;
+        for edit in &self.edits {
+            result.extend_from_slice(&data[pos..edit.start]);
+            result.extend_from_slice(&edit.value);
+            pos = edit.end();
+        }
+        result.extend_from_slice(&data[pos..]);
+        result
+    }
+
+    // Adds an edit while keeping the set ordered.
+    //
+    // An edit of the same range as an earlier one takes precedence over it,
+    // insertions at the same offset are kept in order.
+    fn add(&mut self, edit: Edit) -> bool {
+        let index = self
+            .edits
+            .iter()
+            .position(|other| {
+                other.start > edit.start || (other.start == edit.start && other.size >= edit.size)
+            })
+            .unwrap_or(self.edits.len());
+        if let Some(other) = self.edits.get_mut(index) {
+            if edit.size > 0 && other.start == edit.start && other.size == edit.size {
+                *other = edit;
+                return true;
+            }
+            if edit.size == 0 && other.start == edit.start && other.size == 0 {
+                let index = index
+                    + self.edits[index..]
+                        .iter()
+                        .take_while(|other| other.start == edit.start && other.size == 0)
+                        .count();
+                self.edits.insert(index, edit);
+                return true;
+            }
+            if other.start < edit.end() {
+                return false;
+            }
+        }
+        if index > 0 && self.edits[index - 1].end() > edit.start {
+            return false;
+        }
+        self.edits.insert(index, edit);
+        true
+    }
+}
+
+/// StreamEditor keeps track of a byte stream that is received in chunks
+/// and collects edits to the latest chunk.
+///
+/// Ranges are specified by their offsets within the byte stream.
+#[derive(Debug, Default)]
+pub struct StreamEditor {
+    // Offset of the first byte that hasn't been consumed yet.
+    offset: usize,
+    // Offset of the latest chunk of data.
+    chunk_offset: usize,
+    // Edits to the latest chunk of data.
+    edits: Edits,
+}
+
+impl StreamEditor {
+    /// Returns the offset of the first byte that hasn't been consumed yet.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Is called when a new chunk of data is received while `buffered`
+    /// bytes of earlier chunks haven't been consumed yet.
+    pub fn on_chunk(&mut self, buffered: usize) {
+        self.chunk_offset = self.offset + buffered;
+    }
+
+    /// Marks a given number of bytes as consumed.
+    pub fn consume(&mut self, size: usize) {
+        self.offset += size;
+    }
+
+    /// Replaces a range of bytes within the latest chunk with a given value.
+    ///
+    /// Returns `false` if the range doesn't lie within the latest chunk
+    /// or overlaps with another edit.
+    pub fn replace(&mut self, start: usize, end: usize, value: Vec<u8>) -> bool {
+        if start < self.chunk_offset {
+            log::warn!("cannot edit data that has been received in multiple chunks");
+            return false;
+        }
+        let edit = Edit {
+            start: start - self.chunk_offset,
+            size: end - start,
+            value,
+        };
+        if !self.edits.add(edit) {
+            log::warn!("cannot apply overlapping edits");
+            return false;
+        }
+        true
+    }
+
+    /// Inserts a given value at a given offset within the latest chunk.
+    pub fn insert(&mut self, at: usize, value: Vec<u8>) -> bool {
+        self.replace(at, at, value)
+    }
+
+    /// Removes a range of bytes within the latest chunk.
+    pub fn delete(&mut self, start: usize, end: usize) -> bool {
+        self.replace(start, end, Vec::new())
+    }
+
+    /// Inserts a given value in front of the latest chunk.
+    pub fn prepend(&mut self, value: Vec<u8>) -> bool {
+        self.insert(self.chunk_offset, value)
+    }
+
+    /// Returns edits to the latest chunk.
+    pub fn take_edits(&mut self) -> Edits {
+        std::mem::take(&mut self.edits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_apply_edits_in_order() {
+        let mut editor = StreamEditor::default();
+        editor.on_chunk(0);
+        assert!(editor.replace(5, 11, b"REPLACED".to_vec()));
+        assert!(editor.insert(0, b"FIRST ".to_vec()));
+        assert!(editor.replace(0, 5, b"HELLO".to_vec()));
+        assert!(editor.insert(0, b"SECOND ".to_vec()));
+        let edits = editor.take_edits();
+        assert_eq!(
+            edits.apply(b"hello_world!"),
+            b"FIRST SECOND HELLOREPLACED!".to_vec()
+        );
+        assert!(editor.take_edits().is_empty());
+    }
+
+    #[test]
+    fn should_reject_overlapping_edits() {
+        let mut editor = StreamEditor::default();
+        editor.on_chunk(0);
+        assert!(editor.replace(3, 8, b"x".to_vec()));
+        assert!(!editor.replace(0, 5, b"y".to_vec()));
+        assert!(!editor.insert(4, b"z".to_vec()));
+        assert!(editor.replace(3, 8, b"w".to_vec()));
+        assert_eq!(editor.take_edits().apply(b"0123456789"), b"012w89".to_vec());
+    }
+
+    #[test]
+    fn should_reject_edits_to_earlier_chunks() {
+        let mut editor = StreamEditor::default();
+        editor.on_chunk(0);
+        editor.consume(4);
+        editor.on_chunk(2);
+        assert!(!editor.delete(4, 7));
+        assert!(editor.delete(6, 7));
+        assert_eq!(editor.take_edits().apply(b"abc"), b"bc".to_vec());
+    }
+}
@@ -12,9 +12,30 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-pub use self::session::{Mode, Session};
-pub use self::stats::StatsSink;
+pub use self::clock::{Clock, FakeClock, SystemClock};
+pub use self::command::{Command, ExtensionCommand};
+pub use self::config::{
+    FallbackAction, FallbackConfig, FilterLogLevel, LocalReplies, SessionConfig, Strictness, Tap,
+};
+pub use self::observer::SmtpEventSink;
+pub use self::registry::{CommandExtension, CommandRegistry};
+pub use self::rewrite::{EhloRewrite, RecipientRewrite, ReplyCodeRewrite};
+pub use self::session::{
+    ClientCertificate, ContentCheck, EnvelopeCheck, Event, Handshake, Mode, Offense, Session,
+    SessionSummary, TransactionSummary,
+};
+pub use self::snapshot::{PendingReplySnapshot, SessionSnapshot};
+pub use self::stats::{CompositeSink, StatsSink};
+pub use self::transcript::{Direction, Transcript, TranscriptLine};
 
+mod clock;
 mod command;
+mod config;
+mod edit;
+mod observer;
+mod registry;
+mod rewrite;
 mod session;
+mod snapshot;
 mod stats;
+mod transcript;
@@ -0,0 +1,52 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::Result;
+
+use super::command::Command;
+use super::session::{Mode, SessionSummary, Transaction};
+use crate::smtp::spec::core::Reply;
+
+/// Observer of an SMTP session.
+///
+/// Unlike `StatsSink`, which is only told about the outcome of each step,
+/// observers receive parsed commands, replies and mail transactions as is.
+pub trait SmtpEventSink {
+    /// Is called once per command of SMTP client, before it is forwarded.
+    fn on_command(&self, _command: &Command) -> Result<()> {
+        Ok(())
+    }
+
+    /// Is called once per reply of SMTP server along with the command it
+    /// replies to, if any, e.g. it is `None` for the greeting or the reply
+    /// to a transaction commit.
+    fn on_reply(&self, _command: Option<&Command>, _reply: &Reply) -> Result<()> {
+        Ok(())
+    }
+
+    /// Is called once SMTP server has replied to the commit of a mail transaction.
+    fn on_transaction(&self, _transaction: &Transaction, _reply: &Reply) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_mode_change(&self, _from: Mode, _to: Mode) -> Result<()> {
+        Ok(())
+    }
+
+    /// Is called once the connection has been closed, even if the session
+    /// has fallen back into no-op mode.
+    fn on_close(&self, _summary: &SessionSummary) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use bstr::ByteSlice;
+
+use super::command::{Command, ExtensionCommand};
+use super::session::Mode;
+use crate::smtp::error::{Result, SmtpError};
+use crate::smtp::spec::core::{
+    Data, Ehlo, Expn, Helo, Help, Mail, Noop, Quit, Rcpt, Reply, Rset, Vrfy, SP,
+};
+use crate::smtp::spec::extensions::auth::Auth;
+use crate::smtp::spec::extensions::burl::Burl;
+use crate::smtp::spec::extensions::chunking::Bdat;
+use crate::smtp::spec::extensions::starttls::StartTls;
+use crate::smtp::spec::legacy::Legacy;
+use crate::smtp::spec::unknown::Unknown;
+
+/// Command that is not built into SMTP filter, e.g. a vendor X-command.
+pub trait CommandExtension {
+    /// Returns the verb of the command in upper case, e.g. `XCLIENT`.
+    fn verb(&self) -> &str;
+
+    /// Validates arguments of the command.
+    fn parse(&self, _args: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the mode the session is to switch to on a given reply to the command, if any.
+    ///
+    /// E.g. a command that starts a protocol SMTP filter doesn't understand
+    /// switches the session into `Mode::PassThrough` on a positive reply.
+    fn on_reply(&self, _args: &[u8], _reply: &Reply) -> Option<Mode> {
+        None
+    }
+}
+
+// Parses arguments of a command with a given verb.
+type Parser = Rc<dyn Fn(Vec<u8>) -> Result<Command>>;
+
+/// Registry of commands SMTP filter understands by their verbs.
+///
+/// Commands that are not registered are handled as unknown ones.
+#[derive(Clone)]
+pub struct CommandRegistry {
+    parsers: HashMap<String, Parser>,
+}
+
+impl CommandRegistry {
+    /// Registers a command extension.
+    ///
+    /// Replaces a command with the same verb, if any.
+    pub fn register(&mut self, extension: Rc<dyn CommandExtension>) {
+        let verb = extension.verb().to_ascii_uppercase();
+        self.parsers.insert(
+            verb,
+            Rc::new(move |args| {
+                extension.parse(&args)?;
+                Ok(Command::Extension(ExtensionCommand::new(
+                    Rc::clone(&extension),
+                    args,
+                )))
+            }),
+        );
+    }
+
+    /// Parses a command line without the trailing `CRLF`.
+    pub fn parse(&self, line: Vec<u8>) -> Result<Command> {
+        let (verb, args) = match line.find(SP) {
+            Some(index) => (&line[0..index], &line[index + 1..]),
+            None => (&line[..], &line[0..0]),
+        };
+        let verb = std::str::from_utf8(verb)
+            .map_err(|_| SmtpError::ParseCommand("command verb is not UTF-8".to_owned()))?
+            .to_ascii_uppercase();
+        match self.parsers.get(&verb) {
+            Some(parser) => parser(args.to_vec()),
+            None => Unknown::try_from(line).map(Command::Unknown),
+        }
+    }
+
+    fn insert(&mut self, verb: &str, parser: fn(Vec<u8>) -> Result<Command>) {
+        self.parsers.insert(verb.to_owned(), Rc::new(parser));
+    }
+}
+
+impl Default for CommandRegistry {
+    /// Returns a registry of commands built into SMTP filter.
+    fn default() -> Self {
+        let mut registry = CommandRegistry {
+            parsers: HashMap::new(),
+        };
+        registry.insert(Helo::VERB, |args| Helo::try_from(args).map(Command::Helo));
+        registry.insert(Ehlo::VERB, |args| Ehlo::try_from(args).map(Command::Ehlo));
+        registry.insert(Mail::VERB, |args| Mail::try_from(args).map(Command::Mail));
+        registry.insert(Rcpt::VERB, |args| Rcpt::try_from(args).map(Command::Rcpt));
+        registry.insert(Data::VERB, |_| Ok(Command::Data(Data)));
+        registry.insert(Bdat::VERB, |args| Bdat::try_from(args).map(Command::Bdat));
+        registry.insert(Burl::VERB, |args| Burl::try_from(args).map(Command::Burl));
+        registry.insert(Rset::VERB, |_| Ok(Command::Rset(Rset)));
+        registry.insert(Vrfy::VERB, |args| Vrfy::try_from(args).map(Command::Vrfy));
+        registry.insert(Expn::VERB, |args| Expn::try_from(args).map(Command::Expn));
+        registry.insert(Help::VERB, |args| Help::try_from(args).map(Command::Help));
+        registry.insert(Noop::VERB, |args| Noop::try_from(args).map(Command::Noop));
+        registry.insert(Quit::VERB, |_| Ok(Command::Quit(Quit)));
+        registry.insert(StartTls::VERB, |_| Ok(Command::StartTls(StartTls)));
+        registry.insert(Auth::VERB, |args| Auth::try_from(args).map(Command::Auth));
+        for &verb in Legacy::VERBS {
+            registry.parsers.insert(
+                verb.to_owned(),
+                Rc::new(move |args| Ok(Command::Legacy(Legacy::new(verb, args.into())))),
+            );
+        }
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smtp::spec::line::CommandLine;
+
+    struct Xclient;
+
+    impl CommandExtension for Xclient {
+        fn verb(&self) -> &str {
+            "XCLIENT"
+        }
+
+        fn parse(&self, args: &[u8]) -> Result<()> {
+            if args.is_empty() {
+                return Err(SmtpError::ParseCommand(
+                    "XCLIENT requires attributes".to_owned(),
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_parse_registered_commands() {
+        let mut registry = CommandRegistry::default();
+        assert!(matches!(
+            registry.parse(b"XCLIENT NAME=mx.example.org".to_vec()),
+            Ok(Command::Unknown(_))
+        ));
+
+        registry.register(Rc::new(Xclient));
+        let cmd = registry
+            .parse(b"xclient NAME=mx.example.org".to_vec())
+            .unwrap();
+        assert!(matches!(cmd, Command::Extension(_)));
+        assert_eq!(cmd.args(), b"NAME=mx.example.org");
+        assert!(registry.parse(b"XCLIENT".to_vec()).is_err());
+        assert!(matches!(
+            registry.parse(b"mail FROM:<user@example.org>".to_vec()),
+            Ok(Command::Mail(_))
+        ));
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+
+use bstr::ByteSlice;
+use serde::Deserialize;
+
+use crate::smtp::spec::core::{Capability, Reply, ReplyCode, ReplyLine};
+
+/// Rewrite of the reply to EHLO command that narrows the set of
+/// service extensions advertised to the client.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct EhloRewrite {
+    /// Service extensions to hide from the client, e.g. `["VRFY", "EXPN"]`.
+    pub hidden_capabilities: Vec<String>,
+    /// SASL mechanisms to hide from the client, e.g. `["LOGIN", "PLAIN"]`.
+    ///
+    /// `AUTH` is hidden altogether once no mechanisms are left.
+    pub hidden_auth_mechanisms: Vec<String>,
+    /// Maximum message size to advertise to the client via `SIZE`.
+    pub max_size: Option<u64>,
+}
+
+impl EhloRewrite {
+    /// Returns a rewritten reply or `None` if the reply doesn't need to be changed.
+    pub fn apply(&self, reply: &Reply) -> Option<Reply> {
+        let capabilities = Capability::from_reply(reply);
+        let rewritten: Vec<Capability> = capabilities
+            .iter()
+            .filter_map(|capability| self.apply_to(capability))
+            .collect();
+        let unchanged = rewritten.len() == capabilities.len()
+            && rewritten
+                .iter()
+                .zip(capabilities.iter())
+                .all(|(a, b)| a.to_string() == b.to_string());
+        if unchanged {
+            return None;
+        }
+        let first = reply.lines().first()?.clone();
+        let mut result = Reply::new(first);
+        for capability in rewritten {
+            result.append(ReplyLine::new(reply.code(), capability.to_string().into()));
+        }
+        Some(result)
+    }
+
+    fn apply_to(&self, capability: &Capability) -> Option<Capability> {
+        if contains_ignore_case(&self.hidden_capabilities, capability.keyword()) {
+            return None;
+        }
+        let mut capability = capability.clone();
+        match capability.keyword() {
+            "AUTH" if !self.hidden_auth_mechanisms.is_empty() => {
+                let hidden = &self.hidden_auth_mechanisms;
+                capability
+                    .params_mut()
+                    .retain(|mechanism| !contains_ignore_case(hidden, mechanism));
+                if capability.params().is_empty() {
+                    return None;
+                }
+            }
+            "SIZE" => {
+                if let Some(max_size) = self.max_size {
+                    let size = capability
+                        .params()
+                        .first()
+                        .and_then(|size| size.parse::<u64>().ok())
+                        .filter(|size| *size != 0 && *size <= max_size)
+                        .unwrap_or(max_size);
+                    *capability.params_mut() = vec![size.to_string()];
+                }
+            }
+            _ => {}
+        }
+        Some(capability)
+    }
+}
+
+fn contains_ignore_case(values: &[String], value: &str) -> bool {
+    values.iter().any(|v| v.eq_ignore_ascii_case(value))
+}
+
+/// Rewrite of upstream replies with a given code, e.g. to turn backend `421`
+/// overload replies into `451` with a branded message.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReplyCodeRewrite {
+    /// Name of the rule to use in metric names.
+    pub name: String,
+    /// Code of upstream replies to rewrite, e.g. `421`.
+    pub code: ReplyCode,
+    /// Code to send to the client instead, e.g. `451`.
+    pub replacement_code: ReplyCode,
+    /// Text to send to the client instead.
+    ///
+    /// Text of the upstream reply is preserved if not set.
+    #[serde(default)]
+    pub text: Option<String>,
+}
+
+impl ReplyCodeRewrite {
+    /// Returns a rewritten reply or `None` if the rule doesn't apply to the reply.
+    pub fn apply(&self, reply: &Reply) -> Option<Reply> {
+        if reply.code() != self.code {
+            return None;
+        }
+        let mut lines: Vec<ReplyLine> = match self.text.as_ref() {
+            Some(text) => vec![ReplyLine::new(self.replacement_code, text.as_str().into())],
+            None => reply
+                .lines()
+                .iter()
+                .map(|line| ReplyLine::new(self.replacement_code, line.text().clone()))
+                .collect(),
+        };
+        let mut result = Reply::new(lines.remove(0));
+        for line in lines {
+            result.append(line);
+        }
+        Some(result)
+    }
+}
+
+/// Rewrite of recipients of RCPT commands before they reach SMTP server.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RecipientRewrite {
+    /// Aliases of recipient mailboxes, e.g. `{"info@example.org": "support@example.org"}`.
+    pub aliases: HashMap<String, String>,
+    /// Rewrites of recipient domains, e.g. `{"example.net": "example.org"}`.
+    ///
+    /// Only applies to recipients that don't have an alias.
+    pub domains: HashMap<String, String>,
+}
+
+impl RecipientRewrite {
+    /// Returns a rewritten mailbox or `None` if the mailbox doesn't need to be changed.
+    pub fn apply(&self, mailbox: &[u8]) -> Option<Vec<u8>> {
+        let mailbox = mailbox.to_str().ok()?;
+        if let Some(alias) = find_ignore_case(&self.aliases, mailbox) {
+            return Some(alias.as_bytes().to_vec());
+        }
+        let at = mailbox.rfind('@')?;
+        let (local_part, domain) = (&mailbox[..at], &mailbox[at + 1..]);
+        let domain = find_ignore_case(&self.domains, domain)?;
+        Some(format!("{}@{}", local_part, domain).into_bytes())
+    }
+}
+
+fn find_ignore_case<'a>(values: &'a HashMap<String, String>, key: &str) -> Option<&'a String> {
+    values
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(key))
+        .map(|(_, v)| v)
+}
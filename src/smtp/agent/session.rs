@@ -14,35 +14,146 @@
 
 use std::collections::VecDeque;
 use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
 
+use anyhow::{Error, Result};
 use bstr::{ByteSlice, ByteVec};
-use envoy::error::format_err;
-use envoy::extension::{Error, Result};
-use envoy::host::log;
-use envoy::host::ByteString;
+use proxy_wasm::types::ByteString;
+use serde::Serialize;
 
-use super::command::Command;
+use super::clock::Clock;
+use super::command::{Command, ExtensionCommand};
+use super::config::{
+    FallbackAction, FilterLogLevel, LocalReplies, ParseErrorClass, SessionConfig, Strictness, Tap,
+};
+use super::edit::{Edits, StreamEditor};
+use super::observer::SmtpEventSink;
+use super::registry::CommandRegistry;
+use super::snapshot::{PendingReplySnapshot, SessionSnapshot};
 use super::stats::StatsSink;
+use crate::lists::ListVerdict;
+use crate::smtp::error::SmtpError;
+use crate::smtp::ser;
 use crate::smtp::spec::core::{
-    Data, Ehlo, Expn, Helo, Help, Mail, Noop, Quit, Rcpt, Reply, ReplyLine, Rset, Vrfy, CR_LF,
+    Capability, Data, Ehlo, Expn, Greeting, Helo, Help, Mail, Noop, Quit, Rcpt, Reply, ReplyCode,
+    ReplyLine, ReplyType, Rset, Vrfy, CR_LF,
 };
+use crate::smtp::spec::extensions::auth::{Auth, SaslIdentity};
+use crate::smtp::spec::extensions::burl::Burl;
+use crate::smtp::spec::extensions::chunking::Bdat;
+use crate::smtp::spec::extensions::dsn;
 use crate::smtp::spec::extensions::starttls::StartTls;
+use crate::smtp::spec::extensions::xforward::Xforward;
+use crate::smtp::spec::legacy::Legacy;
+use crate::smtp::spec::line::CommandLine;
 use crate::smtp::spec::unknown::Unknown;
 
 /// Session represents a single SMTP session.
 pub struct Session<S: StatsSink> {
+    config: SessionConfig,
+    // Commands the session understands.
+    commands: Rc<CommandRegistry>,
+
     downstream_buffer: Vec<u8>,
+    // Edits to the downstream byte stream.
+    downstream_editor: StreamEditor,
+    // Whether downstream data is being held back until a verdict is made.
+    downstream_held: bool,
+    // Whether an incomplete command line is being held back until the rest of it is received.
+    partial_command_held: bool,
+    // Envelope commands that are subject to a policy decision.
+    envelope_checks: Vec<EnvelopeCheck>,
+    // Messages that are subject to a policy decision.
+    content_checks: Vec<ContentCheck>,
+    // Misbehaviour of SMTP client that affects its reputation.
+    offenses: Vec<Offense>,
+    // Replies of SMTP server to commands and mail transactions.
+    events: Vec<Event>,
+    // Outcome of the session so far.
+    summary: SessionSummary,
+    // Totals of the session for the summary on close.
+    totals: SessionTotals,
+    // Commands to send to SMTP server ahead of the next chunk of downstream data.
+    downstream_injections: Vec<(&'static str, Vec<u8>)>,
     upstream_buffer: Vec<u8>,
+    // Edits to the upstream byte stream.
+    upstream_editor: StreamEditor,
 
     mode: Mode,
 
+    // Offset of the next command within the downstream byte stream.
+    next_command_offset: usize,
     next_reply: Option<Reply>,
+    // Offset of the next reply within the upstream byte stream.
+    next_reply_offset: usize,
     next_body: Vec<u8>,
 
     pending_replies: VecDeque<PendingReply>,
+    // Recipients as sent by SMTP client, one per pending RCPT command,
+    // if they have been rewritten.
+    original_recipients: VecDeque<Option<ByteString>>,
     active_transaction: Option<Transaction>,
+    // AUTH command SMTP server has challenged, i.e. the next line of SMTP client
+    // is a SASL response rather than a command.
+    sasl_exchange: Option<Auth>,
+    // Identity SMTP client has claimed in the ongoing AUTH exchange, if known.
+    sasl_identity: Option<SaslIdentity>,
+    // Number of responses SMTP client has sent in the ongoing AUTH exchange,
+    // including the initial one.
+    sasl_responses: usize,
+    // Number of AUTH exchanges SMTP server has turned down over the session.
+    auth_failures: u32,
+    // Whether further AUTH commands are turned down regardless of the session,
+    // e.g. since the client address has failed too many times.
+    auth_throttled: bool,
+    // BDAT command whose chunk is being received, i.e. the next octets of SMTP
+    // client are message content rather than commands.
+    chunk: Option<Bdat>,
+    // Number of octets of the chunk that are yet to be received.
+    chunk_remaining: usize,
+    // Message SMTP client is sending in BDAT chunks.
+    chunked_message: Option<ChunkedMessage>,
+    // Messages SMTP client has completed with BDAT LAST command, one per
+    // pending reply to the command.
+    chunked_messages: VecDeque<ChunkedMessage>,
+    // Time of the latest chunk of data, which mail transactions are stamped with.
+    now: Option<SystemTime>,
+    // Source of the current time, if it is not set from outside.
+    clock: Option<Rc<dyn Clock>>,
+    // Id of the connection, e.g. `174a3c1b2e0-2`.
+    connection_id: String,
+    // Number of mail transactions started over the session.
+    started_transactions: usize,
+
+    client_address: Option<SocketAddr>,
+    client_certificate: Option<ClientCertificate>,
+    greeting: Option<Greeting>,
+    handshake: Option<Handshake>,
+    ehlo_rejected: bool,
+    quit: bool,
+    // Whether the traffic in each direction has been recognized as SMTP.
+    downstream_sniffed: bool,
+    upstream_sniffed: bool,
+    closed: bool,
+    // Whether new mail transactions should be turned down.
+    draining: bool,
+    // Whether the connection should be closed, e.g. due to a parsing error.
+    close_requested: bool,
+    // Envelope commands SMTP client has sent so far, regardless of replies,
+    // for the sake of sequence validation.
+    sent_handshake: bool,
+    sent_mail: bool,
+    sent_rcpt: bool,
+    // Whether the message of the latest MAIL command is declared binary.
+    sent_binarymime: bool,
+    // Whether the session has been sampled for full debug logging.
+    debug_logging: bool,
 
     stats_sink: S,
+    // Observers of parsed commands, replies and mail transactions.
+    event_sinks: Vec<Rc<dyn SmtpEventSink>>,
 }
 
 /// PendingReply represents a pending reply from SMTP server
@@ -54,21 +165,216 @@ pub enum PendingReply {
     /// Pending reply to an SMTP command.
     Command(Command),
     /// Pending reply to a mail transaction commit.
-    Commit(Transaction),
+    Commit(Box<Transaction>),
+    /// Pending reply to QUIT command that has replaced MAIL command
+    /// while draining.
+    ///
+    /// Such replies are replaced by `421` reply.
+    Drain,
+    /// Pending reply to NOOP command that has replaced a rejected command.
+    ///
+    /// Such replies are replaced by a local one.
+    Rejected(&'static str, Vec<u8>),
+    /// Pending reply to a command line that couldn't be parsed.
+    Unparsed,
+    /// Pending reply to a command injected by the filter itself.
+    ///
+    /// Such replies are not forwarded to SMTP client.
+    Injected(&'static str),
+}
+
+/// EnvelopeCheck represents an envelope command that is subject to a policy decision.
+#[derive(Debug)]
+pub struct EnvelopeCheck {
+    /// Verb of the command, i.e. `MAIL` or `RCPT`.
+    pub verb: &'static str,
+    /// Mailbox of the sender or the recipient.
+    pub mailbox: ByteString,
+    /// Mailbox of the responsible submitter of MAIL command, if any.
+    pub submitter: Option<ByteString>,
+}
+
+/// ContentCheck represents a message that is subject to a policy decision.
+#[derive(Debug)]
+pub struct ContentCheck {
+    /// Message without the terminating `.` line and with dot-stuffing undone.
+    pub message: ByteString,
+}
+
+/// ClientCertificate represents attributes of the certificate SMTP client
+/// has presented to `Envoy` terminating TLS.
+#[derive(Clone, Eq, PartialEq, Debug, Default, Serialize)]
+pub struct ClientCertificate {
+    /// Subject of the certificate.
+    pub subject: Option<String>,
+    /// URI SAN of the certificate.
+    pub uri_san: Option<String>,
+    /// DNS SAN of the certificate.
+    pub dns_san: Option<String>,
+}
+
+/// Offense represents a misbehaviour of SMTP client.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Offense {
+    /// SMTP client has sent data that cannot be parsed.
+    ParseError,
+    /// SMTP client has failed to authenticate.
+    AuthFailure,
+}
+
+/// SessionSummary represents the outcome of an SMTP session so far.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct SessionSummary {
+    /// Reverse path of the latest mail transaction accepted by SMTP server.
+    pub mail_from: Option<ByteString>,
+    /// Forward paths of the latest mail transaction accepted by SMTP server.
+    pub rcpt_to: Vec<ByteString>,
+    /// Number of recipients accepted by SMTP server.
+    pub rcpt_count: usize,
+    /// Number of messages accepted by SMTP server.
+    pub messages: usize,
+    /// Code of the latest reply of SMTP server.
+    pub last_reply_code: Option<ReplyCode>,
+    /// Id of the latest mail transaction.
+    pub transaction_id: Option<String>,
+    /// Time the latest mail transaction has started at.
+    pub transaction_started_at: Option<SystemTime>,
+    /// Whether SMTP client has completed a successful AUTH exchange.
+    pub authenticated: bool,
+    /// Identity SMTP client has authenticated as, if it could be extracted,
+    /// i.e. for `PLAIN` and `LOGIN` mechanisms.
+    pub auth_identity: Option<SaslIdentity>,
+    /// Responsible submitter of the latest mail transaction accepted by SMTP server,
+    /// as declared by `SUBMITTER` parameter of MAIL command.
+    pub submitter: Option<ByteString>,
+}
+
+/// SessionTotals represents counts of what has happened over an SMTP session,
+/// which unlike [`SessionSummary`] are not exported as they change.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct SessionTotals {
+    /// Number of commands sent by SMTP client.
+    pub commands: usize,
+    /// Number of messages sent by SMTP client.
+    pub transactions: usize,
+    /// Number of protocol parsing errors.
+    pub parse_errors: usize,
+}
+
+/// Event represents a reply of SMTP server worth reporting outside of the filter.
+#[derive(Debug)]
+pub enum Event {
+    /// SMTP server has replied to a command.
+    CommandReply { verb: String, code: ReplyCode },
+    /// SMTP server has replied to a mail transaction commit.
+    Transaction(TransactionSummary),
+}
+
+/// TransactionSummary represents a mail transaction SMTP server has replied to.
+#[derive(Clone, Debug, Serialize)]
+pub struct TransactionSummary {
+    /// Id of the transaction, unique within the Wasm VM.
+    pub id: String,
+    /// Sender mailbox of the transaction, `<>` for the null sender.
+    #[serde(serialize_with = "ser::lossy")]
+    pub from: ByteString,
+    /// Recipient mailboxes of the transaction as sent to SMTP server.
+    #[serde(serialize_with = "ser::lossy_seq")]
+    pub to: Vec<ByteString>,
+    /// Size of the mail data in bytes as sent over the wire.
+    pub size: usize,
+    /// Code of the reply to the transaction commit.
+    pub reply_code: ReplyCode,
+    /// Whether the transaction has followed a successful AUTH exchange.
+    pub authenticated: bool,
+    /// Identity SMTP client has authenticated as, if known.
+    #[serde(serialize_with = "ser::lossy_opt")]
+    pub auth_identity: Option<ByteString>,
+    /// Responsible submitter of the message as declared by MAIL command, if any.
+    #[serde(serialize_with = "ser::lossy_opt")]
+    pub submitter: Option<ByteString>,
+    /// Time SMTP server has accepted MAIL command at.
+    #[serde(rename = "started_at_ms", serialize_with = "ser::unix_millis")]
+    pub started_at: Option<SystemTime>,
+    /// Time SMTP server has accepted DATA command at.
+    #[serde(rename = "data_started_at_ms", serialize_with = "ser::unix_millis")]
+    pub data_started_at: Option<SystemTime>,
+    /// Time SMTP server has replied to the transaction commit at.
+    #[serde(rename = "committed_at_ms", serialize_with = "ser::unix_millis")]
+    pub committed_at: Option<SystemTime>,
+}
+
+impl TransactionSummary {
+    /// Returns the time between acceptance of MAIL command and the reply to
+    /// the transaction commit.
+    pub fn duration(&self) -> Option<Duration> {
+        let (committed_at, started_at) = self.committed_at.zip(self.started_at)?;
+        committed_at.duration_since(started_at).ok()
+    }
 }
 
 /// Transaction represents a single mail transaction.
-#[derive(Debug, Default)]
+///
+/// The mail data is never serialized.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
 pub struct Transaction {
+    id: String,
+    #[serde(rename = "started_at_ms", serialize_with = "ser::unix_millis")]
+    started_at: Option<SystemTime>,
+    #[serde(rename = "data_started_at_ms", serialize_with = "ser::unix_millis")]
+    data_started_at: Option<SystemTime>,
+    client_address: Option<SocketAddr>,
+    client_certificate: Option<ClientCertificate>,
+    #[serde(serialize_with = "ser::lossy")]
     from: ByteString,
-    to: Vec<ByteString>,
+    to: Vec<Recipient>,
+    authenticated: bool,
+    #[serde(serialize_with = "ser::lossy_opt")]
+    auth_identity: Option<ByteString>,
+    #[serde(serialize_with = "ser::lossy_opt")]
+    submitter: Option<ByteString>,
+    #[serde(skip)]
+    null_sender: bool,
+    #[serde(skip)]
     body: ByteString,
 }
 
+/// Message sent in BDAT chunks, which is committed to the mail transaction
+/// once SMTP server has replied to the last chunk.
+#[derive(Debug, Default)]
+struct ChunkedMessage {
+    body: Vec<u8>,
+    started_at: Option<SystemTime>,
+}
+
+/// Recipient represents a single recipient of a mail transaction.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Recipient {
+    /// Mailbox of the recipient as forwarded to SMTP server.
+    #[serde(serialize_with = "ser::lossy")]
+    to: ByteString,
+    /// Mailbox of the recipient as sent by SMTP client if it has been rewritten.
+    #[serde(serialize_with = "ser::lossy_opt")]
+    original_to: Option<ByteString>,
+    /// Domain of the recipient as forwarded to SMTP server.
+    #[serde(serialize_with = "ser::lossy_opt")]
+    domain: Option<ByteString>,
+}
+
+/// Handshake represents a command an SMTP client has identified itself with.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Serialize)]
+pub enum Handshake {
+    /// Legacy handshake, i.e. without support for service extensions.
+    Helo,
+    /// Extended handshake.
+    Ehlo,
+}
+
 /// Mode represents a mode the SMTP session is currently in.
-#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default, Serialize)]
 pub enum Mode {
     /// Mode in which an SMTP client is expected to wait for a reply to connect.
+    #[default]
     Connect,
     /// Mode in which an SMTP client is expected to send SMTP commands.
     Command,
@@ -79,72 +385,540 @@ pub enum Mode {
     PassThrough,
 }
 
-impl Default for Mode {
-    fn default() -> Self {
-        Mode::Connect
-    }
-}
-
 impl<S> Session<S>
 where
     S: StatsSink,
 {
-    pub fn new(stats_sink: S) -> Self {
+    pub fn new(config: SessionConfig, stats_sink: S) -> Self {
         Session {
+            config,
+            commands: Rc::new(CommandRegistry::default()),
             downstream_buffer: Vec::<u8>::new(),
+            downstream_editor: StreamEditor::default(),
+            downstream_held: false,
+            partial_command_held: false,
+            envelope_checks: Vec::new(),
+            content_checks: Vec::new(),
+            offenses: Vec::new(),
+            events: Vec::new(),
+            summary: SessionSummary::default(),
+            totals: SessionTotals::default(),
+            downstream_injections: Vec::new(),
             upstream_buffer: Vec::<u8>::new(),
+            upstream_editor: StreamEditor::default(),
             mode: Mode::Connect,
+            next_command_offset: 0,
             next_reply: None,
+            next_reply_offset: 0,
             next_body: Vec::<u8>::new(),
             pending_replies: VecDeque::<PendingReply>::new(),
+            original_recipients: VecDeque::new(),
             active_transaction: None,
+            sasl_exchange: None,
+            sasl_identity: None,
+            sasl_responses: 0,
+            auth_failures: 0,
+            auth_throttled: false,
+            chunk: None,
+            chunk_remaining: 0,
+            chunked_message: None,
+            chunked_messages: VecDeque::new(),
+            now: None,
+            clock: None,
+            connection_id: String::new(),
+            started_transactions: 0,
+            client_address: None,
+            client_certificate: None,
+            greeting: None,
+            handshake: None,
+            ehlo_rejected: false,
+            quit: false,
+            downstream_sniffed: false,
+            upstream_sniffed: false,
+            closed: false,
+            draining: false,
+            close_requested: false,
+            sent_handshake: false,
+            sent_mail: false,
+            sent_rcpt: false,
+            sent_binarymime: false,
+            debug_logging: false,
             stats_sink,
+            event_sinks: Vec::new(),
+        }
+    }
+
+    /// Replaces the configuration of the session.
+    ///
+    /// Must be called before any data has been observed.
+    pub fn reconfigure(&mut self, config: SessionConfig) {
+        self.config = config
+    }
+
+    /// Returns externally visible state of the session.
+    pub fn snapshot(&self) -> SessionSnapshot {
+        SessionSnapshot {
+            mode: self.mode,
+            handshake: self.handshake,
+            pending_replies: self
+                .pending_replies
+                .iter()
+                .map(|pending| match pending {
+                    PendingReply::Connect => PendingReplySnapshot::Connect,
+                    PendingReply::Command(cmd) => {
+                        let mut line = cmd.to_bytes();
+                        line.truncate(line.len() - CR_LF.len());
+                        PendingReplySnapshot::Command { line }
+                    }
+                    PendingReply::Commit(tx) => PendingReplySnapshot::Commit {
+                        transaction: tx.clone(),
+                    },
+                    PendingReply::Drain => PendingReplySnapshot::Drain,
+                    PendingReply::Rejected(verb, reply) => PendingReplySnapshot::Rejected {
+                        verb,
+                        reply: reply.clone(),
+                    },
+                    PendingReply::Unparsed => PendingReplySnapshot::Unparsed,
+                    PendingReply::Injected(verb) => PendingReplySnapshot::Injected { verb },
+                })
+                .collect(),
+            transaction: self.active_transaction.clone(),
+        }
+    }
+
+    /// Restores the state of the session from a given snapshot.
+    ///
+    /// Must be called before any data has been observed.
+    pub fn restore(&mut self, snapshot: SessionSnapshot) -> Result<()> {
+        let mut pending_replies = VecDeque::with_capacity(snapshot.pending_replies.len());
+        for pending in snapshot.pending_replies {
+            pending_replies.push_back(match pending {
+                PendingReplySnapshot::Connect => PendingReply::Connect,
+                PendingReplySnapshot::Command { line } => {
+                    let cmd = self.commands.parse(line)?;
+                    if let Command::Rcpt(_) = cmd {
+                        self.original_recipients.push_back(None);
+                    }
+                    PendingReply::Command(cmd)
+                }
+                PendingReplySnapshot::Commit { transaction } => PendingReply::Commit(transaction),
+                PendingReplySnapshot::Drain => PendingReply::Drain,
+                PendingReplySnapshot::Rejected { verb, reply } => {
+                    PendingReply::Rejected(verb, reply)
+                }
+                PendingReplySnapshot::Unparsed => PendingReply::Unparsed,
+                PendingReplySnapshot::Injected { verb } => PendingReply::Injected(verb),
+            });
+        }
+        self.mode = snapshot.mode;
+        self.handshake = snapshot.handshake;
+        self.sent_handshake = snapshot.handshake.is_some();
+        self.sent_mail = snapshot.transaction.is_some();
+        self.sent_rcpt = snapshot
+            .transaction
+            .as_ref()
+            .is_some_and(|tx| !tx.to.is_empty());
+        self.pending_replies = pending_replies;
+        self.active_transaction = snapshot.transaction;
+        Ok(())
+    }
+
+    /// Promotes debug and trace logs of the session to `info` level, e.g. for
+    /// a sampled connection.
+    pub fn enable_debug_logging(&mut self) {
+        self.debug_logging = true
+    }
+
+    /// Adds an observer of commands, replies and mail transactions of the session.
+    pub fn add_event_sink(&mut self, event_sink: Rc<dyn SmtpEventSink>) {
+        self.event_sinks.push(event_sink)
+    }
+
+    // Switches the session into a given mode and lets observers know.
+    fn set_mode(&mut self, mode: Mode) -> Result<()> {
+        if self.mode == mode {
+            return Ok(());
+        }
+        let from = std::mem::replace(&mut self.mode, mode);
+        self.notify(|sink| sink.on_mode_change(from, mode))
+    }
+
+    fn notify<F>(&self, event: F) -> Result<()>
+    where
+        F: Fn(&dyn SmtpEventSink) -> Result<()>,
+    {
+        self.event_sinks
+            .iter()
+            .try_for_each(|sink| event(sink.as_ref()))
+    }
+
+    /// Replaces the built-in commands the session understands with a given registry.
+    pub fn set_command_registry(&mut self, commands: Rc<CommandRegistry>) {
+        self.commands = commands
+    }
+
+    // Returns the log level of the session that overrides the one of `Envoy`, if any.
+    fn log_level(&self) -> Option<FilterLogLevel> {
+        if self.debug_logging {
+            Some(FilterLogLevel::Trace)
+        } else {
+            self.config.log_level
         }
     }
 
+    /// Replaces the configuration of the session in the middle of it.
+    ///
+    /// The way the traffic is observed, i.e. `tap`, is kept as is.
+    pub fn update_config(&mut self, config: SessionConfig) {
+        let tap = self.config.tap;
+        self.config = SessionConfig { tap, ..config };
+    }
+
     pub fn mode(&self) -> Mode {
         self.mode
     }
 
+    /// Returns whether the next line of SMTP client is a SASL response
+    /// to a challenge of SMTP server rather than a command.
+    pub fn awaits_sasl_response(&self) -> bool {
+        self.sasl_exchange.is_some()
+    }
+
+    /// Returns the address of SMTP client, if known.
+    pub fn client_address(&self) -> Option<SocketAddr> {
+        self.client_address
+    }
+
+    /// Returns the certificate of SMTP client, if it has presented one.
+    pub fn client_certificate(&self) -> Option<&ClientCertificate> {
+        self.client_certificate.as_ref()
+    }
+
+    /// Returns the greeting SMTP server has replied with upon connect.
+    pub fn greeting(&self) -> Option<&Greeting> {
+        self.greeting.as_ref()
+    }
+
+    /// Sets the address of SMTP client.
+    pub fn set_client_address(&mut self, address: SocketAddr) {
+        self.client_address = Some(address)
+    }
+
+    /// Sets the certificate of SMTP client.
+    pub fn set_client_certificate(&mut self, certificate: ClientCertificate) {
+        self.client_certificate = Some(certificate)
+    }
+
+    /// Returns `true` once, after the session has given up on the connection
+    /// in a way that should close it, e.g. due to a parsing error.
+    pub fn take_close_request(&mut self) -> bool {
+        std::mem::take(&mut self.close_requested)
+    }
+
+    /// Makes the session turn down new mail transactions with `421` reply
+    /// and close the connection, e.g. upon shutdown.
+    pub fn drain(&mut self) {
+        self.draining = true
+    }
+
+    /// Makes the session turn down further AUTH commands, e.g. once the client
+    /// address has failed to authenticate too many times.
+    pub fn throttle_auth(&mut self) {
+        self.auth_throttled = true
+    }
+
+    /// Gives up on the mail transaction in progress, drops buffered data and
+    /// stops interpreting the traffic.
+    pub fn abandon(&mut self, reason: &str) -> Result<()> {
+        if self.mode == Mode::PassThrough {
+            return Ok(());
+        }
+        self.set_mode(Mode::PassThrough)?;
+        self.downstream_buffer = Vec::new();
+        self.next_body = Vec::new();
+        if let Some(tx) = self.active_transaction.take() {
+            self.abort_transaction(tx, reason)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of bytes of an incomplete command line received so far.
+    pub fn partial_command_size(&self) -> usize {
+        match self.mode {
+            Mode::Connect | Mode::Command => self.downstream_buffer.len(),
+            Mode::Data | Mode::PassThrough => 0,
+        }
+    }
+
+    /// Returns `true` if downstream data is being held back until a verdict is made.
+    pub fn is_downstream_held(&self) -> bool {
+        self.downstream_held
+    }
+
+    /// Holds back the latest command along with any downstream data after it.
+    pub fn hold_downstream(&mut self) {
+        self.downstream_held = true
+    }
+
+    /// Releases downstream data that is being held back.
+    pub fn release_downstream(&mut self) {
+        self.downstream_held = false
+    }
+
+    /// Holds back an incomplete command line, if any, so that the command
+    /// can still be rewritten as a whole once the rest of it is received.
+    ///
+    /// Returns the number of bytes being held back.
+    pub fn hold_partial_command(&mut self) -> usize {
+        let size = match self.config.tap {
+            Some(_) => 0, // commands are never rewritten
+            None => self.partial_command_size(),
+        };
+        self.partial_command_held = size > 0;
+        size
+    }
+
+    /// Lets an incomplete command line through, e.g. at the end of the stream.
+    pub fn release_partial_command(&mut self) {
+        self.partial_command_held = false
+    }
+
+    pub fn stats_sink(&self) -> &S {
+        &self.stats_sink
+    }
+
+    pub fn stats_sink_mut(&mut self) -> &mut S {
+        &mut self.stats_sink
+    }
+
+    /// Sets the id of the connection that ids of mail transactions are derived from.
+    ///
+    /// Must be called before any data has been observed.
+    pub fn set_connection_id(&mut self, connection_id: String) {
+        self.connection_id = connection_id
+    }
+
+    /// Sets the time the latest chunk of data has been received at, which
+    /// mail transactions are stamped with.
+    pub fn set_time(&mut self, now: SystemTime) {
+        self.now = Some(now)
+    }
+
+    /// Makes the session read the time from a given clock upon every chunk
+    /// of data instead of waiting for `set_time`.
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.clock = Some(clock)
+    }
+
+    // Reads the time from the clock of the session, if any.
+    fn tick(&mut self) {
+        if let Some(clock) = self.clock.as_ref() {
+            self.now = Some(clock.now());
+        }
+    }
+
     pub fn on_new_conection(&mut self) -> Result<()> {
+        self.tick();
         self.stats_sink.on_smtp_connect()?;
-        self.pending_replies.push_back(PendingReply::Connect);
+        if self.config.tap == Some(Tap::Commands) {
+            // the greeting will never be seen
+            self.set_mode(Mode::Command)?;
+        } else {
+            self.pending_replies.push_back(PendingReply::Connect);
+        }
         Ok(())
     }
 
     pub fn on_downstream_data(&mut self, new_data: ByteString) -> Result<()> {
+        self.tick();
+        if self.config.tap == Some(Tap::Replies) {
+            return Ok(()); // commands are not visible
+        }
         match self.mode {
             Mode::Connect | Mode::Command | Mode::Data => {
+                // an incomplete command line that has been held back is still subject to edits
+                let buffered = if self.partial_command_held {
+                    0
+                } else {
+                    self.downstream_buffer.len()
+                };
+                self.downstream_editor.on_chunk(buffered);
                 self.downstream_buffer.extend(new_data.into_bytes());
             }
             Mode::PassThrough => return Ok(()), // don't even append new data to the buffer
         }
+        if self.mode == Mode::Connect && is_tls_client_hello(&self.downstream_buffer) {
+            filter_debug!(
+                self.log_level(),
+                "falling back into no-op mode due to implicit TLS"
+            );
+            self.stats_sink.on_smtp_implicit_tls()?;
+            self.set_mode(Mode::PassThrough)?;
+            return Ok(());
+        }
+        if !self.downstream_sniffed {
+            let bare_lf = self.config.strictness.allows_bare_lf();
+            match looks_like_command(&self.downstream_buffer, bare_lf) {
+                Some(true) => self.downstream_sniffed = true,
+                Some(false) => return self.bail_out("client"),
+                None => {} // wait for more data
+            }
+        }
+        self.inject_commands();
         loop {
             let mode = self.mode;
             match mode {
                 Mode::Connect | Mode::Command => {
+                    if let Some(bdat) = self.chunk.take() {
+                        if !self.next_chunk() {
+                            self.chunk = Some(bdat);
+                            return Ok(()); // wait for the rest of the chunk
+                        }
+                        if bdat.is_last() {
+                            self.complete_chunked_message()?;
+                        }
+                        if self.config.tap != Some(Tap::Commands) {
+                            self.pending_replies
+                                .push_back(PendingReply::Command(Command::Bdat(bdat)));
+                        }
+                        continue; // to the next command
+                    }
+                    if let Some(auth) = self.sasl_exchange.take() {
+                        if !self.next_sasl_response(&auth) {
+                            self.sasl_exchange = Some(auth);
+                            return Ok(()); // wait for a complete response
+                        }
+                        self.pending_replies
+                            .push_back(PendingReply::Command(Command::Auth(auth)));
+                        continue; // to the next command
+                    }
                     match self.next_command() {
                         Ok(Some(cmd)) => {
                             self.stats_sink.on_smtp_command(cmd.verb())?;
-                            self.pending_replies.push_back(PendingReply::Command(cmd));
+                            self.notify(|sink| sink.on_command(&cmd))?;
+                            self.totals.commands += 1;
+                            if let Err(err) = self.check_sequence(&cmd) {
+                                if !self.fallback(err, ParseErrorClass::Sequence)? {
+                                    return Ok(());
+                                }
+                            }
+                            if matches!(cmd, Command::Mail(_) | Command::Rset(_)) {
+                                // chunks of an abandoned message are never committed
+                                self.chunked_message = None;
+                            }
+                            if self.draining
+                                && matches!(cmd, Command::Mail(_))
+                                && self.drain_transaction()?
+                            {
+                                continue; // to the next command
+                            }
+                            let mut original_to = None;
+                            let cmd = match cmd {
+                                Command::Quit(_) => {
+                                    self.quit = true;
+                                    cmd
+                                }
+                                Command::Mail(mail) => {
+                                    if mail
+                                        .mailbox()
+                                        .is_some_and(|mailbox| self.config.is_verp_sender(mailbox))
+                                    {
+                                        self.stats_sink.on_smtp_verp_sender()?;
+                                    }
+                                    if !self.check_envelope(
+                                        Mail::VERB,
+                                        mail.mailbox(),
+                                        mail.submitter(),
+                                    )? {
+                                        continue; // to the next command
+                                    }
+                                    Command::Mail(mail)
+                                }
+                                Command::Auth(auth) => {
+                                    if self.is_auth_throttled() {
+                                        self.turn_down_auth(&auth)?;
+                                        continue; // to the next command
+                                    }
+                                    let response = auth.initial_response();
+                                    self.sasl_identity =
+                                        response.and_then(|response| auth.identity(response));
+                                    self.sasl_responses = response.map_or(0, |_| 1);
+                                    Command::Auth(auth)
+                                }
+                                Command::Legacy(legacy) => {
+                                    self.stats_sink.on_smtp_legacy_command(legacy.verb())?;
+                                    Command::Legacy(legacy)
+                                }
+                                Command::Burl(burl) => {
+                                    self.stats_sink.on_smtp_burl()?;
+                                    self.start_chunked_message();
+                                    if burl.is_last() {
+                                        self.complete_chunked_message()?;
+                                    }
+                                    Command::Burl(burl)
+                                }
+                                Command::Bdat(bdat) => {
+                                    self.start_chunk(&bdat);
+                                    self.chunk_remaining = bdat.size();
+                                    self.chunk = Some(bdat);
+                                    continue; // to the chunk
+                                }
+                                Command::Rcpt(rcpt) => {
+                                    let (rcpt, original) = self.rewrite_recipient(rcpt)?;
+                                    if rcpt.is_postmaster() {
+                                        // postmaster must be reachable regardless of policy
+                                        self.stats_sink.on_smtp_postmaster_recipient()?;
+                                    } else if !self.check_envelope(
+                                        Rcpt::VERB,
+                                        rcpt.mailbox(),
+                                        None,
+                                    )? {
+                                        continue; // to the next command
+                                    }
+                                    original_to = Some(original);
+                                    Command::Rcpt(rcpt)
+                                }
+                                _ => cmd,
+                            };
+                            if self.config.tap == Some(Tap::Commands) {
+                                self.assume_accepted(&cmd, original_to.flatten())?;
+                            } else {
+                                if let Some(original_to) = original_to {
+                                    // only recipients that reach SMTP server get a reply
+                                    self.original_recipients.push_back(original_to);
+                                }
+                                self.pending_replies.push_back(PendingReply::Command(cmd));
+                            }
                             continue; // to the next command
                         }
                         Ok(None) => return Ok(()), // wait for a complete command
-                        Err(err) => return self.fallback(err),
+                        Err(err) => {
+                            if self.fallback(err, ParseErrorClass::Command)? {
+                                continue; // to the next command
+                            }
+                            return Ok(());
+                        }
                     }
                 }
                 Mode::Data => {
                     match self.next_body() {
                         Some(body) => {
-                            self.active_transaction
-                                .get_or_insert_with(Default::default)
-                                .body = body.into();
-                            if let Some(tx) = self.active_transaction.take() {
-                                log::debug!("committing transaction: {:?}", tx);
-                                self.pending_replies.push_back(PendingReply::Commit(tx));
+                            if self.config.content_checks {
+                                self.content_checks.push(ContentCheck {
+                                    message: Data::message(&body).into(),
+                                });
+                            }
+                            self.transaction().body = body.into();
+                            if let Some(mut tx) = self.active_transaction.take() {
+                                self.prepare_commit(&mut tx);
+                                if self.config.tap != Some(Tap::Commands) {
+                                    self.pending_replies
+                                        .push_back(PendingReply::Commit(Box::new(tx)));
+                                }
                             }
                             self.stats_sink.on_smtp_transaction_commit()?;
-                            self.mode = Mode::Command;
+                            self.totals.transactions += 1;
+                            self.set_mode(Mode::Command)?;
                             continue; // to the next command
                         }
                         None => return Ok(()), // wait until body is complete
@@ -156,12 +930,24 @@ where
     }
 
     pub fn on_upstream_data(&mut self, new_data: ByteString) -> Result<()> {
+        self.tick();
+        if self.config.tap == Some(Tap::Commands) {
+            return Ok(()); // replies are not visible
+        }
         match self.mode {
             Mode::Connect | Mode::Command | Mode::Data => {
+                self.upstream_editor.on_chunk(self.upstream_buffer.len());
                 self.upstream_buffer.extend(new_data.into_bytes());
             }
             Mode::PassThrough => return Ok(()), // don't append new data to the buffer
         }
+        if !self.upstream_sniffed {
+            match looks_like_reply(&self.upstream_buffer) {
+                Some(true) => self.upstream_sniffed = true,
+                Some(false) => return self.bail_out("server"),
+                None => {} // wait for more data
+            }
+        }
         loop {
             let mode = self.mode;
             match mode {
@@ -169,10 +955,20 @@ where
                     match self.next_reply() {
                         Ok(Some(reply)) => match self.handle_reply(reply) {
                             Ok(()) => continue, // to the next reply
-                            Err(err) => return self.fallback(err),
+                            Err(err) => {
+                                if self.fallback(err, ParseErrorClass::Sequence)? {
+                                    continue; // to the next reply
+                                }
+                                return Ok(());
+                            }
                         },
                         Ok(None) => return Ok(()), // wait for a complete reply
-                        Err(err) => return self.fallback(err),
+                        Err(err) => {
+                            if self.fallback(err, ParseErrorClass::Reply)? {
+                                continue; // to the next reply
+                            }
+                            return Ok(());
+                        }
                     }
                 }
                 Mode::PassThrough => return Ok(()), // do nothing
@@ -180,196 +976,1361 @@ where
         }
     }
 
-    fn reset(&mut self) {
-        self.active_transaction = None
-    }
-
-    fn fallback(&mut self, err: Error) -> Result<()> {
-        log::error!(
-            "falling back into no-op mode due to a protocol parsing error: {}",
-            err
-        );
-        self.stats_sink.on_smtp_parse_error()?;
-        self.mode = Mode::PassThrough;
-        Ok(())
-    }
-
-    fn next_command(&mut self) -> Result<Option<Command>> {
-        match next_line(&mut self.downstream_buffer) {
-            Some(line) => Command::try_from(line).map(Option::from),
-            None => Ok(None),
+    /// Is called once SMTP client has closed its side of the connection.
+    pub fn on_downstream_end_of_stream(&mut self) -> Result<()> {
+        self.tick();
+        if self.mode == Mode::PassThrough {
+            return Ok(());
         }
-    }
-
-    fn next_body(&mut self) -> Option<Vec<u8>> {
-        loop {
-            match next_line(&mut self.downstream_buffer) {
-                Some(line) => {
-                    let end = !self.next_body.is_empty() && line == b"."; // <CR><LF>.<CR><LF>
-                    self.next_body.extend(line);
-                    self.next_body.push_str(CR_LF);
-                    if end {
-                        return Some(self.next_body.drain(..).collect());
-                    }
-                    continue; // to the next line
+        if !self.downstream_buffer.is_empty() {
+            // SMTP server is not obliged to reply to an unterminated line
+            let line: Vec<u8> = self.downstream_buffer.drain(..).collect();
+            match self.commands.parse(line.clone()) {
+                Ok(cmd) if self.mode != Mode::Data && self.sasl_exchange.is_none() => {
+                    self.stats_sink.on_smtp_command(cmd.verb())?;
+                    self.notify(|sink| sink.on_command(&cmd))?;
+                    self.totals.commands += 1;
                 }
-                None => return None,
+                _ if self.sasl_exchange.is_some() => filter_debug!(
+                    self.log_level(),
+                    "[{}] client has closed the connection amid an AUTH exchange",
+                    peer(self.client_address)
+                ),
+                _ => filter_debug!(
+                    self.log_level(),
+                    "[{}] client has closed the connection after an unterminated line: {:?}",
+                    peer(self.client_address),
+                    self.config.redaction.data(&line).as_bstr()
+                ),
+            }
+        }
+        if self.chunk.take().is_some() {
+            self.chunked_message = None;
+            if let Some(tx) = self.active_transaction.take() {
+                self.abort_transaction(tx, "client closed the connection")?;
+            }
+        }
+        if self.mode == Mode::Data {
+            self.next_body.clear();
+            self.set_mode(Mode::Command)?;
+            if let Some(tx) = self.active_transaction.take() {
+                self.abort_transaction(tx, "client closed the connection")?;
             }
         }
+        Ok(())
     }
 
-    fn next_reply(&mut self) -> Result<Option<Reply>> {
-        loop {
-            match next_line(&mut self.upstream_buffer) {
-                Some(next) => {
-                    log::debug!("next reply line: {}", next.as_bstr());
-                    let line = ReplyLine::try_from(next)?;
-                    let end_line = line.is_end_line();
-                    if let Some(reply) = self.next_reply.as_mut() {
-                        reply.append(line);
-                    } else {
-                        self.next_reply = Some(Reply::new(line));
-                    }
-                    if end_line {
-                        return Ok(self.next_reply.take());
-                    }
-                }
-                None => return Ok(None),
+    /// Is called once SMTP server has closed its side of the connection.
+    ///
+    /// Replies that are still pending will never be received.
+    pub fn on_upstream_end_of_stream(&mut self) -> Result<()> {
+        self.tick();
+        if self.mode == Mode::PassThrough {
+            return Ok(());
+        }
+        if !self.upstream_buffer.is_empty() {
+            filter_debug!(
+                self.log_level(),
+                "[{}] server has closed the connection after an incomplete reply: {:?}",
+                peer(self.client_address),
+                self.config.redaction.data(&self.upstream_buffer).as_bstr()
+            );
+            self.upstream_buffer.clear();
+            self.next_reply = None;
+        }
+        let pending: Vec<_> = self.pending_replies.drain(..).collect();
+        for pending in pending {
+            if let PendingReply::Commit(tx) = pending {
+                self.abort_transaction(*tx, "server closed the connection")?;
             }
         }
+        Ok(())
     }
 
-    fn handle_reply(&mut self, reply: Reply) -> Result<()> {
-        match self.pending_replies.pop_front() {
-            Some(pending) => {
-                use PendingReply::*;
-                match pending {
-                    Connect => {
-                        self.stats_sink.on_smtp_connect_reply(reply.code())?;
-                        self.mode = Mode::Command;
-                        Ok(())
-                    }
-                    Command(cmd) => {
-                        self.stats_sink
-                            .on_smtp_command_reply(cmd.verb(), reply.code())?;
-                        cmd.handle_reply(self, reply)?;
-                        Ok(())
-                    }
-                    Commit(_) => {
-                        self.stats_sink
-                            .on_smtp_transaction_commit_reply(reply.code())?;
-                        Ok(())
-                    }
-                }
-            }
-            None => Err(format_err!(
-                "received a reply while no command is pending: {:?}",
-                reply
-            )),
+    /// Is called once the connection has been closed.
+    ///
+    /// Subsequent calls have no effect.
+    pub fn on_connection_closed(&mut self) -> Result<()> {
+        self.tick();
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        match self.mode {
+            // session state is no longer reliable
+            Mode::PassThrough => {}
+            Mode::Connect | Mode::Command | Mode::Data => self.close_session()?,
         }
+        self.notify(|sink| sink.on_close(&self.summary))?;
+        self.stats_sink.on_smtp_session_end(&self.summary)
     }
-}
 
-fn next_line(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
-    match buffer.find(CR_LF) {
-        Some(index) => {
-            let line: Vec<u8> = buffer.drain(0..index).collect();
-            buffer.drain(0..CR_LF.len());
-            Some(line)
+    fn close_session(&mut self) -> Result<()> {
+        let pending_commits = self
+            .pending_replies
+            .drain(..)
+            .filter_map(|pending| match pending {
+                PendingReply::Commit(tx) => Some(*tx),
+                _ => None,
+            });
+        let aborted: Vec<_> = self
+            .active_transaction
+            .take()
+            .into_iter()
+            .chain(pending_commits)
+            .collect();
+        for tx in aborted {
+            self.abort_transaction(tx, "connection closed")?;
         }
-        None => None,
+        self.stats_sink.on_smtp_connection_close(self.quit)
     }
-}
 
-trait ReplyHandler {
-    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()>;
-}
+    /// Returns envelope commands of the latest chunk of downstream data
+    /// that are subject to a policy decision.
+    pub fn take_envelope_checks(&mut self) -> Vec<EnvelopeCheck> {
+        self.envelope_checks.drain(..).collect()
+    }
 
-impl ReplyHandler for Command {
-    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
-        use Command::*;
-        match self {
-            Helo(helo) => helo.handle_reply(session, reply),
-            Ehlo(ehlo) => ehlo.handle_reply(session, reply),
-            Mail(mail) => mail.handle_reply(session, reply),
-            Rcpt(rcpt) => rcpt.handle_reply(session, reply),
-            Data(data) => data.handle_reply(session, reply),
-            Rset(rset) => rset.handle_reply(session, reply),
-            Vrfy(vrfy) => vrfy.handle_reply(session, reply),
-            Expn(expn) => expn.handle_reply(session, reply),
-            Help(help) => help.handle_reply(session, reply),
-            Noop(noop) => noop.handle_reply(session, reply),
-            Quit(quit) => quit.handle_reply(session, reply),
-            StartTls(stls) => stls.handle_reply(session, reply),
-            Unknown(unknown) => unknown.handle_reply(session, reply),
-        }
+    /// Returns messages of the latest chunk of downstream data
+    /// that are subject to a policy decision.
+    pub fn take_content_checks(&mut self) -> Vec<ContentCheck> {
+        self.content_checks.drain(..).collect()
     }
-}
 
-impl ReplyHandler for Helo {
-    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
-        log::debug!("handling reply to {}: {:?}", Self::VERB, reply);
-        if reply.code().response_type().is_positive() {
-            session.reset();
-        }
-        Ok(())
+    /// Returns offenses of SMTP client since the last call.
+    pub fn take_offenses(&mut self) -> Vec<Offense> {
+        self.offenses.drain(..).collect()
     }
-}
 
-impl ReplyHandler for Ehlo {
-    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
-        log::debug!("handling reply to {}: {:?}", Self::VERB, reply);
-        if reply.code().response_type().is_positive() {
-            session.reset();
-        }
-        Ok(())
+    pub fn summary(&self) -> &SessionSummary {
+        &self.summary
     }
-}
 
-impl ReplyHandler for Mail {
-    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
-        log::debug!("handling reply to {}: {:?}", Self::VERB, reply);
-        if reply.code().response_type().is_positive() {
-            session
-                .active_transaction
-                .get_or_insert_with(Default::default)
-                .from = self.from().clone();
-        }
-        Ok(())
+    pub fn totals(&self) -> &SessionTotals {
+        &self.totals
     }
-}
 
-impl ReplyHandler for Rcpt {
+    /// Returns events since the last call.
+    pub fn take_events(&mut self) -> Vec<Event> {
+        self.events.drain(..).collect()
+    }
+
+    // Returns the active mail transaction, starting a new one if there is none.
+    fn transaction(&mut self) -> &mut Transaction {
+        if self.active_transaction.is_none() {
+            self.started_transactions += 1;
+            let id = format!("{}.{}", self.connection_id, self.started_transactions);
+            self.summary.transaction_id = Some(id.clone());
+            self.summary.transaction_started_at = self.now;
+            self.active_transaction = Some(Transaction {
+                id,
+                started_at: self.now,
+                authenticated: self.summary.authenticated,
+                auth_identity: self
+                    .summary
+                    .auth_identity
+                    .as_ref()
+                    .map(|identity| identity.authentication.clone()),
+                ..Default::default()
+            });
+        }
+        self.active_transaction.get_or_insert_with(Default::default)
+    }
+
+    /// Returns edits to the latest chunk of downstream data.
+    pub fn take_downstream_edits(&mut self) -> Edits {
+        self.downstream_editor.take_edits()
+    }
+
+    /// Returns edits to the latest chunk of upstream data.
+    pub fn take_upstream_edits(&mut self) -> Edits {
+        self.upstream_editor.take_edits()
+    }
+
+    // Replaces the latest command with a given one.
+    //
+    // Returns `false` if the command cannot be rewritten.
+    fn rewrite_command(&mut self, replacement: Vec<u8>) -> bool {
+        let end = self.downstream_editor.offset();
+        self.downstream_editor
+            .replace(self.next_command_offset, end, replacement)
+    }
+
+    // Looks up the mailbox of an envelope command in policy lists and schedules
+    // a policy decision on the command unless it is listed.
+    //
+    // Returns `false` if the command has been turned down.
+    fn check_envelope(
+        &mut self,
+        verb: &'static str,
+        mailbox: Option<&[u8]>,
+        submitter: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let mailbox = mailbox.unwrap_or_default();
+        let verdict = self
+            .config
+            .lists
+            .as_ref()
+            .and_then(|lists| lists.verdict(verb, mailbox));
+        match verdict {
+            Some(ListVerdict::Deny) => {
+                self.deny_envelope(verb, mailbox)?;
+                return Ok(false);
+            }
+            Some(ListVerdict::Allow) => return Ok(true),
+            None => {}
+        }
+        if self.config.envelope_checks {
+            self.envelope_checks.push(EnvelopeCheck {
+                verb,
+                mailbox: mailbox.into(),
+                submitter: submitter.map(ByteString::from),
+            });
+        }
+        Ok(true)
+    }
+
+    // Turns down an envelope command with a denied mailbox by replacing it with
+    // NOOP command and its reply with a local one, or gives up on the connection
+    // if there is no local reply to send.
+    fn deny_envelope(&mut self, verb: &'static str, mailbox: &[u8]) -> Result<()> {
+        log::info!(
+            "[{}] {} mailbox is on a deny list: {}",
+            peer(self.client_address),
+            verb,
+            self.config.redaction.mailbox(mailbox).as_bstr()
+        );
+        self.stats_sink.on_smtp_list_denied(verb)?;
+        let (template, sender, recipient) = if verb == Mail::VERB {
+            (
+                self.config.local_replies.sender_denied.as_ref(),
+                mailbox,
+                &b""[..],
+            )
+        } else {
+            let sender = self
+                .active_transaction
+                .as_ref()
+                .map(|tx| tx.from.as_bytes())
+                .unwrap_or_default();
+            (
+                self.config.local_replies.recipient_denied.as_ref(),
+                sender,
+                mailbox,
+            )
+        };
+        let reply =
+            template.map(|template| LocalReplies::render(template, sender, recipient, None));
+        self.reject_command(verb, reply)
+    }
+
+    fn is_auth_throttled(&self) -> bool {
+        self.auth_throttled
+            || self
+                .config
+                .max_auth_failures
+                .is_some_and(|max_failures| self.auth_failures >= max_failures)
+    }
+
+    // Turns down AUTH command of a client that has failed too many times by
+    // replacing it with NOOP command and its reply with a local one, or gives up
+    // on the connection if there is no local reply to send.
+    fn turn_down_auth(&mut self, auth: &Auth) -> Result<()> {
+        log::info!(
+            "[{}] throttling {} authentication after {} failures",
+            peer(self.client_address),
+            auth.mechanism(),
+            self.auth_failures
+        );
+        self.stats_sink.on_smtp_auth_throttled()?;
+        let reply = self
+            .config
+            .local_replies
+            .auth_throttled
+            .as_ref()
+            .map(|template| {
+                LocalReplies::render(template, b"", b"", self.config.max_auth_failures)
+            });
+        self.reject_command(Auth::VERB, reply)
+    }
+
+    // Replaces the latest command with NOOP command and its reply with a given
+    // local one, or gives up on the connection if there is no local reply to send
+    // or the command cannot be replaced.
+    fn reject_command(&mut self, verb: &'static str, reply: Option<Vec<u8>>) -> Result<()> {
+        let reply = match reply {
+            // replies are not under control with a tap
+            Some(reply) if self.config.tap.is_none() => reply,
+            _ => {
+                self.set_mode(Mode::PassThrough)?;
+                self.close_requested = true;
+                return Ok(());
+            }
+        };
+        let mut noop = Noop::VERB.as_bytes().to_vec();
+        noop.extend_from_slice(CR_LF);
+        if !self.rewrite_command(noop) {
+            log::warn!(
+                "[{}] cannot turn down {} command, closing the connection",
+                peer(self.client_address),
+                verb
+            );
+            self.stats_sink.on_smtp_local_reply_fallback_close()?;
+            self.set_mode(Mode::PassThrough)?;
+            self.close_requested = true;
+            return Ok(());
+        }
+        self.pending_replies
+            .push_back(PendingReply::Rejected(verb, reply));
+        Ok(())
+    }
+
+    // Sends scheduled commands to SMTP server ahead of the latest chunk of downstream data.
+    fn inject_commands(&mut self) {
+        if self.mode != Mode::Command {
+            return;
+        }
+        let log_level = self.log_level();
+        for (verb, command) in self.downstream_injections.drain(..) {
+            filter_debug!(log_level, "injecting command: {}", command.as_bstr());
+            if self.downstream_editor.prepend(command) {
+                self.pending_replies.push_back(PendingReply::Injected(verb));
+            }
+        }
+    }
+
+    // Applies recipient rewrite rules to the latest RCPT command.
+    //
+    // Returns the command to forward along with the original recipient
+    // if it has been rewritten.
+    fn rewrite_recipient(&mut self, rcpt: Rcpt) -> Result<(Rcpt, Option<ByteString>)> {
+        let rewritten = self
+            .config
+            .recipient_rewrite
+            .as_ref()
+            .and_then(|rewrite| rewrite.apply(rcpt.mailbox()?))
+            .and_then(|mailbox| rcpt.with_mailbox(&mailbox));
+        match rewritten {
+            Some(rewritten) if self.rewrite_command(rewritten.to_bytes()) => {
+                filter_debug!(
+                    self.log_level(),
+                    "rewriting recipient {} to {}",
+                    self.config.redaction.mailbox(rcpt.to()).as_bstr(),
+                    self.config.redaction.mailbox(rewritten.to()).as_bstr()
+                );
+                let original = envelope_mailbox(rcpt.to(), rcpt.mailbox());
+                Ok((rewritten, Some(original)))
+            }
+            Some(_) => {
+                log::warn!("failed to rewrite recipient, forwarding it as is");
+                self.stats_sink.on_smtp_recipient_rewrite_failure()?;
+                Ok((rcpt, None))
+            }
+            None => Ok((rcpt, None)),
+        }
+    }
+
+    // Replaces the latest reply with a given one.
+    //
+    // A later rewrite of the same reply takes precedence over an earlier one.
+    fn rewrite_reply(&mut self, replacement: Vec<u8>) {
+        let end = self.upstream_editor.offset();
+        self.upstream_editor
+            .replace(self.next_reply_offset, end, replacement);
+    }
+
+    // Removes the latest reply so that it doesn't reach SMTP client.
+    fn remove_reply(&mut self) {
+        let end = self.upstream_editor.offset();
+        self.upstream_editor.delete(self.next_reply_offset, end);
+    }
+
+    // Returns the name of the first reply code rewrite rule that applies
+    // to a given reply along with the rewritten reply.
+    fn reply_code_rewrite(&self, reply: &Reply) -> Option<(String, Reply)> {
+        self.config.reply_code_rewrites.iter().find_map(|rule| {
+            rule.apply(reply)
+                .map(|replacement| (rule.name.clone(), replacement))
+        })
+    }
+
+    // Schedules forwarding of the client attributes to SMTP server via XFORWARD command.
+    fn schedule_xforward(&mut self, ehlo: &Ehlo) {
+        let mut xforward = Xforward::default();
+        if let Some(address) = self.client_address {
+            xforward = xforward.addr(address.ip()).port(address.port());
+        }
+        let xforward = xforward.helo(ehlo.domain().as_bytes()).proto("ESMTP");
+        self.downstream_injections
+            .push((Xforward::VERB, xforward.to_bytes()));
+    }
+
+    // Stamps a mail transaction that is about to be committed with the client.
+    fn prepare_commit(&self, tx: &mut Transaction) {
+        tx.client_address = self.client_address;
+        tx.client_certificate = self.client_certificate.clone();
+        let redaction = &self.config.redaction;
+        filter_debug!(
+            self.log_level(),
+            "committing transaction {} from {} to {:?}: {:?}",
+            tx.id,
+            redaction.mailbox(&tx.from).as_bstr(),
+            tx.to
+                .iter()
+                .map(|rcpt| redaction.mailbox(&rcpt.to).to_str_lossy().into_owned())
+                .collect::<Vec<_>>(),
+            redaction.body(&tx.body).as_bstr()
+        );
+        for rcpt in tx.to.iter() {
+            if let Some(original_to) = rcpt.original_to.as_ref() {
+                log::info!(
+                    "[{}] recipient {} of transaction {} has been rewritten to {}",
+                    peer(self.client_address),
+                    redaction.mailbox(original_to).as_bstr(),
+                    tx.id,
+                    redaction.mailbox(&rcpt.to).as_bstr()
+                );
+            }
+        }
+    }
+
+    fn abort_transaction(&mut self, tx: Transaction, reason: &str) -> Result<()> {
+        log::info!(
+            "[{}] {} before mail transaction has been completed: {:?}",
+            peer(self.client_address),
+            reason,
+            tx
+        );
+        self.stats_sink.on_smtp_transaction_abort()
+    }
+
+    fn reset(&mut self) {
+        self.active_transaction = None
+    }
+
+    fn on_handshake(&mut self, handshake: Handshake) -> Result<()> {
+        if self.handshake.is_none() {
+            self.handshake = Some(handshake);
+            let fallback = handshake == Handshake::Helo && self.ehlo_rejected;
+            self.stats_sink.on_smtp_handshake(handshake, fallback)?;
+        }
+        Ok(())
+    }
+
+    // Takes the configured action on a protocol parsing error.
+    //
+    // Returns `true` if the session should keep interpreting the traffic.
+    fn fallback(&mut self, err: Error, class: ParseErrorClass) -> Result<bool> {
+        let action = self.config.fallback.action(class);
+        let category = err.downcast_ref::<SmtpError>().map(SmtpError::category);
+        self.stats_sink.on_smtp_parse_error(action, category)?;
+        self.totals.parse_errors += 1;
+        self.offenses.push(Offense::ParseError);
+        match action {
+            FallbackAction::PassThrough | FallbackAction::Close => {
+                log::error!(
+                    "[{}] falling back into no-op mode due to a protocol parsing error: {}",
+                    peer(self.client_address),
+                    err
+                );
+                self.set_mode(Mode::PassThrough)?;
+                self.close_requested = action == FallbackAction::Close;
+                Ok(false)
+            }
+            FallbackAction::Resync => {
+                log::warn!(
+                    "[{}] skipping a line due to a protocol parsing error: {}",
+                    peer(self.client_address),
+                    err
+                );
+                match class {
+                    // SMTP server is still going to reply to the line
+                    ParseErrorClass::Command if self.config.tap != Some(Tap::Commands) => {
+                        self.pending_replies.push_back(PendingReply::Unparsed)
+                    }
+                    ParseErrorClass::Reply => self.next_reply = None,
+                    _ => {}
+                }
+                Ok(true)
+            }
+        }
+    }
+
+    // Replaces the latest MAIL command with QUIT command so that SMTP server
+    // closes the connection once the client has received `421` reply instead,
+    // or gives up on the connection right away if the command cannot be replaced.
+    //
+    // Returns `false` if replies are not under control.
+    fn drain_transaction(&mut self) -> Result<bool> {
+        if self.config.tap.is_some() {
+            return Ok(false); // replies are not under control
+        }
+        let mut quit = Quit::VERB.as_bytes().to_vec();
+        quit.extend_from_slice(CR_LF);
+        if !self.rewrite_command(quit) {
+            log::warn!(
+                "[{}] cannot turn down a mail transaction while draining, closing the connection",
+                peer(self.client_address)
+            );
+            self.stats_sink.on_smtp_local_reply_fallback_close()?;
+            self.set_mode(Mode::PassThrough)?;
+            self.close_requested = true;
+            return Ok(true);
+        }
+        log::info!(
+            "[{}] turning down a mail transaction while draining",
+            peer(self.client_address)
+        );
+        self.stats_sink.on_smtp_drain()?;
+        self.quit = true;
+        self.pending_replies.push_back(PendingReply::Drain);
+        Ok(true)
+    }
+
+    // Advances the session as if SMTP server has accepted a given command
+    // when replies are not visible.
+    fn assume_accepted(&mut self, cmd: &Command, original_to: Option<ByteString>) -> Result<()> {
+        match cmd {
+            Command::Mail(mail) => self.accept_mail(mail),
+            Command::Rcpt(rcpt) => self.accept_rcpt(rcpt, original_to),
+            Command::Data(_) => {
+                let now = self.now;
+                let tx = self.transaction();
+                tx.body = ByteString::new();
+                tx.data_started_at = now;
+                self.set_mode(Mode::Data)?;
+            }
+            Command::StartTls(_) if !self.config.starttls_offload => {
+                self.set_mode(Mode::PassThrough)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Starts a mail transaction once SMTP server has accepted MAIL command.
+    fn accept_mail(&mut self, mail: &Mail) {
+        let submitter = mail.submitter().map(ByteString::from);
+        let tx = self.transaction();
+        tx.from = envelope_mailbox(mail.from(), mail.mailbox());
+        tx.submitter = submitter.clone();
+        tx.null_sender = mail.mailbox().is_some_and(<[u8]>::is_empty);
+        self.summary.mail_from = Some(mail.from().clone());
+        self.summary.submitter = submitter;
+        self.summary.rcpt_to.clear();
+    }
+
+    // Adds a recipient to the mail transaction once SMTP server has accepted RCPT command.
+    fn accept_rcpt(&mut self, rcpt: &Rcpt, original_to: Option<ByteString>) {
+        self.summary.rcpt_to.push(rcpt.to().clone());
+        self.summary.rcpt_count += 1;
+        self.transaction().to.push(Recipient {
+            to: envelope_mailbox(rcpt.to(), rcpt.mailbox()),
+            original_to,
+            domain: rcpt.domain().map(ByteString::from),
+        });
+    }
+
+    // Stops interpreting the traffic that is clearly not SMTP, e.g. HTTP or binary,
+    // without treating it as a protocol error of an SMTP peer.
+    fn bail_out(&mut self, peer_kind: &str) -> Result<()> {
+        filter_debug!(
+            self.log_level(),
+            "[{}] falling back into no-op mode since {} doesn't speak SMTP",
+            peer(self.client_address),
+            peer_kind
+        );
+        self.stats_sink.on_smtp_not_smtp()?;
+        self.set_mode(Mode::PassThrough)?;
+        Ok(())
+    }
+
+    // Takes a SASL response of SMTP client off the buffer, which is never
+    // logged since it carries credentials; only the identity is extracted
+    // from the first one.
+    fn next_sasl_response(&mut self, auth: &Auth) -> bool {
+        let bare_lf = self.config.strictness.allows_bare_lf();
+        match next_line(&mut self.downstream_buffer, bare_lf) {
+            Some((line, len)) => {
+                self.downstream_editor.consume(len);
+                if self.sasl_responses == 0 {
+                    self.sasl_identity = auth.identity(&line);
+                }
+                self.sasl_responses += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn next_command(&mut self) -> Result<Option<Command>> {
+        self.next_command_offset = self.downstream_editor.offset();
+        let strictness = self.config.strictness;
+        match next_line(&mut self.downstream_buffer, strictness.allows_bare_lf()) {
+            Some((line, len)) => {
+                self.downstream_editor.consume(len);
+                if let Some(max) = strictness.max_line_length().filter(|&max| len > max) {
+                    return Err(SmtpError::Limit {
+                        line: "command",
+                        octets: len,
+                        max,
+                    }
+                    .into());
+                }
+                let cmd = self.commands.parse(line)?;
+                if strictness == Strictness::Strict {
+                    if let Command::Unknown(unknown) = &cmd {
+                        return Err(SmtpError::ParseCommand(format!(
+                            "unknown command: {}",
+                            unknown.verb()
+                        ))
+                        .into());
+                    }
+                }
+                Ok(Some(cmd))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Validates the order of envelope commands in strict mode.
+    //
+    // Commands are validated as they are sent, since pipelining clients don't wait
+    // for replies.
+    fn check_sequence(&mut self, cmd: &Command) -> Result<()> {
+        let in_sequence = match cmd {
+            Command::Helo(_) | Command::Ehlo(_) => {
+                self.sent_handshake = true;
+                self.sent_mail = false;
+                self.sent_rcpt = false;
+                true
+            }
+            Command::Rset(_) => {
+                self.sent_mail = false;
+                self.sent_rcpt = false;
+                true
+            }
+            Command::StartTls(_) => {
+                self.sent_handshake = false;
+                true
+            }
+            Command::Mail(mail) => {
+                self.sent_binarymime = mail.is_binarymime();
+                !std::mem::replace(&mut self.sent_mail, true) && self.sent_handshake
+            }
+            Command::Rcpt(_) => {
+                self.sent_rcpt = true;
+                self.sent_mail
+            }
+            Command::Data(_) => {
+                self.sent_mail = false;
+                // a binary message can only be sent in BDAT chunks
+                std::mem::take(&mut self.sent_rcpt) && !self.sent_binarymime
+            }
+            Command::Bdat(bdat) if bdat.is_last() => {
+                self.sent_mail = false;
+                std::mem::take(&mut self.sent_rcpt)
+            }
+            Command::Bdat(_) => self.sent_rcpt,
+            Command::Burl(burl) if burl.is_last() => {
+                self.sent_mail = false;
+                std::mem::take(&mut self.sent_rcpt)
+            }
+            Command::Burl(_) => self.sent_rcpt,
+            Command::Legacy(legacy) if legacy.starts_transaction() => {
+                !std::mem::replace(&mut self.sent_mail, true) && self.sent_handshake
+            }
+            _ => true,
+        };
+        if in_sequence || self.config.strictness != Strictness::Strict {
+            return Ok(());
+        }
+        Err(SmtpError::Sequence(format!("{} command out of sequence", cmd.verb())).into())
+    }
+
+    fn next_body(&mut self) -> Option<Vec<u8>> {
+        loop {
+            // message lines always end with CRLF, a bare LF is a part of the content
+            match next_line(&mut self.downstream_buffer, false) {
+                Some((line, len)) => {
+                    if self.next_body.is_empty() {
+                        self.inject_trace_header();
+                    }
+                    self.downstream_editor.consume(len);
+                    let end = !self.next_body.is_empty() && line == b"."; // <CR><LF>.<CR><LF>
+                    self.next_body.extend(line);
+                    self.next_body.push_str(CR_LF);
+                    if end {
+                        return Some(self.next_body.drain(..).collect());
+                    }
+                    continue; // to the next line
+                }
+                None => return None,
+            }
+        }
+    }
+
+    // Adds a header with the id of the mail transaction in front of the message,
+    // if configured.
+    fn inject_trace_header(&mut self) {
+        if let Some(header) = self.trace_header() {
+            let offset = self.downstream_editor.offset();
+            self.downstream_editor.insert(offset, header);
+        }
+    }
+
+    // Returns the header with the id of the mail transaction, if configured.
+    fn trace_header(&self) -> Option<Vec<u8>> {
+        let name = self.config.trace_header.as_ref()?;
+        let tx = self.active_transaction.as_ref()?;
+        Some(format!("{}: {}\r\n", name, tx.id).into_bytes())
+    }
+
+    // Starts a message sent in chunks unless one has been started already.
+    //
+    // Returns `false` if the message has been started by an earlier chunk.
+    fn start_chunked_message(&mut self) -> bool {
+        if self.chunked_message.is_some() {
+            return false;
+        }
+        self.chunked_message = Some(ChunkedMessage {
+            body: Vec::new(),
+            started_at: self.now,
+        });
+        true
+    }
+
+    // Starts a message on the first BDAT command of a mail transaction, which
+    // carries the trace header in front of its chunk enlarged accordingly.
+    fn start_chunk(&mut self, bdat: &Bdat) {
+        if !self.start_chunked_message() {
+            return;
+        }
+        if let Some(header) = self.trace_header() {
+            if self.rewrite_command(bdat.with_size(bdat.size() + header.len())) {
+                let offset = self.downstream_editor.offset();
+                self.downstream_editor.insert(offset, header);
+            }
+        }
+    }
+
+    // Receives the chunk of the latest BDAT command, which is counted in octets
+    // rather than terminated by a `.` line, so that binary content with bare CR,
+    // LF and `.` lines is never mistaken for commands.
+    //
+    // Returns `false` until the chunk is complete.
+    fn next_chunk(&mut self) -> bool {
+        let len = self.chunk_remaining.min(self.downstream_buffer.len());
+        self.downstream_editor.consume(len);
+        self.chunked_message
+            .get_or_insert_with(Default::default)
+            .body
+            .extend(self.downstream_buffer.drain(..len));
+        self.chunk_remaining -= len;
+        self.chunk_remaining == 0
+    }
+
+    // Completes the message on the last BDAT chunk, which is committed to the
+    // mail transaction once SMTP server has replied to it.
+    fn complete_chunked_message(&mut self) -> Result<()> {
+        let message = self.chunked_message.take().unwrap_or_default();
+        if self.config.content_checks {
+            self.content_checks.push(ContentCheck {
+                message: message.body.clone().into(),
+            });
+        }
+        if self.config.tap != Some(Tap::Commands) {
+            self.chunked_messages.push_back(message);
+        }
+        self.stats_sink.on_smtp_transaction_commit()?;
+        self.totals.transactions += 1;
+        Ok(())
+    }
+
+    fn next_reply(&mut self) -> Result<Option<Reply>> {
+        loop {
+            let strictness = self.config.strictness;
+            match next_line(&mut self.upstream_buffer, strictness.allows_bare_lf()) {
+                Some((next, len)) => {
+                    filter_trace!(
+                        self.log_level(),
+                        "next reply line: {}",
+                        self.config.redaction.data(&next).as_bstr()
+                    );
+                    if self.next_reply.is_none() {
+                        self.next_reply_offset = self.upstream_editor.offset();
+                    }
+                    self.upstream_editor.consume(len);
+                    if let Some(max) = strictness.max_line_length().filter(|&max| len > max) {
+                        self.next_reply = None;
+                        return Err(SmtpError::Limit {
+                            line: "reply",
+                            octets: len,
+                            max,
+                        }
+                        .into());
+                    }
+                    let line = ReplyLine::try_from(next)?;
+                    let end_line = line.is_end_line();
+                    if let Some(reply) = self.next_reply.as_mut() {
+                        if line.code() != reply.code() {
+                            if self.config.strict_reply_codes {
+                                return Err(SmtpError::ParseReply(format!(
+                                    "reply line code {} doesn't match reply code {}",
+                                    line.code(),
+                                    reply.code()
+                                ))
+                                .into());
+                            }
+                            self.stats_sink.on_smtp_reply_code_mismatch()?;
+                        }
+                        reply.append(line);
+                    } else {
+                        self.next_reply = Some(Reply::new(line));
+                    }
+                    if end_line {
+                        return Ok(self.next_reply.take());
+                    }
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn handle_reply(&mut self, reply: Reply) -> Result<()> {
+        let rewrite = match self.pending_replies.front() {
+            Some(PendingReply::Injected(_))
+            | Some(PendingReply::Drain)
+            | Some(PendingReply::Rejected(..)) => None,
+            _ => self.reply_code_rewrite(&reply),
+        };
+        self.dispatch_reply(reply)?;
+        if let Some((rule, replacement)) = rewrite {
+            filter_debug!(
+                self.log_level(),
+                "rewriting reply by rule {}: {}",
+                rule,
+                replacement
+            );
+            self.rewrite_reply(replacement.to_bytes());
+            self.stats_sink.on_smtp_reply_rewrite(&rule)?;
+        }
+        Ok(())
+    }
+
+    // Returns an error if a given reply cannot be a reply to the next pending one.
+    fn correlate(&self, reply: &Reply) -> Result<()> {
+        let intermediate = reply.code().response_type() == ReplyType::PositiveIntermediateReply;
+        match self.pending_replies.front() {
+            None if self.config.tap == Some(Tap::Replies) => Ok(()),
+            None => Err(SmtpError::Correlation(format!(
+                "received reply {} while no command is pending",
+                reply.code()
+            ))
+            .into()),
+            // SMTP server challenges AUTH command with `334` replies, while
+            // unknown commands may be SASL responses of an unknown state
+            Some(PendingReply::Command(cmd))
+                if intermediate
+                    && !matches!(
+                        cmd,
+                        Command::Data(_) | Command::Auth(_) | Command::Unknown(_)
+                    ) =>
+            {
+                Err(SmtpError::Correlation(format!(
+                    "received intermediate reply {} to {} command",
+                    reply.code(),
+                    cmd.verb()
+                ))
+                .into())
+            }
+            Some(_) => Ok(()),
+        }
+    }
+
+    // Describes pending replies without any arguments of commands, e.g.
+    // `[MAIL, RCPT, commit 174a3c1b2e0-2.1]`.
+    fn describe_pending_replies(&self) -> String {
+        let mut described: Vec<_> = self
+            .pending_replies
+            .iter()
+            .take(MAX_DESCRIBED_PENDING_REPLIES)
+            .map(|pending| match pending {
+                PendingReply::Connect => "connect".to_owned(),
+                PendingReply::Command(cmd) => cmd.verb().to_owned(),
+                PendingReply::Commit(tx) => format!("commit {}", tx.id),
+                PendingReply::Drain => "drain".to_owned(),
+                PendingReply::Rejected(verb, _) => format!("rejected {}", verb),
+                PendingReply::Unparsed => "unparsed".to_owned(),
+                PendingReply::Injected(verb) => format!("injected {}", verb),
+            })
+            .collect();
+        if self.pending_replies.len() > MAX_DESCRIBED_PENDING_REPLIES {
+            described.push(format!(
+                "{} more",
+                self.pending_replies.len() - MAX_DESCRIBED_PENDING_REPLIES
+            ));
+        }
+        format!("[{}]", described.join(", "))
+    }
+
+    fn dispatch_reply(&mut self, reply: Reply) -> Result<()> {
+        if let Err(err) = self.correlate(&reply) {
+            log::warn!(
+                "[{}] {} in {:?} mode, pending replies: {}",
+                peer(self.client_address),
+                err,
+                self.mode,
+                self.describe_pending_replies()
+            );
+            self.stats_sink.on_smtp_reply_correlation_error()?;
+            return Err(err);
+        }
+        let cmd = match self.pending_replies.front() {
+            Some(PendingReply::Command(cmd)) => Some(cmd),
+            _ => None,
+        };
+        self.notify(|sink| sink.on_reply(cmd, &reply))?;
+        match self.pending_replies.pop_front() {
+            Some(pending) => {
+                use PendingReply::*;
+                if !matches!(pending, Injected(_)) {
+                    self.summary.last_reply_code = Some(reply.code());
+                }
+                match pending {
+                    Connect => {
+                        self.stats_sink.on_smtp_connect_reply(reply.code())?;
+                        let greeting = Greeting::try_from(&reply)?;
+                        filter_debug!(self.log_level(), "greeting: {:?}", greeting);
+                        self.greeting = Some(greeting);
+                        if let Some(banner) = self.config.greeting_banner.as_ref() {
+                            let replacement = format!("{} {}\r\n", reply.code(), banner);
+                            self.rewrite_reply(replacement.into_bytes());
+                        }
+                        self.set_mode(Mode::Command)?;
+                        Ok(())
+                    }
+                    Command(cmd) => {
+                        self.stats_sink
+                            .on_smtp_command_reply(cmd.verb(), reply.code())?;
+                        if self.config.command_events {
+                            self.events.push(Event::CommandReply {
+                                verb: cmd.verb().to_owned(),
+                                code: reply.code(),
+                            });
+                        }
+                        cmd.handle_reply(self, reply)?;
+                        Ok(())
+                    }
+                    Commit(tx) => self.on_commit_reply(*tx, &reply),
+                    Unparsed => Ok(()),
+                    Drain => {
+                        let replacement = match self.config.local_replies.drain.as_ref() {
+                            Some(template) => LocalReplies::render(template, b"", b"", None),
+                            None => DRAIN_REPLY.to_vec(),
+                        };
+                        self.summary.last_reply_code =
+                            Some(ReplyCode::try_from(replacement[..3].to_vec())?);
+                        self.rewrite_reply(replacement);
+                        Ok(())
+                    }
+                    Rejected(verb, replacement) => {
+                        let code = ReplyCode::try_from(replacement[..3].to_vec())?;
+                        self.stats_sink.on_smtp_command_reply(verb, code)?;
+                        self.summary.last_reply_code = Some(code);
+                        self.rewrite_reply(replacement);
+                        Ok(())
+                    }
+                    Injected(verb) => {
+                        if !reply.code().response_type().is_positive() {
+                            log::warn!(
+                                "[{}] SMTP server has rejected {} command: {}",
+                                peer(self.client_address),
+                                verb,
+                                reply
+                            );
+                        }
+                        self.remove_reply();
+                        Ok(())
+                    }
+                }
+            }
+            None if self.config.tap == Some(Tap::Replies) => {
+                self.summary.last_reply_code = Some(reply.code());
+                self.stats_sink.on_smtp_uncorrelated_reply(reply.code())
+            }
+            None => Ok(()), // rejected by `correlate`
+        }
+    }
+
+    // Commits the message sent in chunks to the mail transaction on the reply
+    // to the last chunk, which is the reply to the whole message.
+    fn on_chunked_message_reply(&mut self, reply: &Reply) -> Result<()> {
+        let message = self.chunked_messages.pop_front().unwrap_or_default();
+        match self.active_transaction.take() {
+            Some(mut tx) => {
+                tx.body = message.body.into();
+                tx.data_started_at = message.started_at;
+                self.prepare_commit(&mut tx);
+                self.on_commit_reply(tx, reply)
+            }
+            None => Ok(()), // SMTP server hasn't accepted the transaction
+        }
+    }
+
+    // Accounts the reply of SMTP server to a mail transaction commit.
+    fn on_commit_reply(&mut self, tx: Transaction, reply: &Reply) -> Result<()> {
+        self.stats_sink
+            .on_smtp_transaction_commit_reply(reply.code())?;
+        if tx.authenticated {
+            self.stats_sink
+                .on_smtp_authenticated_transaction_commit_reply(reply.code())?;
+        }
+        self.notify(|sink| sink.on_transaction(&tx, reply))?;
+        for rcpt in tx.to.iter() {
+            if let Some(domain) = rcpt.domain.as_ref() {
+                self.stats_sink
+                    .on_smtp_delivery_reply(domain, reply.code())?;
+            }
+        }
+        if reply.code().response_type().is_positive() {
+            self.summary.messages += 1;
+            if tx.null_sender && dsn::is_delivery_status_report(&tx.body) {
+                self.stats_sink.on_smtp_bounce()?;
+            }
+        }
+        let since = |time: Option<SystemTime>| self.now?.duration_since(time?).ok();
+        if let (Some(duration), Some(data_duration)) =
+            (since(tx.started_at), since(tx.data_started_at))
+        {
+            self.stats_sink
+                .on_smtp_transaction_timing(duration, data_duration)?;
+        }
+        if self.config.transaction_events {
+            self.events.push(Event::Transaction(TransactionSummary {
+                id: tx.id,
+                from: tx.from,
+                to: tx.to.into_iter().map(|rcpt| rcpt.to).collect(),
+                size: tx.body.len(),
+                reply_code: reply.code(),
+                authenticated: tx.authenticated,
+                auth_identity: tx.auth_identity,
+                submitter: tx.submitter,
+                started_at: tx.started_at,
+                data_started_at: tx.data_started_at,
+                committed_at: self.now,
+            }));
+        }
+        Ok(())
+    }
+}
+
+// Reply to MAIL command while draining.
+const DRAIN_REPLY: &[u8] = b"421 4.3.2 Service shutting down\r\n";
+
+// Maximum number of pending replies to describe in diagnostics of correlation errors.
+const MAX_DESCRIBED_PENDING_REPLIES: usize = 8;
+
+// Returns the mailbox of a reverse or forward path as reported in mail
+// transactions, i.e. `<>` for the null sender, or the path itself if it
+// doesn't enclose a mailbox.
+fn envelope_mailbox(path: &ByteString, mailbox: Option<&[u8]>) -> ByteString {
+    match mailbox {
+        Some([]) => ByteString::from("<>"),
+        Some(mailbox) => mailbox.into(),
+        None => path.clone(),
+    }
+}
+
+// Renders the client address for logging.
+fn peer(client_address: Option<SocketAddr>) -> String {
+    client_address
+        .map(|address| address.to_string())
+        .unwrap_or_else(|| "-".to_owned())
+}
+
+// Takes the next complete line off a buffer along with its length including
+// the line ending, which is either CRLF or, if allowed, a bare LF.
+fn next_line(buffer: &mut Vec<u8>, bare_lf: bool) -> Option<(Vec<u8>, usize)> {
+    let (index, ending) = if bare_lf {
+        let index = buffer.find_byte(b'\n')?;
+        match index.checked_sub(1) {
+            Some(cr) if buffer[cr] == b'\r' => (cr, CR_LF.len()),
+            _ => (index, 1),
+        }
+    } else {
+        (buffer.find(CR_LF)?, CR_LF.len())
+    };
+    let line: Vec<u8> = buffer.drain(0..index).collect();
+    buffer.drain(0..ending);
+    Some((line, index + ending))
+}
+
+/// Returns `true` if data starts with a TLS handshake record,
+/// i.e. the client speaks TLS rather than SMTP.
+fn is_tls_client_hello(data: &[u8]) -> bool {
+    // ContentType handshake(22), ProtocolVersion {3, 0..=4}
+    matches!(data, [0x16, 0x03, 0x00..=0x04, ..])
+}
+
+/// Returns whether data plausibly starts with an SMTP command, i.e. a line
+/// of printable characters that starts with a letter and isn't an HTTP request,
+/// or `None` if more data is needed to tell.
+fn looks_like_command(data: &[u8], bare_lf: bool) -> Option<bool> {
+    let first = *data.first()?;
+    if !first.is_ascii_alphabetic() {
+        return Some(false);
+    }
+    let end = if bare_lf {
+        data.find_byte(b'\n').map(|end| {
+            if end > 0 && data[end - 1] == b'\r' {
+                end - 1
+            } else {
+                end
+            }
+        })
+    } else {
+        data.find(CR_LF)
+    };
+    let line = match end {
+        Some(end) => &data[..end],
+        None => data,
+    };
+    if !line
+        .iter()
+        .all(|&b| b.is_ascii_graphic() || b == b' ' || b == b'\t')
+    {
+        return Some(false);
+    }
+    if line.len() == data.len() {
+        return None; // incomplete line
+    }
+    Some(line.find(" HTTP/").is_none())
+}
+
+/// Returns whether data plausibly starts with an SMTP reply, i.e. a 3-digit code
+/// followed by a space, a hyphen or the end of the line, or `None` if more data
+/// is needed to tell.
+fn looks_like_reply(data: &[u8]) -> Option<bool> {
+    for (i, &b) in data.iter().take(4).enumerate() {
+        let plausible = match i {
+            0..=2 => b.is_ascii_digit(),
+            _ => b == b' ' || b == b'-' || b == b'\r',
+        };
+        if !plausible {
+            return Some(false);
+        }
+    }
+    if data.len() < 4 {
+        None
+    } else {
+        Some(true)
+    }
+}
+
+trait ReplyHandler {
+    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()>;
+}
+
+impl ReplyHandler for Command {
+    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
+        use Command::*;
+        match self {
+            Helo(helo) => helo.handle_reply(session, reply),
+            Ehlo(ehlo) => ehlo.handle_reply(session, reply),
+            Mail(mail) => mail.handle_reply(session, reply),
+            Rcpt(rcpt) => rcpt.handle_reply(session, reply),
+            Data(data) => data.handle_reply(session, reply),
+            Bdat(bdat) => bdat.handle_reply(session, reply),
+            Burl(burl) => burl.handle_reply(session, reply),
+            Rset(rset) => rset.handle_reply(session, reply),
+            Vrfy(vrfy) => vrfy.handle_reply(session, reply),
+            Expn(expn) => expn.handle_reply(session, reply),
+            Help(help) => help.handle_reply(session, reply),
+            Noop(noop) => noop.handle_reply(session, reply),
+            Quit(quit) => quit.handle_reply(session, reply),
+            StartTls(stls) => stls.handle_reply(session, reply),
+            Auth(auth) => auth.handle_reply(session, reply),
+            Legacy(legacy) => legacy.handle_reply(session, reply),
+            Extension(extension) => extension.handle_reply(session, reply),
+            Unknown(unknown) => unknown.handle_reply(session, reply),
+        }
+    }
+}
+
+impl ReplyHandler for ExtensionCommand {
+    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
+        filter_debug!(
+            session.log_level(),
+            "handling reply to {}: {:?}",
+            self.verb(),
+            session.config.redaction.data(&reply.to_bytes()).as_bstr()
+        );
+        if let Some(mode) = self.extension().on_reply(self.args(), &reply) {
+            session.set_mode(mode)?;
+        }
+        Ok(())
+    }
+}
+
+impl ReplyHandler for Helo {
+    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
+        filter_debug!(
+            session.log_level(),
+            "handling reply to {}: {:?}",
+            Self::VERB,
+            session.config.redaction.data(&reply.to_bytes()).as_bstr()
+        );
+        if reply.code().response_type().is_positive() {
+            session.reset();
+            session.on_handshake(Handshake::Helo)?;
+        }
+        Ok(())
+    }
+}
+
+impl ReplyHandler for Ehlo {
     fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
-        log::debug!("handling reply to {}: {:?}", Self::VERB, reply);
+        filter_debug!(
+            session.log_level(),
+            "handling reply to {}: {:?}",
+            Self::VERB,
+            session.config.redaction.data(&reply.to_bytes()).as_bstr()
+        );
         if reply.code().response_type().is_positive() {
+            session.reset();
+            session.on_handshake(Handshake::Ehlo)?;
+            let capabilities = Capability::from_reply(&reply);
             session
-                .active_transaction
-                .get_or_insert_with(Default::default)
-                .to
-                .push(self.to().clone());
+                .stats_sink
+                .on_smtp_ehlo_capabilities(&capabilities)?;
+            if session.config.xforward
+                && capabilities
+                    .iter()
+                    .any(|capability| capability.keyword() == Xforward::KEYWORD)
+            {
+                session.schedule_xforward(self);
+            }
+            if let Some(rewrite) = session.config.ehlo_rewrite.as_ref() {
+                if let Some(replacement) = rewrite.apply(&reply) {
+                    filter_debug!(
+                        session.log_level(),
+                        "rewriting reply to {}: {}",
+                        Self::VERB,
+                        replacement
+                    );
+                    session.rewrite_reply(replacement.to_bytes());
+                }
+            }
+        } else {
+            session.ehlo_rejected = true;
         }
         Ok(())
     }
 }
 
-impl ReplyHandler for Data {
+impl ReplyHandler for Mail {
     fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
-        log::debug!("handling reply to {}: {:?}", Self::VERB, reply);
+        filter_debug!(
+            session.log_level(),
+            "handling reply to {}: {:?}",
+            Self::VERB,
+            session.config.redaction.data(&reply.to_bytes()).as_bstr()
+        );
         if reply.code().response_type().is_positive() {
+            session.accept_mail(self);
+        }
+        Ok(())
+    }
+}
+
+impl ReplyHandler for Rcpt {
+    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
+        filter_debug!(
+            session.log_level(),
+            "handling reply to {}: {:?}",
+            Self::VERB,
+            session.config.redaction.data(&reply.to_bytes()).as_bstr()
+        );
+        if let Some(domain) = self.domain() {
             session
-                .active_transaction
-                .get_or_insert_with(Default::default)
-                .body = ByteString::new();
-            session.mode = Mode::Data;
+                .stats_sink
+                .on_smtp_recipient_reply(domain, reply.code())?;
+        }
+        let original_to = session.original_recipients.pop_front().flatten();
+        if reply.code().response_type().is_positive() {
+            session.accept_rcpt(self, original_to);
+        }
+        Ok(())
+    }
+}
+
+impl ReplyHandler for Data {
+    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
+        filter_debug!(
+            session.log_level(),
+            "handling reply to {}: {:?}",
+            Self::VERB,
+            session.config.redaction.data(&reply.to_bytes()).as_bstr()
+        );
+        if reply.code().response_type().is_positive() {
+            let now = session.now;
+            let tx = session.transaction();
+            tx.body = ByteString::new();
+            tx.data_started_at = now;
+            session.set_mode(Mode::Data)?;
         }
         Ok(())
     }
 }
 
+impl ReplyHandler for Bdat {
+    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
+        filter_debug!(
+            session.log_level(),
+            "handling reply to {} {}: {:?}",
+            Self::VERB,
+            self.size(),
+            session.config.redaction.data(&reply.to_bytes()).as_bstr()
+        );
+        if !self.is_last() {
+            return Ok(());
+        }
+        session.on_chunked_message_reply(&reply)
+    }
+}
+
+impl ReplyHandler for Burl {
+    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
+        filter_debug!(
+            session.log_level(),
+            "handling reply to {}: {:?}",
+            self,
+            session.config.redaction.data(&reply.to_bytes()).as_bstr()
+        );
+        if !reply.code().response_type().is_positive() {
+            log::info!(
+                "[{}] SMTP server has failed to fetch {} content: {}",
+                peer(session.client_address),
+                self.scheme().as_bstr(),
+                reply
+            );
+        }
+        if !self.is_last() {
+            return Ok(());
+        }
+        session.on_chunked_message_reply(&reply)
+    }
+}
+
 impl ReplyHandler for Rset {
     fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
-        log::debug!("handling reply to {}: {:?}", Self::VERB, reply);
+        filter_debug!(
+            session.log_level(),
+            "handling reply to {}: {:?}",
+            Self::VERB,
+            session.config.redaction.data(&reply.to_bytes()).as_bstr()
+        );
         if reply.code().response_type().is_positive() {
             session.reset();
         }
@@ -378,45 +2339,85 @@ impl ReplyHandler for Rset {
 }
 
 impl ReplyHandler for Vrfy {
-    fn handle_reply<S: StatsSink>(&self, _session: &mut Session<S>, reply: Reply) -> Result<()> {
-        log::debug!("handling reply to {}: {:?}", Self::VERB, reply);
+    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
+        filter_debug!(
+            session.log_level(),
+            "handling reply to {}: {:?}",
+            Self::VERB,
+            session.config.redaction.data(&reply.to_bytes()).as_bstr()
+        );
         Ok(())
     }
 }
 
 impl ReplyHandler for Expn {
-    fn handle_reply<S: StatsSink>(&self, _session: &mut Session<S>, reply: Reply) -> Result<()> {
-        log::debug!("handling reply to {}: {:?}", Self::VERB, reply);
+    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
+        filter_debug!(
+            session.log_level(),
+            "handling reply to {}: {:?}",
+            Self::VERB,
+            session.config.redaction.data(&reply.to_bytes()).as_bstr()
+        );
         Ok(())
     }
 }
 
 impl ReplyHandler for Help {
-    fn handle_reply<S: StatsSink>(&self, _session: &mut Session<S>, reply: Reply) -> Result<()> {
-        log::debug!("handling reply to {}: {:?}", Self::VERB, reply);
+    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
+        filter_debug!(
+            session.log_level(),
+            "handling reply to {}: {:?}",
+            Self::VERB,
+            session.config.redaction.data(&reply.to_bytes()).as_bstr()
+        );
         Ok(())
     }
 }
 
 impl ReplyHandler for Noop {
-    fn handle_reply<S: StatsSink>(&self, _session: &mut Session<S>, reply: Reply) -> Result<()> {
-        log::debug!("handling reply to {}: {:?}", Self::VERB, reply);
+    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
+        filter_debug!(
+            session.log_level(),
+            "handling reply to {}: {:?}",
+            Self::VERB,
+            session.config.redaction.data(&reply.to_bytes()).as_bstr()
+        );
         Ok(())
     }
 }
 
 impl ReplyHandler for Quit {
-    fn handle_reply<S: StatsSink>(&self, _session: &mut Session<S>, reply: Reply) -> Result<()> {
-        log::debug!("handling reply to {}: {:?}", Self::VERB, reply);
+    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
+        filter_debug!(
+            session.log_level(),
+            "handling reply to {}: {:?}",
+            Self::VERB,
+            session.config.redaction.data(&reply.to_bytes()).as_bstr()
+        );
         Ok(())
     }
 }
 
 impl ReplyHandler for StartTls {
     fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
-        log::debug!("handling reply to {}: {:?}", Self::VERB, reply);
+        filter_debug!(
+            session.log_level(),
+            "handling reply to {}: {:?}",
+            Self::VERB,
+            session.config.redaction.data(&reply.to_bytes()).as_bstr()
+        );
         if reply.code().response_type().is_positive() {
-            session.mode = Mode::PassThrough;
+            session.stats_sink.on_smtp_starttls_upgrade()?;
+            if session.config.starttls_offload {
+                // the session starts over, i.e. the client must identify itself
+                // again while any knowledge of the plaintext session is discarded
+                session.handshake = None;
+                session.active_transaction = None;
+                session.summary.authenticated = false;
+                session.summary.auth_identity = None;
+            } else {
+                session.set_mode(Mode::PassThrough)?;
+            }
         }
         Ok(())
     }
@@ -424,14 +2425,1074 @@ impl ReplyHandler for StartTls {
 
 impl ReplyHandler for Unknown {
     fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
-        log::debug!(
+        filter_debug!(
+            session.log_level(),
             "handling reply to unknown command {}: {:?}",
             self.verb(),
-            reply
+            session.config.redaction.data(&reply.to_bytes()).as_bstr()
         );
         if reply.code().response_type().is_positive() {
-            session.mode = Mode::PassThrough;
+            session.set_mode(Mode::PassThrough)?;
+        }
+        Ok(())
+    }
+}
+
+impl ReplyHandler for Legacy {
+    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
+        filter_debug!(
+            session.log_level(),
+            "handling reply to legacy command {}: {:?}",
+            self.verb(),
+            session.config.redaction.data(&reply.to_bytes()).as_bstr()
+        );
+        if !reply.code().response_type().is_positive() {
+            return Ok(());
+        }
+        if self.reverses_roles() {
+            // SMTP server is about to act as a client
+            log::info!(
+                "[{}] SMTP server has accepted {} command, passing the rest of the connection through",
+                peer(session.client_address),
+                self.verb()
+            );
+            session.set_mode(Mode::PassThrough)?;
+        } else {
+            session.transaction().from = envelope_mailbox(self.from(), self.mailbox());
+            session.summary.mail_from = Some(self.from().clone());
+            session.summary.submitter = None;
+            session.summary.rcpt_to.clear();
+        }
+        Ok(())
+    }
+}
+
+impl ReplyHandler for Auth {
+    fn handle_reply<S: StatsSink>(&self, session: &mut Session<S>, reply: Reply) -> Result<()> {
+        filter_debug!(
+            session.log_level(),
+            "handling reply to {} {}: {:?}",
+            Self::VERB,
+            self.mechanism(),
+            session.config.redaction.data(&reply.to_bytes()).as_bstr()
+        );
+        if reply.code().response_type() != ReplyType::PositiveIntermediateReply {
+            session
+                .stats_sink
+                .on_smtp_auth_reply(self.mechanism(), reply.code())?;
+        }
+        match reply.code().response_type() {
+            ReplyType::PositiveIntermediateReply => {
+                if Auth::is_oauth_mechanism(self.mechanism()) && session.sasl_responses > 0 {
+                    // the token has been turned down, yet the exchange fails only
+                    // once SMTP client has acknowledged the error report
+                    let report = reply
+                        .lines()
+                        .first()
+                        .and_then(|line| Auth::error_report(line.text()))
+                        .unwrap_or_default();
+                    filter_debug!(
+                        session.log_level(),
+                        "[{}] server has reported an error of {} exchange: {:?}",
+                        peer(session.client_address),
+                        self.mechanism(),
+                        report.as_bstr()
+                    );
+                    session
+                        .stats_sink
+                        .on_smtp_auth_error_report(self.mechanism())?;
+                }
+                session.sasl_exchange = Some(self.clone());
+            }
+            ReplyType::PositiveCompletionReply => {
+                let identity = session.sasl_identity.take();
+                let redaction = &session.config.redaction;
+                match identity.as_ref() {
+                    Some(identity) => log::info!(
+                        "[{}] client has authenticated with {} mechanism as {}{}",
+                        peer(session.client_address),
+                        self.mechanism(),
+                        redaction.identity(&identity.authentication).as_bstr(),
+                        identity
+                            .authorization
+                            .as_ref()
+                            .map(|authorization| format!(
+                                " on behalf of {}",
+                                redaction.identity(authorization).as_bstr()
+                            ))
+                            .unwrap_or_default()
+                    ),
+                    None => log::info!(
+                        "[{}] client has authenticated with {} mechanism",
+                        peer(session.client_address),
+                        self.mechanism()
+                    ),
+                }
+                session.summary.authenticated = true;
+                session.summary.auth_identity = identity;
+            }
+            _ => {
+                session.sasl_identity = None;
+                // only invalid credentials count, not e.g. an unsupported mechanism
+                if reply.code() == ReplyCode::AUTH_FAILED {
+                    session.auth_failures += 1;
+                    session.offenses.push(Offense::AuthFailure);
+                }
+            }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::lists::{AccessList, PolicyLists};
+    use crate::smtp::agent::{FallbackConfig, RecipientRewrite};
+    use crate::smtp::error::ErrorCategory;
+
+    /// Records stats of a session in the order they have been reported.
+    #[derive(Default)]
+    struct RecordingSink {
+        records: RefCell<Vec<String>>,
+    }
+
+    impl RecordingSink {
+        fn record(&self, record: String) -> Result<()> {
+            self.records.borrow_mut().push(record);
+            Ok(())
+        }
+    }
+
+    impl StatsSink for RecordingSink {
+        fn on_smtp_command(&self, verb: &str) -> Result<()> {
+            self.record(format!("command {}", verb))
+        }
+
+        fn on_smtp_command_reply(&self, verb: &str, code: ReplyCode) -> Result<()> {
+            self.record(format!("command_reply {} {}", verb, code))
+        }
+
+        fn on_smtp_connect_reply(&self, code: ReplyCode) -> Result<()> {
+            self.record(format!("connect_reply {}", code))
+        }
+
+        fn on_smtp_transaction_commit(&self) -> Result<()> {
+            self.record("transaction_commit".to_owned())
+        }
+
+        fn on_smtp_transaction_commit_reply(&self, code: ReplyCode) -> Result<()> {
+            self.record(format!("transaction_commit_reply {}", code))
+        }
+
+        fn on_smtp_transaction_abort(&self) -> Result<()> {
+            self.record("transaction_abort".to_owned())
+        }
+
+        fn on_smtp_uncorrelated_reply(&self, code: ReplyCode) -> Result<()> {
+            self.record(format!("uncorrelated_reply {}", code))
+        }
+
+        fn on_smtp_connection_close(&self, graceful: bool) -> Result<()> {
+            self.record(format!("connection_close graceful={}", graceful))
+        }
+
+        fn on_smtp_session_end(&self, summary: &SessionSummary) -> Result<()> {
+            self.record(format!("session_end messages={}", summary.messages))
+        }
+
+        fn on_smtp_parse_error(
+            &self,
+            action: FallbackAction,
+            _category: Option<ErrorCategory>,
+        ) -> Result<()> {
+            self.record(format!("parse_error {:?}", action))
+        }
+
+        fn on_smtp_drain(&self) -> Result<()> {
+            self.record("drain".to_owned())
+        }
+
+        fn on_smtp_local_reply_fallback_close(&self) -> Result<()> {
+            self.record("local_reply_fallback_close".to_owned())
+        }
+    }
+
+    /// Drives a session with the traffic of a connection and collects data
+    /// forwarded in either direction with edits of the session applied.
+    struct Connection {
+        session: Session<Rc<RecordingSink>>,
+        stats: Rc<RecordingSink>,
+        to_upstream: Vec<u8>,
+        to_downstream: Vec<u8>,
+    }
+
+    impl Connection {
+        fn new(config: SessionConfig) -> Self {
+            let stats = Rc::new(RecordingSink::default());
+            let mut session = Session::new(config, Rc::clone(&stats));
+            session.on_new_conection().unwrap();
+            Connection {
+                session,
+                stats,
+                to_upstream: Vec::new(),
+                to_downstream: Vec::new(),
+            }
+        }
+
+        fn client(&mut self, data: &[u8]) {
+            self.session
+                .on_downstream_data(data.to_vec().into())
+                .unwrap();
+            let edits = self.session.take_downstream_edits();
+            self.to_upstream.extend(edits.apply(data));
+        }
+
+        fn server(&mut self, data: &[u8]) {
+            self.session.on_upstream_data(data.to_vec().into()).unwrap();
+            let edits = self.session.take_upstream_edits();
+            self.to_downstream.extend(edits.apply(data));
+        }
+
+        /// Takes the session through a mail transaction up to the message.
+        fn start_message(&mut self) {
+            self.server(b"220 mail.example.org ESMTP\r\n");
+            self.client(b"HELO client.example.org\r\n");
+            self.server(b"250 mail.example.org\r\n");
+            self.client(b"MAIL FROM:<alice@example.org>\r\n");
+            self.server(b"250 OK\r\n");
+            self.client(b"RCPT TO:<bob@example.com>\r\n");
+            self.server(b"250 OK\r\n");
+            self.client(b"DATA\r\n");
+            self.server(b"354 Go ahead\r\n");
+        }
+
+        /// Returns stats recorded so far that start with a given prefix.
+        fn records(&self, prefix: &str) -> Vec<String> {
+            self.stats
+                .records
+                .borrow()
+                .iter()
+                .filter(|record| record.starts_with(prefix))
+                .cloned()
+                .collect()
+        }
+    }
+
+    #[test]
+    fn should_hold_downstream_until_released() {
+        let mut conn = Connection::new(SessionConfig::default());
+        conn.server(b"220 mail.example.org ESMTP\r\n");
+        conn.client(b"HELO client.example.org\r\n");
+        conn.server(b"250 mail.example.org\r\n");
+        assert!(!conn.session.is_downstream_held());
+
+        conn.session.hold_downstream();
+        // commands keep being interpreted while data is held back
+        conn.client(b"MAIL FROM:<alice@example.org>\r\n");
+        conn.client(b"RCPT TO:<bob@example.org>\r\n");
+        assert!(conn.session.is_downstream_held());
+
+        conn.session.release_downstream();
+        assert!(!conn.session.is_downstream_held());
+        conn.server(b"250 OK\r\n250 OK\r\n");
+        assert_eq!(
+            conn.to_upstream,
+            b"HELO client.example.org\r\n\
+              MAIL FROM:<alice@example.org>\r\n\
+              RCPT TO:<bob@example.org>\r\n"
+                .to_vec()
+        );
+        assert_eq!(
+            conn.records("command"),
+            vec![
+                "command HELO",
+                "command_reply HELO 250",
+                "command MAIL",
+                "command RCPT",
+                "command_reply MAIL 250",
+                "command_reply RCPT 250",
+            ]
+        );
+    }
+
+    #[test]
+    fn should_collect_envelope_commands_for_policy_decisions() {
+        let mut conn = Connection::new(SessionConfig {
+            envelope_checks: true,
+            ..Default::default()
+        });
+        conn.server(b"220 mail.example.org ESMTP\r\n");
+        conn.client(b"EHLO client.example.org\r\n");
+        conn.server(b"250 mail.example.org\r\n");
+        assert!(conn.session.take_envelope_checks().is_empty());
+
+        conn.client(
+            b"MAIL FROM:<alice@example.org> SUBMITTER=secretary@example.org\r\n\
+              RCPT TO:<bob@example.com>\r\n",
+        );
+        let checks: Vec<_> = conn
+            .session
+            .take_envelope_checks()
+            .into_iter()
+            .map(|check| (check.verb, check.mailbox, check.submitter))
+            .collect();
+        assert_eq!(
+            checks,
+            vec![
+                (
+                    "MAIL",
+                    ByteString::from("alice@example.org"),
+                    Some(ByteString::from("secretary@example.org"))
+                ),
+                ("RCPT", ByteString::from("bob@example.com"), None),
+            ]
+        );
+        assert!(conn.session.take_envelope_checks().is_empty());
+        // commands are forwarded as is until a decision is made
+        assert_eq!(
+            conn.to_upstream,
+            b"EHLO client.example.org\r\n\
+              MAIL FROM:<alice@example.org> SUBMITTER=secretary@example.org\r\n\
+              RCPT TO:<bob@example.com>\r\n"
+                .to_vec()
+        );
+        assert_eq!(
+            conn.records("command "),
+            vec!["command EHLO", "command MAIL", "command RCPT"]
+        );
+    }
+
+    #[test]
+    fn should_collect_messages_for_policy_decisions() {
+        let mut conn = Connection::new(SessionConfig {
+            content_checks: true,
+            ..Default::default()
+        });
+        conn.server(b"220 mail.example.org ESMTP\r\n");
+        conn.client(b"HELO client.example.org\r\n");
+        conn.server(b"250 mail.example.org\r\n");
+        conn.client(b"MAIL FROM:<alice@example.org>\r\n");
+        conn.server(b"250 OK\r\n");
+        conn.client(b"RCPT TO:<bob@example.com>\r\n");
+        conn.server(b"250 OK\r\n");
+        conn.client(b"DATA\r\n");
+        conn.server(b"354 Go ahead\r\n");
+        conn.client(b"Subject: Hello\r\n\r\n..hidden\r\n");
+        assert!(conn.session.take_content_checks().is_empty());
+
+        conn.client(b".\r\n");
+        let checks: Vec<_> = conn
+            .session
+            .take_content_checks()
+            .into_iter()
+            .map(|check| check.message)
+            .collect();
+        // without the terminating line and with dot-stuffing undone
+        assert_eq!(
+            checks,
+            vec![ByteString::from("Subject: Hello\r\n\r\n.hidden\r\n")]
+        );
+        // the message is forwarded as is until a decision is made
+        assert!(conn
+            .to_upstream
+            .ends_with(b"DATA\r\nSubject: Hello\r\n\r\n..hidden\r\n.\r\n"));
+        assert_eq!(conn.records("transaction"), vec!["transaction_commit"]);
+    }
+
+    #[test]
+    fn should_report_mailboxes_of_transactions() {
+        let mut conn = Connection::new(SessionConfig {
+            transaction_events: true,
+            ..Default::default()
+        });
+        conn.server(b"220 mail.example.org ESMTP\r\n");
+        conn.client(b"HELO client.example.org\r\n");
+        conn.server(b"250 mail.example.org\r\n");
+        conn.client(b"MAIL FROM:<> SIZE=512\r\n");
+        conn.server(b"250 OK\r\n");
+        conn.client(b"RCPT TO:<bob@example.com> NOTIFY=NEVER\r\n");
+        conn.server(b"250 OK\r\n");
+        conn.client(b"DATA\r\n");
+        conn.server(b"354 Go ahead\r\n");
+        conn.client(b"Subject: Hello\r\n\r\nHi Bob\r\n.\r\n");
+        conn.server(b"250 Queued\r\n");
+
+        let summaries: Vec<_> = conn
+            .session
+            .take_events()
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::Transaction(summary) => Some(json!(summary)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0]["from"], json!("<>"));
+        assert_eq!(summaries[0]["to"], json!(["bob@example.com"]));
+    }
+
+    #[test]
+    fn should_count_unterminated_command_at_end_of_stream() {
+        let mut conn = Connection::new(SessionConfig::default());
+        conn.server(b"220 mail.example.org ESMTP\r\n");
+        conn.client(b"HELO client.example.org\r\n");
+        conn.server(b"250 mail.example.org\r\n");
+        conn.client(b"QUIT");
+        assert_eq!(conn.session.partial_command_size(), 4);
+
+        conn.session.on_downstream_end_of_stream().unwrap();
+        assert_eq!(conn.session.partial_command_size(), 0);
+        assert_eq!(conn.session.totals().commands, 2);
+        assert!(conn.to_upstream.ends_with(b"\r\nQUIT"));
+        assert_eq!(
+            conn.records("command "),
+            vec!["command HELO", "command QUIT"]
+        );
+    }
+
+    #[test]
+    fn should_abort_message_at_client_end_of_stream() {
+        let mut conn = Connection::new(SessionConfig::default());
+        conn.start_message();
+        conn.client(b"Subject: Hello\r\n\r\nHi");
+        assert_eq!(conn.session.mode(), Mode::Data);
+
+        conn.session.on_downstream_end_of_stream().unwrap();
+        assert_eq!(conn.session.mode(), Mode::Command);
+        assert_eq!(conn.records("transaction"), vec!["transaction_abort"]);
+        assert!(conn.session.snapshot().transaction.is_none());
+    }
+
+    #[test]
+    fn should_abort_pending_commit_at_server_end_of_stream() {
+        let mut conn = Connection::new(SessionConfig::default());
+        conn.start_message();
+        conn.client(b"Subject: Hello\r\n\r\nHi Bob\r\n.\r\n");
+        conn.server(b"250 Que");
+
+        conn.session.on_upstream_end_of_stream().unwrap();
+        assert_eq!(
+            conn.records("transaction"),
+            vec!["transaction_commit", "transaction_abort"]
+        );
+        // no reply is expected anymore
+        assert!(conn.session.snapshot().pending_replies.is_empty());
+    }
+
+    #[test]
+    fn should_close_session_after_quit() {
+        let mut conn = Connection::new(SessionConfig::default());
+        conn.start_message();
+        conn.client(b"Subject: Hello\r\n\r\nHi Bob\r\n.\r\nQUIT\r\n");
+        conn.server(b"250 Queued\r\n221 Bye\r\n");
+
+        conn.session.on_connection_closed().unwrap();
+        // subsequent calls have no effect
+        conn.session.on_connection_closed().unwrap();
+        assert_eq!(
+            conn.records("connection_close"),
+            vec!["connection_close graceful=true"]
+        );
+        assert_eq!(conn.records("session_end"), vec!["session_end messages=1"]);
+        assert!(conn.records("transaction_abort").is_empty());
+    }
+
+    #[test]
+    fn should_abort_transactions_upon_connection_close() {
+        let mut conn = Connection::new(SessionConfig::default());
+        conn.start_message();
+        conn.client(b"Subject: Hello\r\n\r\nHi Bob\r\n.\r\n");
+        conn.client(b"MAIL FROM:<alice@example.org>\r\n");
+        conn.server(b"250 Queued\r\n250 OK\r\n");
+        conn.client(b"RCPT TO:<carol@example.com>\r\nDATA\r\n");
+        conn.server(b"250 OK\r\n354 Go ahead\r\n");
+        conn.client(b"Subject: Hi again\r\n\r\nHi Carol\r\n.\r\n");
+
+        conn.session.on_connection_closed().unwrap();
+        assert_eq!(
+            conn.records("transaction"),
+            vec![
+                "transaction_commit",
+                "transaction_commit_reply 250",
+                "transaction_commit",
+                "transaction_abort",
+            ]
+        );
+        assert_eq!(
+            conn.records("connection_close"),
+            vec!["connection_close graceful=false"]
+        );
+        assert_eq!(conn.records("session_end"), vec!["session_end messages=1"]);
+    }
+
+    #[test]
+    fn should_only_end_session_upon_connection_close_in_pass_through_mode() {
+        let mut conn = Connection::new(SessionConfig::default());
+        conn.server(b"220 mail.example.org ESMTP\r\n");
+        conn.client(b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03");
+        assert_eq!(conn.session.mode(), Mode::PassThrough);
+
+        conn.session.on_connection_closed().unwrap();
+        assert!(conn.records("connection_close").is_empty());
+        assert_eq!(conn.records("session_end"), vec!["session_end messages=0"]);
+    }
+
+    #[test]
+    fn should_keep_original_recipients_in_order_after_denied_one() {
+        let config = SessionConfig {
+            recipient_rewrite: Some(RecipientRewrite {
+                aliases: HashMap::from([(
+                    "alias@example.org".to_owned(),
+                    "real@example.org".to_owned(),
+                )]),
+                ..Default::default()
+            }),
+            lists: Some(Rc::new(PolicyLists {
+                recipients: AccessList {
+                    deny: vec!["bad@example.org".to_owned()],
+                    ..Default::default()
+                },
+                ..Default::default()
+            })),
+            local_replies: LocalReplies {
+                recipient_denied: Some("550 5.7.1 Recipient {recipient} denied".to_owned()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut session = Session::new(config, ());
+        session.on_new_conection().unwrap();
+        for (client, data) in [
+            (false, &b"220 mail.example.org ESMTP\r\n"[..]),
+            (true, b"EHLO client.example.org\r\n"),
+            (false, b"250 mail.example.org\r\n"),
+            (true, b"MAIL FROM:<alice@example.org>\r\n"),
+            (false, b"250 Ok\r\n"),
+            (true, b"RCPT TO:<bad@example.org>\r\n"),
+            (false, b"250 Ok\r\n"),
+            (true, b"RCPT TO:<alias@example.org>\r\n"),
+            (false, b"250 Ok\r\n"),
+            (true, b"RCPT TO:<plain@example.org>\r\n"),
+            (false, b"250 Ok\r\n"),
+        ] {
+            if client {
+                session.on_downstream_data(data.to_vec().into()).unwrap();
+                session.take_downstream_edits();
+            } else {
+                session.on_upstream_data(data.to_vec().into()).unwrap();
+                session.take_upstream_edits();
+            }
+        }
+        let transaction = session.snapshot().transaction.unwrap();
+        assert_eq!(
+            json!(transaction.to),
+            json!([
+                {"to": "real@example.org", "original_to": "alias@example.org", "domain": "example.org"},
+                {"to": "plain@example.org", "original_to": null, "domain": "example.org"},
+            ])
+        );
+        assert!(session.original_recipients.is_empty());
+    }
+
+    #[test]
+    fn should_record_envelope_with_tap_on_commands() {
+        let mut conn = Connection::new(SessionConfig {
+            tap: Some(Tap::Commands),
+            ..Default::default()
+        });
+        conn.client(b"EHLO client.example.org\r\n");
+        conn.client(b"MAIL FROM:<alice@example.org> SUBMITTER=secretary@example.org\r\n");
+        conn.client(b"RCPT TO:<bob@example.com>\r\n");
+        conn.client(b"RCPT TO:<carol@example.org>\r\n");
+        conn.client(b"DATA\r\n");
+        assert_eq!(conn.session.mode(), Mode::Data);
+
+        let transaction = conn.session.snapshot().transaction.unwrap();
+        let transaction = json!(transaction);
+        assert_eq!(transaction["from"], json!("alice@example.org"));
+        assert_eq!(transaction["submitter"], json!("secretary@example.org"));
+        assert_eq!(
+            transaction["to"],
+            json!([
+                {"to": "bob@example.com", "original_to": null, "domain": "example.com"},
+                {"to": "carol@example.org", "original_to": null, "domain": "example.org"},
+            ])
+        );
+
+        conn.client(b"Subject: Hello\r\n\r\nHi Bob\r\n.\r\n");
+        assert_eq!(conn.session.mode(), Mode::Command);
+        let summary = conn.session.summary();
+        assert_eq!(
+            summary.mail_from,
+            Some(ByteString::from(
+                "FROM:<alice@example.org> SUBMITTER=secretary@example.org"
+            ))
+        );
+        assert_eq!(
+            summary.rcpt_to,
+            vec![
+                ByteString::from("TO:<bob@example.com>"),
+                ByteString::from("TO:<carol@example.org>"),
+            ]
+        );
+        assert_eq!(summary.rcpt_count, 2);
+        assert_eq!(conn.records("transaction"), vec!["transaction_commit"]);
+    }
+
+    #[test]
+    fn should_count_replies_with_tap_on_replies() {
+        let mut conn = Connection::new(SessionConfig {
+            tap: Some(Tap::Replies),
+            ..Default::default()
+        });
+        conn.server(b"220 mail.example.org ESMTP\r\n");
+        // commands are not visible, even if some data gets through
+        conn.client(b"EHLO client.example.org\r\n");
+        conn.server(b"250-mail.example.org\r\n250 PIPELINING\r\n");
+        conn.server(b"250 OK\r\n250 OK\r\n354 Go ahead\r\n");
+        conn.server(b"250 Queued\r\n");
+        conn.server(b"221 Bye\r\n");
+
+        assert_eq!(conn.session.mode(), Mode::Command);
+        assert_eq!(
+            conn.stats.records.borrow().clone(),
+            vec![
+                "connect_reply 220",
+                "uncorrelated_reply 250",
+                "uncorrelated_reply 250",
+                "uncorrelated_reply 250",
+                "uncorrelated_reply 354",
+                "uncorrelated_reply 250",
+                "uncorrelated_reply 221",
+            ]
+        );
+        assert_eq!(
+            conn.session.summary().last_reply_code,
+            Some(ReplyCode::SERVICE_CLOSING)
+        );
+        assert_eq!(
+            conn.to_downstream,
+            b"220 mail.example.org ESMTP\r\n\
+              250-mail.example.org\r\n250 PIPELINING\r\n\
+              250 OK\r\n250 OK\r\n354 Go ahead\r\n\
+              250 Queued\r\n\
+              221 Bye\r\n"
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn should_not_track_original_recipients_with_tap_on_commands() {
+        let config = SessionConfig {
+            tap: Some(Tap::Commands),
+            recipient_rewrite: Some(RecipientRewrite {
+                domains: HashMap::from([("example.net".to_owned(), "example.org".to_owned())]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut session = Session::new(config, ());
+        session.on_new_conection().unwrap();
+        for data in [
+            &b"EHLO client.example.org\r\n"[..],
+            b"MAIL FROM:<alice@example.org>\r\n",
+            b"RCPT TO:<bob@example.net>\r\n",
+            b"RCPT TO:<carol@example.org>\r\n",
+        ] {
+            session.on_downstream_data(data.to_vec().into()).unwrap();
+            session.take_downstream_edits();
+        }
+        assert!(session.original_recipients.is_empty());
+    }
+
+    /// Returns a strict session configured to take a given action on every
+    /// class of protocol parsing errors.
+    fn with_fallback(action: FallbackAction) -> Connection {
+        let config = SessionConfig {
+            fallback: FallbackConfig {
+                command: action,
+                reply: action,
+                sequence: action,
+            },
+            strictness: Strictness::Strict,
+            ..Default::default()
+        };
+        let mut conn = Connection::new(config);
+        conn.server(b"220 mail.example.org ESMTP\r\n");
+        conn.client(b"HELO client.example.org\r\n");
+        conn.server(b"250 mail.example.org\r\n");
+        conn
+    }
+
+    #[test]
+    fn should_pass_connection_through_upon_parse_error() {
+        let mut conn = with_fallback(FallbackAction::PassThrough);
+        conn.client(b"XYZZY\r\n");
+        assert_eq!(conn.session.mode(), Mode::PassThrough);
+        assert!(!conn.session.take_close_request());
+
+        conn.server(b"501 Syntax error\r\n");
+        conn.client(b"MAIL FROM:<alice@example.org>\r\n");
+        conn.server(b"250 OK\r\n");
+        assert_eq!(
+            conn.to_upstream,
+            b"HELO client.example.org\r\n\
+              XYZZY\r\n\
+              MAIL FROM:<alice@example.org>\r\n"
+                .to_vec()
+        );
+        assert!(conn
+            .to_downstream
+            .ends_with(b"501 Syntax error\r\n250 OK\r\n"));
+        assert_eq!(conn.records("parse_error"), vec!["parse_error PassThrough"]);
+        // the rest of the connection is not interpreted
+        assert_eq!(conn.records("command "), vec!["command HELO"]);
+    }
+
+    #[test]
+    fn should_request_close_upon_parse_error() {
+        let mut conn = with_fallback(FallbackAction::Close);
+        conn.client(b"XYZZY\r\n");
+        assert_eq!(conn.session.mode(), Mode::PassThrough);
+        assert!(conn.session.take_close_request());
+        assert_eq!(
+            conn.to_upstream,
+            b"HELO client.example.org\r\nXYZZY\r\n".to_vec()
+        );
+        assert_eq!(conn.records("parse_error"), vec!["parse_error Close"]);
+    }
+
+    #[test]
+    fn should_resync_at_next_line_upon_parse_error() {
+        let mut conn = with_fallback(FallbackAction::Resync);
+        conn.client(b"XYZZY\r\nMAIL FROM:<alice@example.org>\r\n");
+        assert_ne!(conn.session.mode(), Mode::PassThrough);
+        assert!(!conn.session.take_close_request());
+
+        // the reply to the unparsed line is skipped rather than taken for the next one
+        conn.server(b"501 Syntax error\r\n250 OK\r\n");
+        // so is a malformed reply
+        conn.client(b"RCPT TO:<bob@example.com>\r\n");
+        conn.server(b"25O OK\r\n");
+        conn.client(b"RCPT TO:<carol@example.com>\r\n");
+        conn.server(b"250 OK\r\n");
+        assert_eq!(
+            conn.to_upstream,
+            b"HELO client.example.org\r\n\
+              XYZZY\r\n\
+              MAIL FROM:<alice@example.org>\r\n\
+              RCPT TO:<bob@example.com>\r\n\
+              RCPT TO:<carol@example.com>\r\n"
+                .to_vec()
+        );
+        assert_eq!(
+            conn.records("parse_error"),
+            vec!["parse_error Resync", "parse_error Resync"]
+        );
+        assert_eq!(
+            conn.records("command_reply"),
+            vec![
+                "command_reply HELO 250",
+                "command_reply MAIL 250",
+                "command_reply RCPT 250"
+            ]
+        );
+        assert!(conn.records("uncorrelated_reply").is_empty());
+    }
+
+    #[test]
+    fn should_turn_down_mail_transaction_while_draining() {
+        let mut conn = Connection::new(SessionConfig::default());
+        conn.server(b"220 mail.example.org ESMTP\r\n");
+        conn.client(b"HELO client.example.org\r\n");
+        conn.server(b"250 mail.example.org\r\n");
+
+        conn.session.drain();
+        conn.client(b"MAIL FROM:<alice@example.org>\r\n");
+        conn.server(b"221 Bye\r\n");
+        assert_eq!(
+            conn.to_upstream,
+            b"HELO client.example.org\r\nQUIT\r\n".to_vec()
+        );
+        assert!(conn
+            .to_downstream
+            .ends_with(b"\r\n421 4.3.2 Service shutting down\r\n"));
+        assert_eq!(conn.records("drain"), vec!["drain"]);
+        assert!(!conn.session.take_close_request());
+    }
+
+    #[test]
+    fn should_render_drain_reply_from_template() {
+        let mut config = SessionConfig::default();
+        config.local_replies.drain = Some("421 4.3.2 Try mx2.example.org".to_owned());
+        let mut conn = Connection::new(config);
+        conn.server(b"220 mail.example.org ESMTP\r\n");
+        conn.client(b"HELO client.example.org\r\n");
+        conn.server(b"250 mail.example.org\r\n");
+
+        conn.session.drain();
+        conn.client(b"MAIL FROM:<alice@example.org>\r\n");
+        conn.server(b"221 Bye\r\n");
+        assert!(conn
+            .to_downstream
+            .ends_with(b"\r\n421 4.3.2 Try mx2.example.org\r\n"));
+        assert_eq!(conn.records("drain"), vec!["drain"]);
+    }
+
+    #[test]
+    fn should_close_connection_if_mail_transaction_cannot_be_turned_down() {
+        let mut conn = Connection::new(SessionConfig::default());
+        conn.server(b"220 mail.example.org ESMTP\r\n");
+        conn.client(b"HELO client.example.org\r\n");
+        conn.server(b"250 mail.example.org\r\n");
+
+        conn.session.drain();
+        // the beginning of the command has already been forwarded
+        conn.client(b"MAIL FROM:<alice@");
+        conn.client(b"example.org>\r\n");
+        assert_eq!(
+            conn.to_upstream,
+            b"HELO client.example.org\r\nMAIL FROM:<alice@example.org>\r\n".to_vec()
+        );
+        assert!(conn.session.take_close_request());
+        assert_eq!(conn.session.mode(), Mode::PassThrough);
+        assert!(conn.records("drain").is_empty());
+        assert_eq!(
+            conn.records("local_reply_fallback_close"),
+            vec!["local_reply_fallback_close"]
+        );
+    }
+
+    /// Returns a session that denies `mallory@example.org` as either
+    /// a sender or a recipient with given local replies.
+    fn with_deny_list(local_replies: LocalReplies) -> Connection {
+        let config = SessionConfig {
+            lists: Some(Rc::new(PolicyLists {
+                senders: AccessList {
+                    deny: vec!["mallory@example.org".to_owned()],
+                    ..Default::default()
+                },
+                recipients: AccessList {
+                    deny: vec!["mallory@example.org".to_owned()],
+                    ..Default::default()
+                },
+                ..Default::default()
+            })),
+            local_replies,
+            ..Default::default()
+        };
+        let mut conn = Connection::new(config);
+        conn.server(b"220 mail.example.org ESMTP\r\n");
+        conn.client(b"HELO client.example.org\r\n");
+        conn.server(b"250 mail.example.org\r\n");
+        conn
+    }
+
+    #[test]
+    fn should_render_sender_denied_reply_from_template() {
+        let mut conn = with_deny_list(LocalReplies {
+            sender_denied: Some("550 5.7.1 Sender {sender} is not accepted here".to_owned()),
+            ..Default::default()
+        });
+        conn.client(b"MAIL FROM:<mallory@example.org>\r\n");
+        conn.server(b"250 2.0.0 OK\r\n");
+        assert_eq!(
+            conn.to_upstream,
+            b"HELO client.example.org\r\nNOOP\r\n".to_vec()
+        );
+        assert!(conn
+            .to_downstream
+            .ends_with(b"\r\n550 5.7.1 Sender mallory@example.org is not accepted here\r\n"));
+        assert_eq!(
+            conn.records("command_reply MAIL"),
+            vec!["command_reply MAIL 550"]
+        );
+        assert!(!conn.session.take_close_request());
+    }
+
+    #[test]
+    fn should_render_recipient_denied_reply_from_template() {
+        let mut conn = with_deny_list(LocalReplies {
+            recipient_denied: Some(
+                "550 5.7.1 Mail from {sender} to {recipient} is not accepted".to_owned(),
+            ),
+            ..Default::default()
+        });
+        conn.client(b"MAIL FROM:<alice@example.org>\r\n");
+        conn.server(b"250 OK\r\n");
+        conn.client(b"RCPT TO:<mallory@example.org>\r\n");
+        conn.server(b"250 2.0.0 OK\r\n");
+        assert_eq!(
+            conn.to_upstream,
+            b"HELO client.example.org\r\n\
+              MAIL FROM:<alice@example.org>\r\n\
+              NOOP\r\n"
+                .to_vec()
+        );
+        assert!(conn.to_downstream.ends_with(
+            b"\r\n550 5.7.1 Mail from alice@example.org to mallory@example.org is not accepted\r\n"
+        ));
+        assert_eq!(
+            conn.records("command_reply RCPT"),
+            vec!["command_reply RCPT 550"]
+        );
+        assert!(!conn.session.take_close_request());
+    }
+
+    #[test]
+    fn should_render_auth_throttled_reply_from_template() {
+        let config = SessionConfig {
+            max_auth_failures: Some(1),
+            local_replies: LocalReplies {
+                auth_throttled: Some(
+                    "535 5.7.8 More than {limit} authentication failures".to_owned(),
+                ),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut conn = Connection::new(config);
+        conn.server(b"220 mail.example.org ESMTP\r\n");
+        conn.client(b"EHLO client.example.org\r\n");
+        conn.server(b"250-mail.example.org\r\n250 AUTH PLAIN\r\n");
+        conn.client(b"AUTH PLAIN AHVzZXIAd3Jvbmc=\r\n");
+        conn.server(b"535 5.7.8 Authentication credentials invalid\r\n");
+
+        conn.client(b"AUTH PLAIN AHVzZXIAc2VjcmV0\r\n");
+        conn.server(b"250 2.0.0 OK\r\n");
+        assert!(conn.to_upstream.ends_with(b"\r\nNOOP\r\n"));
+        assert!(conn
+            .to_downstream
+            .ends_with(b"\r\n535 5.7.8 More than 1 authentication failures\r\n"));
+        assert_eq!(
+            conn.records("command_reply AUTH"),
+            vec!["command_reply AUTH 535", "command_reply AUTH 535"]
+        );
+        assert!(!conn.session.take_close_request());
+    }
+
+    #[test]
+    fn should_close_connection_if_denied_command_cannot_be_turned_down() {
+        let mut conn = with_deny_list(LocalReplies {
+            sender_denied: Some("550 5.7.1 Sender {sender} is not accepted here".to_owned()),
+            ..Default::default()
+        });
+        // the beginning of the command has already been forwarded
+        conn.client(b"MAIL FROM:<mallory@");
+        conn.client(b"example.org>\r\n");
+        assert_eq!(
+            conn.to_upstream,
+            b"HELO client.example.org\r\nMAIL FROM:<mallory@example.org>\r\n".to_vec()
+        );
+        assert!(conn.session.take_close_request());
+        assert_eq!(conn.session.mode(), Mode::PassThrough);
+        assert_eq!(
+            conn.records("local_reply_fallback_close"),
+            vec!["local_reply_fallback_close"]
+        );
+    }
+
+    /// Returns a session with a given strictness that has completed the handshake.
+    fn with_strictness(strictness: Strictness) -> Connection {
+        let config = SessionConfig {
+            strictness,
+            ..Default::default()
+        };
+        let mut conn = Connection::new(config);
+        conn.server(b"220 mail.example.org ESMTP\r\n");
+        conn.client(b"HELO client.example.org\r\n");
+        conn.server(b"250 mail.example.org\r\n");
+        conn
+    }
+
+    #[test]
+    fn should_treat_out_of_sequence_commands_as_errors_in_strict_mode() {
+        let mut conn = with_strictness(Strictness::Strict);
+        conn.client(b"RCPT TO:<bob@example.com>\r\n");
+        assert_eq!(conn.session.mode(), Mode::PassThrough);
+        conn.server(b"503 5.5.1 Need MAIL first\r\n");
+        assert_eq!(
+            conn.to_upstream,
+            b"HELO client.example.org\r\nRCPT TO:<bob@example.com>\r\n".to_vec()
+        );
+        assert!(conn
+            .to_downstream
+            .ends_with(b"\r\n503 5.5.1 Need MAIL first\r\n"));
+        assert_eq!(conn.records("parse_error"), vec!["parse_error PassThrough"]);
+    }
+
+    #[test]
+    fn should_treat_overlong_commands_as_errors_in_strict_mode() {
+        let mut conn = with_strictness(Strictness::Strict);
+        let mut line = b"MAIL FROM:<".to_vec();
+        line.extend_from_slice(&[b'a'; 500]);
+        line.extend_from_slice(b"@example.org>\r\n");
+        conn.client(&line);
+        assert_eq!(conn.session.mode(), Mode::PassThrough);
+        assert!(conn.to_upstream.ends_with(&line));
+        assert_eq!(conn.records("parse_error"), vec!["parse_error PassThrough"]);
+        assert_eq!(conn.records("command "), vec!["command HELO"]);
+    }
+
+    #[test]
+    fn should_tolerate_what_can_be_parsed_in_lenient_mode() {
+        let mut conn = with_strictness(Strictness::Lenient);
+        conn.client(b"XYZZY\r\n");
+        conn.server(b"500 5.5.2 Unrecognized command\r\n");
+        conn.client(b"RCPT TO:<bob@example.com>\r\n");
+        conn.server(b"503 5.5.1 Need MAIL first\r\n");
+        assert_eq!(conn.session.mode(), Mode::Command);
+        assert_eq!(
+            conn.to_upstream,
+            b"HELO client.example.org\r\n\
+              XYZZY\r\n\
+              RCPT TO:<bob@example.com>\r\n"
+                .to_vec()
+        );
+        assert!(conn.records("parse_error").is_empty());
+        assert_eq!(
+            conn.records("command_reply"),
+            vec![
+                "command_reply HELO 250",
+                "command_reply XYZZY 500",
+                "command_reply RCPT 503"
+            ]
+        );
+
+        // a bare LF doesn't end a command line
+        conn.client(b"MAIL FROM:<alice@example.org>\n");
+        assert!(conn.records("command MAIL").is_empty());
+    }
+
+    #[test]
+    fn should_accept_bare_lf_line_endings_in_permissive_mode() {
+        let mut conn = with_strictness(Strictness::Permissive);
+        conn.client(b"MAIL FROM:<alice@example.org>\n");
+        conn.server(b"250 OK\n");
+        conn.client(b"RCPT TO:<bob@example.com>\n");
+        conn.server(b"250 OK\r\n");
+        assert_eq!(conn.session.mode(), Mode::Command);
+        assert_eq!(
+            conn.to_upstream,
+            b"HELO client.example.org\r\n\
+              MAIL FROM:<alice@example.org>\n\
+              RCPT TO:<bob@example.com>\n"
+                .to_vec()
+        );
+        assert!(conn.records("parse_error").is_empty());
+        assert_eq!(
+            conn.records("command_reply"),
+            vec![
+                "command_reply HELO 250",
+                "command_reply MAIL 250",
+                "command_reply RCPT 250"
+            ]
+        );
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bstr::ByteSlice;
+use serde::{Serialize, Serializer};
+
+use super::session::{Handshake, Mode, Transaction};
+
+/// SessionSnapshot represents externally visible state of a session,
+/// e.g. to compare it against the expected one in tests.
+///
+/// A session can be restored from a snapshot with `Session::restore`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SessionSnapshot {
+    pub mode: Mode,
+    pub handshake: Option<Handshake>,
+    /// Replies SMTP server is yet to send, in order.
+    pub pending_replies: Vec<PendingReplySnapshot>,
+    /// Mail transaction in progress, if any.
+    pub transaction: Option<Transaction>,
+}
+
+/// PendingReplySnapshot represents a kind of reply SMTP server is yet to send.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PendingReplySnapshot {
+    Connect,
+    /// Reply to a command with a given line without the trailing `CRLF`.
+    ///
+    /// Only the verb is serialized since arguments may carry credentials.
+    Command {
+        #[serde(rename = "verb", serialize_with = "verb")]
+        line: Vec<u8>,
+    },
+    Commit {
+        transaction: Box<Transaction>,
+    },
+    Drain,
+    Rejected {
+        verb: &'static str,
+        #[serde(skip)]
+        reply: Vec<u8>,
+    },
+    Unparsed,
+    Injected {
+        verb: &'static str,
+    },
+}
+
+fn verb<S: Serializer>(line: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    let verb = line.split_str(" ").next().unwrap_or_default();
+    serializer.serialize_str(&verb.to_str_lossy().to_ascii_uppercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::smtp::agent::{Session, SessionConfig};
+
+    #[test]
+    fn should_restore_session_from_snapshot() {
+        let mut session = Session::new(SessionConfig::default(), ());
+        session.on_new_conection().unwrap();
+        session
+            .on_upstream_data(b"220 mx.example.org ESMTP\r\n".to_vec().into())
+            .unwrap();
+        session
+            .on_downstream_data(
+                b"EHLO client.example.org\r\nMAIL FROM:<>\r\n"
+                    .to_vec()
+                    .into(),
+            )
+            .unwrap();
+        let snapshot = session.snapshot();
+        assert_eq!(
+            json!(snapshot.pending_replies),
+            json!([{"kind": "command", "verb": "EHLO"}, {"kind": "command", "verb": "MAIL"}])
+        );
+
+        let mut restored = Session::new(SessionConfig::default(), ());
+        restored.restore(snapshot.clone()).unwrap();
+        assert_eq!(restored.snapshot(), snapshot);
+    }
+}
@@ -14,10 +14,14 @@
 
 use std::ops::Deref;
 use std::rc::Rc;
+use std::time::Duration;
 
-use envoy::extension::Result;
+use anyhow::Result;
 
-use crate::smtp::spec::core::ReplyCode;
+use super::config::FallbackAction;
+use super::session::{Handshake, SessionSummary};
+use crate::smtp::error::ErrorCategory;
+use crate::smtp::spec::core::{Capability, ReplyCode};
 
 pub trait StatsSink {
     fn on_smtp_connect(&self) -> Result<()> {
@@ -32,10 +36,81 @@ pub trait StatsSink {
         Ok(())
     }
 
+    fn on_smtp_handshake(&self, _handshake: Handshake, _fallback: bool) -> Result<()> {
+        Ok(())
+    }
+
     fn on_smtp_command_reply(&self, _verb: &str, _code: ReplyCode) -> Result<()> {
         Ok(())
     }
 
+    fn on_smtp_recipient_reply(&self, _domain: &[u8], _code: ReplyCode) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_smtp_ehlo_capabilities(&self, _capabilities: &[Capability]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Is called once SMTP server has completed an AUTH exchange with a given
+    /// mechanism in upper case, i.e. with any reply other than a challenge.
+    fn on_smtp_auth_reply(&self, _mechanism: &str, _code: ReplyCode) -> Result<()> {
+        Ok(())
+    }
+
+    /// Is called once SMTP server has reported an error of an OAuth mechanism
+    /// in a `334` challenge rather than failing the exchange right away.
+    fn on_smtp_auth_error_report(&self, _mechanism: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Is called when AUTH command is turned down since the client has failed
+    /// to authenticate too many times.
+    fn on_smtp_auth_throttled(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Is called on BURL command, which appends content referenced by a URL
+    /// to the message rather than sending it.
+    fn on_smtp_burl(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Is called on an obsolete command, e.g. TURN, which is passed through
+    /// to SMTP server as is.
+    fn on_smtp_legacy_command(&self, _verb: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Is called on RCPT command addressed to the postmaster, which is exempt
+    /// from deny lists and envelope policy.
+    fn on_smtp_postmaster_recipient(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Is called on MAIL command with a sender that matches a VERP pattern.
+    fn on_smtp_verp_sender(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Is called on RCPT command that is subject to a recipient rewrite
+    /// but cannot be rewritten, so that it is forwarded as is.
+    fn on_smtp_recipient_rewrite_failure(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Is called when SMTP server has accepted a delivery status notification
+    /// sent by the null sender, i.e. a bounce.
+    fn on_smtp_bounce(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Is called once per accepted recipient of a mail transaction
+    /// upon a reply to the transaction commit.
+    fn on_smtp_delivery_reply(&self, _domain: &[u8], _code: ReplyCode) -> Result<()> {
+        Ok(())
+    }
+
     fn on_smtp_transaction_commit(&self) -> Result<()> {
         Ok(())
     }
@@ -44,11 +119,95 @@ pub trait StatsSink {
         Ok(())
     }
 
-    fn on_smtp_parse_error(&self) -> Result<()> {
+    /// Is called along with `on_smtp_transaction_commit_reply` for mail transactions
+    /// that have followed a successful AUTH exchange.
+    fn on_smtp_authenticated_transaction_commit_reply(&self, _code: ReplyCode) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_smtp_starttls_upgrade(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_smtp_implicit_tls(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_smtp_not_smtp(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_smtp_transaction_abort(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Is called once SMTP server has replied to a mail transaction with the
+    /// time since MAIL and DATA commands have been accepted.
+    fn on_smtp_transaction_timing(
+        &self,
+        _duration: Duration,
+        _data_duration: Duration,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_smtp_reply_code_mismatch(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_smtp_uncorrelated_reply(&self, _code: ReplyCode) -> Result<()> {
+        Ok(())
+    }
+
+    /// Is called when a reply cannot be correlated with a pending command,
+    /// e.g. when no command is pending.
+    fn on_smtp_reply_correlation_error(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_smtp_reply_rewrite(&self, _rule: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Is called on a protocol error with the category of the error,
+    /// unless it is a failure of the host.
+    fn on_smtp_parse_error(
+        &self,
+        _action: FallbackAction,
+        _category: Option<ErrorCategory>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_smtp_drain(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Is called when a command that is to be turned down with a local reply
+    /// cannot be replaced, so that the connection is closed instead.
+    fn on_smtp_local_reply_fallback_close(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Is called when the mailbox of a MAIL or RCPT command is on a deny list.
+    fn on_smtp_list_denied(&self, _verb: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_smtp_connection_close(&self, _graceful: bool) -> Result<()> {
+        Ok(())
+    }
+
+    /// Is called once per session upon teardown, even if it has fallen back
+    /// into no-op mode.
+    fn on_smtp_session_end(&self, _summary: &SessionSummary) -> Result<()> {
         Ok(())
     }
 }
 
+/// Discards stats, e.g. for a session outside of `Envoy`.
+impl StatsSink for () {}
+
 impl<T: StatsSink> StatsSink for Rc<T> {
     fn on_smtp_connect(&self) -> Result<()> {
         self.deref().on_smtp_connect()
@@ -62,10 +221,62 @@ impl<T: StatsSink> StatsSink for Rc<T> {
         self.deref().on_smtp_command(verb)
     }
 
+    fn on_smtp_handshake(&self, handshake: Handshake, fallback: bool) -> Result<()> {
+        self.deref().on_smtp_handshake(handshake, fallback)
+    }
+
     fn on_smtp_command_reply(&self, verb: &str, code: ReplyCode) -> Result<()> {
         self.deref().on_smtp_command_reply(verb, code)
     }
 
+    fn on_smtp_recipient_reply(&self, domain: &[u8], code: ReplyCode) -> Result<()> {
+        self.deref().on_smtp_recipient_reply(domain, code)
+    }
+
+    fn on_smtp_ehlo_capabilities(&self, capabilities: &[Capability]) -> Result<()> {
+        self.deref().on_smtp_ehlo_capabilities(capabilities)
+    }
+
+    fn on_smtp_auth_reply(&self, mechanism: &str, code: ReplyCode) -> Result<()> {
+        self.deref().on_smtp_auth_reply(mechanism, code)
+    }
+
+    fn on_smtp_auth_error_report(&self, mechanism: &str) -> Result<()> {
+        self.deref().on_smtp_auth_error_report(mechanism)
+    }
+
+    fn on_smtp_auth_throttled(&self) -> Result<()> {
+        self.deref().on_smtp_auth_throttled()
+    }
+
+    fn on_smtp_burl(&self) -> Result<()> {
+        self.deref().on_smtp_burl()
+    }
+
+    fn on_smtp_legacy_command(&self, verb: &str) -> Result<()> {
+        self.deref().on_smtp_legacy_command(verb)
+    }
+
+    fn on_smtp_postmaster_recipient(&self) -> Result<()> {
+        self.deref().on_smtp_postmaster_recipient()
+    }
+
+    fn on_smtp_verp_sender(&self) -> Result<()> {
+        self.deref().on_smtp_verp_sender()
+    }
+
+    fn on_smtp_recipient_rewrite_failure(&self) -> Result<()> {
+        self.deref().on_smtp_recipient_rewrite_failure()
+    }
+
+    fn on_smtp_bounce(&self) -> Result<()> {
+        self.deref().on_smtp_bounce()
+    }
+
+    fn on_smtp_delivery_reply(&self, domain: &[u8], code: ReplyCode) -> Result<()> {
+        self.deref().on_smtp_delivery_reply(domain, code)
+    }
+
     fn on_smtp_transaction_commit(&self) -> Result<()> {
         self.deref().on_smtp_transaction_commit()
     }
@@ -74,7 +285,312 @@ impl<T: StatsSink> StatsSink for Rc<T> {
         self.deref().on_smtp_transaction_commit_reply(code)
     }
 
-    fn on_smtp_parse_error(&self) -> Result<()> {
-        self.deref().on_smtp_parse_error()
+    fn on_smtp_authenticated_transaction_commit_reply(&self, code: ReplyCode) -> Result<()> {
+        self.deref()
+            .on_smtp_authenticated_transaction_commit_reply(code)
+    }
+
+    fn on_smtp_starttls_upgrade(&self) -> Result<()> {
+        self.deref().on_smtp_starttls_upgrade()
+    }
+
+    fn on_smtp_implicit_tls(&self) -> Result<()> {
+        self.deref().on_smtp_implicit_tls()
+    }
+
+    fn on_smtp_not_smtp(&self) -> Result<()> {
+        self.deref().on_smtp_not_smtp()
+    }
+
+    fn on_smtp_transaction_abort(&self) -> Result<()> {
+        self.deref().on_smtp_transaction_abort()
+    }
+
+    fn on_smtp_transaction_timing(
+        &self,
+        duration: Duration,
+        data_duration: Duration,
+    ) -> Result<()> {
+        self.deref()
+            .on_smtp_transaction_timing(duration, data_duration)
+    }
+
+    fn on_smtp_reply_code_mismatch(&self) -> Result<()> {
+        self.deref().on_smtp_reply_code_mismatch()
+    }
+
+    fn on_smtp_uncorrelated_reply(&self, code: ReplyCode) -> Result<()> {
+        self.deref().on_smtp_uncorrelated_reply(code)
+    }
+
+    fn on_smtp_reply_correlation_error(&self) -> Result<()> {
+        self.deref().on_smtp_reply_correlation_error()
+    }
+
+    fn on_smtp_reply_rewrite(&self, rule: &str) -> Result<()> {
+        self.deref().on_smtp_reply_rewrite(rule)
+    }
+
+    fn on_smtp_parse_error(
+        &self,
+        action: FallbackAction,
+        category: Option<ErrorCategory>,
+    ) -> Result<()> {
+        self.deref().on_smtp_parse_error(action, category)
+    }
+
+    fn on_smtp_drain(&self) -> Result<()> {
+        self.deref().on_smtp_drain()
+    }
+
+    fn on_smtp_local_reply_fallback_close(&self) -> Result<()> {
+        self.deref().on_smtp_local_reply_fallback_close()
+    }
+
+    fn on_smtp_list_denied(&self, verb: &str) -> Result<()> {
+        self.deref().on_smtp_list_denied(verb)
+    }
+
+    fn on_smtp_connection_close(&self, graceful: bool) -> Result<()> {
+        self.deref().on_smtp_connection_close(graceful)
+    }
+
+    fn on_smtp_session_end(&self, summary: &SessionSummary) -> Result<()> {
+        self.deref().on_smtp_session_end(summary)
+    }
+}
+
+/// Forwards each callback to several sinks, e.g. to Envoy stats and to an
+/// access log at the same time.
+///
+/// Every sink is called even if one of them fails, in which case the first
+/// error is returned.
+pub struct CompositeSink<'a, S> {
+    primary: S,
+    others: Vec<Rc<dyn StatsSink + 'a>>,
+}
+
+impl<'a, S: StatsSink> CompositeSink<'a, S> {
+    pub fn new(primary: S) -> Self {
+        CompositeSink {
+            primary,
+            others: Vec::new(),
+        }
+    }
+
+    /// Adds a sink that is called after the ones added earlier.
+    pub fn push(&mut self, sink: Rc<dyn StatsSink + 'a>) {
+        self.others.push(sink)
+    }
+
+    /// Returns the sink that is called first.
+    pub fn primary(&self) -> &S {
+        &self.primary
+    }
+
+    pub fn primary_mut(&mut self) -> &mut S {
+        &mut self.primary
+    }
+
+    fn each<F>(&self, callback: F) -> Result<()>
+    where
+        F: Fn(&dyn StatsSink) -> Result<()>,
+    {
+        let result = callback(&self.primary);
+        self.others
+            .iter()
+            .fold(result, |result, sink| result.and(callback(sink.as_ref())))
+    }
+}
+
+impl<'a, S: StatsSink> StatsSink for CompositeSink<'a, S> {
+    fn on_smtp_connect(&self) -> Result<()> {
+        self.each(|sink| sink.on_smtp_connect())
+    }
+
+    fn on_smtp_connect_reply(&self, code: ReplyCode) -> Result<()> {
+        self.each(|sink| sink.on_smtp_connect_reply(code))
+    }
+
+    fn on_smtp_command(&self, verb: &str) -> Result<()> {
+        self.each(|sink| sink.on_smtp_command(verb))
+    }
+
+    fn on_smtp_handshake(&self, handshake: Handshake, fallback: bool) -> Result<()> {
+        self.each(|sink| sink.on_smtp_handshake(handshake, fallback))
+    }
+
+    fn on_smtp_command_reply(&self, verb: &str, code: ReplyCode) -> Result<()> {
+        self.each(|sink| sink.on_smtp_command_reply(verb, code))
+    }
+
+    fn on_smtp_recipient_reply(&self, domain: &[u8], code: ReplyCode) -> Result<()> {
+        self.each(|sink| sink.on_smtp_recipient_reply(domain, code))
+    }
+
+    fn on_smtp_ehlo_capabilities(&self, capabilities: &[Capability]) -> Result<()> {
+        self.each(|sink| sink.on_smtp_ehlo_capabilities(capabilities))
+    }
+
+    fn on_smtp_auth_reply(&self, mechanism: &str, code: ReplyCode) -> Result<()> {
+        self.each(|sink| sink.on_smtp_auth_reply(mechanism, code))
+    }
+
+    fn on_smtp_auth_error_report(&self, mechanism: &str) -> Result<()> {
+        self.each(|sink| sink.on_smtp_auth_error_report(mechanism))
+    }
+
+    fn on_smtp_auth_throttled(&self) -> Result<()> {
+        self.each(|sink| sink.on_smtp_auth_throttled())
+    }
+
+    fn on_smtp_burl(&self) -> Result<()> {
+        self.each(|sink| sink.on_smtp_burl())
+    }
+
+    fn on_smtp_legacy_command(&self, verb: &str) -> Result<()> {
+        self.each(|sink| sink.on_smtp_legacy_command(verb))
+    }
+
+    fn on_smtp_postmaster_recipient(&self) -> Result<()> {
+        self.each(|sink| sink.on_smtp_postmaster_recipient())
+    }
+
+    fn on_smtp_verp_sender(&self) -> Result<()> {
+        self.each(|sink| sink.on_smtp_verp_sender())
+    }
+
+    fn on_smtp_recipient_rewrite_failure(&self) -> Result<()> {
+        self.each(|sink| sink.on_smtp_recipient_rewrite_failure())
+    }
+
+    fn on_smtp_bounce(&self) -> Result<()> {
+        self.each(|sink| sink.on_smtp_bounce())
+    }
+
+    fn on_smtp_delivery_reply(&self, domain: &[u8], code: ReplyCode) -> Result<()> {
+        self.each(|sink| sink.on_smtp_delivery_reply(domain, code))
+    }
+
+    fn on_smtp_transaction_commit(&self) -> Result<()> {
+        self.each(|sink| sink.on_smtp_transaction_commit())
+    }
+
+    fn on_smtp_transaction_commit_reply(&self, code: ReplyCode) -> Result<()> {
+        self.each(|sink| sink.on_smtp_transaction_commit_reply(code))
+    }
+
+    fn on_smtp_authenticated_transaction_commit_reply(&self, code: ReplyCode) -> Result<()> {
+        self.each(|sink| sink.on_smtp_authenticated_transaction_commit_reply(code))
+    }
+
+    fn on_smtp_starttls_upgrade(&self) -> Result<()> {
+        self.each(|sink| sink.on_smtp_starttls_upgrade())
+    }
+
+    fn on_smtp_implicit_tls(&self) -> Result<()> {
+        self.each(|sink| sink.on_smtp_implicit_tls())
+    }
+
+    fn on_smtp_not_smtp(&self) -> Result<()> {
+        self.each(|sink| sink.on_smtp_not_smtp())
+    }
+
+    fn on_smtp_transaction_abort(&self) -> Result<()> {
+        self.each(|sink| sink.on_smtp_transaction_abort())
+    }
+
+    fn on_smtp_transaction_timing(
+        &self,
+        duration: Duration,
+        data_duration: Duration,
+    ) -> Result<()> {
+        self.each(|sink| sink.on_smtp_transaction_timing(duration, data_duration))
+    }
+
+    fn on_smtp_reply_code_mismatch(&self) -> Result<()> {
+        self.each(|sink| sink.on_smtp_reply_code_mismatch())
+    }
+
+    fn on_smtp_uncorrelated_reply(&self, code: ReplyCode) -> Result<()> {
+        self.each(|sink| sink.on_smtp_uncorrelated_reply(code))
+    }
+
+    fn on_smtp_reply_correlation_error(&self) -> Result<()> {
+        self.each(|sink| sink.on_smtp_reply_correlation_error())
+    }
+
+    fn on_smtp_reply_rewrite(&self, rule: &str) -> Result<()> {
+        self.each(|sink| sink.on_smtp_reply_rewrite(rule))
+    }
+
+    fn on_smtp_parse_error(
+        &self,
+        action: FallbackAction,
+        category: Option<ErrorCategory>,
+    ) -> Result<()> {
+        self.each(|sink| sink.on_smtp_parse_error(action, category))
+    }
+
+    fn on_smtp_drain(&self) -> Result<()> {
+        self.each(|sink| sink.on_smtp_drain())
+    }
+
+    fn on_smtp_local_reply_fallback_close(&self) -> Result<()> {
+        self.each(|sink| sink.on_smtp_local_reply_fallback_close())
+    }
+
+    fn on_smtp_list_denied(&self, verb: &str) -> Result<()> {
+        self.each(|sink| sink.on_smtp_list_denied(verb))
+    }
+
+    fn on_smtp_connection_close(&self, graceful: bool) -> Result<()> {
+        self.each(|sink| sink.on_smtp_connection_close(graceful))
+    }
+
+    fn on_smtp_session_end(&self, summary: &SessionSummary) -> Result<()> {
+        self.each(|sink| sink.on_smtp_session_end(summary))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use anyhow::format_err;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Counter {
+        commands: Cell<usize>,
+        broken: bool,
+    }
+
+    impl StatsSink for Counter {
+        fn on_smtp_command(&self, _verb: &str) -> Result<()> {
+            self.commands.set(self.commands.get() + 1);
+            if self.broken {
+                return Err(format_err!("sink is broken"));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_forward_to_every_sink() {
+        let broken = Rc::new(Counter {
+            broken: true,
+            ..Counter::default()
+        });
+        let last = Rc::new(Counter::default());
+        let mut sink = CompositeSink::new(Counter::default());
+        sink.push(Rc::clone(&broken) as Rc<dyn StatsSink>);
+        sink.push(Rc::clone(&last) as Rc<dyn StatsSink>);
+
+        assert!(sink.on_smtp_command("MAIL").is_err());
+        assert_eq!(sink.primary().commands.get(), 1);
+        assert_eq!(broken.commands.get(), 1);
+        assert_eq!(last.commands.get(), 1);
     }
 }
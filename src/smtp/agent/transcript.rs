@@ -0,0 +1,134 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{anyhow, Result};
+use bstr::ByteSlice;
+
+use super::session::{Mode, Session};
+use super::stats::StatsSink;
+use crate::smtp::spec::core::CR_LF;
+
+/// Enumerates parties of an SMTP session that send lines of a transcript.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Direction {
+    /// Line sent by SMTP client, i.e. downstream.
+    Client,
+    /// Line sent by SMTP server, i.e. upstream.
+    Server,
+}
+
+/// TranscriptLine represents a single line of a transcript.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct TranscriptLine {
+    pub direction: Direction,
+    /// Line as sent over the wire, including the trailing `CRLF`.
+    pub data: Vec<u8>,
+}
+
+/// Transcript represents a captured SMTP session, i.e. lines sent by SMTP
+/// client and server in the order they have been observed.
+///
+/// In the text form, every line sent by SMTP client is prefixed with `C:`
+/// and every line sent by SMTP server with `S:`, e.g.
+///
+/// ```text
+/// S: 220 mail.example.org ESMTP
+/// C: EHLO client.example.org
+/// S: 250 mail.example.org
+/// ```
+///
+/// A single space after the prefix is optional. Blank lines and lines
+/// starting with `#` are ignored.
+#[derive(Clone, Debug, Default)]
+pub struct Transcript {
+    lines: Vec<TranscriptLine>,
+}
+
+impl Transcript {
+    /// Parses a transcript in the text form.
+    pub fn parse(text: &[u8]) -> Result<Self> {
+        let mut lines = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            if line.is_empty() || line.starts_with(b"#") {
+                continue;
+            }
+            let direction = match &line[..line.len().min(2)] {
+                b"C:" => Direction::Client,
+                b"S:" => Direction::Server,
+                _ => {
+                    return Err(anyhow!(
+                        "line {} of the transcript is neither `C:` nor `S:`",
+                        index + 1
+                    ))
+                }
+            };
+            let line = &line[2..];
+            let mut data = line.strip_prefix(b" ").unwrap_or(line).to_vec();
+            data.extend_from_slice(CR_LF);
+            lines.push(TranscriptLine { direction, data });
+        }
+        Ok(Transcript { lines })
+    }
+
+    pub fn lines(&self) -> &[TranscriptLine] {
+        &self.lines
+    }
+
+    /// Feeds the transcript through a new session from connect to close,
+    /// one line at a time.
+    ///
+    /// Returns the mode of the session after every line.
+    pub fn replay<S: StatsSink>(&self, session: &mut Session<S>) -> Result<Vec<Mode>> {
+        session.on_new_conection()?;
+        let mut modes = Vec::with_capacity(self.lines.len());
+        for line in &self.lines {
+            match line.direction {
+                Direction::Client => session.on_downstream_data(line.data.clone().into())?,
+                Direction::Server => session.on_upstream_data(line.data.clone().into())?,
+            }
+            modes.push(session.mode());
+        }
+        session.on_connection_closed()?;
+        Ok(modes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_transcript() {
+        let transcript = Transcript::parse(
+            b"# greeting\nS: 220 mail.example.org ESMTP\r\n\nC:DATA\nC: \nC:\nS: 354 Go ahead\n",
+        )
+        .unwrap();
+        let lines = transcript
+            .lines()
+            .iter()
+            .map(|line| (line.direction, line.data.as_slice()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                (Direction::Server, &b"220 mail.example.org ESMTP\r\n"[..]),
+                (Direction::Client, b"DATA\r\n"),
+                (Direction::Client, b"\r\n"),
+                (Direction::Client, b"\r\n"),
+                (Direction::Server, b"354 Go ahead\r\n"),
+            ]
+        );
+        assert!(Transcript::parse(b"EHLO client.example.org\n").is_err());
+    }
+}
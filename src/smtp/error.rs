@@ -0,0 +1,112 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::string::String;
+
+use core::error;
+use core::fmt;
+
+/// Enumerates errors of interpreting SMTP traffic.
+///
+/// Errors are converted into `anyhow::Error`, i.e. `envoy::error::Error`, by `?`,
+/// so that callers can still branch on the kind of an error with
+/// `downcast_ref::<SmtpError>()`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SmtpError {
+    /// SMTP client has sent a line that is not a valid command.
+    ParseCommand(String),
+    /// SMTP server has sent a line that is not a valid reply.
+    ParseReply(String),
+    /// Command is valid on its own but not at this point of the session.
+    Sequence(String),
+    /// Reply is valid on its own but cannot be a reply to the pending command.
+    Correlation(String),
+    /// Line is longer than allowed.
+    Limit {
+        line: &'static str,
+        octets: usize,
+        max: usize,
+    },
+}
+
+impl fmt::Display for SmtpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmtpError::ParseCommand(reason)
+            | SmtpError::ParseReply(reason)
+            | SmtpError::Sequence(reason)
+            | SmtpError::Correlation(reason) => write!(f, "{}", reason),
+            SmtpError::Limit { line, octets, max } => write!(
+                f,
+                "{} line is {} octets long, at most {} are allowed",
+                line, octets, max
+            ),
+        }
+    }
+}
+
+impl error::Error for SmtpError {}
+
+impl SmtpError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            SmtpError::ParseCommand(_) => ErrorCategory::Command,
+            SmtpError::ParseReply(_) => ErrorCategory::Reply,
+            SmtpError::Sequence(_) => ErrorCategory::Sequence,
+            SmtpError::Correlation(_) => ErrorCategory::Correlation,
+            SmtpError::Limit { .. } => ErrorCategory::Limit,
+        }
+    }
+}
+
+/// Enumerates categories of errors that are counted separately, so that
+/// malformed clients can be told from malformed servers.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ErrorCategory {
+    Command,
+    Reply,
+    Sequence,
+    Correlation,
+    Limit,
+}
+
+pub type Result<T> = core::result::Result<T, SmtpError>;
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use core::convert::TryFrom;
+
+    use super::*;
+    use crate::smtp::spec::core::ReplyLine;
+
+    #[test]
+    fn should_keep_kind_of_error() {
+        let err = ReplyLine::try_from(b"2x0 OK".to_vec()).unwrap_err();
+        assert_eq!(err.category(), ErrorCategory::Reply);
+
+        let err = anyhow::Error::from(SmtpError::Limit {
+            line: "command",
+            octets: 1001,
+            max: 1000,
+        });
+        assert_eq!(
+            err.to_string(),
+            "command line is 1001 octets long, at most 1000 are allowed"
+        );
+        assert!(matches!(
+            err.downcast_ref::<SmtpError>(),
+            Some(SmtpError::Limit { octets: 1001, .. })
+        ));
+    }
+}
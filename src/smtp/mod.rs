@@ -12,5 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(feature = "std")]
 pub mod agent;
+pub mod error;
+mod ser;
 pub mod spec;
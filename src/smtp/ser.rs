@@ -0,0 +1,86 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for `#[serde(serialize_with)]` attributes of SMTP types.
+
+use alloc::string::String;
+#[cfg(feature = "std")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Serializer};
+
+use crate::smtp::spec::ByteString;
+
+/// Serializes bytes as a string with invalid UTF-8 sequences replaced.
+pub fn lossy<S: Serializer>(bytes: &ByteString, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&String::from_utf8_lossy(bytes.as_bytes()))
+}
+
+pub fn lossy_opt<S: Serializer>(
+    bytes: &Option<ByteString>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    bytes
+        .as_ref()
+        .map(|bytes| String::from_utf8_lossy(bytes.as_bytes()))
+        .serialize(serializer)
+}
+
+#[cfg(feature = "std")]
+pub fn lossy_seq<S: Serializer>(bytes: &[ByteString], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(
+        bytes
+            .iter()
+            .map(|bytes| String::from_utf8_lossy(bytes.as_bytes())),
+    )
+}
+
+/// Serializes time as milliseconds since the Unix epoch.
+#[cfg(feature = "std")]
+pub fn unix_millis<S: Serializer>(
+    time: &Option<SystemTime>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    time.map(|time| {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    })
+    .serialize(serializer)
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use core::convert::TryFrom;
+
+    use serde_json::json;
+
+    use crate::smtp::spec::core::{Mail, Reply, ReplyLine};
+
+    #[test]
+    fn should_serialize_spec_types() {
+        let mail = Mail::try_from(b"FROM:<user@example.org> SIZE=1024".to_vec()).unwrap();
+        assert_eq!(
+            json!(mail),
+            json!({"from": "FROM:<user@example.org> SIZE=1024"})
+        );
+
+        let mut reply = Reply::new(ReplyLine::try_from(b"250-mx.example.org".to_vec()).unwrap());
+        reply.append(ReplyLine::try_from(b"250 PIPELINING".to_vec()).unwrap());
+        assert_eq!(
+            json!(reply),
+            json!({"code": "250", "lines": ["mx.example.org", "PIPELINING"]})
+        );
+    }
+}
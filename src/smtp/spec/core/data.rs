@@ -12,12 +12,52 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use alloc::vec::Vec;
+use core::fmt;
+
+use bstr::ByteSlice;
+use serde::Serialize;
+
+use super::syntax::CR_LF;
+
+use crate::smtp::spec::line::CommandLine;
+
 /// DATA command causes the mail data to be appended to the mail data buffer.
 ///
 /// The mail data may contain any of the 128 ASCII character codes.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Data;
 
 impl Data {
     pub const VERB: &'static str = "DATA";
+
+    /// Returns the message carried by mail data, i.e. without
+    /// the terminating `.` line and with dot-stuffing undone.
+    pub fn message(data: &[u8]) -> Vec<u8> {
+        let mut message = Vec::with_capacity(data.len());
+        for line in data.split_str(CR_LF) {
+            if line == b"." {
+                break;
+            }
+            message.extend_from_slice(line.strip_prefix(b".").unwrap_or(line));
+            message.extend_from_slice(CR_LF);
+        }
+        message
+    }
+}
+
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Self::VERB)
+    }
+}
+
+impl CommandLine for Data {
+    fn verb(&self) -> &str {
+        Self::VERB
+    }
+
+    fn args(&self) -> &[u8] {
+        &[]
+    }
 }
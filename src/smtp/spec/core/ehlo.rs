@@ -12,20 +12,30 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::convert::TryFrom;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
 
-use envoy::extension::{Error, Result};
-use envoy::host::ByteString;
+use bstr::ByteSlice;
+use serde::Serialize;
+
+use super::reply::Reply;
+use crate::smtp::error::{Result, SmtpError};
+use crate::smtp::ser;
+use crate::smtp::spec::line::CommandLine;
+use crate::smtp::spec::ByteString;
 
 /// EHLO command is used to identify the SMTP client to the SMTP server.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Ehlo {
     /// Domain / address-literal
+    #[serde(serialize_with = "ser::lossy")]
     domain: ByteString,
 }
 
 impl TryFrom<Vec<u8>> for Ehlo {
-    type Error = Error;
+    type Error = SmtpError;
 
     fn try_from(args: Vec<u8>) -> Result<Self> {
         Ok(Ehlo {
@@ -36,4 +46,87 @@ impl TryFrom<Vec<u8>> for Ehlo {
 
 impl Ehlo {
     pub const VERB: &'static str = "EHLO";
+
+    pub fn domain(&self) -> &ByteString {
+        &self.domain
+    }
+}
+
+impl fmt::Display for Ehlo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", Self::VERB, self.domain)
+    }
+}
+
+impl CommandLine for Ehlo {
+    fn verb(&self) -> &str {
+        Self::VERB
+    }
+
+    fn args(&self) -> &[u8] {
+        self.domain.as_bytes()
+    }
+}
+
+/// Represents an SMTP service extension advertised in a reply to EHLO command.
+#[derive(Clone, Debug)]
+pub struct Capability {
+    // ehlo-keyword
+    keyword: String,
+    // ehlo-param
+    params: Vec<String>,
+}
+
+impl TryFrom<&[u8]> for Capability {
+    type Error = SmtpError;
+
+    fn try_from(line: &[u8]) -> Result<Self> {
+        let mut words = line
+            .fields()
+            .map(|word| String::from_utf8_lossy(word).into_owned());
+        let mut keyword = words.next().unwrap_or_default();
+        keyword.make_ascii_uppercase();
+        Ok(Capability {
+            keyword,
+            params: words.collect(),
+        })
+    }
+}
+
+impl Capability {
+    /// Returns service extensions advertised in a positive reply to EHLO command.
+    ///
+    /// The first line of the reply carries the server's domain and greeting
+    /// rather than a service extension.
+    pub fn from_reply(reply: &Reply) -> Vec<Capability> {
+        reply
+            .lines()
+            .iter()
+            .skip(1)
+            .filter_map(|line| Capability::try_from(line.text().as_bytes()).ok())
+            .filter(|capability| !capability.keyword.is_empty())
+            .collect()
+    }
+
+    pub fn keyword(&self) -> &str {
+        &self.keyword
+    }
+
+    pub fn params(&self) -> &[String] {
+        &self.params
+    }
+
+    pub fn params_mut(&mut self) -> &mut Vec<String> {
+        &mut self.params
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.keyword)?;
+        for param in &self.params {
+            write!(f, " {}", param)?;
+        }
+        Ok(())
+    }
 }
@@ -12,22 +12,29 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::convert::TryFrom;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
 
-use envoy::extension::{Error, Result};
-use envoy::host::ByteString;
+use serde::Serialize;
+
+use crate::smtp::error::{Result, SmtpError};
+use crate::smtp::ser;
+use crate::smtp::spec::line::CommandLine;
+use crate::smtp::spec::ByteString;
 
 /// EXPAND command asks the receiver to confirm that the argument
 /// identifies a mailing list, and if so, to return the membership of
 /// that list.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Expn {
     // mailing list
+    #[serde(serialize_with = "ser::lossy")]
     mailing_list: ByteString,
 }
 
 impl TryFrom<Vec<u8>> for Expn {
-    type Error = Error;
+    type Error = SmtpError;
 
     fn try_from(args: Vec<u8>) -> Result<Self> {
         Ok(Expn {
@@ -39,3 +46,19 @@ impl TryFrom<Vec<u8>> for Expn {
 impl Expn {
     pub const VERB: &'static str = "EXPN";
 }
+
+impl fmt::Display for Expn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", Self::VERB, self.mailing_list)
+    }
+}
+
+impl CommandLine for Expn {
+    fn verb(&self) -> &str {
+        Self::VERB
+    }
+
+    fn args(&self) -> &[u8] {
+        self.mailing_list.as_bytes()
+    }
+}
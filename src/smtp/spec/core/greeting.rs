@@ -0,0 +1,66 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::convert::TryFrom;
+
+use bstr::ByteSlice;
+
+use super::reply::Reply;
+use crate::smtp::error::{Result, SmtpError};
+use crate::smtp::spec::ByteString;
+
+/// Greeting is a reply SMTP server sends to a client upon connect.
+///
+/// It carries the server's domain optionally followed by an arbitrary text,
+/// e.g. `220 mx.example.org ESMTP Postfix`.
+#[derive(Debug)]
+pub struct Greeting {
+    // Domain / address-literal
+    domain: ByteString,
+    // textstring
+    text: ByteString,
+}
+
+impl TryFrom<&Reply> for Greeting {
+    type Error = SmtpError;
+
+    fn try_from(reply: &Reply) -> Result<Self> {
+        let line = match reply.lines().first() {
+            Some(line) => line.text().as_bytes(),
+            None => &[],
+        };
+        let line = line.trim_with(char::is_whitespace);
+        let (domain, text) = match line.find_byte(b' ') {
+            Some(index) => (
+                &line[0..index],
+                line[index + 1..].trim_start_with(char::is_whitespace),
+            ),
+            None => (line, &line[0..0]),
+        };
+        Ok(Greeting {
+            domain: domain.into(),
+            text: text.into(),
+        })
+    }
+}
+
+impl Greeting {
+    pub fn domain(&self) -> &ByteString {
+        &self.domain
+    }
+
+    pub fn text(&self) -> &ByteString {
+        &self.text
+    }
+}
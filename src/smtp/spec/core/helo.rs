@@ -12,20 +12,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::convert::TryFrom;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
 
-use envoy::extension::{Error, Result};
-use envoy::host::ByteString;
+use serde::Serialize;
+
+use crate::smtp::error::{Result, SmtpError};
+use crate::smtp::ser;
+use crate::smtp::spec::line::CommandLine;
+use crate::smtp::spec::ByteString;
 
 /// HELO command is used to identify the SMTP client to the SMTP server.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Helo {
     /// Domain
+    #[serde(serialize_with = "ser::lossy")]
     domain: ByteString,
 }
 
 impl TryFrom<Vec<u8>> for Helo {
-    type Error = Error;
+    type Error = SmtpError;
 
     fn try_from(args: Vec<u8>) -> Result<Self> {
         Ok(Helo {
@@ -37,3 +44,19 @@ impl TryFrom<Vec<u8>> for Helo {
 impl Helo {
     pub const VERB: &'static str = "HELO";
 }
+
+impl fmt::Display for Helo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", Self::VERB, self.domain)
+    }
+}
+
+impl CommandLine for Helo {
+    fn verb(&self) -> &str {
+        Self::VERB
+    }
+
+    fn args(&self) -> &[u8] {
+        self.domain.as_bytes()
+    }
+}
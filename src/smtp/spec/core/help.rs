@@ -12,20 +12,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::convert::TryFrom;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
 
-use envoy::extension::{Error, Result};
-use envoy::host::ByteString;
+use serde::Serialize;
+
+use crate::smtp::error::{Result, SmtpError};
+use crate::smtp::ser;
+use crate::smtp::spec::line::CommandLine;
+use crate::smtp::spec::ByteString;
 
 /// HELP command causes the server to send helpful information to the client.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Help {
     // command name
+    #[serde(serialize_with = "ser::lossy_opt")]
     command_name: Option<ByteString>,
 }
 
 impl TryFrom<Vec<u8>> for Help {
-    type Error = Error;
+    type Error = SmtpError;
 
     fn try_from(args: Vec<u8>) -> Result<Self> {
         if args.is_empty() {
@@ -41,3 +48,24 @@ impl TryFrom<Vec<u8>> for Help {
 impl Help {
     pub const VERB: &'static str = "HELP";
 }
+
+impl fmt::Display for Help {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.command_name.as_ref() {
+            Some(command_name) => write!(f, "{} {}", Self::VERB, command_name),
+            None => write!(f, "{}", Self::VERB),
+        }
+    }
+}
+
+impl CommandLine for Help {
+    fn verb(&self) -> &str {
+        Self::VERB
+    }
+
+    fn args(&self) -> &[u8] {
+        self.command_name
+            .as_ref()
+            .map_or(&[], |command_name| command_name.as_bytes())
+    }
+}
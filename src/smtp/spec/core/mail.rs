@@ -12,35 +12,149 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::convert::TryFrom;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
 
-use envoy::extension::{Error, Result};
-use envoy::host::ByteString;
+use bstr::ByteSlice;
+use serde::Serialize;
+
+use super::syntax::SP;
+
+use crate::smtp::error::{Result, SmtpError};
+use crate::smtp::ser;
+use crate::smtp::spec::line::CommandLine;
+use crate::smtp::spec::ByteString;
 
 /// MAIL command is used to initiate a mail transaction.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Mail {
-    // Reverse-path
+    // Reverse-path [SP Mail-parameters]
+    #[serde(serialize_with = "ser::lossy")]
     from: ByteString,
-    // Mail-parameters
-    params: Option<ByteString>,
 }
 
 impl TryFrom<Vec<u8>> for Mail {
-    type Error = Error;
+    type Error = SmtpError;
 
     fn try_from(args: Vec<u8>) -> Result<Self> {
-        Ok(Mail {
-            from: args.into(),
-            params: None,
-        })
+        Ok(Mail { from: args.into() })
     }
 }
 
 impl Mail {
     pub const VERB: &'static str = "MAIL";
+    pub const BODY: &'static str = "BODY";
+    pub const BINARYMIME: &'static str = "BINARYMIME";
+    pub const SUBMITTER: &'static str = "SUBMITTER";
 
     pub fn from(&self) -> &ByteString {
         &self.from
     }
+
+    /// Returns the sender mailbox, if any.
+    ///
+    /// E.g., `user@example.org` for `FROM:<user@example.org>` and
+    /// an empty mailbox for `FROM:<>`.
+    pub fn mailbox(&self) -> Option<&[u8]> {
+        let path = self.from.as_bytes();
+        let start = path.find_byte(b'<')? + 1;
+        let end = start + path[start..].find_byte(b'>')?;
+        Some(&path[start..end])
+    }
+
+    /// Returns the value of a given mail-parameter, e.g. `BINARYMIME` of
+    /// `BODY` parameter for `FROM:<user@example.org> BODY=BINARYMIME`.
+    ///
+    /// Keywords are matched regardless of their case, and a parameter
+    /// without a value has an empty one.
+    pub fn param(&self, keyword: &str) -> Option<&[u8]> {
+        let args = self.from.as_bytes();
+        let start = args.find_byte(b'>').map_or(args.len(), |end| end + 1);
+        args[start..].split_str(SP).find_map(|param| {
+            let (key, value) = match param.find_byte(b'=') {
+                Some(index) => (&param[..index], &param[index + 1..]),
+                None => (param, &b""[..]),
+            };
+            if key.eq_ignore_ascii_case(keyword.as_bytes()) {
+                Some(value)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Returns the mailbox of the responsible submitter of the message (RFC 4405),
+    /// i.e. `SUBMITTER` parameter decoded from `xtext`, if any and valid.
+    pub fn submitter(&self) -> Option<Vec<u8>> {
+        self.param(Self::SUBMITTER)
+            .filter(|submitter| !submitter.is_empty())
+            .and_then(decode_xtext)
+    }
+
+    /// Returns `true` if the message is declared to be binary (RFC 3030),
+    /// i.e. it can only be sent with BDAT commands.
+    pub fn is_binarymime(&self) -> bool {
+        self.param(Self::BODY)
+            .is_some_and(|body| body.eq_ignore_ascii_case(Self::BINARYMIME.as_bytes()))
+    }
+}
+
+/// Decodes a value encoded as `xtext` (RFC 3461), e.g. `a+2Bb@example.org`
+/// into `a+b@example.org`.
+fn decode_xtext(value: &[u8]) -> Option<Vec<u8>> {
+    let mut result = Vec::with_capacity(value.len());
+    let mut octets = value.iter();
+    while let Some(&octet) = octets.next() {
+        match octet {
+            b'+' => {
+                let hex = [*octets.next()?, *octets.next()?];
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+                let hex = core::str::from_utf8(&hex).ok()?;
+                result.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'!'..=b'~' if octet != b'=' => result.push(octet),
+            _ => return None,
+        }
+    }
+    Some(result)
+}
+
+impl fmt::Display for Mail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", Self::VERB, self.from)
+    }
+}
+
+impl CommandLine for Mail {
+    fn verb(&self) -> &str {
+        Self::VERB
+    }
+
+    fn args(&self) -> &[u8] {
+        self.from.as_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_mail_parameters() {
+        let mail =
+            Mail::try_from(b"FROM:<a=b@example.org> SIZE=120 body=binarymime".to_vec()).unwrap();
+        assert_eq!(mail.param("SIZE"), Some(&b"120"[..]));
+        assert_eq!(mail.param("AUTH"), None);
+        assert!(mail.is_binarymime());
+        let mail = Mail::try_from(b"FROM:<> BODY=8BITMIME SMTPUTF8".to_vec()).unwrap();
+        assert_eq!(mail.param("smtputf8"), Some(&b""[..]));
+        assert!(!mail.is_binarymime());
+        let mail = Mail::try_from(b"FROM:<> SUBMITTER=a+2Bb@example.org".to_vec()).unwrap();
+        assert_eq!(mail.submitter(), Some(b"a+b@example.org".to_vec()));
+        let mail = Mail::try_from(b"FROM:<> SUBMITTER=a+2@example.org".to_vec()).unwrap();
+        assert_eq!(mail.submitter(), None);
+    }
 }
@@ -14,15 +14,16 @@
 
 pub use self::{
     data::Data,
-    ehlo::Ehlo,
+    ehlo::{Capability, Ehlo},
     expn::Expn,
+    greeting::Greeting,
     helo::Helo,
     help::Help,
     mail::Mail,
     noop::Noop,
     quit::Quit,
     rcpt::Rcpt,
-    reply::{Reply, ReplyCategory, ReplyCode, ReplyGradation, ReplyLine},
+    reply::{Reply, ReplyCode, ReplyLine, ReplyType},
     rset::Rset,
     syntax::{CR_LF, SP},
     vrfy::Vrfy,
@@ -31,6 +32,7 @@ pub use self::{
 mod data;
 mod ehlo;
 mod expn;
+mod greeting;
 mod helo;
 mod help;
 mod mail;
@@ -12,22 +12,29 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::convert::TryFrom;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
 
-use envoy::extension::{Error, Result};
-use envoy::host::ByteString;
+use serde::Serialize;
+
+use crate::smtp::error::{Result, SmtpError};
+use crate::smtp::ser;
+use crate::smtp::spec::line::CommandLine;
+use crate::smtp::spec::ByteString;
 
 /// NOOP command does not affect any parameters or previously entered commands.
 ///
 /// It specifies no action other than that the receiver send a "250 OK" reply.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Noop {
     // comment
+    #[serde(serialize_with = "ser::lossy_opt")]
     comment: Option<ByteString>,
 }
 
 impl TryFrom<Vec<u8>> for Noop {
-    type Error = Error;
+    type Error = SmtpError;
 
     fn try_from(args: Vec<u8>) -> Result<Self> {
         if args.is_empty() {
@@ -43,3 +50,24 @@ impl TryFrom<Vec<u8>> for Noop {
 impl Noop {
     pub const VERB: &'static str = "NOOP";
 }
+
+impl fmt::Display for Noop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.comment.as_ref() {
+            Some(comment) => write!(f, "{} {}", Self::VERB, comment),
+            None => write!(f, "{}", Self::VERB),
+        }
+    }
+}
+
+impl CommandLine for Noop {
+    fn verb(&self) -> &str {
+        Self::VERB
+    }
+
+    fn args(&self) -> &[u8] {
+        self.comment
+            .as_ref()
+            .map_or(&[], |comment| comment.as_bytes())
+    }
+}
@@ -12,11 +12,33 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use core::fmt;
+
+use serde::Serialize;
+
+use crate::smtp::spec::line::CommandLine;
+
 /// QUIT command specifies that the receiver MUST send a "221 OK" reply,
 /// and then close the transmission channel.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Quit;
 
 impl Quit {
     pub const VERB: &'static str = "QUIT";
 }
+
+impl fmt::Display for Quit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Self::VERB)
+    }
+}
+
+impl CommandLine for Quit {
+    fn verb(&self) -> &str {
+        Self::VERB
+    }
+
+    fn args(&self) -> &[u8] {
+        &[]
+    }
+}
@@ -12,37 +12,125 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::convert::TryFrom;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
 
-use envoy::extension::{Error, Result};
-use envoy::host::ByteString;
+use bstr::ByteSlice;
+use serde::Serialize;
+
+use crate::smtp::error::{Result, SmtpError};
+use crate::smtp::ser;
+use crate::smtp::spec::line::CommandLine;
+use crate::smtp::spec::ByteString;
 
 /// RECIPIENT command is used to identify an individual recipient of the mail data.
 ///
 /// Multiple recipients are specified by multiple uses of this command.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Rcpt {
-    // "<Postmaster@" Domain ">" / "<Postmaster>" / Forward-path
+    // ( "<Postmaster@" Domain ">" / "<Postmaster>" / Forward-path ) [SP Rcpt-parameters]
+    #[serde(serialize_with = "ser::lossy")]
     to: ByteString,
-    // Rcpt-parameters
-    params: Option<ByteString>,
 }
 
 impl TryFrom<Vec<u8>> for Rcpt {
-    type Error = Error;
+    type Error = SmtpError;
 
     fn try_from(args: Vec<u8>) -> Result<Self> {
-        Ok(Rcpt {
-            to: args.into(),
-            params: None,
-        })
+        Ok(Rcpt { to: args.into() })
     }
 }
 
 impl Rcpt {
     pub const VERB: &'static str = "RCPT";
+    const POSTMASTER: &'static [u8] = b"postmaster";
 
     pub fn to(&self) -> &ByteString {
         &self.to
     }
+
+    /// Returns the recipient mailbox, if any.
+    ///
+    /// E.g., `user@example.org` for `TO:<user@example.org>`.
+    pub fn mailbox(&self) -> Option<&[u8]> {
+        let (start, end) = self.mailbox_range()?;
+        Some(&self.to.as_bytes()[start..end])
+    }
+
+    /// Returns the domain part of the recipient mailbox, if any.
+    ///
+    /// E.g., `example.org` for `TO:<user@example.org>`.
+    pub fn domain(&self) -> Option<&[u8]> {
+        let mailbox = self.mailbox()?;
+        let at = mailbox.rfind_byte(b'@')?;
+        Some(&mailbox[at + 1..])
+    }
+
+    /// Returns whether the recipient is the postmaster, i.e. `TO:<Postmaster>`
+    /// or `TO:<Postmaster@example.org>`, which SMTP servers must accept
+    /// (RFC 5321, Section 4.5.1).
+    pub fn is_postmaster(&self) -> bool {
+        let mailbox = match self.mailbox() {
+            Some(mailbox) => mailbox,
+            None => return false,
+        };
+        let local_part = match mailbox.rfind_byte(b'@') {
+            Some(at) => &mailbox[..at],
+            None => mailbox,
+        };
+        local_part.eq_ignore_ascii_case(Self::POSTMASTER)
+    }
+
+    /// Returns a copy of the command with the recipient mailbox replaced
+    /// by a given one, or `None` if the command has no mailbox.
+    pub fn with_mailbox(&self, mailbox: &[u8]) -> Option<Rcpt> {
+        let (start, end) = self.mailbox_range()?;
+        let path = self.to.as_bytes();
+        let mut to = Vec::with_capacity(path.len() - (end - start) + mailbox.len());
+        to.extend_from_slice(&path[..start]);
+        to.extend_from_slice(mailbox);
+        to.extend_from_slice(&path[end..]);
+        Some(Rcpt { to: to.into() })
+    }
+
+    fn mailbox_range(&self) -> Option<(usize, usize)> {
+        let path = self.to.as_bytes();
+        let start = path.find_byte(b'<')? + 1;
+        let end = start + path[start..].find_byte(b'>')?;
+        Some((start, end))
+    }
+}
+
+impl fmt::Display for Rcpt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", Self::VERB, self.to)
+    }
+}
+
+impl CommandLine for Rcpt {
+    fn verb(&self) -> &str {
+        Self::VERB
+    }
+
+    fn args(&self) -> &[u8] {
+        self.to.as_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_detect_postmaster() {
+        let rcpt = Rcpt::try_from(b"TO:<Postmaster>".to_vec()).unwrap();
+        assert!(rcpt.is_postmaster());
+        let rcpt = Rcpt::try_from(b"TO:<postmaster@example.org> NOTIFY=NEVER".to_vec()).unwrap();
+        assert!(rcpt.is_postmaster());
+        let rcpt = Rcpt::try_from(b"TO:<postmaster.sales@example.org>".to_vec()).unwrap();
+        assert!(!rcpt.is_postmaster());
+        let rcpt = Rcpt::try_from(b"TO:postmaster".to_vec()).unwrap();
+        assert!(!rcpt.is_postmaster());
+    }
 }
@@ -12,12 +12,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::convert::TryFrom;
-use std::fmt;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
 
-use envoy::error::format_err;
-use envoy::extension::{Error, Result};
-use envoy::host::ByteString;
+use bstr::ByteSlice;
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
+
+use super::syntax::CR_LF;
+use crate::smtp::error::{Result, SmtpError};
+use crate::smtp::spec::ByteString;
 
 /// Represents an SMTP Reply.
 #[derive(Debug)]
@@ -34,20 +42,64 @@ impl Reply {
         self.lines.push(line)
     }
 
+    pub fn lines(&self) -> &[ReplyLine] {
+        &self.lines
+    }
+
+    /// Returns the reply in the form it is sent over the wire.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (i, line) in self.lines.iter().enumerate() {
+            let sep = if i + 1 == self.lines.len() {
+                b' '
+            } else {
+                b'-'
+            };
+            bytes.extend(line.code().to_string().as_bytes());
+            bytes.push(sep);
+            bytes.extend(line.text().as_bytes());
+            bytes.extend(CR_LF);
+        }
+        bytes
+    }
+
     pub fn code(&self) -> ReplyCode {
         self.lines
             .first()
             .map(|line| line.code())
-            .unwrap_or_else(|| ReplyCode {
-                x: ReplyType::PermanentNegativeCompletionReply,
-                y: ReplyCategory::Syntax,
-                z: ReplyGradation(0),
-            })
+            .unwrap_or(ReplyCode::SYNTAX_ERROR)
+    }
+}
+
+/// Reply is displayed in the form it is sent over the wire, without the final `CRLF`,
+/// e.g. `250-mx.example.org\r\n250 PIPELINING`.
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self.to_bytes();
+        let bytes = bytes.strip_suffix(CR_LF).unwrap_or(&bytes);
+        write!(f, "{}", bytes.as_bstr())
+    }
+}
+
+/// Reply is serialized as its code and text lines, e.g.
+/// `{"code": "250", "lines": ["mx.example.org", "PIPELINING"]}`.
+impl Serialize for Reply {
+    fn serialize<S: Serializer>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error> {
+        let lines: Vec<_> = self
+            .lines
+            .iter()
+            .map(|line| String::from_utf8_lossy(line.text().as_bytes()))
+            .collect();
+        let mut reply = serializer.serialize_struct("Reply", 2)?;
+        reply.serialize_field("code", &self.code())?;
+        reply.serialize_field("lines", &lines)?;
+        reply.end()
     }
 }
 
 /// Represents an SMTP Reply type.
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum ReplyType {
     PositiveCompletionReply,
     PositiveIntermediateReply,
@@ -65,21 +117,26 @@ impl ReplyType {
     }
 }
 
+impl ReplyType {
+    fn digit(self) -> u8 {
+        use ReplyType::*;
+        match self {
+            PositiveCompletionReply => 2,
+            PositiveIntermediateReply => 3,
+            TransientNegativeCompletionReply => 4,
+            PermanentNegativeCompletionReply => 5,
+        }
+    }
+}
+
 impl fmt::Display for ReplyType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        use ReplyType::*;
-        let digit = match self {
-            PositiveCompletionReply => '2',
-            PositiveIntermediateReply => '3',
-            TransientNegativeCompletionReply => '4',
-            PermanentNegativeCompletionReply => '5',
-        };
-        write!(f, "{}", digit)
+        write!(f, "{}", self.digit())
     }
 }
 
 impl TryFrom<u8> for ReplyType {
-    type Error = Error;
+    type Error = SmtpError;
 
     fn try_from(octet: u8) -> Result<Self> {
         use ReplyType::*;
@@ -88,7 +145,10 @@ impl TryFrom<u8> for ReplyType {
             b'3' => Ok(PositiveIntermediateReply),
             b'4' => Ok(TransientNegativeCompletionReply),
             b'5' => Ok(PermanentNegativeCompletionReply),
-            _ => Err(format_err!("not a valid reply type: {}", octet)),
+            _ => Err(SmtpError::ParseReply(format!(
+                "not a valid reply type: {}",
+                octet
+            ))),
         }
     }
 }
@@ -104,23 +164,28 @@ pub enum ReplyCategory {
     MailSystem,
 }
 
+impl ReplyCategory {
+    fn digit(self) -> u8 {
+        use ReplyCategory::*;
+        match self {
+            Syntax => 0,
+            Information => 1,
+            Connections => 2,
+            X3Z => 3,
+            X4Z => 4,
+            MailSystem => 5,
+        }
+    }
+}
+
 impl fmt::Display for ReplyCategory {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        use ReplyCategory::*;
-        let digit = match self {
-            Syntax => '0',
-            Information => '1',
-            Connections => '2',
-            X3Z => '3',
-            X4Z => '4',
-            MailSystem => '5',
-        };
-        write!(f, "{}", digit)
+        write!(f, "{}", self.digit())
     }
 }
 
 impl TryFrom<u8> for ReplyCategory {
-    type Error = Error;
+    type Error = SmtpError;
 
     fn try_from(octet: u8) -> Result<Self> {
         use ReplyCategory::*;
@@ -131,7 +196,10 @@ impl TryFrom<u8> for ReplyCategory {
             b'3' => Ok(X3Z),
             b'4' => Ok(X4Z),
             b'5' => Ok(MailSystem),
-            _ => Err(format_err!("not a valid reply category: {}", octet)),
+            _ => Err(SmtpError::ParseReply(format!(
+                "not a valid reply category: {}",
+                octet
+            ))),
         }
     }
 }
@@ -147,18 +215,22 @@ impl fmt::Display for ReplyGradation {
 }
 
 impl TryFrom<u8> for ReplyGradation {
-    type Error = Error;
+    type Error = SmtpError;
 
     fn try_from(octet: u8) -> Result<Self> {
         match octet {
             b'0'..=b'9' => Ok(ReplyGradation(octet - b'0')),
-            _ => Err(format_err!("not a valid reply gradation: {}", octet)),
+            _ => Err(SmtpError::ParseReply(format!(
+                "not a valid reply gradation: {}",
+                octet
+            ))),
         }
     }
 }
 
 /// Represents SMTP Reply code.
-#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Deserialize)]
+#[serde(try_from = "String")]
 pub struct ReplyCode {
     x: ReplyType,
     y: ReplyCategory,
@@ -166,9 +238,76 @@ pub struct ReplyCode {
 }
 
 impl ReplyCode {
+    /// `220 <domain> Service ready`
+    pub const SERVICE_READY: ReplyCode = ReplyCode::new(220);
+    /// `221 <domain> Service closing transmission channel`
+    pub const SERVICE_CLOSING: ReplyCode = ReplyCode::new(221);
+    /// `235 Authentication succeeded` (RFC 4954)
+    pub const AUTH_SUCCEEDED: ReplyCode = ReplyCode::new(235);
+    /// `250 Requested mail action okay, completed`
+    pub const OK: ReplyCode = ReplyCode::new(250);
+    /// `334 <server challenge>` (RFC 4954)
+    pub const AUTH_CHALLENGE: ReplyCode = ReplyCode::new(334);
+    /// `354 Start mail input; end with <CRLF>.<CRLF>`
+    pub const START_MAIL_INPUT: ReplyCode = ReplyCode::new(354);
+    /// `421 <domain> Service not available, closing transmission channel`
+    pub const SERVICE_NOT_AVAILABLE: ReplyCode = ReplyCode::new(421);
+    /// `450 Requested mail action not taken: mailbox unavailable`
+    pub const MAILBOX_BUSY: ReplyCode = ReplyCode::new(450);
+    /// `451 Requested action aborted: local error in processing`
+    pub const LOCAL_ERROR: ReplyCode = ReplyCode::new(451);
+    /// `452 Requested action not taken: insufficient system storage`
+    pub const INSUFFICIENT_STORAGE: ReplyCode = ReplyCode::new(452);
+    /// `500 Syntax error, command unrecognized`
+    pub const SYNTAX_ERROR: ReplyCode = ReplyCode::new(500);
+    /// `501 Syntax error in parameters or arguments`
+    pub const PARAMETER_SYNTAX_ERROR: ReplyCode = ReplyCode::new(501);
+    /// `502 Command not implemented`
+    pub const NOT_IMPLEMENTED: ReplyCode = ReplyCode::new(502);
+    /// `503 Bad sequence of commands`
+    pub const BAD_SEQUENCE: ReplyCode = ReplyCode::new(503);
+    /// `530 Authentication required` (RFC 4954)
+    pub const AUTH_REQUIRED: ReplyCode = ReplyCode::new(530);
+    /// `535 Authentication credentials invalid` (RFC 4954)
+    pub const AUTH_FAILED: ReplyCode = ReplyCode::new(535);
+    /// `550 Requested action not taken: mailbox unavailable`
+    pub const MAILBOX_UNAVAILABLE: ReplyCode = ReplyCode::new(550);
+    /// `554 Transaction failed`
+    pub const TRANSACTION_FAILED: ReplyCode = ReplyCode::new(554);
+
+    // Makes a reply code out of a valid number at compile time.
+    const fn new(code: u16) -> Self {
+        let x = match code / 100 {
+            2 => ReplyType::PositiveCompletionReply,
+            3 => ReplyType::PositiveIntermediateReply,
+            4 => ReplyType::TransientNegativeCompletionReply,
+            5 => ReplyType::PermanentNegativeCompletionReply,
+            _ => panic!("not a valid reply type"),
+        };
+        let y = match code / 10 % 10 {
+            0 => ReplyCategory::Syntax,
+            1 => ReplyCategory::Information,
+            2 => ReplyCategory::Connections,
+            3 => ReplyCategory::X3Z,
+            4 => ReplyCategory::X4Z,
+            5 => ReplyCategory::MailSystem,
+            _ => panic!("not a valid reply category"),
+        };
+        ReplyCode {
+            x,
+            y,
+            z: ReplyGradation((code % 10) as u8),
+        }
+    }
+
     pub fn response_type(&self) -> ReplyType {
         self.x
     }
+
+    /// Returns the code as a number, e.g. `250`.
+    pub fn as_u16(&self) -> u16 {
+        u16::from(self.x.digit()) * 100 + u16::from(self.y.digit()) * 10 + u16::from(self.z.0)
+    }
 }
 
 impl fmt::Display for ReplyCode {
@@ -178,14 +317,14 @@ impl fmt::Display for ReplyCode {
 }
 
 impl TryFrom<Vec<u8>> for ReplyCode {
-    type Error = Error;
+    type Error = SmtpError;
 
     fn try_from(line: Vec<u8>) -> Result<Self> {
         if line.len() != 3 {
-            return Err(format_err!(
+            return Err(SmtpError::ParseReply(format!(
                 "not a valid reply code: {}",
                 ByteString::from(line)
-            ));
+            )));
         }
         let response_type = ReplyType::try_from(line[0])?;
         let category = ReplyCategory::try_from(line[1])?;
@@ -198,8 +337,31 @@ impl TryFrom<Vec<u8>> for ReplyCode {
     }
 }
 
+/// ReplyCode is serialized as a string, e.g. `"250"`, the same way it is configured.
+impl Serialize for ReplyCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl TryFrom<u16> for ReplyCode {
+    type Error = SmtpError;
+
+    fn try_from(code: u16) -> Result<Self> {
+        ReplyCode::try_from(code.to_string().into_bytes())
+    }
+}
+
+impl TryFrom<String> for ReplyCode {
+    type Error = SmtpError;
+
+    fn try_from(code: String) -> Result<Self> {
+        ReplyCode::try_from(code.into_bytes())
+    }
+}
+
 /// Represents a single line of the SMTP Reply.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct ReplyLine {
     code: ReplyCode,
     last: bool,
@@ -207,25 +369,25 @@ pub struct ReplyLine {
 }
 
 impl TryFrom<Vec<u8>> for ReplyLine {
-    type Error = Error;
+    type Error = SmtpError;
 
     fn try_from(mut line: Vec<u8>) -> Result<Self> {
         if line.len() < 3 {
-            return Err(format_err!(
+            return Err(SmtpError::ParseReply(format!(
                 "not a valid reply line: {}",
                 ByteString::from(line)
-            ));
+            )));
         }
         let code = ReplyCode::try_from(line.drain(0..3).collect::<Vec<u8>>())?;
-        let sep = line.drain(0..1).collect::<Vec<u8>>();
+        let sep = line.drain(0..line.len().min(1)).collect::<Vec<u8>>();
         let last = match sep[..] {
             [] | [b' '] => true,
             [b'-'] => false,
             _ => {
-                return Err(format_err!(
+                return Err(SmtpError::ParseReply(format!(
                     "not a valid reply line: {}",
                     ByteString::from(line)
-                ))
+                )))
             }
         };
         Ok(ReplyLine {
@@ -237,6 +399,14 @@ impl TryFrom<Vec<u8>> for ReplyLine {
 }
 
 impl ReplyLine {
+    pub fn new(code: ReplyCode, text: ByteString) -> Self {
+        ReplyLine {
+            code,
+            last: true,
+            text,
+        }
+    }
+
     pub fn code(&self) -> ReplyCode {
         self.code
     }
@@ -244,4 +414,42 @@ impl ReplyLine {
     pub fn is_end_line(&self) -> bool {
         self.last
     }
+
+    pub fn text(&self) -> &ByteString {
+        &self.text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_convert_reply_codes() {
+        assert_eq!(ReplyCode::START_MAIL_INPUT.as_u16(), 354);
+        assert_eq!(ReplyCode::try_from(250u16).unwrap(), ReplyCode::OK);
+        assert_eq!(ReplyCode::AUTH_REQUIRED.to_string(), "530");
+        assert_eq!(
+            ReplyCode::SERVICE_NOT_AVAILABLE.response_type(),
+            ReplyType::TransientNegativeCompletionReply
+        );
+        assert!(ReplyCode::try_from(99u16).is_err());
+        assert!(ReplyCode::try_from(260u16).is_err());
+    }
+
+    #[test]
+    fn should_parse_reply_lines() {
+        let line = ReplyLine::try_from(b"250-mail.example.org".to_vec()).unwrap();
+        assert_eq!(line.code(), ReplyCode::OK);
+        assert!(!line.is_end_line());
+        assert_eq!(line.text().as_bytes(), b"mail.example.org");
+
+        let line = ReplyLine::try_from(b"421".to_vec()).unwrap();
+        assert_eq!(line.code(), ReplyCode::SERVICE_NOT_AVAILABLE);
+        assert!(line.is_end_line());
+        assert!(line.text().is_empty());
+
+        assert!(ReplyLine::try_from(b"250+OK".to_vec()).is_err());
+        assert!(ReplyLine::try_from(b"25".to_vec()).is_err());
+    }
 }
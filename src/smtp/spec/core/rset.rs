@@ -12,10 +12,32 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use core::fmt;
+
+use serde::Serialize;
+
+use crate::smtp::spec::line::CommandLine;
+
 /// RESET command specifies that the current mail transaction will be aborted.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Rset;
 
 impl Rset {
     pub const VERB: &'static str = "RSET";
 }
+
+impl fmt::Display for Rset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Self::VERB)
+    }
+}
+
+impl CommandLine for Rset {
+    fn verb(&self) -> &str {
+        Self::VERB
+    }
+
+    fn args(&self) -> &[u8] {
+        &[]
+    }
+}
@@ -12,20 +12,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::convert::TryFrom;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
 
-use envoy::extension::{Error, Result};
-use envoy::host::ByteString;
+use serde::Serialize;
+
+use crate::smtp::error::{Result, SmtpError};
+use crate::smtp::ser;
+use crate::smtp::spec::line::CommandLine;
+use crate::smtp::spec::ByteString;
 
 /// VERIFY command asks the receiver to confirm that the argument identifies a user or mailbox.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Vrfy {
     // user or mailbox
+    #[serde(serialize_with = "ser::lossy")]
     user_or_mailbox: ByteString,
 }
 
 impl TryFrom<Vec<u8>> for Vrfy {
-    type Error = Error;
+    type Error = SmtpError;
 
     fn try_from(args: Vec<u8>) -> Result<Self> {
         Ok(Vrfy {
@@ -37,3 +44,19 @@ impl TryFrom<Vec<u8>> for Vrfy {
 impl Vrfy {
     pub const VERB: &'static str = "VRFY";
 }
+
+impl fmt::Display for Vrfy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", Self::VERB, self.user_or_mailbox)
+    }
+}
+
+impl CommandLine for Vrfy {
+    fn verb(&self) -> &str {
+        Self::VERB
+    }
+
+    fn args(&self) -> &[u8] {
+        self.user_or_mailbox.as_bytes()
+    }
+}
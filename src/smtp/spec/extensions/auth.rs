@@ -0,0 +1,282 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bstr::ByteSlice;
+use serde::Serialize;
+
+use crate::smtp::error::{Result, SmtpError};
+use crate::smtp::spec::core::SP;
+use crate::smtp::spec::line::CommandLine;
+use crate::smtp::spec::ByteString;
+
+/// AUTH command starts a SASL exchange (RFC 4954), e.g. `AUTH PLAIN AHVzZXIAcGFzcw==`.
+///
+/// The initial response is never serialized since it may carry credentials.
+#[derive(Clone, Debug, Serialize)]
+pub struct Auth {
+    // sasl-mech in upper case
+    mechanism: String,
+    // args as sent, including the initial response, if any
+    #[serde(skip)]
+    args: ByteString,
+}
+
+impl TryFrom<Vec<u8>> for Auth {
+    type Error = SmtpError;
+
+    fn try_from(args: Vec<u8>) -> Result<Self> {
+        let mechanism = match args.find(SP) {
+            Some(index) => &args[..index],
+            None => &args[..],
+        };
+        // sasl-mech = 1*20mech-char
+        let is_mechanism = !mechanism.is_empty()
+            && mechanism.len() <= 20
+            && mechanism
+                .iter()
+                .all(|&c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_');
+        if !is_mechanism {
+            return Err(SmtpError::ParseCommand(
+                "AUTH command requires a SASL mechanism".to_owned(),
+            ));
+        }
+        let mut mechanism = String::from_utf8_lossy(mechanism).into_owned();
+        mechanism.make_ascii_uppercase();
+        Ok(Auth {
+            mechanism,
+            args: args.into(),
+        })
+    }
+}
+
+/// Identity claimed by SMTP client in an AUTH exchange.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SaslIdentity {
+    /// Identity SMTP client acts as, if it differs from the authentication one.
+    pub authorization: Option<ByteString>,
+    /// Identity whose credentials SMTP client presents.
+    pub authentication: ByteString,
+}
+
+impl Auth {
+    pub const VERB: &'static str = "AUTH";
+    pub const PLAIN: &'static str = "PLAIN";
+    pub const LOGIN: &'static str = "LOGIN";
+    pub const XOAUTH2: &'static str = "XOAUTH2";
+    pub const OAUTHBEARER: &'static str = "OAUTHBEARER";
+
+    /// Returns `true` for a given OAuth mechanism, i.e. `XOAUTH2` or `OAUTHBEARER`
+    /// (RFC 7628), regardless of its case.
+    pub fn is_oauth_mechanism(mechanism: &str) -> bool {
+        mechanism.eq_ignore_ascii_case(Self::XOAUTH2)
+            || mechanism.eq_ignore_ascii_case(Self::OAUTHBEARER)
+    }
+
+    /// Decodes an error report of an OAuth mechanism out of the text of a `334`
+    /// challenge, e.g. `{"status":"invalid_token","schemes":"bearer"}`.
+    ///
+    /// SMTP server reports an error that way instead of failing the exchange
+    /// right away, and SMTP client has to respond with a dummy response to get
+    /// the final `535` reply.
+    pub fn error_report(challenge: &[u8]) -> Option<Vec<u8>> {
+        STANDARD
+            .decode(challenge.trim_with(|c| c.is_ascii_whitespace()))
+            .ok()
+    }
+
+    /// Returns the name of the SASL mechanism in upper case, e.g. `PLAIN`.
+    pub fn mechanism(&self) -> &str {
+        &self.mechanism
+    }
+
+    /// Returns the initial response, if any, where `=` stands for an empty one.
+    pub fn initial_response(&self) -> Option<&[u8]> {
+        let index = self.args.as_bytes().find(SP)?;
+        Some(&self.args.as_bytes()[index + 1..])
+    }
+
+    /// Extracts the identity from the first response of SMTP client, either
+    /// the initial one or the one to the first challenge, in base64.
+    ///
+    /// `PLAIN`, `LOGIN`, `XOAUTH2` and `OAUTHBEARER` mechanisms are supported.
+    /// Passwords and tokens are discarded right away.
+    pub fn identity(&self, response: &[u8]) -> Option<SaslIdentity> {
+        let decoded = STANDARD.decode(response).ok()?;
+        match self.mechanism.as_str() {
+            // message = [authzid] UTF8NUL authcid UTF8NUL passwd
+            Self::PLAIN => {
+                let mut fields = decoded.splitn(3, |&octet| octet == 0);
+                let authorization = fields.next()?;
+                let authentication = fields.next()?;
+                fields.next()?;
+                if authentication.is_empty() {
+                    return None;
+                }
+                Some(SaslIdentity {
+                    authorization: Some(authorization)
+                        .filter(|authorization| {
+                            !authorization.is_empty() && authorization != &authentication
+                        })
+                        .map(ByteString::from),
+                    authentication: authentication.into(),
+                })
+            }
+            // user name in response to `Username:` challenge
+            Self::LOGIN if !decoded.is_empty() => Some(SaslIdentity {
+                authorization: None,
+                authentication: decoded.into(),
+            }),
+            // "user=" user %x01 "auth=Bearer " token %x01 %x01
+            Self::XOAUTH2 => decoded
+                .split(|&octet| octet == OAUTH_SEPARATOR)
+                .find_map(|field| field.strip_prefix(b"user="))
+                .filter(|user| !user.is_empty())
+                .map(|user| SaslIdentity {
+                    authorization: None,
+                    authentication: user.into(),
+                }),
+            // gs2-header %x01 *(kvpair %x01) %x01, where
+            // gs2-header = gs2-cbind-flag "," [ "a=" saslname ] ","
+            Self::OAUTHBEARER => {
+                let header = decoded.split(|&octet| octet == OAUTH_SEPARATOR).next()?;
+                let name = header
+                    .split(|&octet| octet == b',')
+                    .find_map(|field| field.strip_prefix(b"a="))
+                    .filter(|name| !name.is_empty())?;
+                Some(SaslIdentity {
+                    authorization: None,
+                    authentication: unescape_saslname(name).into(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// AUTH command is displayed with the initial response masked, e.g. `AUTH PLAIN ***`.
+impl fmt::Display for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.initial_response() {
+            Some(_) => write!(f, "{} {} ***", Self::VERB, self.mechanism),
+            None => write!(f, "{} {}", Self::VERB, self.mechanism),
+        }
+    }
+}
+
+impl CommandLine for Auth {
+    fn verb(&self) -> &str {
+        Self::VERB
+    }
+
+    fn args(&self) -> &[u8] {
+        self.args.as_bytes()
+    }
+}
+
+// Separator of key-value pairs in OAuth responses, i.e. `^A`.
+const OAUTH_SEPARATOR: u8 = 0x01;
+
+// Undoes escaping of `,` and `=` as `=2C` and `=3D` in a GS2 saslname (RFC 5801).
+fn unescape_saslname(name: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(name.len());
+    let mut rest = name;
+    while let Some((&octet, tail)) = rest.split_first() {
+        match (octet, tail) {
+            (b'=', [b'2', b'C', tail @ ..]) => {
+                unescaped.push(b',');
+                rest = tail;
+            }
+            (b'=', [b'3', b'D', tail @ ..]) => {
+                unescaped.push(b'=');
+                rest = tail;
+            }
+            _ => {
+                unescaped.push(octet);
+                rest = tail;
+            }
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn should_parse_auth_commands() {
+        let auth = Auth::try_from(b"plain AHVzZXIAcGFzcw==".to_vec()).unwrap();
+        assert_eq!(auth.mechanism(), "PLAIN");
+        assert_eq!(auth.initial_response(), Some(&b"AHVzZXIAcGFzcw=="[..]));
+        assert_eq!(auth.to_string(), "AUTH PLAIN ***");
+        assert_eq!(auth.to_bytes(), b"AUTH plain AHVzZXIAcGFzcw==\r\n");
+
+        let identity = auth.identity(auth.initial_response().unwrap()).unwrap();
+        assert_eq!(identity.authorization, None);
+        assert_eq!(identity.authentication, ByteString::from("user"));
+        let identity = auth.identity(b"YWRtaW4AdXNlcgBwYXNz").unwrap();
+        assert_eq!(identity.authorization, Some(ByteString::from("admin")));
+        assert_eq!(auth.identity(b"dXNlcg=="), None);
+
+        let auth = Auth::try_from(b"LOGIN".to_vec()).unwrap();
+        assert_eq!(auth.initial_response(), None);
+        assert_eq!(auth.to_string(), "AUTH LOGIN");
+        assert_eq!(
+            auth.identity(b"dXNlcg==").unwrap().authentication,
+            ByteString::from("user")
+        );
+        assert_eq!(auth.identity(b"*"), None);
+
+        // user=user@example.org^Aauth=Bearer token^A^A
+        let auth = Auth::try_from(
+            b"XOAUTH2 dXNlcj11c2VyQGV4YW1wbGUub3JnAWF1dGg9QmVhcmVyIHRva2VuAQE=".to_vec(),
+        )
+        .unwrap();
+        assert_eq!(
+            auth.identity(auth.initial_response().unwrap())
+                .unwrap()
+                .authentication,
+            ByteString::from("user@example.org")
+        );
+        // n,a=user=2Cx@example.org,^Aauth=Bearer token^A^A
+        let auth = Auth::try_from(
+            b"oauthbearer bixhPXVzZXI9MkN4QGV4YW1wbGUub3JnLAFhdXRoPUJlYXJlciB0b2tlbgEB".to_vec(),
+        )
+        .unwrap();
+        assert_eq!(
+            auth.identity(auth.initial_response().unwrap())
+                .unwrap()
+                .authentication,
+            ByteString::from("user,x@example.org")
+        );
+        assert_eq!(
+            Auth::error_report(b"eyJzdGF0dXMiOiI0MDEifQ=="),
+            Some(b"{\"status\":\"401\"}".to_vec())
+        );
+        assert!(Auth::is_oauth_mechanism("xoauth2"));
+
+        assert!(Auth::try_from(Vec::new()).is_err());
+        assert!(Auth::try_from(b"PL@IN".to_vec()).is_err());
+    }
+}
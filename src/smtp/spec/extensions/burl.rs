@@ -0,0 +1,161 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::borrow::ToOwned;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
+
+use bstr::ByteSlice;
+use serde::Serialize;
+
+use crate::smtp::error::{Result, SmtpError};
+use crate::smtp::spec::core::SP;
+use crate::smtp::spec::line::CommandLine;
+use crate::smtp::spec::ByteString;
+
+/// BURL command appends content referenced by a URL to the message (RFC 4468),
+/// e.g. `BURL imap://joe@example.org/Drafts;UIDVALIDITY=385759045/;UID=20;urlauth=submit+joe:internal:91354a473744909de610943775f92038 LAST`.
+///
+/// The URL is never serialized since it carries an access token.
+#[derive(Clone, Debug, Serialize)]
+pub struct Burl {
+    // whether the content completes the message
+    last: bool,
+    // args as sent, i.e. the URL and the end marker, if any
+    #[serde(skip)]
+    args: ByteString,
+}
+
+impl TryFrom<Vec<u8>> for Burl {
+    type Error = SmtpError;
+
+    fn try_from(args: Vec<u8>) -> Result<Self> {
+        let mut fields = args.split_str(SP).filter(|field| !field.is_empty());
+        let url = fields.next().filter(|url| scheme(url).is_some());
+        let last = match fields.next() {
+            None => false,
+            Some(last) if last.eq_ignore_ascii_case(b"LAST") => true,
+            Some(_) => {
+                return Err(SmtpError::ParseCommand(
+                    "BURL command has an unexpected argument".to_owned(),
+                ))
+            }
+        };
+        match (url, fields.next()) {
+            (Some(_), None) => Ok(Burl {
+                last,
+                args: args.into(),
+            }),
+            _ => Err(SmtpError::ParseCommand(
+                "BURL command requires an absolute URL".to_owned(),
+            )),
+        }
+    }
+}
+
+impl Burl {
+    pub const VERB: &'static str = "BURL";
+
+    /// Returns the URL of the content.
+    pub fn url(&self) -> &[u8] {
+        self.args
+            .as_bytes()
+            .split_str(SP)
+            .find(|field| !field.is_empty())
+            .unwrap_or_default()
+    }
+
+    /// Returns the scheme of the URL, e.g. `imap`.
+    pub fn scheme(&self) -> &[u8] {
+        scheme(self.url()).unwrap_or_default()
+    }
+
+    /// Returns `true` if the content completes the message.
+    pub fn is_last(&self) -> bool {
+        self.last
+    }
+}
+
+// Returns the scheme of an absolute URL, if it has a valid one.
+//
+// scheme = ALPHA *( ALPHA / DIGIT / "+" / "-" / "." )
+fn scheme(url: &[u8]) -> Option<&[u8]> {
+    let scheme = &url[..url.find_byte(b':')?];
+    let is_scheme = scheme.first().is_some_and(u8::is_ascii_alphabetic)
+        && scheme
+            .iter()
+            .all(|&c| c.is_ascii_alphanumeric() || c == b'+' || c == b'-' || c == b'.');
+    if is_scheme {
+        Some(scheme)
+    } else {
+        None
+    }
+}
+
+/// BURL command is displayed with the access token of the URL masked, e.g.
+/// `BURL imap://joe@example.org/Drafts;UID=20;urlauth=submit+joe:internal:*** LAST`.
+impl fmt::Display for Burl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let url = self.url();
+        // the token is the last component of `;urlauth=<access>:<mechanism>:<token>`
+        let masked = match url.to_ascii_lowercase().rfind(b";urlauth=") {
+            Some(start) => match url[start..].rfind_byte(b':') {
+                Some(end) => [&url[..start + end + 1], b"***"].concat(),
+                None => url.to_vec(),
+            },
+            None => url.to_vec(),
+        };
+        write!(f, "{} {}", Self::VERB, masked.as_bstr())?;
+        if self.last {
+            write!(f, " LAST")?;
+        }
+        Ok(())
+    }
+}
+
+impl CommandLine for Burl {
+    fn verb(&self) -> &str {
+        Self::VERB
+    }
+
+    fn args(&self) -> &[u8] {
+        self.args.as_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn should_parse_burl_commands() {
+        let burl = Burl::try_from(
+            b"imap://joe@example.org/Drafts;UID=20;URLAUTH=submit+joe:internal:9135 last".to_vec(),
+        )
+        .unwrap();
+        assert_eq!(burl.scheme(), b"imap");
+        assert!(burl.is_last());
+        assert_eq!(
+            burl.to_string(),
+            "BURL imap://joe@example.org/Drafts;UID=20;URLAUTH=submit+joe:internal:*** LAST"
+        );
+        let burl = Burl::try_from(b"imap://example.org/INBOX;UID=1".to_vec()).unwrap();
+        assert!(!burl.is_last());
+        assert!(Burl::try_from(b"".to_vec()).is_err());
+        assert!(Burl::try_from(b"/INBOX LAST".to_vec()).is_err());
+        assert!(Burl::try_from(b"imap://example.org/ FIRST".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::borrow::ToOwned;
+use alloc::format;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
+
+use bstr::ByteSlice;
+use serde::Serialize;
+
+use crate::smtp::error::{Result, SmtpError};
+use crate::smtp::spec::core::{CR_LF, SP};
+use crate::smtp::spec::line::CommandLine;
+use crate::smtp::spec::ByteString;
+
+/// BDAT command carries a chunk of a message of a given size (RFC 3030),
+/// e.g. `BDAT 1024 LAST`.
+///
+/// The chunk follows the command line right away and is counted in octets
+/// rather than terminated by a `.` line, so it may carry arbitrary binary data.
+#[derive(Clone, Debug, Serialize)]
+pub struct Bdat {
+    // chunk-size
+    size: usize,
+    // whether the chunk is the last one of the message
+    last: bool,
+    #[serde(skip)]
+    args: ByteString,
+}
+
+impl TryFrom<Vec<u8>> for Bdat {
+    type Error = SmtpError;
+
+    fn try_from(args: Vec<u8>) -> Result<Self> {
+        let mut fields = args.split_str(SP).filter(|field| !field.is_empty());
+        // chunk-size = 1*DIGIT
+        let size = fields
+            .next()
+            .filter(|size| size.iter().all(u8::is_ascii_digit))
+            .and_then(|size| size.to_str().ok())
+            .and_then(|size| size.parse().ok());
+        let last = match fields.next() {
+            None => false,
+            Some(last) if last.eq_ignore_ascii_case(b"LAST") => true,
+            Some(_) => {
+                return Err(SmtpError::ParseCommand(
+                    "BDAT command has an unexpected argument".to_owned(),
+                ))
+            }
+        };
+        match (size, fields.next()) {
+            (Some(size), None) => Ok(Bdat {
+                size,
+                last,
+                args: args.into(),
+            }),
+            _ => Err(SmtpError::ParseCommand(
+                "BDAT command requires a chunk size".to_owned(),
+            )),
+        }
+    }
+}
+
+impl Bdat {
+    pub const VERB: &'static str = "BDAT";
+
+    /// Returns the size of the chunk in octets.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns `true` if the chunk completes the message.
+    pub fn is_last(&self) -> bool {
+        self.last
+    }
+
+    /// Returns the command line for a chunk of a given size, e.g. one that
+    /// has been extended with a header.
+    pub fn with_size(&self, size: usize) -> Vec<u8> {
+        let mut line = if self.last {
+            format!("{} {} LAST", Self::VERB, size)
+        } else {
+            format!("{} {}", Self::VERB, size)
+        }
+        .into_bytes();
+        line.extend_from_slice(CR_LF);
+        line
+    }
+}
+
+impl fmt::Display for Bdat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", Self::VERB, self.args)
+    }
+}
+
+impl CommandLine for Bdat {
+    fn verb(&self) -> &str {
+        Self::VERB
+    }
+
+    fn args(&self) -> &[u8] {
+        self.args.as_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_bdat_commands() {
+        let bdat = Bdat::try_from(b"1024 last".to_vec()).unwrap();
+        assert_eq!(bdat.size(), 1024);
+        assert!(bdat.is_last());
+        assert_eq!(bdat.with_size(1050), b"BDAT 1050 LAST\r\n");
+        let bdat = Bdat::try_from(b"0".to_vec()).unwrap();
+        assert_eq!(bdat.size(), 0);
+        assert!(!bdat.is_last());
+        assert!(Bdat::try_from(b"".to_vec()).is_err());
+        assert!(Bdat::try_from(b"-1 LAST".to_vec()).is_err());
+        assert!(Bdat::try_from(b"10 FIRST".to_vec()).is_err());
+        assert!(Bdat::try_from(b"10 LAST 20".to_vec()).is_err());
+    }
+}
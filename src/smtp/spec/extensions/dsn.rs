@@ -0,0 +1,99 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::vec::Vec;
+
+use bstr::ByteSlice;
+
+const CONTENT_TYPE: &[u8] = b"Content-Type";
+const MULTIPART_REPORT: &[u8] = b"multipart/report";
+const REPORT_TYPE: &[u8] = b"report-type";
+const DELIVERY_STATUS: &[u8] = b"delivery-status";
+
+/// Returns whether a message is a delivery status notification (RFC 3464),
+/// i.e. its content type is `multipart/report; report-type=delivery-status`.
+///
+/// Only the header section of the message is looked at.
+pub fn is_delivery_status_report(message: &[u8]) -> bool {
+    content_type(message).is_some_and(|value| is_delivery_status(&value))
+}
+
+// Returns the unfolded value of Content-Type header field, if any.
+fn content_type(message: &[u8]) -> Option<Vec<u8>> {
+    let mut value: Option<Vec<u8>> = None;
+    for line in message.split_str(b"\n") {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            break; // end of the header section
+        }
+        if line.starts_with(b" ") || line.starts_with(b"\t") {
+            if let Some(value) = value.as_mut() {
+                value.extend_from_slice(line);
+            }
+            continue;
+        }
+        if value.is_some() {
+            break;
+        }
+        if let Some(colon) = line.find_byte(b':') {
+            if trim(&line[..colon]).eq_ignore_ascii_case(CONTENT_TYPE) {
+                value = Some(line[colon + 1..].to_vec());
+            }
+        }
+    }
+    value
+}
+
+fn is_delivery_status(content_type: &[u8]) -> bool {
+    let mut parts = content_type.split_str(b";");
+    let media_type = parts.next().map(trim).unwrap_or_default();
+    media_type.eq_ignore_ascii_case(MULTIPART_REPORT)
+        && parts.any(|param| match param.find_byte(b'=') {
+            Some(eq) => {
+                let value = trim(&param[eq + 1..]);
+                let value = value
+                    .strip_prefix(b"\"")
+                    .and_then(|value| value.strip_suffix(b"\""))
+                    .unwrap_or(value);
+                trim(&param[..eq]).eq_ignore_ascii_case(REPORT_TYPE)
+                    && value.eq_ignore_ascii_case(DELIVERY_STATUS)
+            }
+            None => false,
+        })
+}
+
+fn trim(value: &[u8]) -> &[u8] {
+    value.trim_with(|c| c == ' ' || c == '\t')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_detect_delivery_status_reports() {
+        let bounce = b"From: MAILER-DAEMON@example.org\r\n\
+            Content-Type: multipart/report;\r\n\
+            \treport-type=\"delivery-status\";\r\n\
+            \tboundary=\"boundary\"\r\n\
+            \r\n\
+            --boundary\r\n";
+        assert!(is_delivery_status_report(bounce));
+        let mdn = b"content-type: multipart/report; report-type=disposition-notification\r\n\r\n";
+        assert!(!is_delivery_status_report(mdn));
+        let body =
+            b"Subject: Hi\r\n\r\nContent-Type: multipart/report; report-type=delivery-status\r\n";
+        assert!(!is_delivery_status_report(body));
+    }
+}
@@ -12,4 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod auth;
+pub mod burl;
+pub mod chunking;
+pub mod dsn;
 pub mod starttls;
+pub mod xforward;
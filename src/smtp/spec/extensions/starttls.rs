@@ -12,10 +12,32 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use core::fmt;
+
+use serde::Serialize;
+
+use crate::smtp::spec::line::CommandLine;
+
 /// STARTTLS command.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct StartTls;
 
 impl StartTls {
     pub const VERB: &'static str = "STARTTLS";
 }
+
+impl fmt::Display for StartTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Self::VERB)
+    }
+}
+
+impl CommandLine for StartTls {
+    fn verb(&self) -> &str {
+        Self::VERB
+    }
+
+    fn args(&self) -> &[u8] {
+        &[]
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::net::IpAddr;
+
+use crate::smtp::spec::core::{CR_LF, SP};
+
+/// XFORWARD command is used by a trusted SMTP client to forward attributes
+/// of the original SMTP session to SMTP server.
+///
+/// See <http://www.postfix.org/XFORWARD_README.html>.
+#[derive(Debug, Default)]
+pub struct Xforward {
+    // xforward-attribute
+    attributes: Vec<(&'static str, String)>,
+}
+
+impl Xforward {
+    pub const VERB: &'static str = "XFORWARD";
+    /// Name of the service extension SMTP server advertises support for XFORWARD with.
+    pub const KEYWORD: &'static str = "XFORWARD";
+
+    /// Adds up-stream client IP address.
+    pub fn addr(mut self, addr: IpAddr) -> Self {
+        let value = match addr {
+            IpAddr::V4(addr) => addr.to_string(),
+            IpAddr::V6(addr) => format!("IPV6:{}", addr),
+        };
+        self.attributes.push(("ADDR", value));
+        self
+    }
+
+    /// Adds up-stream client TCP port number.
+    pub fn port(mut self, port: u16) -> Self {
+        self.attributes.push(("PORT", port.to_string()));
+        self
+    }
+
+    /// Adds up-stream client HELO or EHLO command parameter.
+    pub fn helo(mut self, helo: &[u8]) -> Self {
+        self.attributes.push(("HELO", xtext(helo)));
+        self
+    }
+
+    /// Adds up-stream protocol, i.e. `SMTP` or `ESMTP`.
+    pub fn proto(mut self, proto: &str) -> Self {
+        self.attributes.push(("PROTO", xtext(proto.as_bytes())));
+        self
+    }
+
+    /// Returns the command line in the form it is sent over the wire.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::from(Self::VERB.as_bytes());
+        for (name, value) in &self.attributes {
+            bytes.extend(SP);
+            bytes.extend(name.as_bytes());
+            bytes.push(b'=');
+            bytes.extend(value.as_bytes());
+        }
+        bytes.extend(CR_LF);
+        bytes
+    }
+}
+
+/// Encodes a value as `xtext` (RFC 3461).
+fn xtext(value: &[u8]) -> String {
+    let mut result = String::with_capacity(value.len());
+    for &octet in value {
+        match octet {
+            b'!'..=b'~' if octet != b'+' && octet != b'=' => result.push(octet as char),
+            _ => result.push_str(&format!("+{:02X}", octet)),
+        }
+    }
+    result
+}
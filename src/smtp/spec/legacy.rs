@@ -0,0 +1,100 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::fmt;
+
+use bstr::ByteSlice;
+use serde::Serialize;
+
+use crate::smtp::spec::line::CommandLine;
+use crate::smtp::spec::ByteString;
+
+/// Obsolete command of RFC 821, i.e. TURN, SEND, SOML or SAML, or ATRN command
+/// of On-Demand Mail Relay (RFC 2645).
+///
+/// Such commands are rarely supported by SMTP servers, yet they are recognized
+/// so that they are accounted for rather than taken for unknown ones.
+#[derive(Debug, Serialize)]
+pub struct Legacy {
+    // verb
+    verb: &'static str,
+    // args, e.g. the reverse-path of SEND command
+    #[serde(skip)]
+    args: ByteString,
+}
+
+impl Legacy {
+    pub const TURN: &'static str = "TURN";
+    pub const ATRN: &'static str = "ATRN";
+    pub const SEND: &'static str = "SEND";
+    pub const SOML: &'static str = "SOML";
+    pub const SAML: &'static str = "SAML";
+
+    /// Verbs of legacy commands.
+    pub const VERBS: &'static [&'static str] =
+        &[Self::TURN, Self::ATRN, Self::SEND, Self::SOML, Self::SAML];
+
+    pub fn new(verb: &'static str, args: ByteString) -> Self {
+        Legacy { verb, args }
+    }
+
+    /// Returns `true` for a command that makes SMTP client and server swap
+    /// their roles once it has been accepted, i.e. TURN and ATRN.
+    pub fn reverses_roles(&self) -> bool {
+        self.verb == Self::TURN || self.verb == Self::ATRN
+    }
+
+    /// Returns `true` for a command that starts a mail transaction like MAIL
+    /// command does, i.e. SEND, SOML and SAML.
+    pub fn starts_transaction(&self) -> bool {
+        !self.reverses_roles()
+    }
+
+    /// Returns the reverse-path of a command that starts a mail transaction,
+    /// e.g. `FROM:<user@example.org>`.
+    pub fn from(&self) -> &ByteString {
+        &self.args
+    }
+
+    /// Returns the sender mailbox of a command that starts a mail transaction, if any.
+    ///
+    /// E.g., `user@example.org` for `FROM:<user@example.org>` and
+    /// an empty mailbox for `FROM:<>`.
+    pub fn mailbox(&self) -> Option<&[u8]> {
+        let path = self.args.as_bytes();
+        let start = path.find_byte(b'<')? + 1;
+        let end = start + path[start..].find_byte(b'>')?;
+        Some(&path[start..end])
+    }
+}
+
+impl fmt::Display for Legacy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.args.is_empty() {
+            write!(f, "{}", self.verb)
+        } else {
+            write!(f, "{} {}", self.verb, self.args)
+        }
+    }
+}
+
+impl CommandLine for Legacy {
+    fn verb(&self) -> &str {
+        self.verb
+    }
+
+    fn args(&self) -> &[u8] {
+        self.args.as_bytes()
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::vec::Vec;
+
+use super::core::{CR_LF, SP};
+
+/// Uniform access to the line of an SMTP command.
+pub trait CommandLine {
+    /// Returns the verb of the command in upper case, e.g. `MAIL`.
+    fn verb(&self) -> &str;
+
+    /// Returns arguments of the command as sent by SMTP client,
+    /// e.g. `FROM:<user@example.org> SIZE=1024`, or an empty slice if there are none.
+    fn args(&self) -> &[u8];
+
+    /// Returns the command line in the form it is sent over the wire.
+    ///
+    /// The verb is in upper case regardless of the case SMTP client has sent it in.
+    fn to_bytes(&self) -> Vec<u8> {
+        let verb = self.verb().as_bytes();
+        let args = self.args();
+        let mut bytes = Vec::with_capacity(verb.len() + SP.len() + args.len() + CR_LF.len());
+        bytes.extend(verb);
+        if !args.is_empty() {
+            bytes.extend(SP);
+            bytes.extend(args);
+        }
+        bytes.extend(CR_LF);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::TryFrom;
+
+    use super::*;
+    use crate::smtp::spec::core::{Help, Mail, Quit};
+    use crate::smtp::spec::unknown::Unknown;
+
+    #[test]
+    fn should_reproduce_command_lines() {
+        let mail = Mail::try_from(b"FROM:<user@example.org> SIZE=1024".to_vec()).unwrap();
+        assert_eq!(mail.args(), b"FROM:<user@example.org> SIZE=1024");
+        assert_eq!(
+            mail.to_bytes(),
+            b"MAIL FROM:<user@example.org> SIZE=1024\r\n"
+        );
+
+        let help = Help::try_from(Vec::new()).unwrap();
+        assert_eq!(help.args(), b"");
+        assert_eq!(help.to_bytes(), b"HELP\r\n");
+        assert_eq!(Quit.to_bytes(), b"QUIT\r\n");
+
+        let unknown = Unknown::try_from(b"xclient NAME=mx.example.org".to_vec()).unwrap();
+        assert_eq!(unknown.verb(), "XCLIENT");
+        assert_eq!(unknown.to_bytes(), b"XCLIENT NAME=mx.example.org\r\n");
+    }
+}
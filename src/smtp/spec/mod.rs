@@ -12,6 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(feature = "std")]
+pub use proxy_wasm::types::ByteString;
+
+#[cfg(not(feature = "std"))]
+pub use self::bytes::ByteString;
+
+#[cfg(not(feature = "std"))]
+mod bytes;
 pub mod core;
 pub mod extensions;
+pub mod legacy;
+pub mod line;
 pub mod unknown;
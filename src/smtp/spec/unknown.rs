@@ -12,25 +12,33 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::convert::TryFrom;
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
 
 use bstr::ByteSlice;
-use envoy::extension::{Error, Result};
-use envoy::host::ByteString;
+use serde::Serialize;
 
+use crate::smtp::error::{Result, SmtpError};
 use crate::smtp::spec::core::SP;
+use crate::smtp::spec::extensions::auth::Auth;
+use crate::smtp::spec::line::CommandLine;
+use crate::smtp::spec::ByteString;
 
 /// Represent unknown command.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Unknown {
     // verb
     verb: String,
-    // args
+    // args, never serialized since they may carry credentials, e.g. of AUTH command
+    #[serde(skip)]
     args: ByteString,
 }
 
 impl TryFrom<Vec<u8>> for Unknown {
-    type Error = Error;
+    type Error = SmtpError;
 
     fn try_from(line: Vec<u8>) -> Result<Self> {
         let (verb, args) = match line.find(SP) {
@@ -38,7 +46,8 @@ impl TryFrom<Vec<u8>> for Unknown {
             None => (&line[..], &line[0..0]),
         };
 
-        let mut verb = String::from_utf8(verb.to_vec())?;
+        let mut verb = String::from_utf8(verb.to_vec())
+            .map_err(|_| SmtpError::ParseCommand("command verb is not UTF-8".to_owned()))?;
         verb.make_ascii_uppercase();
         let args = args.to_vec();
 
@@ -54,3 +63,32 @@ impl Unknown {
         &self.verb
     }
 }
+
+/// Unknown command is displayed as sent, except for credentials of AUTH command,
+/// e.g. `AUTH PLAIN ***`.
+impl fmt::Display for Unknown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.args.is_empty() {
+            return write!(f, "{}", self.verb);
+        }
+        if self.verb == Auth::VERB {
+            let mut args = self.args.as_bytes().splitn_str(2, " ");
+            let mechanism = args.next().unwrap_or_default().as_bstr();
+            return match args.next() {
+                Some(_) => write!(f, "{} {} ***", self.verb, mechanism),
+                None => write!(f, "{} {}", self.verb, mechanism),
+            };
+        }
+        write!(f, "{} {}", self.verb, self.args)
+    }
+}
+
+impl CommandLine for Unknown {
+    fn verb(&self) -> &str {
+        &self.verb
+    }
+
+    fn args(&self) -> &[u8] {
+        self.args.as_bytes()
+    }
+}
@@ -0,0 +1,66 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Well-known filter state exported by SMTP filter.
+//!
+//! `Envoy` stores stream properties set by Wasm extensions as filter state
+//! with `wasm.` prefix, e.g. `%FILTER_STATE(wasm.smtp.mail_from:PLAIN)%`
+//! in an access log format.
+
+/// Domain of the greeting of SMTP server.
+pub const GREETING_DOMAIN: &str = "smtp.greeting.domain";
+/// Text of the greeting of SMTP server.
+pub const GREETING_TEXT: &str = "smtp.greeting.text";
+/// Reverse path of the latest mail transaction accepted by SMTP server.
+pub const MAIL_FROM: &str = "smtp.mail_from";
+/// Responsible submitter of the latest mail transaction accepted by SMTP server,
+/// as declared by `SUBMITTER` parameter of MAIL command.
+pub const SUBMITTER: &str = "smtp.submitter";
+/// Number of recipients accepted by SMTP server.
+pub const RCPT_COUNT: &str = "smtp.rcpt_count";
+/// Number of messages accepted by SMTP server.
+pub const MESSAGES: &str = "smtp.messages";
+/// Code of the latest reply of SMTP server.
+pub const LAST_REPLY_CODE: &str = "smtp.last_reply_code";
+/// Id of the latest mail transaction, e.g. `174a3c1b2e0-2.1`.
+pub const TRANSACTION_ID: &str = "smtp.transaction_id";
+/// Set to `true` once the client has completed a successful AUTH exchange.
+pub const AUTHENTICATED: &str = "smtp.authenticated";
+/// Identity the client has authenticated as, e.g. `user@example.org`.
+pub const AUTH_IDENTITY: &str = "smtp.auth.identity";
+/// Identity the client has authenticated on behalf of, if it differs from `smtp.auth.identity`.
+pub const AUTH_AUTHORIZATION_IDENTITY: &str = "smtp.auth.authorization_identity";
+/// JSON description of the envelope of the latest mail transaction.
+///
+/// Stands in for dynamic metadata which cannot be set through `Proxy Wasm` ABI.
+pub const ENVELOPE: &str = "smtp.envelope";
+/// Subject of the certificate presented by the client.
+pub const CLIENT_SUBJECT: &str = "smtp.client.subject";
+/// URI SAN of the certificate presented by the client.
+pub const CLIENT_URI_SAN: &str = "smtp.client.uri_san";
+/// DNS SAN of the certificate presented by the client.
+pub const CLIENT_DNS_SAN: &str = "smtp.client.dns_san";
+/// Set to `true` once the client has been idle for longer than the idle timeout.
+pub const IDLE_TIMEOUT: &str = "smtp.idle_timeout";
+/// Set to `true` once the client has been found to trickle commands.
+pub const SLOW_CLIENT: &str = "smtp.slow_client";
+/// Number of offenses of the client as of the start of the connection.
+#[cfg(feature = "policy")]
+pub const REPUTATION_OFFENSES: &str = "smtp.reputation.offenses";
+/// DNSBL zone the client has been found in.
+#[cfg(feature = "policy")]
+pub const DNSBL_LISTED: &str = "smtp.dnsbl.listed";
+/// Result of the content scan as reported by the scanning service.
+#[cfg(feature = "content-inspection")]
+pub const SCAN_RESULT: &str = "smtp.scan.result";
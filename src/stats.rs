@@ -12,18 +12,152 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cell::RefCell;
+#[cfg(feature = "detailed-stats")]
+use std::collections::HashMap;
+use std::collections::HashSet;
+#[cfg(not(feature = "detailed-stats"))]
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::ops::Deref;
+use std::rc::Rc;
+use std::time::Duration;
+
 use envoy::extension::Result;
-use envoy::host::stats::{Counter, Stats};
+use envoy::host::stats::{Counter, Gauge, Histogram, Stats};
+
+use crate::config::{DetailedStatsSelection, Direction, SmtpFilterConfig, SourceStatsMode};
+use crate::policy::{Callout, Decision};
+use crate::smtp::agent::{FallbackAction, Handshake, SessionSummary, StatsSink};
+use crate::smtp::error::ErrorCategory;
+use crate::smtp::spec::core::{
+    Capability, Data, Ehlo, Expn, Helo, Help, Mail, Noop, Quit, Rcpt, ReplyCode, ReplyType, Rset,
+    Vrfy,
+};
+use crate::smtp::spec::extensions::auth::Auth;
+use crate::smtp::spec::extensions::burl::Burl;
+use crate::smtp::spec::extensions::chunking::Bdat;
+use crate::smtp::spec::extensions::starttls::StartTls;
+use crate::smtp::spec::legacy::Legacy;
+
+// Verbs that are allowed to appear in metric names.
+//
+// Any other verb is folded into `smtp.command.unknown.*` to prevent clients
+// from creating arbitrary metrics by sending garbage commands.
+const KNOWN_VERBS: &[&str] = &[
+    Helo::VERB,
+    Ehlo::VERB,
+    Mail::VERB,
+    Rcpt::VERB,
+    Data::VERB,
+    Bdat::VERB,
+    Burl::VERB,
+    Rset::VERB,
+    Vrfy::VERB,
+    Expn::VERB,
+    Help::VERB,
+    Noop::VERB,
+    Quit::VERB,
+    StartTls::VERB,
+    Legacy::TURN,
+    Legacy::ATRN,
+    Legacy::SEND,
+    Legacy::SOML,
+    Legacy::SAML,
+];
+
+const UNKNOWN: &str = "unknown";
 
-use crate::smtp::agent::StatsSink;
-use crate::smtp::spec::core::ReplyCode;
+// Service extensions that are allowed to appear in metric names.
+const KNOWN_CAPABILITIES: &[&str] = &[
+    "8BITMIME",
+    "AUTH",
+    "BINARYMIME",
+    "BURL",
+    "CHUNKING",
+    "DELIVERBY",
+    "DSN",
+    "ENHANCEDSTATUSCODES",
+    "ETRN",
+    "EXPN",
+    "HELP",
+    "MT-PRIORITY",
+    "PIPELINING",
+    "REQUIRETLS",
+    "SIZE",
+    "SMTPUTF8",
+    "STARTTLS",
+    "VRFY",
+];
+
+// SASL mechanisms that are allowed to appear in metric names.
+const KNOWN_AUTH_MECHANISMS: &[&str] = &[
+    "ANONYMOUS",
+    "CRAM-MD5",
+    "DIGEST-MD5",
+    "EXTERNAL",
+    "GSSAPI",
+    "LOGIN",
+    "NTLM",
+    "OAUTHBEARER",
+    "PLAIN",
+    "SCRAM-SHA-1",
+    "SCRAM-SHA-256",
+    "XOAUTH2",
+];
+
+// Bucket for recipient domains in excess of the configured limit.
+const OTHER_DOMAIN: &str = "other";
+
+// Prefix of tag names, e.g. `smtp_verb`.
+#[cfg(feature = "detailed-stats")]
+const TAG_PREFIX: &str = "smtp_";
 
 // SMTP stats.
 pub struct SmtpFilterStats<'a> {
     detailed: bool,
+    selection: DetailedStatsSelection,
+    tagged: bool,
+    outbound: bool,
+    recipient_domain_limit: usize,
+    #[cfg(feature = "detailed-stats")]
     stats: &'a dyn Stats,
     connections_total: Box<dyn Counter>,
+    connections_active: Box<dyn Gauge>,
     connections_errors_total: Box<dyn Counter>,
+    connections_implicit_tls_total: Box<dyn Counter>,
+    connections_not_smtp_total: Box<dyn Counter>,
+    connections_drained_total: Box<dyn Counter>,
+    local_replies_fallback_close_total: Box<dyn Counter>,
+    connections_idle_timeouts_total: Box<dyn Counter>,
+    connections_slow_clients_total: Box<dyn Counter>,
+    parse_errors_passthrough_total: Box<dyn Counter>,
+    parse_errors_close_total: Box<dyn Counter>,
+    parse_errors_resync_total: Box<dyn Counter>,
+    reply_correlation_errors_total: Box<dyn Counter>,
+    errors_command_total: Box<dyn Counter>,
+    errors_reply_total: Box<dyn Counter>,
+    errors_sequence_total: Box<dyn Counter>,
+    errors_correlation_total: Box<dyn Counter>,
+    errors_limit_total: Box<dyn Counter>,
+    lists_senders_denied_total: Box<dyn Counter>,
+    auth_attempts_total: Box<dyn Counter>,
+    auth_succeeded_total: Box<dyn Counter>,
+    auth_failed_total: Box<dyn Counter>,
+    auth_rejected_total: Box<dyn Counter>,
+    auth_oauth_attempts_total: Box<dyn Counter>,
+    auth_oauth_error_reports_total: Box<dyn Counter>,
+    auth_throttled_total: Box<dyn Counter>,
+    burl_total: Box<dyn Counter>,
+    commands_legacy_total: Box<dyn Counter>,
+    rcpt_postmaster_total: Box<dyn Counter>,
+    mail_verp_total: Box<dyn Counter>,
+    rcpt_rewrites_failed_total: Box<dyn Counter>,
+    bounces_total: Box<dyn Counter>,
+    sessions_ehlo_oauth_total: Box<dyn Counter>,
+    lists_recipients_denied_total: Box<dyn Counter>,
+    connections_closed_graceful_total: Box<dyn Counter>,
+    connections_closed_ungraceful_total: Box<dyn Counter>,
     connects_total: Box<dyn Counter>,
     connects_replies_total: Box<dyn Counter>,
     connects_replies_positive_total: Box<dyn Counter>,
@@ -36,18 +170,95 @@ pub struct SmtpFilterStats<'a> {
     transaction_commits_replies_total: Box<dyn Counter>,
     transaction_commits_replies_positive_total: Box<dyn Counter>,
     transaction_commits_replies_negative_total: Box<dyn Counter>,
+    transaction_commits_authenticated_replies_total: Box<dyn Counter>,
+    transaction_commits_authenticated_replies_positive_total: Box<dyn Counter>,
+    transaction_commits_authenticated_replies_negative_total: Box<dyn Counter>,
+    transaction_aborts_total: Box<dyn Counter>,
+    transaction_data_timeouts_total: Box<dyn Counter>,
+    transaction_duration: Box<dyn Histogram>,
+    transaction_data_duration: Box<dyn Histogram>,
+    replies_code_mismatches_total: Box<dyn Counter>,
+    replies_uncorrelated_total: Box<dyn Counter>,
     mails_total: Box<dyn Counter>,
     mails_sent_total: Box<dyn Counter>,
     mails_rejected_total: Box<dyn Counter>,
+    starttls_upgrades_total: Box<dyn Counter>,
+    sessions_helo_total: Box<dyn Counter>,
+    sessions_ehlo_total: Box<dyn Counter>,
+    sessions_helo_fallbacks_total: Box<dyn Counter>,
+    sessions_authenticated_total: Box<dyn Counter>,
+    sessions_recipients: Box<dyn Histogram>,
+    sessions_messages: Box<dyn Histogram>,
+    reputation_offenses_total: Box<dyn Counter>,
+    reputation_offenders_total: Box<dyn Counter>,
+    webhook_sent_total: Box<dyn Counter>,
+    webhook_delivered_total: Box<dyn Counter>,
+    webhook_failures_total: Box<dyn Counter>,
+    events_published_total: Box<dyn Counter>,
+    events_dropped_total: Box<dyn Counter>,
+    policy_envelope: PolicyStats,
+    policy_content: PolicyStats,
+    policy_dnsbl: PolicyStats,
+    // Recipient domains that have individual stats.
+    recipient_domains: RefCell<HashSet<String>>,
+    // Detailed counters that have already been defined.
+    #[cfg(feature = "detailed-stats")]
+    detailed_counters: RefCell<HashMap<String, Box<dyn Counter>>>,
+    // Binds the lifetime of Envoy stats that are not referred to otherwise.
+    #[cfg(not(feature = "detailed-stats"))]
+    stats: PhantomData<&'a dyn Stats>,
 }
 
 impl<'a> SmtpFilterStats<'a> {
-    pub fn new(detailed: bool, stats: &'a dyn Stats) -> Result<Self> {
+    pub fn new(config: &SmtpFilterConfig, stats: &'a dyn Stats) -> Result<Self> {
         Ok(SmtpFilterStats {
-            detailed,
+            detailed: config.detailed_stats,
+            selection: config.detailed_stats_selection.clone().unwrap_or_default(),
+            tagged: config.tagged_stats,
+            outbound: config.direction == Direction::Outbound,
+            recipient_domain_limit: config.recipient_domain_stats_limit,
+            #[cfg(feature = "detailed-stats")]
             stats,
             connections_total: stats.counter("smtp.connections.total")?,
+            connections_active: stats.gauge("smtp.connections.active")?,
             connections_errors_total: stats.counter("smtp.connections.parse_errors.total")?,
+            connections_implicit_tls_total: stats.counter("smtp.connections.implicit_tls.total")?,
+            connections_not_smtp_total: stats.counter("smtp.connections.not_smtp.total")?,
+            connections_drained_total: stats.counter("smtp.connections.drained.total")?,
+            local_replies_fallback_close_total: stats
+                .counter("smtp.local_replies.fallback_close.total")?,
+            connections_idle_timeouts_total: stats
+                .counter("smtp.connections.idle_timeouts.total")?,
+            connections_slow_clients_total: stats.counter("smtp.connections.slow_clients.total")?,
+            parse_errors_passthrough_total: stats.counter("smtp.parse_errors.passthrough.total")?,
+            parse_errors_close_total: stats.counter("smtp.parse_errors.close.total")?,
+            parse_errors_resync_total: stats.counter("smtp.parse_errors.resync.total")?,
+            reply_correlation_errors_total: stats.counter("smtp.reply_correlation_errors.total")?,
+            errors_command_total: stats.counter("smtp.errors.command.total")?,
+            errors_reply_total: stats.counter("smtp.errors.reply.total")?,
+            errors_sequence_total: stats.counter("smtp.errors.sequence.total")?,
+            errors_correlation_total: stats.counter("smtp.errors.correlation.total")?,
+            errors_limit_total: stats.counter("smtp.errors.limit.total")?,
+            lists_senders_denied_total: stats.counter("smtp.lists.senders.denied.total")?,
+            auth_attempts_total: stats.counter("smtp.auth.attempts.total")?,
+            auth_succeeded_total: stats.counter("smtp.auth.succeeded.total")?,
+            auth_failed_total: stats.counter("smtp.auth.failed.total")?,
+            auth_rejected_total: stats.counter("smtp.auth.rejected.total")?,
+            auth_oauth_attempts_total: stats.counter("smtp.auth.oauth.attempts.total")?,
+            auth_oauth_error_reports_total: stats.counter("smtp.auth.oauth.error_reports.total")?,
+            auth_throttled_total: stats.counter("smtp.auth.throttled.total")?,
+            burl_total: stats.counter("smtp.burl.total")?,
+            commands_legacy_total: stats.counter("smtp.commands.legacy.total")?,
+            rcpt_postmaster_total: stats.counter("smtp.rcpt.postmaster.total")?,
+            mail_verp_total: stats.counter("smtp.mail.verp.total")?,
+            rcpt_rewrites_failed_total: stats.counter("smtp.rcpt.rewrites.failed.total")?,
+            bounces_total: stats.counter("smtp.bounces.total")?,
+            sessions_ehlo_oauth_total: stats.counter("smtp.sessions.ehlo.oauth.total")?,
+            lists_recipients_denied_total: stats.counter("smtp.lists.recipients.denied.total")?,
+            connections_closed_graceful_total: stats
+                .counter("smtp.connections.closed.graceful.total")?,
+            connections_closed_ungraceful_total: stats
+                .counter("smtp.connections.closed.ungraceful.total")?,
             connects_total: stats.counter("smtp.connects.total")?,
             connects_replies_total: stats.counter("smtp.connects.replies.total")?,
             connects_replies_positive_total: stats
@@ -67,20 +278,452 @@ impl<'a> SmtpFilterStats<'a> {
                 .counter("smtp.transactions.commits.replies.positive.total")?,
             transaction_commits_replies_negative_total: stats
                 .counter("smtp.transactions.commits.replies.negative.total")?,
+            transaction_commits_authenticated_replies_total: stats
+                .counter("smtp.transactions.commits.authenticated.replies.total")?,
+            transaction_commits_authenticated_replies_positive_total: stats
+                .counter("smtp.transactions.commits.authenticated.replies.positive.total")?,
+            transaction_commits_authenticated_replies_negative_total: stats
+                .counter("smtp.transactions.commits.authenticated.replies.negative.total")?,
+            transaction_aborts_total: stats.counter("smtp.transactions.aborts.total")?,
+            transaction_data_timeouts_total: stats
+                .counter("smtp.transactions.data_timeouts.total")?,
+            transaction_duration: stats.histogram("smtp.transactions.duration_ms")?,
+            transaction_data_duration: stats.histogram("smtp.transactions.data_duration_ms")?,
+            replies_code_mismatches_total: stats.counter("smtp.replies.code_mismatches.total")?,
+            replies_uncorrelated_total: stats.counter("smtp.replies.uncorrelated.total")?,
             mails_total: stats.counter("smtp.mails.total")?,
             mails_sent_total: stats.counter("smtp.mails.sent.total")?,
             mails_rejected_total: stats.counter("smtp.mails.rejected.total")?,
+            starttls_upgrades_total: stats.counter("smtp.starttls.upgrades.total")?,
+            sessions_helo_total: stats.counter("smtp.sessions.helo.total")?,
+            sessions_ehlo_total: stats.counter("smtp.sessions.ehlo.total")?,
+            sessions_helo_fallbacks_total: stats.counter("smtp.sessions.helo.fallbacks.total")?,
+            sessions_authenticated_total: stats.counter("smtp.sessions.authenticated.total")?,
+            sessions_recipients: stats.histogram("smtp.sessions.recipients")?,
+            sessions_messages: stats.histogram("smtp.sessions.messages")?,
+            reputation_offenses_total: stats.counter("smtp.reputation.offenses.total")?,
+            reputation_offenders_total: stats.counter("smtp.reputation.offenders.total")?,
+            webhook_sent_total: stats.counter("smtp.webhook.sent.total")?,
+            webhook_delivered_total: stats.counter("smtp.webhook.delivered.total")?,
+            webhook_failures_total: stats.counter("smtp.webhook.failures.total")?,
+            events_published_total: stats.counter("smtp.events.published.total")?,
+            events_dropped_total: stats.counter("smtp.events.dropped.total")?,
+            policy_envelope: PolicyStats::new("envelope", stats)?,
+            policy_content: PolicyStats::new("content", stats)?,
+            policy_dnsbl: PolicyStats::new("dnsbl", stats)?,
+            recipient_domains: RefCell::new(HashSet::new()),
+            #[cfg(feature = "detailed-stats")]
+            detailed_counters: RefCell::new(HashMap::new()),
+            #[cfg(not(feature = "detailed-stats"))]
+            stats: PhantomData,
+        })
+    }
+
+    /// Returns `true` if these stats have been created for a given config.
+    pub fn is_configured_for(&self, config: &SmtpFilterConfig) -> bool {
+        self.detailed == config.detailed_stats
+            && self.selection == config.detailed_stats_selection.clone().unwrap_or_default()
+            && self.tagged == config.tagged_stats
+            && self.outbound == (config.direction == Direction::Outbound)
+            && self.recipient_domain_limit == config.recipient_domain_stats_limit
+    }
+
+    /// Is called when a policy decision has been made.
+    pub fn on_policy_decision(
+        &self,
+        callout: Callout,
+        decision: Decision,
+        latency: Duration,
+    ) -> Result<()> {
+        let stats = match callout {
+            Callout::Envelope => &self.policy_envelope,
+            Callout::Content => &self.policy_content,
+            Callout::Dnsbl(_) => &self.policy_dnsbl,
+        };
+        stats.latency.record(latency.as_millis() as u64)?;
+        match decision {
+            Decision::Allow => stats.allowed_total.inc(),
+            Decision::Deny => stats.denied_total.inc(),
+            Decision::Failure => stats.failures_total.inc(),
+        }
+    }
+
+    /// Is called when a policy profile has been selected for a connection.
+    pub fn on_policy_profile(&self, profile: &str) -> Result<()> {
+        self.inc_detailed(
+            "smtp.profiles.{policy_profile}.connections.total",
+            &[("policy_profile", profile)],
+        )
+    }
+
+    /// Is called when a message has taken longer than the DATA timeout to arrive.
+    pub fn on_data_timeout(&self) -> Result<()> {
+        self.transaction_data_timeouts_total.inc()
+    }
+
+    /// Is called when a client has been found to trickle commands.
+    pub fn on_slow_client(&self) -> Result<()> {
+        self.connections_slow_clients_total.inc()
+    }
+
+    /// Is called when a client has been idle for longer than the idle timeout.
+    pub fn on_idle_timeout(&self) -> Result<()> {
+        self.connections_idle_timeouts_total.inc()
+    }
+
+    /// Is called when an event has been published onto the shared queue or dropped.
+    pub fn on_event_published(&self, published: bool) -> Result<()> {
+        if published {
+            self.events_published_total.inc()
+        } else {
+            self.events_dropped_total.inc()
+        }
+    }
+
+    /// Is called when a transaction summary has been sent to the webhook.
+    pub fn on_webhook_sent(&self) -> Result<()> {
+        self.webhook_sent_total.inc()
+    }
+
+    /// Is called when the webhook has replied to a transaction summary
+    /// or has failed to.
+    pub fn on_webhook_response(&self, delivered: bool) -> Result<()> {
+        if delivered {
+            self.webhook_delivered_total.inc()
+        } else {
+            self.webhook_failures_total.inc()
+        }
+    }
+
+    /// Is called when a client has committed an offense that affects its reputation.
+    pub fn on_reputation_offense(&self) -> Result<()> {
+        self.reputation_offenses_total.inc()
+    }
+
+    /// Is called when a client has exceeded the limit of offenses.
+    pub fn on_reputation_offender(&self) -> Result<()> {
+        self.reputation_offenders_total.inc()
+    }
+
+    /// Is called when the client address has been found in a DNSBL zone.
+    pub fn on_dnsbl_listed(&self, zone: &str) -> Result<()> {
+        self.inc_detailed(
+            "smtp.dnsbl.{dnsbl_zone}.listed.total",
+            &[("dnsbl_zone", &zone.to_ascii_lowercase().replace('.', "_"))],
+        )
+    }
+
+    // Accounts a delivery result toward a destination domain in outbound direction,
+    // i.e. `accepted`, `deferred` or `bounced` along with the reply code.
+    fn on_destination_reply(&self, domain: &[u8], code: ReplyCode) -> Result<()> {
+        if !self.outbound || self.recipient_domain_limit == 0 {
+            return Ok(());
+        }
+        let domain = self.stat_domain(domain);
+        let outcome = match code.response_type() {
+            ReplyType::TransientNegativeCompletionReply => "deferred",
+            ReplyType::PermanentNegativeCompletionReply => "bounced",
+            _ => "accepted",
+        };
+        self.inc_detailed(
+            &format!(
+                "smtp.destinations.domain.{{recipient_domain}}.{}.total",
+                outcome
+            ),
+            &[("recipient_domain", &domain)],
+        )?;
+        self.inc_detailed(
+            "smtp.destinations.domain.{recipient_domain}.reply.{reply_code}.total",
+            &[
+                ("recipient_domain", &domain),
+                ("reply_code", &code.to_string()),
+            ],
+        )
+    }
+
+    // Returns the domain to use in metric names.
+    //
+    // Once the limit is reached, new domains are folded into the `other` bucket.
+    fn stat_domain(&self, domain: &[u8]) -> String {
+        let domain = match std::str::from_utf8(domain) {
+            Ok(domain) if is_valid_domain(domain) => domain.to_ascii_lowercase().replace('.', "_"),
+            _ => return OTHER_DOMAIN.to_owned(),
+        };
+        let mut domains = self.recipient_domains.borrow_mut();
+        if domains.contains(&domain) {
+            domain
+        } else if domains.len() < self.recipient_domain_limit {
+            domains.insert(domain.clone());
+            domain
+        } else {
+            OTHER_DOMAIN.to_owned()
+        }
+    }
+
+    // Increments a counter with a given name pattern and tag values.
+    //
+    // Pattern refers to tag values by name, e.g. `smtp.command.{verb}.total`.
+    #[cfg(feature = "detailed-stats")]
+    fn inc_detailed(&self, pattern: &str, tags: &[(&str, &str)]) -> Result<()> {
+        let name = if self.tagged {
+            tagged_name(pattern, tags)
+        } else {
+            dotted_name(pattern, tags)
+        };
+        let mut counters = self.detailed_counters.borrow_mut();
+        if let Some(counter) = counters.get(&name) {
+            return counter.inc();
+        }
+        let counter = self.stats.counter(&name)?;
+        counter.inc()?;
+        counters.insert(name, counter);
+        Ok(())
+    }
+
+    // Detailed stats are compiled out.
+    #[cfg(not(feature = "detailed-stats"))]
+    fn inc_detailed(&self, _pattern: &str, _tags: &[(&str, &str)]) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Stats of policy requests of a given kind.
+struct PolicyStats {
+    allowed_total: Box<dyn Counter>,
+    denied_total: Box<dyn Counter>,
+    failures_total: Box<dyn Counter>,
+    latency: Box<dyn Histogram>,
+}
+
+impl PolicyStats {
+    fn new(kind: &str, stats: &dyn Stats) -> Result<Self> {
+        Ok(PolicyStats {
+            allowed_total: stats.counter(&format!("smtp.policy.{}.allowed.total", kind))?,
+            denied_total: stats.counter(&format!("smtp.policy.{}.denied.total", kind))?,
+            failures_total: stats.counter(&format!("smtp.policy.{}.failures.total", kind))?,
+            latency: stats.histogram(&format!("smtp.policy.{}.latency_ms", kind))?,
         })
     }
+}
+
+/// Sink of stats of a single connection, including those of filter features
+/// beyond the SMTP session itself, e.g. policy callouts and webhooks.
+pub trait FilterStatsSink: StatsSink {
+    fn upstream_cluster(&self) -> Option<&str> {
+        None
+    }
+
+    /// Scopes subsequent stats to the upstream cluster of the connection.
+    fn set_upstream_cluster(&mut self, _name: String) {}
+
+    fn tenant(&self) -> Option<&str> {
+        None
+    }
+
+    /// Scopes subsequent stats to the tenant of the connection.
+    fn set_tenant(&mut self, _tenant: &str) {}
+
+    /// Is called when a new connection from a given source has been open.
+    fn on_source_connection(&self, _source: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_policy_decision(
+        &self,
+        _callout: Callout,
+        _decision: Decision,
+        _latency: Duration,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_policy_profile(&self, _profile: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_data_timeout(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_slow_client(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_idle_timeout(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_event_published(&self, _published: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_webhook_sent(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_webhook_response(&self, _delivered: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_reputation_offense(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_reputation_offender(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_dnsbl_listed(&self, _zone: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Stats of a single SMTP session.
+//
+// Detailed stats are scoped to the upstream cluster once its name is known.
+pub struct SmtpSessionStats<'a> {
+    filter_stats: Rc<SmtpFilterStats<'a>>,
+    upstream_cluster: Option<String>,
+    tenant: Option<String>,
+}
+
+impl<'a> SmtpSessionStats<'a> {
+    pub fn new(filter_stats: Rc<SmtpFilterStats<'a>>) -> Self {
+        SmtpSessionStats {
+            filter_stats,
+            upstream_cluster: None,
+            tenant: None,
+        }
+    }
+
+    // Increments a detailed counter scoped to the tenant and the upstream cluster,
+    // if known, e.g. `smtp.tenant.<id>.cluster.<name>.command.DATA.total`.
+    #[cfg(feature = "detailed-stats")]
+    fn inc_detailed(&self, pattern: &str, tags: &[(&str, &str)]) -> Result<()> {
+        let mut scope = String::from("smtp.");
+        let mut scoped_tags = Vec::new();
+        if let Some(tenant) = self.tenant() {
+            scope.push_str("tenant.{tenant}.");
+            scoped_tags.push(("tenant", tenant));
+        }
+        if let Some(cluster) = self.upstream_cluster() {
+            scope.push_str("cluster.{upstream_cluster}.");
+            scoped_tags.push(("upstream_cluster", cluster));
+        }
+        scoped_tags.extend_from_slice(tags);
+        let pattern = if self.tagged {
+            format!("smtp.{}", pattern)
+        } else {
+            format!("{}{}", scope, pattern)
+        };
+        self.filter_stats.inc_detailed(&pattern, &scoped_tags)
+    }
+
+    // Detailed stats are compiled out.
+    #[cfg(not(feature = "detailed-stats"))]
+    fn inc_detailed(&self, _pattern: &str, _tags: &[(&str, &str)]) -> Result<()> {
+        Ok(())
+    }
+}
 
-    pub fn is_detailed(&self) -> bool {
-        self.detailed
+impl<'a> Deref for SmtpSessionStats<'a> {
+    type Target = SmtpFilterStats<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.filter_stats
     }
 }
 
-impl<'a> StatsSink for SmtpFilterStats<'a> {
+impl<'a> From<Rc<SmtpFilterStats<'a>>> for SmtpSessionStats<'a> {
+    fn from(filter_stats: Rc<SmtpFilterStats<'a>>) -> Self {
+        SmtpSessionStats::new(filter_stats)
+    }
+}
+
+impl<'a> FilterStatsSink for SmtpSessionStats<'a> {
+    fn upstream_cluster(&self) -> Option<&str> {
+        self.upstream_cluster.as_deref()
+    }
+
+    fn set_upstream_cluster(&mut self, name: String) {
+        self.upstream_cluster = Some(name)
+    }
+
+    fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    /// Characters other than alphanumerics, `-` and `_` are replaced with `_`.
+    fn set_tenant(&mut self, tenant: &str) {
+        let tenant = tenant
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect();
+        self.tenant = Some(tenant)
+    }
+
+    fn on_source_connection(&self, source: &str) -> Result<()> {
+        self.inc_detailed("sources.{source}.connections.total", &[("source", source)])
+    }
+
+    fn on_policy_decision(
+        &self,
+        callout: Callout,
+        decision: Decision,
+        latency: Duration,
+    ) -> Result<()> {
+        self.filter_stats
+            .on_policy_decision(callout, decision, latency)
+    }
+
+    fn on_policy_profile(&self, profile: &str) -> Result<()> {
+        self.filter_stats.on_policy_profile(profile)
+    }
+
+    fn on_data_timeout(&self) -> Result<()> {
+        self.filter_stats.on_data_timeout()
+    }
+
+    fn on_slow_client(&self) -> Result<()> {
+        self.filter_stats.on_slow_client()
+    }
+
+    fn on_idle_timeout(&self) -> Result<()> {
+        self.filter_stats.on_idle_timeout()
+    }
+
+    fn on_event_published(&self, published: bool) -> Result<()> {
+        self.filter_stats.on_event_published(published)
+    }
+
+    fn on_webhook_sent(&self) -> Result<()> {
+        self.filter_stats.on_webhook_sent()
+    }
+
+    fn on_webhook_response(&self, delivered: bool) -> Result<()> {
+        self.filter_stats.on_webhook_response(delivered)
+    }
+
+    fn on_reputation_offense(&self) -> Result<()> {
+        self.filter_stats.on_reputation_offense()
+    }
+
+    fn on_reputation_offender(&self) -> Result<()> {
+        self.filter_stats.on_reputation_offender()
+    }
+
+    fn on_dnsbl_listed(&self, zone: &str) -> Result<()> {
+        self.filter_stats.on_dnsbl_listed(zone)
+    }
+}
+
+impl<'a> StatsSink for SmtpSessionStats<'a> {
     fn on_smtp_connect(&self) -> Result<()> {
         self.connections_total.inc()?;
+        self.connections_active.inc()?;
         self.connects_total.inc()
     }
 
@@ -91,20 +734,32 @@ impl<'a> StatsSink for SmtpFilterStats<'a> {
         } else {
             self.connects_replies_negative_total.inc()?;
         }
-        if self.detailed {
-            self.stats
-                .counter(&format!("smtp.connects.reply.{}.total", code))?
-                .inc()?;
+        let code = code.to_string();
+        if self.detailed && self.selection.includes_reply_code(&code) {
+            self.inc_detailed(
+                "connects.reply.{reply_code}.total",
+                &[("reply_code", &code)],
+            )?;
         }
         Ok(())
     }
 
     fn on_smtp_command(&self, verb: &str) -> Result<()> {
         self.commands_total.inc()?;
-        if self.detailed {
-            self.stats
-                .counter(&format!("smtp.command.{}.total", verb))?
-                .inc()?;
+        if self.detailed && self.selection.includes_verb(verb) {
+            let verb = stat_verb(verb);
+            self.inc_detailed("command.{verb}.total", &[("verb", verb)])?;
+        }
+        Ok(())
+    }
+
+    fn on_smtp_handshake(&self, handshake: Handshake, fallback: bool) -> Result<()> {
+        match handshake {
+            Handshake::Helo => self.sessions_helo_total.inc()?,
+            Handshake::Ehlo => self.sessions_ehlo_total.inc()?,
+        }
+        if fallback {
+            self.sessions_helo_fallbacks_total.inc()?;
         }
         Ok(())
     }
@@ -116,26 +771,151 @@ impl<'a> StatsSink for SmtpFilterStats<'a> {
         } else {
             self.commands_replies_negative_total.inc()?;
         }
-        if self.detailed {
-            self.stats
-                .counter(&format!("smtp.command.{}.replies.total", verb))?
-                .inc()?;
-            self.stats
-                .counter(&format!("smtp.command.{}.reply.{}.total", verb, code))?
-                .inc()?;
+        if self.detailed && self.selection.includes_verb(verb) {
+            let verb = stat_verb(verb);
+            self.inc_detailed("command.{verb}.replies.total", &[("verb", verb)])?;
+            let code_name = code.to_string();
+            if self.selection.includes_reply_code(&code_name) {
+                self.inc_detailed(
+                    "command.{verb}.reply.{reply_code}.total",
+                    &[("verb", verb), ("reply_code", &code_name)],
+                )?;
+            }
             if code.response_type().is_positive() {
-                self.stats
-                    .counter(&format!("smtp.command.{}.replies.positive.total", verb))?
-                    .inc()?;
+                self.inc_detailed("command.{verb}.replies.positive.total", &[("verb", verb)])?;
             } else {
-                self.stats
-                    .counter(&format!("smtp.command.{}.replies.negative.total", verb))?
-                    .inc()?;
+                self.inc_detailed("command.{verb}.replies.negative.total", &[("verb", verb)])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn on_smtp_recipient_reply(&self, domain: &[u8], code: ReplyCode) -> Result<()> {
+        if self.recipient_domain_limit == 0 {
+            return Ok(());
+        }
+        if !code.response_type().is_positive() {
+            // the message will never be delivered to this recipient
+            self.filter_stats.on_destination_reply(domain, code)?;
+        }
+        let domain = self.stat_domain(domain);
+        let outcome = if code.response_type().is_positive() {
+            "accepted"
+        } else {
+            "rejected"
+        };
+        self.filter_stats.inc_detailed(
+            &format!(
+                "smtp.recipients.domain.{{recipient_domain}}.{}.total",
+                outcome
+            ),
+            &[("recipient_domain", &domain)],
+        )
+    }
+
+    fn on_smtp_delivery_reply(&self, domain: &[u8], code: ReplyCode) -> Result<()> {
+        self.filter_stats.on_destination_reply(domain, code)
+    }
+
+    fn on_smtp_ehlo_capabilities(&self, capabilities: &[Capability]) -> Result<()> {
+        let offers_oauth = capabilities.iter().any(|capability| {
+            capability.keyword() == Auth::VERB
+                && capability
+                    .params()
+                    .iter()
+                    .any(|mechanism| Auth::is_oauth_mechanism(mechanism))
+        });
+        if offers_oauth {
+            self.sessions_ehlo_oauth_total.inc()?;
+        }
+        if !self.detailed {
+            return Ok(());
+        }
+        for capability in capabilities {
+            let keyword = stat_name_from(KNOWN_CAPABILITIES, capability.keyword());
+            self.inc_detailed(
+                "ehlo.capability.{capability}.total",
+                &[("capability", keyword)],
+            )?;
+            if keyword == "AUTH" {
+                for mechanism in capability.params() {
+                    let mechanism =
+                        stat_name_from(KNOWN_AUTH_MECHANISMS, &mechanism.to_ascii_uppercase());
+                    self.inc_detailed(
+                        "ehlo.capability.AUTH.mechanism.{auth_mechanism}.total",
+                        &[("auth_mechanism", mechanism)],
+                    )?;
+                }
             }
         }
         Ok(())
     }
 
+    fn on_smtp_auth_reply(&self, mechanism: &str, code: ReplyCode) -> Result<()> {
+        self.auth_attempts_total.inc()?;
+        let outcome = auth_outcome(code);
+        match outcome {
+            "succeeded" => self.auth_succeeded_total.inc()?,
+            "failed" => self.auth_failed_total.inc()?,
+            _ => self.auth_rejected_total.inc()?,
+        }
+        if Auth::is_oauth_mechanism(mechanism) {
+            self.auth_oauth_attempts_total.inc()?;
+        }
+        if self.detailed {
+            let mechanism = stat_name_from(KNOWN_AUTH_MECHANISMS, mechanism);
+            self.inc_detailed(
+                "auth.mechanism.{auth_mechanism}.total",
+                &[("auth_mechanism", mechanism)],
+            )?;
+            self.inc_detailed(
+                &format!("auth.mechanism.{{auth_mechanism}}.{}.total", outcome),
+                &[("auth_mechanism", mechanism)],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn on_smtp_auth_error_report(&self, mechanism: &str) -> Result<()> {
+        self.auth_oauth_error_reports_total.inc()?;
+        if self.detailed {
+            let mechanism = stat_name_from(KNOWN_AUTH_MECHANISMS, mechanism);
+            self.inc_detailed(
+                "auth.mechanism.{auth_mechanism}.error_reports.total",
+                &[("auth_mechanism", mechanism)],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn on_smtp_auth_throttled(&self) -> Result<()> {
+        self.auth_throttled_total.inc()
+    }
+
+    fn on_smtp_burl(&self) -> Result<()> {
+        self.burl_total.inc()
+    }
+
+    fn on_smtp_legacy_command(&self, _verb: &str) -> Result<()> {
+        self.commands_legacy_total.inc()
+    }
+
+    fn on_smtp_postmaster_recipient(&self) -> Result<()> {
+        self.rcpt_postmaster_total.inc()
+    }
+
+    fn on_smtp_verp_sender(&self) -> Result<()> {
+        self.mail_verp_total.inc()
+    }
+
+    fn on_smtp_recipient_rewrite_failure(&self) -> Result<()> {
+        self.rcpt_rewrites_failed_total.inc()
+    }
+
+    fn on_smtp_bounce(&self) -> Result<()> {
+        self.bounces_total.inc()
+    }
+
     fn on_smtp_transaction_commit(&self) -> Result<()> {
         self.transaction_commits_total.inc()?;
         self.mails_total.inc()
@@ -153,15 +933,287 @@ impl<'a> StatsSink for SmtpFilterStats<'a> {
         } else {
             self.mails_rejected_total.inc()?;
         }
-        if self.detailed {
-            self.stats
-                .counter(&format!("smtp.transactions.commits.reply.{}.total", code))?
-                .inc()?;
+        let code = code.to_string();
+        if self.detailed && self.selection.includes_reply_code(&code) {
+            self.inc_detailed(
+                "transactions.commits.reply.{reply_code}.total",
+                &[("reply_code", &code)],
+            )?;
         }
         Ok(())
     }
 
-    fn on_smtp_parse_error(&self) -> Result<()> {
-        self.connections_errors_total.inc()
+    fn on_smtp_authenticated_transaction_commit_reply(&self, code: ReplyCode) -> Result<()> {
+        self.transaction_commits_authenticated_replies_total.inc()?;
+        if code.response_type().is_positive() {
+            self.transaction_commits_authenticated_replies_positive_total
+                .inc()
+        } else {
+            self.transaction_commits_authenticated_replies_negative_total
+                .inc()
+        }
+    }
+
+    fn on_smtp_starttls_upgrade(&self) -> Result<()> {
+        self.starttls_upgrades_total.inc()
+    }
+
+    fn on_smtp_implicit_tls(&self) -> Result<()> {
+        self.connections_implicit_tls_total.inc()
+    }
+
+    fn on_smtp_not_smtp(&self) -> Result<()> {
+        self.connections_not_smtp_total.inc()
+    }
+
+    fn on_smtp_drain(&self) -> Result<()> {
+        self.connections_drained_total.inc()
+    }
+
+    fn on_smtp_local_reply_fallback_close(&self) -> Result<()> {
+        self.local_replies_fallback_close_total.inc()
+    }
+
+    fn on_smtp_list_denied(&self, verb: &str) -> Result<()> {
+        if verb == Mail::VERB {
+            self.lists_senders_denied_total.inc()
+        } else {
+            self.lists_recipients_denied_total.inc()
+        }
+    }
+
+    fn on_smtp_transaction_abort(&self) -> Result<()> {
+        self.transaction_aborts_total.inc()
+    }
+
+    fn on_smtp_transaction_timing(
+        &self,
+        duration: Duration,
+        data_duration: Duration,
+    ) -> Result<()> {
+        self.transaction_duration
+            .record(duration.as_millis() as u64)?;
+        self.transaction_data_duration
+            .record(data_duration.as_millis() as u64)
+    }
+
+    fn on_smtp_reply_code_mismatch(&self) -> Result<()> {
+        self.replies_code_mismatches_total.inc()
+    }
+
+    fn on_smtp_uncorrelated_reply(&self, code: ReplyCode) -> Result<()> {
+        self.replies_uncorrelated_total.inc()?;
+        let code = code.to_string();
+        if self.detailed && self.selection.includes_reply_code(&code) {
+            self.inc_detailed(
+                "replies.uncorrelated.reply.{reply_code}.total",
+                &[("reply_code", &code)],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn on_smtp_reply_correlation_error(&self) -> Result<()> {
+        self.reply_correlation_errors_total.inc()
+    }
+
+    fn on_smtp_reply_rewrite(&self, rule: &str) -> Result<()> {
+        self.filter_stats.inc_detailed(
+            "smtp.replies.rewrites.{reply_rewrite_rule}.total",
+            &[("reply_rewrite_rule", rule)],
+        )
+    }
+
+    fn on_smtp_parse_error(
+        &self,
+        action: FallbackAction,
+        category: Option<ErrorCategory>,
+    ) -> Result<()> {
+        self.connections_errors_total.inc()?;
+        match category {
+            Some(ErrorCategory::Command) => self.errors_command_total.inc()?,
+            Some(ErrorCategory::Reply) => self.errors_reply_total.inc()?,
+            Some(ErrorCategory::Sequence) => self.errors_sequence_total.inc()?,
+            Some(ErrorCategory::Correlation) => self.errors_correlation_total.inc()?,
+            Some(ErrorCategory::Limit) => self.errors_limit_total.inc()?,
+            None => {}
+        }
+        match action {
+            FallbackAction::PassThrough => self.parse_errors_passthrough_total.inc(),
+            FallbackAction::Close => self.parse_errors_close_total.inc(),
+            FallbackAction::Resync => self.parse_errors_resync_total.inc(),
+        }
+    }
+
+    fn on_smtp_connection_close(&self, graceful: bool) -> Result<()> {
+        if graceful {
+            self.connections_closed_graceful_total.inc()
+        } else {
+            self.connections_closed_ungraceful_total.inc()
+        }
+    }
+
+    fn on_smtp_session_end(&self, summary: &SessionSummary) -> Result<()> {
+        self.connections_active.dec()?;
+        if summary.authenticated {
+            self.sessions_authenticated_total.inc()?;
+        }
+        self.sessions_recipients.record(summary.rcpt_count as u64)?;
+        self.sessions_messages.record(summary.messages as u64)
+    }
+}
+
+/// Returns the verb to use in metric names.
+fn stat_verb(verb: &str) -> &str {
+    stat_name_from(KNOWN_VERBS, verb)
+}
+
+/// Returns the outcome of an AUTH exchange to use in metric names, i.e. `succeeded`,
+/// `failed` for invalid credentials (`535`) or `rejected` for any other reason,
+/// e.g. an unsupported mechanism.
+fn auth_outcome(code: ReplyCode) -> &'static str {
+    if code.response_type().is_positive() {
+        "succeeded"
+    } else if code == ReplyCode::AUTH_FAILED {
+        "failed"
+    } else {
+        "rejected"
+    }
+}
+
+/// Returns a given name if it is known or `unknown` otherwise.
+fn stat_name_from(known: &[&'static str], name: &str) -> &'static str {
+    known
+        .iter()
+        .find(|known| **known == name)
+        .copied()
+        .unwrap_or(UNKNOWN)
+}
+
+/// Returns `true` if a given domain is safe to use in metric names.
+fn is_valid_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.len() <= 255
+        && domain
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+}
+
+/// Renders stat name with tag values embedded into it,
+/// e.g. `smtp.command.EHLO.total`.
+#[cfg(feature = "detailed-stats")]
+fn dotted_name(pattern: &str, tags: &[(&str, &str)]) -> String {
+    tags.iter().fold(pattern.to_owned(), |name, (tag, value)| {
+        name.replace(&format!("{{{}}}", tag), value)
+    })
+}
+
+/// Returns a client address in the form to use in metric names.
+pub fn stat_source(address: IpAddr, mode: SourceStatsMode) -> String {
+    match (mode, address) {
+        (SourceStatsMode::Truncated, IpAddr::V4(address)) => {
+            let octets = address.octets();
+            format!("{}_{}_{}_0", octets[0], octets[1], octets[2])
+        }
+        (SourceStatsMode::Truncated, IpAddr::V6(address)) => {
+            let segments = address.segments();
+            format!("{:x}_{:x}_{:x}__", segments[0], segments[1], segments[2])
+        }
+        (SourceStatsMode::Hashed, address) => {
+            // FNV-1a keeps buckets stable across processes and toolchains
+            let octets = match address {
+                IpAddr::V4(address) => address.octets().to_vec(),
+                IpAddr::V6(address) => address.octets().to_vec(),
+            };
+            let hash = octets.iter().fold(0x811c_9dc5_u32, |hash, octet| {
+                (hash ^ u32::from(*octet)).wrapping_mul(0x0100_0193)
+            });
+            format!("bucket_{:02x}", hash as u8)
+        }
+    }
+}
+
+/// Renders stat name with tag values appended to it in a form suitable
+/// for tag extraction by `Envoy`, e.g. `smtp.command.total.smtp_verb=.=EHLO;.;`.
+///
+/// Tags must be declared in `stats_config.stats_tags` of the `Envoy` bootstrap,
+/// e.g. `{ tag_name: smtp_verb, regex: "(smtp_verb=\\.=(.*?);\\.;)" }`.
+#[cfg(feature = "detailed-stats")]
+fn tagged_name(pattern: &str, tags: &[(&str, &str)]) -> String {
+    let mut name = tags.iter().fold(pattern.to_owned(), |name, (tag, _)| {
+        name.replace(&format!(".{{{}}}", tag), "")
+    });
+    for (i, (tag, value)) in tags.iter().enumerate() {
+        if i == 0 {
+            name.push('.');
+        }
+        name.push_str(&format!("{}{}=.={};.;", TAG_PREFIX, tag, value));
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "detailed-stats")]
+    fn should_render_dotted_name() {
+        assert_eq!(
+            dotted_name(
+                "smtp.command.{verb}.reply.{reply_code}.total",
+                &[("verb", "EHLO"), ("reply_code", "250")]
+            ),
+            "smtp.command.EHLO.reply.250.total"
+        );
+    }
+
+    #[test]
+    fn should_truncate_sources() {
+        assert_eq!(
+            stat_source("192.0.2.1".parse().unwrap(), SourceStatsMode::Truncated),
+            "192_0_2_0"
+        );
+        assert_eq!(
+            stat_source(
+                "2001:db8:1:2::1".parse().unwrap(),
+                SourceStatsMode::Truncated
+            ),
+            "2001_db8_1__"
+        );
+    }
+
+    #[test]
+    fn should_select_detailed_stats() {
+        let selection = DetailedStatsSelection {
+            verbs: vec!["MAIL".to_owned(), "RCPT".to_owned()],
+            reply_codes: vec!["4xx".to_owned(), "550".to_owned()],
+        };
+        assert!(selection.includes_verb("rcpt"));
+        assert!(!selection.includes_verb("EHLO"));
+        assert!(selection.includes_reply_code("421"));
+        assert!(selection.includes_reply_code("550"));
+        assert!(!selection.includes_reply_code("554"));
+        assert!(!selection.includes_reply_code("250"));
+        assert!(DetailedStatsSelection::default().includes_reply_code("250"));
+    }
+
+    #[test]
+    fn should_classify_auth_outcomes() {
+        assert_eq!(auth_outcome(ReplyCode::AUTH_SUCCEEDED), "succeeded");
+        assert_eq!(auth_outcome(ReplyCode::AUTH_FAILED), "failed");
+        assert_eq!(auth_outcome(ReplyCode::NOT_IMPLEMENTED), "rejected");
+    }
+
+    #[test]
+    #[cfg(feature = "detailed-stats")]
+    fn should_render_tagged_name() {
+        assert_eq!(
+            tagged_name(
+                "smtp.command.{verb}.reply.{reply_code}.total",
+                &[("verb", "EHLO"), ("reply_code", "250")]
+            ),
+            "smtp.command.reply.total.smtp_verb=.=EHLO;.;smtp_reply_code=.=250;.;"
+        );
     }
 }
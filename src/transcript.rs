@@ -0,0 +1,146 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Capture of command and reply transcripts of SMTP sessions.
+
+use bstr::ByteSlice;
+
+/// Party of an SMTP session whose data is recorded.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Party {
+    Client,
+    Server,
+}
+
+impl Party {
+    fn prefix(self) -> &'static [u8] {
+        match self {
+            Party::Client => b"C: ",
+            Party::Server => b"S: ",
+        }
+    }
+}
+
+/// Transcript of an SMTP session with mail data elided, e.g.
+///
+/// ```text
+/// S: 220 mx.example.org ESMTP
+/// C: EHLO client.example.org
+/// ```
+pub struct Transcript {
+    // Recorded lines, each prefixed with the party that has sent it.
+    text: Vec<u8>,
+    // Limit on the size of the transcript.
+    max_bytes: usize,
+    // Whether the transcript has been cut short of the limit.
+    truncated: bool,
+    // Party whose line has not been terminated yet, if any.
+    open_line: Option<Party>,
+    // Size of mail data that has been elided since the latest line.
+    elided: usize,
+}
+
+impl Transcript {
+    pub fn new(max_bytes: usize) -> Self {
+        Transcript {
+            text: Vec::new(),
+            max_bytes,
+            truncated: false,
+            open_line: None,
+            elided: 0,
+        }
+    }
+
+    /// Records commands or replies sent by a given party.
+    pub fn record(&mut self, party: Party, data: &[u8]) {
+        self.flush_elided();
+        for line in data.split_inclusive(|&octet| octet == b'\n') {
+            if self.open_line != Some(party) {
+                if self.open_line.is_some() {
+                    self.append(b"\n");
+                }
+                self.append(party.prefix());
+            }
+            let terminated = line.ends_with(b"\n");
+            let content = line.trim_end_with(|c| c == '\r' || c == '\n');
+            self.append(content);
+            if terminated {
+                self.append(b"\n");
+                self.open_line = None;
+            } else {
+                self.open_line = Some(party);
+            }
+        }
+    }
+
+    /// Accounts for mail data sent by SMTP client without recording it.
+    pub fn elide(&mut self, size: usize) {
+        self.elided += size;
+    }
+
+    /// Returns the transcript as text.
+    pub fn finish(mut self) -> String {
+        self.flush_elided();
+        if self.open_line.is_some() {
+            self.append(b"\n");
+        }
+        if self.truncated {
+            self.text.extend_from_slice(b"[truncated]\n");
+        }
+        self.text.to_str_lossy().into_owned()
+    }
+
+    fn flush_elided(&mut self) {
+        if self.elided > 0 {
+            let note = format!("C: [{} octets of mail data]\n", self.elided);
+            self.elided = 0;
+            self.append(note.as_bytes());
+        }
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        let room = self.max_bytes.saturating_sub(self.text.len());
+        if data.len() > room {
+            self.truncated = true;
+        }
+        self.text.extend_from_slice(&data[..data.len().min(room)]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_record_transcript() {
+        let mut transcript = Transcript::new(1024);
+        transcript.record(Party::Server, b"220 mx.example.org ESMTP\r\n");
+        transcript.record(Party::Client, b"DATA\r");
+        transcript.record(Party::Client, b"\n");
+        transcript.record(Party::Server, b"354 go ahead\r\n");
+        transcript.elide(12);
+        transcript.record(Party::Server, b"250 OK\r\n");
+
+        assert_eq!(
+            transcript.finish(),
+            "S: 220 mx.example.org ESMTP\nC: DATA\nS: 354 go ahead\nC: [12 octets of mail data]\nS: 250 OK\n"
+        );
+
+        let mut transcript = Transcript::new(8);
+        transcript.record(Party::Client, b"QUIT\r\n");
+        transcript.record(Party::Server, b"221 bye\r\n");
+
+        assert_eq!(transcript.finish(), "C: QUIT\n[truncated]\n");
+    }
+}
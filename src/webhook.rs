@@ -0,0 +1,66 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Notification of external services on completed mail transactions.
+
+use std::collections::HashSet;
+
+use envoy::extension::Result;
+use envoy::host::{log, HttpClient, HttpClientRequestHandle};
+
+use crate::config::WebhookConfig;
+
+/// Client of a transaction webhook.
+///
+/// Unlike policy requests, notifications never hold back the traffic.
+pub struct WebhookClient<'a> {
+    // HTTP Client API implementation.
+    http_client: &'a dyn HttpClient,
+    // Notifications in flight.
+    pending: HashSet<HttpClientRequestHandle>,
+}
+
+impl<'a> WebhookClient<'a> {
+    pub fn new(http_client: &'a dyn HttpClient) -> Self {
+        WebhookClient {
+            http_client,
+            pending: HashSet::new(),
+        }
+    }
+
+    /// Sends a JSON summary of a mail transaction.
+    pub fn notify(&mut self, config: &WebhookConfig, body: &str) -> Result<()> {
+        log::debug!("sending transaction summary: {}", body);
+        let headers = [
+            (":method", "POST"),
+            (":path", &config.path),
+            (":authority", &config.cluster),
+            ("content-type", "application/json"),
+        ];
+        let request = self.http_client.send_request(
+            &config.cluster,
+            &headers,
+            Some(body.as_bytes()),
+            None,
+            config.timeout(),
+        )?;
+        self.pending.insert(request);
+        Ok(())
+    }
+
+    /// Returns `true` if a given response belongs to a notification.
+    pub fn on_response(&mut self, request: HttpClientRequestHandle) -> bool {
+        self.pending.remove(&request)
+    }
+}
@@ -0,0 +1,409 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Replays golden transcripts of SMTP sessions under `tests/transcripts`.
+//!
+//! Every `<name>.txt` transcript is driven through a `Session`, and a report
+//! of modes, mail transactions and stats of the session is compared against
+//! `<name>.golden`. Run with `UPDATE_GOLDEN=1` to (re)write golden reports.
+
+#![cfg(feature = "std")]
+
+use std::cell::RefCell;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::Result;
+use bstr::ByteSlice;
+
+use envoy_smtp_filter::smtp::agent::{
+    Event, FallbackAction, Handshake, LocalReplies, Session, SessionConfig, SessionSummary,
+    StatsSink, Transcript,
+};
+use envoy_smtp_filter::smtp::error::ErrorCategory;
+use envoy_smtp_filter::smtp::spec::core::{Capability, ReplyCode};
+
+/// Records stats of a session in the order they have been reported.
+#[derive(Default)]
+struct RecordingSink {
+    records: RefCell<Vec<String>>,
+}
+
+impl RecordingSink {
+    fn record(&self, record: String) -> Result<()> {
+        self.records.borrow_mut().push(record);
+        Ok(())
+    }
+}
+
+impl StatsSink for RecordingSink {
+    fn on_smtp_connect(&self) -> Result<()> {
+        self.record("connect".to_owned())
+    }
+
+    fn on_smtp_connect_reply(&self, code: ReplyCode) -> Result<()> {
+        self.record(format!("connect_reply {}", code))
+    }
+
+    fn on_smtp_command(&self, verb: &str) -> Result<()> {
+        self.record(format!("command {}", verb))
+    }
+
+    fn on_smtp_handshake(&self, handshake: Handshake, fallback: bool) -> Result<()> {
+        self.record(format!("handshake {:?} fallback={}", handshake, fallback))
+    }
+
+    fn on_smtp_command_reply(&self, verb: &str, code: ReplyCode) -> Result<()> {
+        self.record(format!("command_reply {} {}", verb, code))
+    }
+
+    fn on_smtp_recipient_reply(&self, domain: &[u8], code: ReplyCode) -> Result<()> {
+        self.record(format!("recipient_reply {} {}", domain.as_bstr(), code))
+    }
+
+    fn on_smtp_ehlo_capabilities(&self, capabilities: &[Capability]) -> Result<()> {
+        let keywords = capabilities
+            .iter()
+            .map(Capability::keyword)
+            .collect::<Vec<_>>();
+        self.record(format!("ehlo_capabilities {}", keywords.join(",")))
+    }
+
+    fn on_smtp_auth_reply(&self, mechanism: &str, code: ReplyCode) -> Result<()> {
+        self.record(format!("auth_reply {} {}", mechanism, code))
+    }
+
+    fn on_smtp_auth_error_report(&self, mechanism: &str) -> Result<()> {
+        self.record(format!("auth_error_report {}", mechanism))
+    }
+
+    fn on_smtp_auth_throttled(&self) -> Result<()> {
+        self.record("auth_throttled".to_owned())
+    }
+
+    fn on_smtp_burl(&self) -> Result<()> {
+        self.record("burl".to_owned())
+    }
+
+    fn on_smtp_legacy_command(&self, verb: &str) -> Result<()> {
+        self.record(format!("legacy_command {}", verb))
+    }
+
+    fn on_smtp_postmaster_recipient(&self) -> Result<()> {
+        self.record("postmaster_recipient".to_owned())
+    }
+
+    fn on_smtp_verp_sender(&self) -> Result<()> {
+        self.record("verp_sender".to_owned())
+    }
+
+    fn on_smtp_recipient_rewrite_failure(&self) -> Result<()> {
+        self.record("recipient_rewrite_failure".to_owned())
+    }
+
+    fn on_smtp_bounce(&self) -> Result<()> {
+        self.record("bounce".to_owned())
+    }
+
+    fn on_smtp_delivery_reply(&self, domain: &[u8], code: ReplyCode) -> Result<()> {
+        self.record(format!("delivery_reply {} {}", domain.as_bstr(), code))
+    }
+
+    fn on_smtp_transaction_commit(&self) -> Result<()> {
+        self.record("transaction_commit".to_owned())
+    }
+
+    fn on_smtp_transaction_commit_reply(&self, code: ReplyCode) -> Result<()> {
+        self.record(format!("transaction_commit_reply {}", code))
+    }
+
+    fn on_smtp_authenticated_transaction_commit_reply(&self, code: ReplyCode) -> Result<()> {
+        self.record(format!("authenticated_transaction_commit_reply {}", code))
+    }
+
+    fn on_smtp_starttls_upgrade(&self) -> Result<()> {
+        self.record("starttls_upgrade".to_owned())
+    }
+
+    fn on_smtp_implicit_tls(&self) -> Result<()> {
+        self.record("implicit_tls".to_owned())
+    }
+
+    fn on_smtp_not_smtp(&self) -> Result<()> {
+        self.record("not_smtp".to_owned())
+    }
+
+    fn on_smtp_transaction_abort(&self) -> Result<()> {
+        self.record("transaction_abort".to_owned())
+    }
+
+    // Timing depends on the clock, so only the fact of it is recorded.
+    fn on_smtp_transaction_timing(&self, _: Duration, _: Duration) -> Result<()> {
+        self.record("transaction_timing".to_owned())
+    }
+
+    fn on_smtp_reply_code_mismatch(&self) -> Result<()> {
+        self.record("reply_code_mismatch".to_owned())
+    }
+
+    fn on_smtp_uncorrelated_reply(&self, code: ReplyCode) -> Result<()> {
+        self.record(format!("uncorrelated_reply {}", code))
+    }
+
+    fn on_smtp_reply_correlation_error(&self) -> Result<()> {
+        self.record("reply_correlation_error".to_owned())
+    }
+
+    fn on_smtp_reply_rewrite(&self, rule: &str) -> Result<()> {
+        self.record(format!("reply_rewrite {}", rule))
+    }
+
+    fn on_smtp_parse_error(
+        &self,
+        action: FallbackAction,
+        category: Option<ErrorCategory>,
+    ) -> Result<()> {
+        self.record(format!("parse_error {:?} {:?}", action, category))
+    }
+
+    fn on_smtp_drain(&self) -> Result<()> {
+        self.record("drain".to_owned())
+    }
+
+    fn on_smtp_local_reply_fallback_close(&self) -> Result<()> {
+        self.record("local_reply_fallback_close".to_owned())
+    }
+
+    fn on_smtp_list_denied(&self, verb: &str) -> Result<()> {
+        self.record(format!("list_denied {}", verb))
+    }
+
+    fn on_smtp_connection_close(&self, graceful: bool) -> Result<()> {
+        self.record(format!("connection_close graceful={}", graceful))
+    }
+
+    fn on_smtp_session_end(&self, summary: &SessionSummary) -> Result<()> {
+        self.record(format!(
+            "session_end messages={} rcpt_count={}",
+            summary.messages, summary.rcpt_count
+        ))
+    }
+}
+
+// Replays a transcript and renders a report of the session.
+fn replay(transcript: &Transcript) -> Result<String> {
+    let config = SessionConfig {
+        transaction_events: true,
+        ..Default::default()
+    };
+    let mut session = Session::new(config, RecordingSink::default());
+    session.set_connection_id("golden".to_owned());
+    session.set_time(UNIX_EPOCH);
+    let modes = transcript.replay(&mut session)?;
+
+    let mut report = String::new();
+    writeln!(report, "# modes")?;
+    for (line, mode) in transcript.lines().iter().zip(modes) {
+        let data = line.data.trim_end_with(|c| c == '\r' || c == '\n');
+        writeln!(
+            report,
+            "{:?} {} -> {:?}",
+            line.direction,
+            data.as_bstr(),
+            mode
+        )?;
+    }
+    writeln!(report, "# transactions")?;
+    for event in session.take_events() {
+        if let Event::Transaction(transaction) = event {
+            let to = transaction
+                .to
+                .iter()
+                .map(|to| to.to_string())
+                .collect::<Vec<_>>();
+            let identity = transaction
+                .auth_identity
+                .map(|identity| format!(" identity={}", identity))
+                .unwrap_or_default();
+            let submitter = transaction
+                .submitter
+                .map(|submitter| format!(" submitter={}", submitter))
+                .unwrap_or_default();
+            writeln!(
+                report,
+                "{} from={} to=[{}] size={} reply={} authenticated={}{}{}",
+                transaction.id,
+                transaction.from,
+                to.join(","),
+                transaction.size,
+                transaction.reply_code,
+                transaction.authenticated,
+                identity,
+                submitter
+            )?;
+        }
+    }
+    writeln!(report, "# stats")?;
+    for record in session.stats_sink().records.borrow().iter() {
+        writeln!(report, "{}", record)?;
+    }
+    Ok(report)
+}
+
+#[test]
+fn should_replay_golden_transcripts() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/transcripts");
+    let update = env::var_os("UPDATE_GOLDEN").is_some();
+    let mut paths = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "txt"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert!(!paths.is_empty(), "no transcripts in {}", dir.display());
+
+    for path in paths {
+        let transcript = Transcript::parse(&fs::read(&path).unwrap()).unwrap();
+        let report = replay(&transcript)
+            .unwrap_or_else(|err| panic!("failed to replay {}: {}", path.display(), err));
+        let golden = path.with_extension("golden");
+        if update {
+            fs::write(&golden, &report).unwrap();
+            continue;
+        }
+        let expected = fs::read_to_string(&golden)
+            .unwrap_or_else(|err| panic!("failed to read {}: {}", golden.display(), err));
+        assert_eq!(report, expected, "report of {}", path.display());
+    }
+}
+
+#[test]
+fn should_throttle_auth_after_repeated_failures() {
+    let config = SessionConfig {
+        max_auth_failures: Some(1),
+        local_replies: LocalReplies {
+            auth_throttled: Some("535 5.7.8 Too many authentication failures".to_owned()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut session = Session::new(config, RecordingSink::default());
+    session.on_new_conection().unwrap();
+    for (client, data) in [
+        (false, &b"220 mail.example.org ESMTP\r\n"[..]),
+        (true, b"EHLO client.example.org\r\n"),
+        (false, b"250-mail.example.org\r\n250 AUTH PLAIN\r\n"),
+        (true, b"AUTH PLAIN AHVzZXIAd3Jvbmc=\r\n"),
+        (false, b"535 5.7.8 Authentication credentials invalid\r\n"),
+    ] {
+        if client {
+            session.on_downstream_data(data.into()).unwrap();
+        } else {
+            session.on_upstream_data(data.into()).unwrap();
+        }
+    }
+    session.take_downstream_edits();
+    session.take_upstream_edits();
+
+    let auth = b"AUTH PLAIN AHVzZXIAc2VjcmV0\r\n";
+    session.on_downstream_data(auth.as_slice().into()).unwrap();
+    assert_eq!(
+        session.take_downstream_edits().apply(auth).as_bstr(),
+        "NOOP\r\n"
+    );
+    let reply = b"250 2.0.0 OK\r\n";
+    session.on_upstream_data(reply.as_slice().into()).unwrap();
+    assert_eq!(
+        session.take_upstream_edits().apply(reply).as_bstr(),
+        "535 5.7.8 Too many authentication failures\r\n"
+    );
+    assert!(!session.take_close_request());
+    assert!(session
+        .stats_sink()
+        .records
+        .borrow()
+        .contains(&"auth_throttled".to_owned()));
+}
+
+#[test]
+fn should_not_throttle_auth_after_unsupported_mechanism() {
+    let config = SessionConfig {
+        max_auth_failures: Some(1),
+        ..Default::default()
+    };
+    let mut session = Session::new(config, RecordingSink::default());
+    session.on_new_conection().unwrap();
+    for (client, data) in [
+        (false, &b"220 mail.example.org ESMTP\r\n"[..]),
+        (true, b"EHLO client.example.org\r\n"),
+        (false, b"250-mail.example.org\r\n250 AUTH PLAIN\r\n"),
+        (true, b"AUTH CRAM-MD5\r\n"),
+        (false, b"504 5.5.4 Unrecognized authentication type\r\n"),
+    ] {
+        if client {
+            session.on_downstream_data(data.into()).unwrap();
+        } else {
+            session.on_upstream_data(data.into()).unwrap();
+        }
+    }
+    session.take_downstream_edits();
+
+    let auth = b"AUTH PLAIN AHVzZXIAc2VjcmV0\r\n";
+    session.on_downstream_data(auth.as_slice().into()).unwrap();
+    assert_eq!(
+        session.take_downstream_edits().apply(auth).as_bstr(),
+        auth.as_bstr()
+    );
+    assert!(!session
+        .stats_sink()
+        .records
+        .borrow()
+        .contains(&"auth_throttled".to_owned()));
+}
+
+#[test]
+fn should_count_verp_senders() {
+    let config = SessionConfig {
+        verp_patterns: vec!["bounce-*=*@lists.example.org".to_owned()],
+        ..Default::default()
+    };
+    let mut session = Session::new(config, RecordingSink::default());
+    session.on_new_conection().unwrap();
+    for (client, data) in [
+        (false, &b"220 mail.example.org ESMTP\r\n"[..]),
+        (true, b"HELO lists.example.org\r\n"),
+        (false, b"250 mail.example.org\r\n"),
+        (
+            true,
+            b"MAIL FROM:<Bounce-bob=example.com@lists.example.org>\r\n",
+        ),
+        (false, b"250 Ok\r\n"),
+        (true, b"RSET\r\n"),
+        (false, b"250 Ok\r\n"),
+        (true, b"MAIL FROM:<owner@lists.example.org>\r\n"),
+        (false, b"250 Ok\r\n"),
+    ] {
+        if client {
+            session.on_downstream_data(data.into()).unwrap();
+        } else {
+            session.on_upstream_data(data.into()).unwrap();
+        }
+    }
+    let records = session.stats_sink().records.borrow();
+    let verp_senders = records.iter().filter(|r| *r == "verp_sender").count();
+    assert_eq!(verp_senders, 1);
+}
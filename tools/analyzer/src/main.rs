@@ -0,0 +1,400 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Feeds a captured SMTP session through the SMTP session state machine of
+//! the filter and prints what the filter would make of it, i.e. mail
+//! transactions, protocol violations and stats.
+//!
+//! ```shell
+//! smtp-analyzer [--tshark] <FILE | ->
+//! ```
+//!
+//! A transcript is either in the text form, i.e. with lines sent by SMTP
+//! client prefixed with `C:` and lines sent by SMTP server prefixed with `S:`,
+//! or, with `--tshark`, the output of `tshark -q -z follow,tcp,raw,<stream>`
+//! on a capture, in which case chunks of data are fed as they were captured
+//! and the node that has opened the connection is taken for SMTP client.
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+use std::rc::Rc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use bstr::ByteSlice;
+
+use envoy_smtp_filter::smtp::agent::{
+    Direction, Event, FallbackAction, Handshake, Mode, Session, SessionConfig, SessionSummary,
+    StatsSink, Transcript, TranscriptLine,
+};
+use envoy_smtp_filter::smtp::error::ErrorCategory;
+use envoy_smtp_filter::smtp::spec::core::{Capability, ReplyCode};
+
+/// Analysis collects stats and violations reported by a session, along with
+/// the line of the transcript they have been reported on.
+#[derive(Default)]
+struct Analysis {
+    line: Cell<usize>,
+    stats: RefCell<BTreeMap<String, u64>>,
+    violations: RefCell<Vec<(usize, String)>>,
+}
+
+impl Analysis {
+    fn count(&self, stat: String) -> Result<()> {
+        *self.stats.borrow_mut().entry(stat).or_default() += 1;
+        Ok(())
+    }
+
+    fn violation(&self, violation: String) -> Result<()> {
+        self.violations
+            .borrow_mut()
+            .push((self.line.get(), violation));
+        Ok(())
+    }
+}
+
+impl StatsSink for Analysis {
+    fn on_smtp_connect(&self) -> Result<()> {
+        self.count("connects".to_owned())
+    }
+
+    fn on_smtp_connect_reply(&self, code: ReplyCode) -> Result<()> {
+        self.count(format!("connects.replies.{}", code))
+    }
+
+    fn on_smtp_command(&self, verb: &str) -> Result<()> {
+        self.count(format!("commands.{}", verb))
+    }
+
+    fn on_smtp_handshake(&self, handshake: Handshake, fallback: bool) -> Result<()> {
+        let fallback = if fallback { ".fallback" } else { "" };
+        self.count(format!("handshakes.{:?}{}", handshake, fallback))
+    }
+
+    fn on_smtp_command_reply(&self, verb: &str, code: ReplyCode) -> Result<()> {
+        self.count(format!("commands.{}.replies.{}", verb, code))
+    }
+
+    fn on_smtp_recipient_reply(&self, domain: &[u8], code: ReplyCode) -> Result<()> {
+        self.count(format!("recipients.{}.replies.{}", domain.as_bstr(), code))
+    }
+
+    fn on_smtp_ehlo_capabilities(&self, capabilities: &[Capability]) -> Result<()> {
+        for capability in capabilities {
+            self.count(format!("ehlo.capabilities.{}", capability.keyword()))?;
+        }
+        Ok(())
+    }
+
+    fn on_smtp_auth_reply(&self, mechanism: &str, code: ReplyCode) -> Result<()> {
+        self.count(format!("auth.{}.replies.{}", mechanism, code))
+    }
+
+    fn on_smtp_auth_error_report(&self, mechanism: &str) -> Result<()> {
+        self.count(format!("auth.{}.error_reports", mechanism))
+    }
+
+    fn on_smtp_auth_throttled(&self) -> Result<()> {
+        self.count("auth.throttled".to_owned())
+    }
+
+    fn on_smtp_burl(&self) -> Result<()> {
+        self.count("burl".to_owned())
+    }
+
+    fn on_smtp_legacy_command(&self, verb: &str) -> Result<()> {
+        self.count(format!("commands.legacy.{}", verb))
+    }
+
+    fn on_smtp_postmaster_recipient(&self) -> Result<()> {
+        self.count("rcpt.postmaster".to_owned())
+    }
+
+    fn on_smtp_verp_sender(&self) -> Result<()> {
+        self.count("mail.verp".to_owned())
+    }
+
+    fn on_smtp_recipient_rewrite_failure(&self) -> Result<()> {
+        self.count("rcpt.rewrites.failed".to_owned())
+    }
+
+    fn on_smtp_bounce(&self) -> Result<()> {
+        self.count("bounces".to_owned())
+    }
+
+    fn on_smtp_delivery_reply(&self, domain: &[u8], code: ReplyCode) -> Result<()> {
+        self.count(format!("deliveries.{}.replies.{}", domain.as_bstr(), code))
+    }
+
+    fn on_smtp_transaction_commit(&self) -> Result<()> {
+        self.count("transactions.commits".to_owned())
+    }
+
+    fn on_smtp_transaction_commit_reply(&self, code: ReplyCode) -> Result<()> {
+        self.count(format!("transactions.commits.replies.{}", code))
+    }
+
+    fn on_smtp_authenticated_transaction_commit_reply(&self, code: ReplyCode) -> Result<()> {
+        self.count(format!(
+            "transactions.commits.authenticated.replies.{}",
+            code
+        ))
+    }
+
+    fn on_smtp_starttls_upgrade(&self) -> Result<()> {
+        self.count("starttls.upgrades".to_owned())
+    }
+
+    fn on_smtp_implicit_tls(&self) -> Result<()> {
+        self.count("connections.implicit_tls".to_owned())?;
+        self.violation("TLS handshake instead of SMTP".to_owned())
+    }
+
+    fn on_smtp_not_smtp(&self) -> Result<()> {
+        self.count("connections.not_smtp".to_owned())?;
+        self.violation("traffic is not SMTP".to_owned())
+    }
+
+    fn on_smtp_transaction_abort(&self) -> Result<()> {
+        self.count("transactions.aborts".to_owned())
+    }
+
+    fn on_smtp_transaction_timing(&self, _: Duration, _: Duration) -> Result<()> {
+        Ok(()) // timing of a capture is not known
+    }
+
+    fn on_smtp_reply_code_mismatch(&self) -> Result<()> {
+        self.count("replies.code_mismatches".to_owned())?;
+        self.violation("reply code does not match the pending command".to_owned())
+    }
+
+    fn on_smtp_uncorrelated_reply(&self, code: ReplyCode) -> Result<()> {
+        self.count(format!("replies.uncorrelated.{}", code))?;
+        self.violation(format!("reply {} is not expected by the command", code))
+    }
+
+    fn on_smtp_reply_correlation_error(&self) -> Result<()> {
+        self.count("replies.correlation_errors".to_owned())?;
+        self.violation("reply without a pending command".to_owned())
+    }
+
+    fn on_smtp_reply_rewrite(&self, rule: &str) -> Result<()> {
+        self.count(format!("replies.rewrites.{}", rule))
+    }
+
+    fn on_smtp_parse_error(
+        &self,
+        action: FallbackAction,
+        category: Option<ErrorCategory>,
+    ) -> Result<()> {
+        self.count(format!("parse_errors.{:?}", action).to_lowercase())?;
+        match category {
+            Some(category) => self.violation(format!(
+                "{:?} error, falling back to {:?}",
+                category, action
+            )),
+            None => self.violation(format!("error, falling back to {:?}", action)),
+        }
+    }
+
+    fn on_smtp_drain(&self) -> Result<()> {
+        self.count("connections.drained".to_owned())
+    }
+
+    fn on_smtp_local_reply_fallback_close(&self) -> Result<()> {
+        self.count("local_replies.fallback_close".to_owned())
+    }
+
+    fn on_smtp_list_denied(&self, verb: &str) -> Result<()> {
+        self.count(format!("lists.denied.{}", verb))
+    }
+
+    fn on_smtp_connection_close(&self, graceful: bool) -> Result<()> {
+        let graceful = if graceful { "graceful" } else { "ungraceful" };
+        self.count(format!("connections.closed.{}", graceful))
+    }
+
+    fn on_smtp_session_end(&self, _summary: &SessionSummary) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Parses the output of `tshark -q -z follow,tcp,raw,<stream>`, where chunks
+// sent by the node that has accepted the connection are indented with a tab.
+fn parse_tshark(text: &[u8]) -> Result<Vec<TranscriptLine>> {
+    let mut lines = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let (direction, hex) = match line.strip_prefix(b"\t") {
+            Some(hex) => (Direction::Server, hex),
+            None => (Direction::Client, line),
+        };
+        if hex.is_empty() || !hex.iter().all(u8::is_ascii_hexdigit) {
+            continue; // separators and headers, e.g. `Node 0: 192.0.2.1:40000`
+        }
+        if hex.len() % 2 != 0 {
+            bail!("line {} has an odd number of hex digits", index + 1);
+        }
+        let data = hex
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(pair.to_str().unwrap(), 16))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        lines.push(TranscriptLine { direction, data });
+    }
+    Ok(lines)
+}
+
+fn analyze(lines: &[TranscriptLine]) -> Result<()> {
+    let analysis = Rc::new(Analysis::default());
+    let config = SessionConfig {
+        transaction_events: true,
+        ..Default::default()
+    };
+    let mut session = Session::new(config, Rc::clone(&analysis));
+    session.set_connection_id("transcript".to_owned());
+    session.on_new_conection()?;
+
+    println!("# modes");
+    let mut mode = session.mode();
+    for (index, line) in lines.iter().enumerate() {
+        analysis.line.set(index + 1);
+        let result = match line.direction {
+            Direction::Client => session.on_downstream_data(line.data.clone().into()),
+            Direction::Server => session.on_upstream_data(line.data.clone().into()),
+        };
+        if let Err(err) = result {
+            analysis.violation(format!("session has failed: {}", err))?;
+            break;
+        }
+        if session.mode() != mode {
+            let data = line.data.trim_end_with(|c| c == '\r' || c == '\n');
+            println!(
+                "{:>4} {:?} {} -> {:?}",
+                index + 1,
+                line.direction,
+                data.as_bstr(),
+                session.mode()
+            );
+            mode = session.mode();
+        }
+        if mode == Mode::PassThrough {
+            break; // nothing is interpreted anymore
+        }
+    }
+    analysis.line.set(lines.len());
+    session.on_connection_closed()?;
+
+    println!("# transactions");
+    for event in session.take_events() {
+        if let Event::Transaction(transaction) = event {
+            let to = transaction
+                .to
+                .iter()
+                .map(|to| to.to_string())
+                .collect::<Vec<_>>();
+            let identity = transaction
+                .auth_identity
+                .map(|identity| format!(" identity={}", identity))
+                .unwrap_or_default();
+            let submitter = transaction
+                .submitter
+                .map(|submitter| format!(" submitter={}", submitter))
+                .unwrap_or_default();
+            println!(
+                "{} from={} to=[{}] size={} reply={} authenticated={}{}{}",
+                transaction.id,
+                transaction.from,
+                to.join(","),
+                transaction.size,
+                transaction.reply_code,
+                transaction.authenticated,
+                identity,
+                submitter
+            );
+        }
+    }
+    println!("# violations");
+    for (line, violation) in analysis.violations.borrow().iter() {
+        println!("{:>4} {}", line, violation);
+    }
+    println!("# stats");
+    for (stat, value) in analysis.stats.borrow().iter() {
+        println!("{} {}", stat, value);
+    }
+    Ok(())
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let (tshark, path) = match args {
+        [path] => (false, path),
+        [flag, path] if flag == "--tshark" => (true, path),
+        _ => bail!("usage: smtp-analyzer [--tshark] <FILE | ->"),
+    };
+    let text = if path == "-" {
+        let mut text = Vec::new();
+        io::stdin().read_to_end(&mut text)?;
+        text
+    } else {
+        fs::read(path).with_context(|| format!("failed to read {}", path))?
+    };
+    let lines = if tshark {
+        parse_tshark(&text)?
+    } else {
+        Transcript::parse(&text)?.lines().to_vec()
+    };
+    analyze(&lines)
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if let Err(err) = run(&args) {
+        eprintln!("{:#}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_tshark_output() {
+        let text = b"\
+===================================================================
+Follow: tcp,raw
+Filter: tcp.stream eq 0
+Node 0: 192.0.2.1:40000
+Node 1: 192.0.2.25:25
+\t3232300d0a
+4e4f4f500d0a
+===================================================================
+";
+        assert_eq!(
+            parse_tshark(text).unwrap(),
+            vec![
+                TranscriptLine {
+                    direction: Direction::Server,
+                    data: b"220\r\n".to_vec(),
+                },
+                TranscriptLine {
+                    direction: Direction::Client,
+                    data: b"NOOP\r\n".to_vec(),
+                },
+            ]
+        );
+    }
+}
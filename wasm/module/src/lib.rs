@@ -1,6 +1,6 @@
 use envoy::extension::{entrypoint, Module, Result};
 
-use envoy_smtp_filter::SmtpFilterFactory;
+use envoy_smtp_filter::{SmtpAccessLogger, SmtpEventAggregator, SmtpFilterFactory};
 
 // Generate the `_start` function that will be called by `Envoy` to let
 // WebAssembly module initialize itself.
@@ -10,7 +10,11 @@ entrypoint! { initialize }
 ///
 /// Returns a registry of extensions provided by this module.
 fn initialize() -> Result<Module> {
-    Module::new().add_network_filter(|_instance_id| SmtpFilterFactory::default())
+    Module::new()
+        .add_network_filter(|_instance_id| SmtpFilterFactory::builder().build())?
+        .add_network_filter(|_instance_id| SmtpFilterFactory::builder().build_enforcing())?
+        .add_access_logger(|_instance_id| Ok(SmtpAccessLogger))?
+        .add_service(|_instance_id| SmtpEventAggregator::default())
 }
 
 #[cfg(test)]